@@ -1,6 +1,9 @@
-use ecash_client::Wallet;
+use ecash_client::{Amount, CoinSelector, Decomposition, KeyPinPolicy, KeySource, Wallet, WalletSeed};
 use std::io::{self, Write};
 
+/// Tokens that stop being redeemable within this many days are renewed.
+const RENEW_WITHIN_DAYS: i64 = 7;
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     println!("=================================");
@@ -17,19 +20,84 @@ async fn main() -> anyhow::Result<()> {
     println!("Database: {}\n", db_path);
 
     let mut wallet = Wallet::new(server_url, db_path)?;
+    match std::env::var("ECASH_API_KEY") {
+        Ok(api_key) => wallet = wallet.with_api_key(api_key),
+        Err(_) => println!("ECASH_API_KEY not set; withdrawals will be rejected\n"),
+    }
+    match std::env::var("ECASH_MNEMONIC") {
+        Ok(phrase) => {
+            let passphrase = std::env::var("ECASH_MNEMONIC_PASSPHRASE").unwrap_or_default();
+            wallet = wallet.with_seed(WalletSeed::from_mnemonic(&phrase, &passphrase)?);
+        }
+        Err(_) => {
+            println!("ECASH_MNEMONIC not set; tokens are lost with the database");
+            println!("A new seed phrase you could use:\n  {}\n", WalletSeed::generate_mnemonic());
+        }
+    }
+    
+    match std::env::var("ECASH_WALLET_PASSPHRASE") {
+        Ok(passphrase) if wallet.is_encrypted() => wallet.unlock(&passphrase)?,
+        Ok(passphrase) => {
+            wallet.encrypt(&passphrase)?;
+            println!("✓ Wallet encrypted with ECASH_WALLET_PASSPHRASE\n");
+        }
+        Err(_) if wallet.is_encrypted() => println!("Wallet is locked; choose 7 to unlock it\n"),
+        Err(_) => println!("ECASH_WALLET_PASSPHRASE not set; tokens are stored unencrypted\n"),
+    }
+    
+    if let Ok(url) = std::env::var("ECASH_KEY_MIRROR") {
+        wallet = wallet.with_key_source(KeySource::Mirror(url));
+    } else if let Ok(path) = std::env::var("ECASH_KEY_DIRECTORY") {
+        wallet = wallet.with_key_source(KeySource::Directory(path.into()));
+    }
+    if std::env::var("ECASH_KEY_PIN_POLICY").is_ok_and(|policy| policy == "warn") {
+        wallet = wallet.with_key_pin_policy(KeyPinPolicy::Warn);
+    }
+    if let Ok(fingerprint) = std::env::var("ECASH_IDENTITY_KEY") {
+        wallet = wallet.with_identity_key(fingerprint);
+    }
     
     println!("Initializing wallet...");
     wallet.initialize().await?;
     println!("✓ Wallet initialized\n");
+    if wallet.key_directory().is_none() {
+        println!("⚠ The server publishes no signed key directory\n");
+    }
+    for change in wallet.key_changes() {
+        println!("⚠ Key set {} has changed since it was pinned", change.key_id);
+        println!("  pinned {}\n  served {}\n", change.pinned, change.served);
+    }
+    if !wallet.is_locked() {
+        recover_pending(&wallet).await?;
+    }
 
     loop {
+        // Keys rotate and tokens expire while the wallet stays open.
+        if wallet.refresh_keys().await? {
+            println!("\n✓ Keys refreshed");
+        }
+        if !wallet.is_locked() {
+            renew_expiring(&wallet).await?;
+        }
+        
+
         println!("\n--- Menu ---");
         println!("1. Check balance");
         println!("2. Withdraw tokens");
         println!("3. Spend tokens");
         println!("4. List tokens");
         println!("5. Health check");
-        println!("6. Exit");
+        println!("6. Restore from seed phrase");
+        let lock_action = if !wallet.is_encrypted() {
+            "Encrypt"
+        } else if wallet.is_locked() {
+            "Unlock"
+        } else {
+            "Lock"
+        };
+        println!("7. {} wallet", lock_action);
+        println!("8. Change passphrase");
+        println!("9. Exit");
         print!("\nChoice: ");
         io::stdout().flush()?;
 
@@ -43,7 +111,10 @@ async fn main() -> anyhow::Result<()> {
             "3" => spend_tokens(&wallet).await?,
             "4" => list_tokens(&wallet).await?,
             "5" => health_check(&wallet).await?,
-            "6" => {
+            "6" => restore_tokens(&wallet).await?,
+            "7" => toggle_lock(&mut wallet).await?,
+            "8" => change_passphrase(&mut wallet)?,
+            "9" => {
                 println!("Goodbye!");
                 break;
             }
@@ -55,40 +126,80 @@ async fn main() -> anyhow::Result<()> {
 }
 
 async fn check_balance(wallet: &Wallet) -> anyhow::Result<()> {
-    let balance = wallet.get_balance()?;
-    println!("\n💰 Balance: ${}", balance);
+    let balances = wallet.get_balances()?;
+    if balances.is_empty() {
+        println!("\n💰 Balance: 0");
+    }
+    for balance in balances.values() {
+        println!("\n💰 Balance: {}", balance);
+    }
+    if let Ok(account_balance) = wallet.get_account_balance().await {
+        println!("🏦 Account: {}", account_balance);
+    }
     Ok(())
 }
 
-async fn withdraw_tokens(wallet: &Wallet) -> anyhow::Result<()> {
-    print!("Amount: $");
+/// Asks for a currency, defaulting to the institution's default one.
+fn read_currency(wallet: &Wallet) -> anyhow::Result<String> {
+    print!("Currency ({}) [{}]: ", wallet.currencies().join("/"), wallet.default_currency());
+    io::stdout().flush()?;
+    let mut currency = String::new();
+    io::stdin().read_line(&mut currency)?;
+    let currency = currency.trim().to_uppercase();
+    if currency.is_empty() {
+        Ok(wallet.default_currency().to_string())
+    } else {
+        Ok(currency)
+    }
+}
+
+/// Asks for an amount of `currency` in major units, such as `12.50`.
+fn read_amount(wallet: &Wallet, currency: &str, prompt: &str) -> anyhow::Result<Amount> {
+    print!("{} in {}: ", prompt, currency);
     io::stdout().flush()?;
     let mut amount = String::new();
     io::stdin().read_line(&mut amount)?;
-    let amount: u64 = amount.trim().parse()?;
+    Ok(wallet.parse_amount(currency, amount.trim())?)
+}
+
+async fn withdraw_tokens(wallet: &Wallet) -> anyhow::Result<()> {
+    let currency = read_currency(wallet)?;
+    let amount = read_amount(wallet, &currency, "Amount")?;
 
-    print!("Denomination (10/50/100/500/1000): $");
+    print!("Split into smaller tokens for privacy? (y/N): ");
     io::stdout().flush()?;
-    let mut denom = String::new();
-    io::stdin().read_line(&mut denom)?;
-    let denom: u64 = denom.trim().parse()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    let strategy = if answer.trim().eq_ignore_ascii_case("y") {
+        Decomposition::PrivacyAware { max_tokens: 20 }
+    } else {
+        Decomposition::Greedy
+    };
 
     println!("\n⏳ Withdrawing...");
-    let tokens = wallet.withdraw(amount, denom).await?;
+    let tokens = wallet.withdraw(&amount, strategy).await?;
     println!("✓ Withdrew {} tokens!", tokens.len());
     
     Ok(())
 }
 
 async fn spend_tokens(wallet: &Wallet) -> anyhow::Result<()> {
-    print!("Amount to spend: $");
+    let currency = read_currency(wallet)?;
+    let amount = read_amount(wallet, &currency, "Amount to spend")?;
+
+    print!("Pay with (1) exact match, (2) fewest tokens, (3) earliest expiry, (4) privacy [1]: ");
     io::stdout().flush()?;
-    let mut amount = String::new();
-    io::stdin().read_line(&mut amount)?;
-    let amount: u64 = amount.trim().parse()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    let selector = match answer.trim() {
+        "2" => CoinSelector::FewestTokens,
+        "3" => CoinSelector::EarliestExpiry,
+        "4" => CoinSelector::Privacy,
+        _ => CoinSelector::ExactMatch,
+    };
 
     println!("\n⏳ Spending...");
-    let tx_id = wallet.spend(amount).await?;
+    let tx_id = wallet.spend(&amount, selector).await?;
     println!("✓ Spent! Transaction ID: {}", tx_id);
     
     Ok(())
@@ -103,13 +214,14 @@ async fn list_tokens(wallet: &Wallet) -> anyhow::Result<()> {
     }
 
     println!("\n📋 Available Tokens:");
-    println!("{:<10} {:<15} {:<20}", "Denom", "Status", "Created");
-    println!("{}", "-".repeat(50));
+    println!("{:<10} {:<10} {:<15} {:<20}", "Denom", "Currency", "Status", "Created");
+    println!("{}", "-".repeat(60));
     
     for token in tokens {
         println!(
-            "${:<9} {:<15} {}",
+            "{:<10} {:<10} {:<15} {}",
             token.token.denomination,
+            token.token.currency,
             "Available",
             token.created_at.format("%Y-%m-%d %H:%M")
         );
@@ -118,6 +230,77 @@ async fn list_tokens(wallet: &Wallet) -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn restore_tokens(wallet: &Wallet) -> anyhow::Result<()> {
+    println!("\n⏳ Restoring from seed phrase...");
+    let summary = wallet.restore().await?;
+    println!("✓ Restored {} tokens", summary.restored.len());
+    if summary.spent > 0 || summary.expired > 0 {
+        println!("  Skipped {} spent and {} expired tokens", summary.spent, summary.expired);
+    }
+    
+    Ok(())
+}
+
+/// Settles withdrawals and payments interrupted by a crash or a lost
+/// connection.
+async fn recover_pending(wallet: &Wallet) -> anyhow::Result<()> {
+    let summary = wallet.recover_pending().await?;
+    if summary.completed > 0 || summary.rolled_back > 0 {
+        println!("✓ Finished {} and rolled back {} interrupted operations", summary.completed, summary.rolled_back);
+    }
+    if summary.unresolved > 0 {
+        println!("⚠ {} interrupted operations could not be settled yet", summary.unresolved);
+    }
+    
+    Ok(())
+}
+
+async fn renew_expiring(wallet: &Wallet) -> anyhow::Result<()> {
+    let renewed = wallet.renew_expiring(chrono::Duration::days(RENEW_WITHIN_DAYS)).await?;
+    if !renewed.is_empty() {
+        println!("\n✓ Renewed expiring tokens into {} new tokens", renewed.len());
+    }
+    
+    Ok(())
+}
+
+fn read_passphrase(prompt: &str) -> anyhow::Result<String> {
+    print!("{}: ", prompt);
+    io::stdout().flush()?;
+    let mut passphrase = String::new();
+    io::stdin().read_line(&mut passphrase)?;
+    Ok(passphrase.trim_end_matches(['\r', '\n']).to_string())
+}
+
+async fn toggle_lock(wallet: &mut Wallet) -> anyhow::Result<()> {
+    if !wallet.is_encrypted() {
+        wallet.encrypt(&read_passphrase("New passphrase")?)?;
+        println!("✓ Wallet encrypted");
+    } else if wallet.is_locked() {
+        wallet.unlock(&read_passphrase("Passphrase")?)?;
+        println!("✓ Wallet unlocked");
+        recover_pending(wallet).await?;
+    } else {
+        wallet.lock();
+        println!("🔒 Wallet locked");
+    }
+    
+    Ok(())
+}
+
+fn change_passphrase(wallet: &mut Wallet) -> anyhow::Result<()> {
+    if !wallet.is_encrypted() {
+        println!("Wallet is not encrypted; choose 7 to encrypt it");
+        return Ok(());
+    }
+    let old_passphrase = read_passphrase("Current passphrase")?;
+    let new_passphrase = read_passphrase("New passphrase")?;
+    wallet.change_passphrase(&old_passphrase, &new_passphrase)?;
+    println!("✓ Passphrase changed");
+    
+    Ok(())
+}
+
 async fn health_check(wallet: &Wallet) -> anyhow::Result<()> {
     println!("\n⏳ Checking server health...");
     let healthy = wallet.health_check().await?;
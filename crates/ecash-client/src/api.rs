@@ -1,21 +1,86 @@
 use crate::error::{ClientError, Result};
-use ecash_core::{BlindedToken, BlindSignature, Token};
-use reqwest::Client;
+use chrono::{DateTime, Utc};
+use ecash_core::{Amount, BlindedToken, BlindSignature, Currency, IssuedToken, Jwk, KeyStatus, RsaBssaVariant, SignedKeyDirectory, Token};
+use reqwest::{Client, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use uuid::Uuid;
+
+pub use ecash_core::{ExchangeRequest, ExchangeResponse};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// Attempts made for a withdraw or redeem before the error is returned.
+const MAX_ATTEMPTS: u32 = 4;
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct PublicKeyResponse {
     pub key_id: String,
     pub institution_id: String,
+    pub currency: String,
+    pub denominations: Vec<u64>,
+    #[serde(default)]
+    pub currencies: Vec<Currency>,
+    #[serde(default)]
+    pub variant: RsaBssaVariant,
+    pub expiry_epoch: u64,
+    #[serde(default)]
+    pub exchange_fee: u64,
+    pub public_keys: Vec<DenominationKeyInfo>,
+    /// Every key set, including retired ones whose tokens are still
+    /// redeemable. Older servers only publish the active `public_keys`.
+    #[serde(default)]
+    pub keys: Vec<KeyInfo>,
+}
+
+impl PublicKeyResponse {
+    /// Every currency the institution issues. Servers that predate
+    /// `currencies` only publish their single `currency`.
+    pub fn currencies(&self) -> Vec<Currency> {
+        if !self.currencies.is_empty() {
+            return self.currencies.clone();
+        }
+        vec![Currency {
+            code: self.currency.clone(),
+            exponent: ecash_core::iso4217_exponent(&self.currency).unwrap_or(2),
+            denominations: self.denominations.clone(),
+        }]
+    }
+
+    pub fn currency(&self, code: &str) -> Result<Currency> {
+        self.currencies()
+            .into_iter()
+            .find(|currency| currency.code == code)
+            .ok_or_else(|| ClientError::UnsupportedCurrency(code.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeyInfo {
+    pub key_id: String,
+    pub status: KeyStatus,
+    pub public_keys: Vec<DenominationKeyInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DenominationKeyInfo {
+    pub currency: String,
+    pub denomination: u64,
     pub public_key_n: String,
     pub public_key_e: String,
-    pub denominations: Vec<u64>,
+    /// The same key as a JWK. Older servers only send `public_key_n` and
+    /// `public_key_e`, in decimal.
+    #[serde(default)]
+    pub jwk: Option<Jwk>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WithdrawRequest {
-    pub amount: u64,
-    pub denomination: u64,
+    pub amount: Amount,
+    pub expiry_epoch: u64,
     pub blinded_tokens: Vec<BlindedToken>,
 }
 
@@ -25,22 +90,93 @@ pub struct WithdrawResponse {
     pub key_id: String,
     pub expires_at: String,
     pub transaction_id: String,
+    pub balance: Amount,
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct RedeemRequest {
     pub tokens: Vec<Token>,
-    pub merchant_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct RedeemResponse {
     pub accepted_count: usize,
-    pub total_amount: u64,
+    pub total_amount: Amount,
+    pub transaction_id: String,
+    pub timestamp: String,
+    pub balance: Amount,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyRequest {
+    pub token: Token,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VerifyResponse {
+    pub valid: bool,
+    pub expired: bool,
+    pub spent: bool,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RestoreRequest {
+    pub recovery_ids: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RestoreResponse {
+    pub tokens: Vec<IssuedToken>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccountResponse {
+    pub account_id: String,
+    pub name: String,
+    pub kind: String,
+    pub currency: String,
+    pub balance: Amount,
+    pub updated_at: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SettlementResponse {
+    pub account_id: String,
+    pub currency: String,
+    pub from: String,
+    pub to: String,
+    pub redemption_count: usize,
+    pub token_count: u64,
+    pub total_amount: Amount,
+    pub redemptions: Vec<RedemptionInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedemptionInfo {
     pub transaction_id: String,
+    pub amount: Amount,
+    pub token_count: u64,
     pub timestamp: String,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct PayoutRequest {
+    pub amount: Amount,
+    pub destination: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PayoutResponse {
+    pub payout_id: String,
+    pub amount: Amount,
+    pub destination: String,
+    pub status: String,
+    pub reference: Option<String>,
+    pub error: Option<String>,
+    pub created_at: String,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ApiErrorResponse {
     pub error: String,
@@ -49,6 +185,7 @@ pub struct ApiErrorResponse {
 pub struct ApiClient {
     client: Client,
     base_url: String,
+    api_key: Option<String>,
 }
 
 impl ApiClient {
@@ -56,6 +193,21 @@ impl ApiClient {
         Self {
             client: Client::new(),
             base_url,
+            api_key: None,
+        }
+    }
+
+    /// Authenticates requests as the account owning `api_key`. Withdrawals,
+    /// redemptions and the merchant endpoints require it.
+    pub fn with_api_key(mut self, api_key: String) -> Self {
+        self.api_key = Some(api_key);
+        self
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.api_key {
+            Some(api_key) => request.bearer_auth(api_key),
+            None => request,
         }
     }
 
@@ -71,24 +223,188 @@ impl ApiClient {
         Ok(response.json().await?)
     }
 
-    pub async fn withdraw(&self, request: WithdrawRequest) -> Result<WithdrawResponse> {
-        let url = format!("{}/api/v1/withdraw", self.base_url);
-        let response = self.client.post(&url).json(&request).send().await?;
+    /// The institution's signed key directory; `None` from servers that do
+    /// not publish one.
+    pub async fn get_key_directory(&self) -> Result<Option<SignedKeyDirectory>> {
+        let url = format!("{}/api/v1/keys/directory", self.base_url);
+        let response = self.client.get(&url).send().await?;
         
-        if !response.status().is_success() {
-            let error: ApiErrorResponse = response.json().await?;
-            return Err(ClientError::ApiError(error.error));
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
         }
+        Self::parse_response(response).await.map(Some)
+    }
+
+    pub async fn get_account(&self) -> Result<AccountResponse> {
+        let url = format!("{}/api/v1/account", self.base_url);
+        let response = self.authorize(self.client.get(&url)).send().await?;
         
-        Ok(response.json().await?)
+        Self::parse_response(response).await
+    }
+
+    pub async fn withdraw(&self, request: WithdrawRequest) -> Result<WithdrawResponse> {
+        self.withdraw_with_key(request, &Self::new_idempotency_key()).await
+    }
+
+    /// Sends a withdrawal under `idempotency_key`. Sending the same request
+    /// with the same key again returns the original response, for as long
+    /// as the server keeps it, instead of debiting the account twice.
+    pub async fn withdraw_with_key(&self, request: WithdrawRequest, idempotency_key: &str) -> Result<WithdrawResponse> {
+        self.post_idempotent("/api/v1/withdraw", &request, true, idempotency_key).await
     }
 
     pub async fn redeem(&self, request: RedeemRequest) -> Result<RedeemResponse> {
-        let url = format!("{}/api/v1/redeem", self.base_url);
+        self.redeem_with_key(request, &Self::new_idempotency_key()).await
+    }
+
+    pub async fn redeem_with_key(&self, request: RedeemRequest, idempotency_key: &str) -> Result<RedeemResponse> {
+        self.post_idempotent("/api/v1/redeem", &request, true, idempotency_key).await
+    }
+
+    /// Exchanges tokens for blind signatures on new ones. Sent without the
+    /// API key, so the exchange cannot be tied to the account.
+    pub async fn exchange(&self, request: ExchangeRequest) -> Result<ExchangeResponse> {
+        self.exchange_with_key(request, &Self::new_idempotency_key()).await
+    }
+
+    pub async fn exchange_with_key(&self, request: ExchangeRequest, idempotency_key: &str) -> Result<ExchangeResponse> {
+        self.post_idempotent("/api/v1/exchange", &request, false, idempotency_key).await
+    }
+
+    pub fn new_idempotency_key() -> String {
+        Uuid::new_v4().to_string()
+    }
+
+    /// Checks a token's signature, expiry and spent status without
+    /// redeeming it. Sent without the API key.
+    pub async fn verify(&self, request: VerifyRequest) -> Result<VerifyResponse> {
+        let url = format!("{}/api/v1/verify", self.base_url);
         let response = self.client.post(&url).json(&request).send().await?;
         
+        Self::parse_response(response).await
+    }
+
+    /// Fetches the blind signatures the server kept for `recovery_ids`.
+    /// Sent without the API key, so a restore cannot be tied to the account.
+    pub async fn restore(&self, request: RestoreRequest) -> Result<RestoreResponse> {
+        let url = format!("{}/api/v1/restore", self.base_url);
+        let response = self.client.post(&url).json(&request).send().await?;
+        
+        Self::parse_response(response).await
+    }
+
+    /// Redemptions credited to this merchant account between `from` and
+    /// `to`; the server defaults to the last 24 hours.
+    pub async fn get_settlement(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<SettlementResponse> {
+        let url = format!("{}/api/v1/merchant/settlements", self.base_url);
+        let mut query = Vec::new();
+        if let Some(from) = from {
+            query.push(("from", from.to_rfc3339()));
+        }
+        if let Some(to) = to {
+            query.push(("to", to.to_rfc3339()));
+        }
+        
+        let response = self.authorize(self.client.get(&url))
+            .query(&query)
+            .send()
+            .await?;
+        
+        Self::parse_response(response).await
+    }
+
+    pub async fn request_payout(&self, request: PayoutRequest) -> Result<PayoutResponse> {
+        self.post_idempotent("/api/v1/merchant/payouts", &request, true, &Self::new_idempotency_key()).await
+    }
+
+    /// Posts `request` under `idempotency_key` and retries with the same key
+    /// after connection failures, server errors and responses carrying
+    /// `Retry-After`. The server replays the original response for a retried
+    /// key, so a lost response never burns tokens or signatures. The API key
+    /// is only sent if `authenticated` is set.
+    async fn post_idempotent<Req, Resp>(
+        &self,
+        path: &str,
+        request: &Req,
+        authenticated: bool,
+        idempotency_key: &str,
+    ) -> Result<Resp>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let url = format!("{}{}", self.base_url, path);
+        let mut attempt = 1;
+        
+        loop {
+            let mut builder = self.client.post(&url);
+            if authenticated {
+                builder = self.authorize(builder);
+            }
+            let result = builder
+                .header(IDEMPOTENCY_KEY_HEADER, idempotency_key)
+                .json(request)
+                .send()
+                .await;
+            
+            let retry_delay = match &result {
+                Ok(response) => Self::retry_delay(response, attempt),
+                Err(e) if e.is_connect() || e.is_timeout() || e.is_request() => {
+                    Some(RETRY_BASE_DELAY * 2u32.pow(attempt - 1))
+                }
+                Err(_) => None,
+            };
+            
+            match retry_delay {
+                Some(delay) if attempt < MAX_ATTEMPTS => {
+                    tokio::time::sleep(delay.min(MAX_RETRY_DELAY)).await;
+                    attempt += 1;
+                }
+                _ => return Self::parse_response(result?).await,
+            }
+        }
+    }
+
+    fn retry_delay(response: &Response, attempt: u32) -> Option<Duration> {
+        let retry_after = response.headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_secs);
+        
+        if retry_after.is_some() {
+            // Waits longer than we are willing to block are left to the caller.
+            retry_after.filter(|delay| *delay <= MAX_RETRY_DELAY)
+        } else if response.status().is_server_error() {
+            Some(RETRY_BASE_DELAY * 2u32.pow(attempt - 1))
+        } else {
+            None
+        }
+    }
+
+    async fn parse_response<Resp: DeserializeOwned>(response: Response) -> Result<Resp> {
+        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response.headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(1);
+            return Err(ClientError::RateLimited { retry_after });
+        }
+        
+        // Server errors and requests still in progress (409 with
+        // `Retry-After`) leave open whether the request took effect.
+        let undecided = response.status().is_server_error()
+            || response.headers().contains_key(reqwest::header::RETRY_AFTER);
         if !response.status().is_success() {
             let error: ApiErrorResponse = response.json().await?;
+            if undecided {
+                return Err(ClientError::ServerError(error.error));
+            }
             return Err(ClientError::ApiError(error.error));
         }
         
@@ -0,0 +1,131 @@
+//! Verification of the institution's signed key directory.
+//!
+//! The directory lists every key set with its validity window and is signed
+//! with the institution's identity key. The identity key is pinned the first
+//! time a directory is seen, unless its fingerprint was given up front, so
+//! that a server swapping it is noticed. A verified directory then vouches
+//! for the keys served on `/api/v1/keys`, and says when to fetch them again.
+
+use crate::api::PublicKeyResponse;
+use crate::error::{ClientError, Result};
+use crate::pinning::{active_key_set_fingerprint, key_set_fingerprints};
+use chrono::{DateTime, Utc};
+use ecash_core::{KeyDirectory, SignedKeyDirectory};
+
+/// Checks signed key directories of one institution against the fingerprint
+/// of its identity key.
+#[derive(Debug, Clone)]
+pub struct DirectoryVerifier {
+    institution_id: String,
+    identity_fingerprint: String,
+}
+
+impl DirectoryVerifier {
+    /// `identity_fingerprint` is the hex SHA-256 of the identity key's SPKI
+    /// encoding, as printed by `ecash_core::identity_fingerprint`.
+    pub fn new(institution_id: String, identity_fingerprint: String) -> Self {
+        Self {
+            institution_id,
+            identity_fingerprint: identity_fingerprint.to_lowercase(),
+        }
+    }
+
+    pub fn identity_fingerprint(&self) -> &str {
+        &self.identity_fingerprint
+    }
+
+    /// The directory, if `signed` is signed by the pinned identity key and
+    /// lists this institution's keys. Old directories pass: they still show
+    /// which keys the institution published.
+    pub fn verify(&self, signed: &SignedKeyDirectory) -> Result<KeyDirectory> {
+        if signed.identity_fingerprint()? != self.identity_fingerprint {
+            return Err(ClientError::IdentityKeyMismatch {
+                institution_id: self.institution_id.clone(),
+            });
+        }
+        let directory = signed.verify()?;
+        
+        if directory.institution_id != self.institution_id {
+            return Err(ClientError::InvalidResponse(format!(
+                "Key directory lists keys of {}, not {}",
+                directory.institution_id, self.institution_id
+            )));
+        }
+        Ok(directory)
+    }
+
+    /// As `verify`, but also refuses a directory that is no longer current
+    /// at `now`, which a server could replay to hide a revocation.
+    pub fn verify_current(&self, signed: &SignedKeyDirectory, now: DateTime<Utc>) -> Result<KeyDirectory> {
+        let directory = self.verify(signed)?;
+        if now > directory.next_update {
+            return Err(ClientError::StaleKeyDirectory { next_update: directory.next_update });
+        }
+        Ok(directory)
+    }
+}
+
+/// Makes sure every key set in `response`, and the active keys wallets
+/// blind under, are listed in `directory` with the same fingerprint.
+pub fn confirm_key_sets(directory: &KeyDirectory, response: &PublicKeyResponse) -> Result<()> {
+    let active = (response.key_id.clone(), active_key_set_fingerprint(response)?);
+    for (key_id, served) in key_set_fingerprints(response)?.into_iter().chain([active]) {
+        let listed = directory.entry(&key_id).map(|entry| &entry.fingerprint);
+        if listed != Some(&served) {
+            return Err(ClientError::KeyNotConfirmed {
+                key_id,
+                checked_against: "the signed key directory".to_string(),
+            });
+        }
+    }
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pinning::tests::{key_info, key_response};
+    use ecash_core::{DirectoryEntry, KeyStatus};
+    use std::collections::BTreeMap;
+    
+    fn directory(response: &PublicKeyResponse) -> KeyDirectory {
+        let now = Utc::now();
+        KeyDirectory {
+            institution_id: response.institution_id.clone(),
+            issued_at: now,
+            next_update: now + chrono::Duration::hours(1),
+            keys: vec![DirectoryEntry {
+                key_id: response.key_id.clone(),
+                status: KeyStatus::Active,
+                created_at: now,
+                expires_at: None,
+                issue_until: None,
+                revoked_at: None,
+                denominations: BTreeMap::from([("USD".to_string(), vec![10, 50])]),
+                fingerprint: active_key_set_fingerprint(response).unwrap(),
+            }],
+        }
+    }
+    
+    #[test]
+    fn test_blinding_keys_must_be_in_directory() {
+        let response = key_response();
+        let directory = directory(&response);
+        assert!(confirm_key_sets(&directory, &response).is_ok());
+        
+        // Blinding keys of its own for one wallet, listed consistently in
+        // `keys`, are still not the keys the institution signed for.
+        let mut tagged = response.clone();
+        tagged.public_keys[0] = key_info(10);
+        tagged.keys[0].public_keys = tagged.public_keys.clone();
+        assert!(matches!(
+            confirm_key_sets(&directory, &tagged),
+            Err(ClientError::KeyNotConfirmed { key_id, .. }) if key_id == "key_1"
+        ));
+        
+        let mut legacy = tagged;
+        legacy.keys.clear();
+        assert!(confirm_key_sets(&directory, &legacy).is_err());
+    }
+}
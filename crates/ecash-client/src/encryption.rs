@@ -0,0 +1,168 @@
+//! Encryption of the tokens kept in the wallet database.
+//!
+//! Each token row is sealed with XChaCha20-Poly1305 under a random 256-bit
+//! data key, with a fresh nonce per row and the row's id, currency and
+//! denomination as associated data, so a row cannot be moved or relabelled
+//! without failing to open. Pending operations, which hold blinding factors,
+//! are sealed the same way. The data key is stored wrapped under a key
+//! derived from the user's passphrase with Argon2id; the salt and cost
+//! parameters are stored next to it.
+
+use crate::error::{ClientError, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const SALT_LEN: usize = 16;
+
+/// Associated data of the wrapped data key.
+const WRAPPED_KEY_AAD: &[u8] = b"ecash-wallet/data-key/v1";
+
+/// Argon2id settings a passphrase is stretched with. Stored with the wallet
+/// so they can be raised for new wallets without breaking old ones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub salt: Vec<u8>,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl KdfParams {
+    /// A fresh salt with the OWASP-recommended Argon2id costs.
+    pub fn generate() -> Self {
+        let mut salt = vec![0u8; SALT_LEN];
+        rand_bytes(&mut salt);
+        
+        Self {
+            salt,
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+
+    fn derive_key(&self, passphrase: &str) -> Result<Zeroizing<[u8; KEY_LEN]>> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, Some(KEY_LEN))
+            .map_err(|e| ClientError::StorageCorrupted(format!("Invalid KDF parameters: {}", e)))?;
+        
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, key.as_mut())
+            .map_err(|e| ClientError::StorageCorrupted(format!("Key derivation failed: {}", e)))?;
+        
+        Ok(key)
+    }
+}
+
+/// The data key, unwrapped. Dropping it locks the wallet.
+pub struct StorageCipher {
+    key: Zeroizing<[u8; KEY_LEN]>,
+}
+
+impl StorageCipher {
+    /// A new random data key.
+    pub fn generate() -> Self {
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        rand_bytes(key.as_mut());
+        Self { key }
+    }
+
+    /// Unwraps a data key stored by `wrap`. Fails with `WrongPassphrase`
+    /// if `passphrase` is not the one it was wrapped with.
+    pub fn unwrap(wrapped_key: &[u8], passphrase: &str, kdf: &KdfParams) -> Result<Self> {
+        let kek = Self { key: kdf.derive_key(passphrase)? };
+        let plaintext = kek.open(WRAPPED_KEY_AAD, wrapped_key)
+            .map_err(|_| ClientError::WrongPassphrase)?;
+        
+        let key: [u8; KEY_LEN] = plaintext.as_slice().try_into()
+            .map_err(|_| ClientError::StorageCorrupted("Wrapped key has the wrong length".to_string()))?;
+        Ok(Self { key: Zeroizing::new(key) })
+    }
+
+    /// This data key, sealed under a key derived from `passphrase`.
+    pub fn wrap(&self, passphrase: &str, kdf: &KdfParams) -> Result<Vec<u8>> {
+        let kek = Self { key: kdf.derive_key(passphrase)? };
+        Ok(kek.seal(WRAPPED_KEY_AAD, self.key.as_ref()))
+    }
+
+    /// Encrypts `plaintext` bound to `aad`, returning the nonce followed by
+    /// the ciphertext.
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher()
+            .encrypt(&nonce, Payload { msg: plaintext, aad })
+            .expect("encryption of an in-memory buffer cannot fail");
+        
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypts what `seal` returned for the same `aad`.
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(ClientError::StorageCorrupted("Sealed data is truncated".to_string()));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        
+        self.cipher()
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map(Zeroizing::new)
+            .map_err(|_| ClientError::StorageCorrupted("Sealed data failed authentication".to_string()))
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(self.key.as_ref().into())
+    }
+}
+
+fn rand_bytes(buf: &mut [u8]) {
+    OsRng.fill_bytes(buf);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_sealed_data_opens_only_with_its_key_and_aad() {
+        let cipher = StorageCipher::generate();
+        let sealed = cipher.seal(b"row-1", b"token json");
+        
+        assert_eq!(cipher.open(b"row-1", &sealed).unwrap().as_slice(), b"token json");
+        assert!(cipher.open(b"row-2", &sealed).is_err());
+        assert!(StorageCipher::generate().open(b"row-1", &sealed).is_err());
+        
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(cipher.open(b"row-1", &tampered).is_err());
+        assert!(cipher.open(b"row-1", &sealed[..NONCE_LEN - 1]).is_err());
+    }
+    
+    #[test]
+    fn test_wrapped_key_needs_its_passphrase() {
+        let cipher = StorageCipher::generate();
+        let kdf = KdfParams::generate();
+        let wrapped_key = cipher.wrap("correct horse", &kdf).unwrap();
+        
+        let unwrapped = StorageCipher::unwrap(&wrapped_key, "correct horse", &kdf).unwrap();
+        let sealed = cipher.seal(b"row", b"secret");
+        assert_eq!(unwrapped.open(b"row", &sealed).unwrap().as_slice(), b"secret");
+        
+        assert!(matches!(
+            StorageCipher::unwrap(&wrapped_key, "wrong horse", &kdf),
+            Err(ClientError::WrongPassphrase)
+        ));
+        // The salt is part of the key derivation.
+        assert!(matches!(
+            StorageCipher::unwrap(&wrapped_key, "correct horse", &KdfParams::generate()),
+            Err(ClientError::WrongPassphrase)
+        ));
+    }
+}
@@ -1,3 +1,5 @@
+use chrono::{DateTime, Utc};
+use ecash_core::Amount;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -15,7 +17,7 @@ pub enum ClientError {
     Serialization(#[from] serde_json::Error),
     
     #[error("Insufficient balance: required {required}, available {available}")]
-    InsufficientBalance { required: u64, available: u64 },
+    InsufficientBalance { required: Amount, available: Amount },
     
     #[error("No tokens available")]
     NoTokensAvailable,
@@ -23,14 +25,73 @@ pub enum ClientError {
     #[error("Invalid server response: {0}")]
     InvalidResponse(String),
     
+    /// The server refused the request without acting on it.
     #[error("API error: {0}")]
     ApiError(String),
     
+    /// The server failed, or is still processing the same request; it may
+    /// or may not have taken effect.
+    #[error("Server error: {0}")]
+    ServerError(String),
+    
+    #[error("Rate limited; retry after {retry_after} seconds")]
+    RateLimited { retry_after: u64 },
+    
     #[error("Invalid denomination: {0}")]
     InvalidDenomination(u64),
     
+    #[error("Currency not issued by the institution: {0}")]
+    UnsupportedCurrency(String),
+    
+    #[error("Token not found: {0}")]
+    TokenNotFound(String),
+    
+    #[error("Wallet is locked")]
+    WalletLocked,
+    
+    #[error("Wrong passphrase")]
+    WrongPassphrase,
+    
+    #[error("Wallet database is corrupted: {0}")]
+    StorageCorrupted(String),
+    
+    /// A key set differs from the one pinned when it was first seen.
+    #[error("Key set {key_id} has changed since it was pinned")]
+    KeyChanged { key_id: String },
+    
+    /// The second key source does not list the key set tokens would be
+    /// issued under.
+    #[error("Key set {key_id} is not confirmed by {checked_against}")]
+    KeyNotConfirmed { key_id: String, checked_against: String },
+    
+    /// The key directory is signed by an identity key other than the one
+    /// pinned for the institution.
+    #[error("Key directory of {institution_id} is not signed by its pinned identity key")]
+    IdentityKeyMismatch { institution_id: String },
+    
+    #[error("Key directory was due to be replaced at {next_update}")]
+    StaleKeyDirectory { next_update: DateTime<Utc> },
+    
+    /// The key set tokens would be issued under stops issuing soon, or the
+    /// directory saying otherwise is out of date; `Wallet::refresh_keys`
+    /// fetches the current keys.
+    #[error("Key set {key_id} is about to stop issuing tokens; refresh the keys")]
+    KeysOutdated { key_id: String },
+    
+    #[error("Wallet has no seed phrase")]
+    NoSeed,
+    
     #[error("QR code error: {0}")]
     QrCode(String),
 }
 
+impl ClientError {
+    /// Whether the request is known not to have taken effect, so its
+    /// tokens or blinding secrets can be released. Connection failures and
+    /// server errors leave that open.
+    pub fn is_rejection(&self) -> bool {
+        matches!(self, ClientError::ApiError(_) | ClientError::RateLimited { .. })
+    }
+}
+
 pub type Result<T> = std::result::Result<T, ClientError>;
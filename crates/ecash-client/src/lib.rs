@@ -1,11 +1,19 @@
 pub mod api;
+pub mod directory;
+pub mod encryption;
 pub mod error;
+pub mod pinning;
 pub mod qr;
+pub mod selection;
 pub mod storage;
 pub mod wallet;
 
 pub use api::ApiClient;
 pub use error::{ClientError, Result};
+pub use directory::DirectoryVerifier;
+pub use pinning::{KeyChange, KeyPinPolicy, KeySource};
 pub use qr::QrCodeGenerator;
-pub use storage::{StoredToken, TokenStatus, WalletStorage};
-pub use wallet::Wallet;
+pub use selection::CoinSelector;
+pub use storage::{PendingOperation, StoredToken, TokenStatus, WalletStorage};
+pub use wallet::{RecoverySummary, RestoreSummary, Wallet};
+pub use ecash_core::{Amount, Currency, Decomposition, KeyDirectory, SignedKeyDirectory, WalletSeed};
@@ -0,0 +1,196 @@
+//! Checks on the institution's public keys.
+//!
+//! Blind signatures only keep withdrawals and payments unlinkable if every
+//! wallet blinds under the same keys. An institution serving one wallet a
+//! key set of its own could recognise that wallet's tokens when they are
+//! spent. So the fingerprint of every key set is pinned the first time it
+//! is seen, a pinned key set that later changes is reported, and before
+//! tokens are issued the key set can be compared with a second source that
+//! other wallets see as well. The signed key directory (see
+//! `crate::directory`) is checked on top of this wherever the server
+//! publishes one.
+
+use crate::api::{ApiClient, DenominationKeyInfo, PublicKeyResponse};
+use crate::directory::DirectoryVerifier;
+use crate::error::{ClientError, Result};
+use crate::wallet::parse_public_keys;
+use ecash_core::key_set_fingerprint;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// What `Wallet::initialize` does when a pinned key set has changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyPinPolicy {
+    /// Record the change in `Wallet::key_changes` and carry on.
+    Warn,
+    /// Fail with `ClientError::KeyChanged`.
+    #[default]
+    Refuse,
+}
+
+/// A pinned key set served with different keys.
+#[derive(Debug, Clone)]
+pub struct KeyChange {
+    pub key_id: String,
+    pub pinned: String,
+    pub served: String,
+}
+
+/// A second publication of the institution's keys, checked before tokens
+/// are issued.
+#[derive(Debug, Clone)]
+pub enum KeySource {
+    /// Another server publishing the institution's `/api/v1/keys`, such as
+    /// a mirror or a proxy run by someone else.
+    Mirror(String),
+    /// A signed key directory file obtained out of band, checked against
+    /// the pinned identity key.
+    Directory(PathBuf),
+}
+
+impl KeySource {
+    /// Key set fingerprints by key id, as published by this source for
+    /// `institution_id`. A directory file needs `verifier` to be checked.
+    pub(crate) async fn fingerprints(
+        &self,
+        institution_id: &str,
+        verifier: Option<&DirectoryVerifier>,
+    ) -> Result<BTreeMap<String, String>> {
+        let (published_by, fingerprints) = match self {
+            KeySource::Mirror(url) => {
+                let response = ApiClient::new(url.clone()).get_public_key().await?;
+                (response.institution_id.clone(), key_set_fingerprints(&response)?)
+            }
+            KeySource::Directory(path) => {
+                let verifier = verifier.ok_or_else(|| ClientError::InvalidResponse(format!(
+                    "No identity key of {} is pinned to check {} with",
+                    institution_id, self
+                )))?;
+                let json = std::fs::read_to_string(path)
+                    .map_err(|e| ClientError::InvalidResponse(format!("Cannot read {}: {}", path.display(), e)))?;
+                let directory = verifier.verify(&serde_json::from_str(&json)?)?;
+                let fingerprints = directory.keys
+                    .into_iter()
+                    .map(|entry| (entry.key_id, entry.fingerprint))
+                    .collect();
+                (directory.institution_id, fingerprints)
+            }
+        };
+        
+        if published_by != institution_id {
+            return Err(ClientError::InvalidResponse(format!(
+                "{} lists keys of {}, not {}",
+                self, published_by, institution_id
+            )));
+        }
+        Ok(fingerprints)
+    }
+}
+
+impl std::fmt::Display for KeySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeySource::Mirror(url) => write!(f, "mirror {}", url),
+            KeySource::Directory(path) => write!(f, "key directory {}", path.display()),
+        }
+    }
+}
+
+/// Fingerprints of every key set in `response` by key id, including
+/// retired ones. Servers that predate `keys` only publish the active set.
+///
+/// Wallets blind under the active `public_keys`, so those must be the keys
+/// listed for `key_id` in `keys`; otherwise a server could pass every check
+/// with honest `keys` while handing each wallet blinding keys of its own.
+pub fn key_set_fingerprints(response: &PublicKeyResponse) -> Result<BTreeMap<String, String>> {
+    let active = active_key_set_fingerprint(response)?;
+    if response.keys.is_empty() {
+        return Ok(BTreeMap::from([(response.key_id.clone(), active)]));
+    }
+    
+    let fingerprints: BTreeMap<String, String> = response.keys.iter()
+        .map(|key| Ok((key.key_id.clone(), fingerprint(&key.key_id, &key.public_keys)?)))
+        .collect::<Result<_>>()?;
+    if fingerprints.get(&response.key_id) != Some(&active) {
+        return Err(ClientError::KeyNotConfirmed {
+            key_id: response.key_id.clone(),
+            checked_against: "the key sets served with it".to_string(),
+        });
+    }
+    Ok(fingerprints)
+}
+
+/// Fingerprint of the active `public_keys`, the keys wallets blind under.
+pub(crate) fn active_key_set_fingerprint(response: &PublicKeyResponse) -> Result<String> {
+    fingerprint(&response.key_id, &response.public_keys)
+}
+
+fn fingerprint(key_id: &str, keys: &[DenominationKeyInfo]) -> Result<String> {
+    let public_keys = parse_public_keys(keys)?;
+    Ok(key_set_fingerprint(
+        key_id,
+        public_keys.iter().flat_map(|(currency, keys)| {
+            keys.iter().map(move |(denomination, public_key)| (currency.as_str(), *denomination, public_key))
+        }),
+    ))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::api::KeyInfo;
+    use chacha20poly1305::aead::OsRng;
+    use ecash_core::{KeyStatus, RsaBssaVariant};
+    use rsa::{RsaPrivateKey, RsaPublicKey};
+    
+    pub(crate) fn key_info(denomination: u64) -> DenominationKeyInfo {
+        let public_key = RsaPublicKey::from(&RsaPrivateKey::new(&mut OsRng, 512).unwrap());
+        DenominationKeyInfo {
+            currency: "USD".to_string(),
+            denomination,
+            public_key_n: String::new(),
+            public_key_e: String::new(),
+            jwk: Some(ecash_core::Jwk::from_public_key(&public_key)),
+        }
+    }
+    
+    /// A response whose active `public_keys` are the ones listed in `keys`.
+    pub(crate) fn key_response() -> PublicKeyResponse {
+        let public_keys = vec![key_info(10), key_info(50)];
+        PublicKeyResponse {
+            key_id: "key_1".to_string(),
+            institution_id: "test-institution".to_string(),
+            currency: "USD".to_string(),
+            denominations: vec![10, 50],
+            currencies: Vec::new(),
+            variant: RsaBssaVariant::default(),
+            expiry_epoch: 0,
+            exchange_fee: 0,
+            public_keys: public_keys.clone(),
+            keys: vec![KeyInfo {
+                key_id: "key_1".to_string(),
+                status: KeyStatus::Active,
+                public_keys,
+            }],
+        }
+    }
+    
+    #[test]
+    fn test_blinding_keys_must_match_listed_key_set() {
+        let response = key_response();
+        let fingerprints = key_set_fingerprints(&response).unwrap();
+        assert_eq!(fingerprints["key_1"], fingerprint("key_1", &response.public_keys).unwrap());
+        
+        // Honest `keys`, but blinding keys meant for one wallet.
+        let mut tagged = response.clone();
+        tagged.public_keys[1] = key_info(50);
+        assert!(matches!(
+            key_set_fingerprints(&tagged),
+            Err(ClientError::KeyNotConfirmed { key_id, .. }) if key_id == "key_1"
+        ));
+        
+        let mut unlisted = response;
+        unlisted.keys[0].key_id = "key_0".to_string();
+        assert!(key_set_fingerprints(&unlisted).is_err());
+    }
+}
@@ -0,0 +1,304 @@
+//! Choosing which stored tokens pay an amount.
+//!
+//! Tokens cannot be split when spent, so whatever is selected beyond the
+//! amount must first be exchanged for the payment plus change, which costs
+//! the exchange fee and a round trip. Which tokens go together matters too:
+//! the server saw each withdrawal's tokens together, so paying with several
+//! of them links the payment to that withdrawal.
+
+use crate::storage::{StoredToken, TokenStatus};
+use chrono::{DateTime, Utc};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// Largest subset-sum table `CoinSelector::ExactMatch` builds, in entries
+/// of tokens times totals. Past it, selection falls back to `FewestTokens`.
+const MAX_SUBSET_SUM_CELLS: u64 = 16_000_000;
+
+/// How `Wallet::spend` picks the tokens to pay with. Every strategy skips
+/// tokens that are expired or not available, and covers the amount
+/// whenever the remaining tokens are worth enough.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CoinSelector {
+    /// Tokens adding up to exactly the amount if any subset does, so no
+    /// exchange is needed; otherwise the subset that overpays least.
+    #[default]
+    ExactMatch,
+    /// As few tokens as possible, largest first.
+    FewestTokens,
+    /// Tokens closest to expiry first, so fewer of them expire unspent.
+    EarliestExpiry,
+    /// One token per withdrawal or exchange before a second from any of
+    /// them, so the payment is linked to as few of them as the amount allows.
+    Privacy,
+}
+
+impl CoinSelector {
+    /// Picks tokens from `candidates` worth at least `target` minor units,
+    /// skipping those not available or expired at `now`. `None` if the
+    /// rest are worth less than `target`.
+    pub fn select(self, candidates: &[StoredToken], target: u64, now: DateTime<Utc>) -> Option<Vec<StoredToken>> {
+        let eligible: Vec<&StoredToken> = candidates.iter()
+            .filter(|stored| stored.status == TokenStatus::Available && stored.token.expires_at >= now)
+            .collect();
+        let total = eligible.iter().fold(0u64, |total, stored| total.saturating_add(stored.token.denomination));
+        if total < target {
+            return None;
+        }
+        
+        let selected = match self {
+            CoinSelector::ExactMatch => least_overpayment(&eligible, target)
+                .unwrap_or_else(|| covering_prefix(largest_first(eligible), target)),
+            CoinSelector::FewestTokens => covering_prefix(largest_first(eligible), target),
+            CoinSelector::EarliestExpiry => {
+                let mut ordered = eligible;
+                ordered.sort_by_key(|stored| (stored.token.expires_at, Reverse(stored.token.denomination)));
+                covering_prefix(ordered, target)
+            }
+            CoinSelector::Privacy => covering_prefix(spread_over_batches(eligible), target),
+        };
+        
+        Some(selected.into_iter().cloned().collect())
+    }
+}
+
+fn largest_first(mut tokens: Vec<&StoredToken>) -> Vec<&StoredToken> {
+    tokens.sort_by_key(|stored| Reverse(stored.token.denomination));
+    tokens
+}
+
+/// The largest token of every batch, then the second largest of every
+/// batch, and so on, each round largest first. Tokens without a batch are
+/// each a batch of their own.
+fn spread_over_batches(tokens: Vec<&StoredToken>) -> Vec<&StoredToken> {
+    let mut taken: HashMap<&str, usize> = HashMap::new();
+    let mut ranked: Vec<(usize, &StoredToken)> = largest_first(tokens)
+        .into_iter()
+        .map(|stored| {
+            let rank = match &stored.batch_id {
+                Some(batch_id) => {
+                    let count = taken.entry(batch_id).or_default();
+                    *count += 1;
+                    *count - 1
+                }
+                None => 0,
+            };
+            (rank, stored)
+        })
+        .collect();
+    ranked.sort_by_key(|(rank, stored)| (*rank, Reverse(stored.token.denomination)));
+    
+    ranked.into_iter().map(|(_, stored)| stored).collect()
+}
+
+/// The shortest prefix of `ordered` worth at least `target`, less any of
+/// its tokens it can do without, dropped latest first.
+fn covering_prefix(ordered: Vec<&StoredToken>, target: u64) -> Vec<&StoredToken> {
+    let mut selected = Vec::new();
+    let mut total = 0u64;
+    for stored in ordered {
+        if total >= target {
+            break;
+        }
+        total += stored.token.denomination;
+        selected.push(stored);
+    }
+    
+    for index in (0..selected.len()).rev() {
+        let value = selected[index].token.denomination;
+        if total - value >= target {
+            total -= value;
+            selected.remove(index);
+        }
+    }
+    
+    selected
+}
+
+/// The subset of `tokens` with the smallest total at or above `target`,
+/// preferring larger tokens; `None` if the table it takes is too large.
+/// Totals are counted in multiples of the tokens' common divisor, and a
+/// minimal subset stays below `target` plus the largest token, so only
+/// totals up to that are tracked.
+fn least_overpayment<'a>(tokens: &[&'a StoredToken], target: u64) -> Option<Vec<&'a StoredToken>> {
+    let tokens = largest_first(tokens.to_vec());
+    let unit = tokens.iter().fold(0, |unit, stored| gcd(unit, stored.token.denomination)).max(1);
+    let target = target.div_ceil(unit);
+    let bound = target.checked_add(tokens.first()?.token.denomination / unit)?;
+    if (bound + 1).saturating_mul(tokens.len() as u64) > MAX_SUBSET_SUM_CELLS {
+        return None;
+    }
+    
+    // `reached_by[total]` is the token that first made `total` reachable,
+    // so the rest of that total is made of tokens before it.
+    const UNREACHED: u32 = u32::MAX;
+    const START: u32 = u32::MAX - 1;
+    let mut reached_by = vec![UNREACHED; bound as usize + 1];
+    reached_by[0] = START;
+    for (index, stored) in tokens.iter().enumerate() {
+        let value = (stored.token.denomination / unit) as usize;
+        for total in (value.max(1)..reached_by.len()).rev() {
+            if reached_by[total] == UNREACHED && reached_by[total - value] != UNREACHED {
+                reached_by[total] = index as u32;
+            }
+        }
+    }
+    
+    let mut total = (target as usize..reached_by.len()).find(|&total| reached_by[total] != UNREACHED)?;
+    let mut selected = Vec::new();
+    while reached_by[total] != START {
+        let stored = tokens[reached_by[total] as usize];
+        selected.push(stored);
+        total -= (stored.token.denomination / unit) as usize;
+    }
+    
+    Some(selected)
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use ecash_core::Token;
+    use proptest::prelude::*;
+    use std::collections::HashSet;
+
+    const DENOMINATIONS: &[u64] = &[10, 50, 100, 500, 1000];
+    const SELECTORS: &[CoinSelector] = &[
+        CoinSelector::ExactMatch,
+        CoinSelector::FewestTokens,
+        CoinSelector::EarliestExpiry,
+        CoinSelector::Privacy,
+    ];
+
+    fn now() -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000, 0).unwrap()
+    }
+
+    fn stored_token(index: usize, denomination: u64, expires_in_hours: i64, status: TokenStatus, batch: u8) -> StoredToken {
+        StoredToken {
+            id: index.to_string(),
+            token: Token::new(
+                vec![index as u8; 32],
+                denomination,
+                "USD".to_string(),
+                vec![0; 32],
+                now() + Duration::hours(expires_in_hours),
+                "test-institution".to_string(),
+                "key-1".to_string(),
+            ),
+            status,
+            created_at: now(),
+            spent_at: None,
+            batch_id: (batch > 0).then(|| format!("batch-{}", batch)),
+        }
+    }
+
+    fn wallet_tokens() -> impl Strategy<Value = Vec<StoredToken>> {
+        let status = prop_oneof![
+            3 => Just(TokenStatus::Available),
+            1 => Just(TokenStatus::Pending),
+            1 => Just(TokenStatus::Spent),
+        ];
+        prop::collection::vec((prop::sample::select(DENOMINATIONS), -48i64..48, status, 0u8..4), 0..12)
+            .prop_map(|tokens| {
+                tokens.into_iter()
+                    .enumerate()
+                    .map(|(index, (denomination, expires_in_hours, status, batch))| {
+                        stored_token(index, denomination, expires_in_hours, status, batch)
+                    })
+                    .collect()
+            })
+    }
+
+    fn is_eligible(stored: &StoredToken) -> bool {
+        stored.status == TokenStatus::Available && stored.token.expires_at >= now()
+    }
+
+    fn value(tokens: &[StoredToken]) -> u64 {
+        tokens.iter().map(|stored| stored.token.denomination).sum()
+    }
+
+    /// The smallest total at or above `target` of any subset of `tokens`.
+    fn least_covering_total(tokens: &[&StoredToken], target: u64) -> Option<u64> {
+        (0u32..1 << tokens.len())
+            .map(|subset| {
+                tokens.iter()
+                    .enumerate()
+                    .filter(|(index, _)| subset & (1 << index) != 0)
+                    .map(|(_, stored)| stored.token.denomination)
+                    .sum::<u64>()
+            })
+            .filter(|total| *total >= target)
+            .min()
+    }
+
+    proptest! {
+        #[test]
+        fn test_selection_covers_target_with_eligible_tokens(
+            tokens in wallet_tokens(),
+            target in 0u64..3_000,
+        ) {
+            let spendable: u64 = tokens.iter()
+                .filter(|stored| is_eligible(stored))
+                .map(|stored| stored.token.denomination)
+                .sum();
+            
+            for selector in SELECTORS {
+                let selected = selector.select(&tokens, target, now());
+                prop_assert_eq!(selected.is_some(), spendable >= target, "{:?}", selector);
+                let Some(selected) = selected else {
+                    continue;
+                };
+                
+                prop_assert!(value(&selected) >= target, "{:?} underpays", selector);
+                prop_assert!(selected.iter().all(is_eligible), "{:?}", selector);
+                let ids: HashSet<&str> = selected.iter().map(|stored| stored.id.as_str()).collect();
+                prop_assert_eq!(ids.len(), selected.len(), "{:?} picks a token twice", selector);
+            }
+        }
+
+        #[test]
+        fn test_exact_match_overpays_least(
+            tokens in wallet_tokens(),
+            target in 0u64..3_000,
+        ) {
+            let eligible: Vec<&StoredToken> = tokens.iter().filter(|stored| is_eligible(stored)).collect();
+            
+            let selected = CoinSelector::ExactMatch.select(&tokens, target, now());
+            prop_assert_eq!(selected.map(|selected| value(&selected)), least_covering_total(&eligible, target));
+        }
+
+        #[test]
+        fn test_privacy_takes_one_token_per_batch_when_enough(
+            tokens in wallet_tokens(),
+            target in 0u64..3_000,
+        ) {
+            let mut largest_per_batch: HashMap<Option<&str>, u64> = HashMap::new();
+            for stored in tokens.iter().filter(|stored| is_eligible(stored)) {
+                match stored.batch_id.as_deref() {
+                    Some(batch_id) => {
+                        let largest = largest_per_batch.entry(Some(batch_id)).or_default();
+                        *largest = (*largest).max(stored.token.denomination);
+                    }
+                    None => *largest_per_batch.entry(None).or_default() += stored.token.denomination,
+                }
+            }
+            // Only amounts one token per batch can cover.
+            let target = target % (largest_per_batch.values().sum::<u64>() + 1);
+            
+            let selected = CoinSelector::Privacy.select(&tokens, target, now()).unwrap();
+            let batches: Vec<&str> = selected.iter().filter_map(|stored| stored.batch_id.as_deref()).collect();
+            let distinct: HashSet<&str> = batches.iter().copied().collect();
+            prop_assert_eq!(distinct.len(), batches.len());
+        }
+    }
+}
@@ -1,8 +1,13 @@
-use crate::error::Result;
+use crate::api::WithdrawRequest;
+use crate::encryption::{KdfParams, StorageCipher};
+use crate::error::{ClientError, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use chrono::{DateTime, Utc};
-use ecash_core::Token;
-use rusqlite::{params, Connection};
+use ecash_core::{Amount, EcashError, ExchangeRequest, Token, TokenMetadata};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -12,6 +17,11 @@ pub struct StoredToken {
     pub status: TokenStatus,
     pub created_at: DateTime<Utc>,
     pub spent_at: Option<DateTime<Utc>>,
+    /// The withdrawal or exchange the token was issued in. The server saw
+    /// those tokens together, so spending them together links the payment
+    /// to it. `None` for restored tokens and those from older wallets.
+    #[serde(default)]
+    pub batch_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -43,13 +53,77 @@ impl TokenStatus {
     }
 }
 
+/// A request whose effect on the server the wallet must not lose track of.
+/// It is stored, with the secrets needed to finish it, before the request
+/// is sent, and removed in the same transaction that stores its result.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PendingOperation {
+    Withdraw {
+        request: WithdrawRequest,
+        metadata: Vec<TokenMetadata>,
+    },
+    /// The inputs are stored tokens, marked pending meanwhile.
+    Exchange {
+        input_ids: Vec<String>,
+        request: ExchangeRequest,
+        metadata: Vec<TokenMetadata>,
+    },
+    /// Stored tokens sent for redemption, marked pending meanwhile.
+    Redeem {
+        token_ids: Vec<String>,
+        amount: Amount,
+    },
+}
+
+impl PendingOperation {
+    fn kind(&self) -> &'static str {
+        match self {
+            PendingOperation::Withdraw { .. } => "withdraw",
+            PendingOperation::Exchange { .. } => "exchange",
+            PendingOperation::Redeem { .. } => "redeem",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingRecord {
+    pub id: String,
+    /// Sent with the request, so that resending it cannot apply it twice.
+    pub idempotency_key: String,
+    pub operation: PendingOperation,
+    pub created_at: DateTime<Utc>,
+}
+
+/// How a pending operation ended, applied by `finish_pending`.
+#[derive(Debug, Default)]
+pub struct PendingOutcome {
+    /// Pending tokens the server has accepted.
+    pub spent: Vec<String>,
+    /// Pending tokens the server never received, available again.
+    pub released: Vec<String>,
+    /// Tokens the operation produced, stored in order.
+    pub new_tokens: Vec<Token>,
+}
+
+/// Token rows are plain JSON until `encrypt` is called. From then on each
+/// row's `token_data` is sealed (see `encryption`), and tokens can only be
+/// read or stored while the wallet is unlocked. The currency and
+/// denomination of each token are kept in the clear, so balances are
+/// available while locked.
 pub struct WalletStorage {
     conn: Connection,
+    encrypted: bool,
+    /// The data key while unlocked.
+    cipher: Option<StorageCipher>,
 }
 
 impl WalletStorage {
     pub fn new(db_path: &str) -> Result<Self> {
         let conn = Connection::open(db_path)?;
+        // Freed pages are zeroed, so rows rewritten by `encrypt` or `rekey`
+        // leave no plaintext or old ciphertext behind in the file.
+        conn.pragma_update(None, "secure_delete", "ON")?;
         
         conn.execute(
             r#"
@@ -70,6 +144,7 @@ impl WalletStorage {
                 id TEXT PRIMARY KEY,
                 tx_type TEXT NOT NULL,
                 amount INTEGER NOT NULL,
+                currency TEXT,
                 token_count INTEGER NOT NULL,
                 created_at TEXT NOT NULL,
                 metadata TEXT
@@ -78,61 +153,286 @@ impl WalletStorage {
             [],
         )?;
         
+        // Wallets created before amounts carried a currency lack the column.
+        add_column(&conn, "transactions", "currency", "TEXT")?;
+        
+        // Balances are computed from these so that they need no decryption.
+        // Older wallets only have the token JSON; fill them in from it.
+        if add_column(&conn, "tokens", "currency", "TEXT")? {
+            add_column(&conn, "tokens", "denomination", "INTEGER")?;
+            let rows = conn.prepare("SELECT id, token_data FROM tokens")?
+                .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            for (id, token_json) in rows {
+                let token: Token = serde_json::from_str(&token_json)?;
+                conn.execute(
+                    "UPDATE tokens SET currency = ?1, denomination = ?2 WHERE id = ?3",
+                    params![token.currency, token.denomination as i64, id],
+                )?;
+            }
+        }
+        
+        add_column(&conn, "tokens", "batch_id", "TEXT")?;
+        
+        // Write-ahead records of requests in flight; `operation_data` is
+        // sealed like `token_data`.
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS pending_operations (
+                id TEXT PRIMARY KEY,
+                kind TEXT NOT NULL,
+                idempotency_key TEXT NOT NULL,
+                operation_data TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            )
+            "#,
+            [],
+        )?;
+        
+        // The data key, wrapped under the passphrase, once encryption is on.
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS wallet_key (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                kdf_params TEXT NOT NULL,
+                wrapped_key BLOB NOT NULL
+            )
+            "#,
+            [],
+        )?;
+        
+        // Next unused derivation index of each key set, for seeded wallets.
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS seed_indices (
+                key_id TEXT PRIMARY KEY,
+                next_index INTEGER NOT NULL
+            )
+            "#,
+            [],
+        )?;
+        
+        // Fingerprint of every key set seen, trusted on first use.
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS pinned_keys (
+                institution_id TEXT NOT NULL,
+                key_id TEXT NOT NULL,
+                fingerprint TEXT NOT NULL,
+                pinned_at TEXT NOT NULL,
+                PRIMARY KEY (institution_id, key_id)
+            )
+            "#,
+            [],
+        )?;
+        
+        // Fingerprint of the identity key each institution signs its key
+        // directory with, trusted on first use like the key sets.
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS pinned_identities (
+                institution_id TEXT PRIMARY KEY,
+                fingerprint TEXT NOT NULL,
+                pinned_at TEXT NOT NULL
+            )
+            "#,
+            [],
+        )?;
+        
         conn.execute(
             "CREATE INDEX IF NOT EXISTS idx_tokens_status ON tokens(status)",
             [],
         )?;
         
-        Ok(Self { conn })
+        let encrypted = conn.query_row("SELECT COUNT(*) > 0 FROM wallet_key", [], |row| row.get(0))?;
+        
+        Ok(Self { conn, encrypted, cipher: None })
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    /// Whether tokens are unreadable until `unlock`.
+    pub fn is_locked(&self) -> bool {
+        self.encrypted && self.cipher.is_none()
+    }
+
+    pub fn unlock(&mut self, passphrase: &str) -> Result<()> {
+        let (kdf, wrapped_key) = self.wallet_key()?.ok_or_else(|| {
+            ClientError::StorageCorrupted("Wallet is not encrypted".to_string())
+        })?;
+        self.cipher = Some(StorageCipher::unwrap(&wrapped_key, passphrase, &kdf)?);
+        Ok(())
+    }
+
+    /// Forgets the data key; tokens stay unreadable until `unlock`.
+    pub fn lock(&mut self) {
+        self.cipher = None;
+    }
+
+    /// Seals every token under a new data key wrapped with `passphrase`.
+    /// The wallet is left unlocked.
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<()> {
+        if self.encrypted {
+            return Err(ClientError::StorageCorrupted("Wallet is already encrypted".to_string()));
+        }
+        self.rekey(passphrase)
+    }
+
+    /// Re-seals every token under a new data key wrapped with
+    /// `new_passphrase`, so that neither the old passphrase nor the old
+    /// data key opens the wallet afterwards.
+    pub fn change_passphrase(&mut self, old_passphrase: &str, new_passphrase: &str) -> Result<()> {
+        self.unlock(old_passphrase)?;
+        self.rekey(new_passphrase)
+    }
+
+    /// Replaces the data key in one transaction: every row is opened with
+    /// the current key (or read as plain JSON) and sealed with the new one.
+    fn rekey(&mut self, passphrase: &str) -> Result<()> {
+        let cipher = StorageCipher::generate();
+        let kdf = KdfParams::generate();
+        let wrapped_key = cipher.wrap(passphrase, &kdf)?;
+        
+        let tx = self.conn.unchecked_transaction()?;
+        let rows = tx.prepare("SELECT id, token_data, currency, denomination FROM tokens")?
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, i64>(3)?)))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for (id, token_data, currency, denomination) in rows {
+            let aad = token_aad(&id, &currency, denomination);
+            let token: Token = self.open_row(&aad, &token_data)?;
+            let sealed = seal_row(Some(&cipher), &aad, &token)?;
+            tx.execute("UPDATE tokens SET token_data = ?1 WHERE id = ?2", params![sealed, id])?;
+        }
+        let rows = tx.prepare("SELECT id, kind, idempotency_key, operation_data FROM pending_operations")?
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?)))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for (id, kind, idempotency_key, operation_data) in rows {
+            let aad = pending_aad(&id, &kind, &idempotency_key);
+            let operation: PendingOperation = self.open_row(&aad, &operation_data)?;
+            let sealed = seal_row(Some(&cipher), &aad, &operation)?;
+            tx.execute("UPDATE pending_operations SET operation_data = ?1 WHERE id = ?2", params![sealed, id])?;
+        }
+        store_wallet_key(&tx, &kdf, &wrapped_key)?;
+        tx.commit()?;
+        
+        // Rebuild the file and empty the write-ahead log, so no copy of the
+        // old rows survives outside the pages `secure_delete` zeroes.
+        self.conn.execute_batch("VACUUM; PRAGMA wal_checkpoint(TRUNCATE);")?;
+        
+        self.encrypted = true;
+        self.cipher = Some(cipher);
+        Ok(())
+    }
+
+    fn wallet_key(&self) -> Result<Option<(KdfParams, Vec<u8>)>> {
+        let row = self.conn.query_row(
+            "SELECT kdf_params, wrapped_key FROM wallet_key WHERE id = 1",
+            [],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)),
+        ).optional()?;
+        
+        row.map(|(kdf_params, wrapped_key)| Ok((serde_json::from_str(&kdf_params)?, wrapped_key)))
+            .transpose()
+    }
+
+    /// The data key, or `WalletLocked`. `None` for unencrypted wallets.
+    fn cipher(&self) -> Result<Option<&StorageCipher>> {
+        match (&self.cipher, self.encrypted) {
+            (Some(cipher), _) => Ok(Some(cipher)),
+            (None, true) => Err(ClientError::WalletLocked),
+            (None, false) => Ok(None),
+        }
+    }
+
+    /// Reads a column written by `seal_row` with the same `aad`.
+    fn open_row<T: DeserializeOwned>(&self, aad: &[u8], data: &str) -> Result<T> {
+        let Some(cipher) = self.cipher()? else {
+            return Ok(serde_json::from_str(data)?);
+        };
+        
+        let sealed = BASE64.decode(data)
+            .map_err(|e| ClientError::StorageCorrupted(format!("Invalid sealed data: {}", e)))?;
+        let json = cipher.open(aad, &sealed)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    fn open_token(&self, id: &str, currency: &str, denomination: i64, token_data: &str) -> Result<Token> {
+        self.open_row(&token_aad(id, currency, denomination), token_data)
     }
 
     pub fn store_token(&self, token: Token) -> Result<StoredToken> {
+        self.insert_token(&self.conn, token, None)
+    }
+
+    fn insert_token(&self, conn: &Connection, token: Token, batch_id: Option<&str>) -> Result<StoredToken> {
         let stored = StoredToken {
             id: Uuid::new_v4().to_string(),
             token,
             status: TokenStatus::Available,
             created_at: Utc::now(),
             spent_at: None,
+            batch_id: batch_id.map(str::to_string),
         };
         
-        let token_json = serde_json::to_string(&stored.token)?;
+        let token_data = seal_row(self.cipher()?, &token_aad(&stored.id, &stored.token.currency, stored.token.denomination as i64), &stored.token)?;
         
-        self.conn.execute(
-            "INSERT INTO tokens (id, token_data, status, created_at, spent_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        conn.execute(
+            "INSERT INTO tokens (id, token_data, status, created_at, spent_at, currency, denomination, batch_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
             params![
                 &stored.id,
-                token_json,
+                token_data,
                 stored.status.to_string(),
                 stored.created_at.to_rfc3339(),
                 stored.spent_at.map(|dt| dt.to_rfc3339()),
+                &stored.token.currency,
+                stored.token.denomination as i64,
+                &stored.batch_id,
             ],
         )?;
         
         Ok(stored)
     }
 
+    /// The tokens that can be spent. Fails with `WalletLocked` while the
+    /// wallet is locked.
     pub fn get_available_tokens(&self) -> Result<Vec<StoredToken>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, token_data, status, created_at, spent_at FROM tokens WHERE status = 'available' ORDER BY created_at"
+            "SELECT id, token_data, status, created_at, spent_at, currency, denomination, batch_id FROM tokens WHERE status = 'available' ORDER BY created_at"
         )?;
         
-        let tokens = stmt.query_map([], |row| {
-            let token_json: String = row.get(1)?;
-            let token: Token = serde_json::from_str(&token_json).unwrap();
+        let rows = stmt.query_map([], |row| {
             let created_str: String = row.get(3)?;
             let spent_str: Option<String> = row.get(4)?;
             
-            Ok(StoredToken {
-                id: row.get(0)?,
-                token,
-                status: TokenStatus::from_str(&row.get::<_, String>(2)?),
-                created_at: DateTime::parse_from_rfc3339(&created_str).unwrap().with_timezone(&Utc),
-                spent_at: spent_str.and_then(|s| DateTime::parse_from_rfc3339(&s).ok().map(|dt| dt.with_timezone(&Utc))),
-            })
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                TokenStatus::from_str(&row.get::<_, String>(2)?),
+                DateTime::parse_from_rfc3339(&created_str).unwrap().with_timezone(&Utc),
+                spent_str.and_then(|s| DateTime::parse_from_rfc3339(&s).ok().map(|dt| dt.with_timezone(&Utc))),
+                row.get::<_, String>(5)?,
+                row.get::<_, i64>(6)?,
+                row.get::<_, Option<String>>(7)?,
+            ))
         })?
         .collect::<std::result::Result<Vec<_>, _>>()?;
         
-        Ok(tokens)
+        // Decrypted outside the query so that a locked wallet or a damaged
+        // row is reported as an error.
+        rows.into_iter()
+            .map(|(id, token_data, status, created_at, spent_at, currency, denomination, batch_id)| {
+                Ok(StoredToken {
+                    token: self.open_token(&id, &currency, denomination, &token_data)?,
+                    id,
+                    status,
+                    created_at,
+                    spent_at,
+                    batch_id,
+                })
+            })
+            .collect()
     }
 
     pub fn mark_tokens_spent(&self, token_ids: &[String]) -> Result<()> {
@@ -149,33 +449,219 @@ impl WalletStorage {
         Ok(())
     }
 
-    pub fn get_balance(&self) -> Result<u64> {
+    /// The tokens stored under `ids`, whatever their status, in order.
+    pub fn get_tokens(&self, ids: &[String]) -> Result<Vec<Token>> {
+        ids.iter()
+            .map(|id| {
+                let (token_data, currency, denomination) = self.conn.query_row(
+                    "SELECT token_data, currency, denomination FROM tokens WHERE id = ?1",
+                    params![id],
+                    |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?)),
+                )
+                .optional()?
+                .ok_or_else(|| ClientError::TokenNotFound(id.clone()))?;
+                self.open_token(id, &currency, denomination, &token_data)
+            })
+            .collect()
+    }
+
+    /// Records `operation` before its request is sent, and marks `token_ids`
+    /// pending so that no other operation uses them. Fails if any of them is
+    /// not available.
+    pub fn begin_pending(&self, idempotency_key: &str, operation: &PendingOperation, token_ids: &[String]) -> Result<String> {
+        let id = Uuid::new_v4().to_string();
+        let kind = operation.kind();
+        let operation_data = seal_row(self.cipher()?, &pending_aad(&id, kind, idempotency_key), operation)?;
+        
+        let tx = self.conn.unchecked_transaction()?;
+        for token_id in token_ids {
+            let updated = tx.execute(
+                "UPDATE tokens SET status = 'pending' WHERE id = ?1 AND status = 'available'",
+                params![token_id],
+            )?;
+            if updated != 1 {
+                return Err(ClientError::TokenNotFound(token_id.clone()));
+            }
+        }
+        tx.execute(
+            "INSERT INTO pending_operations (id, kind, idempotency_key, operation_data, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![&id, kind, idempotency_key, operation_data, Utc::now().to_rfc3339()],
+        )?;
+        tx.commit()?;
+        
+        Ok(id)
+    }
+
+    /// Operations begun but not finished, oldest first.
+    pub fn pending_operations(&self) -> Result<Vec<PendingRecord>> {
         let mut stmt = self.conn.prepare(
-            "SELECT token_data FROM tokens WHERE status = 'available'"
+            "SELECT id, kind, idempotency_key, operation_data, created_at FROM pending_operations ORDER BY created_at"
+        )?;
+        
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?, row.get::<_, String>(4)?))
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+        
+        rows.into_iter()
+            .map(|(id, kind, idempotency_key, operation_data, created_at)| {
+                let created_at = DateTime::parse_from_rfc3339(&created_at)
+                    .map_err(|e| ClientError::StorageCorrupted(format!("Invalid created_at: {}", e)))?
+                    .with_timezone(&Utc);
+                Ok(PendingRecord {
+                    operation: self.open_row(&pending_aad(&id, &kind, &idempotency_key), &operation_data)?,
+                    id,
+                    idempotency_key,
+                    created_at,
+                })
+            })
+            .collect()
+    }
+
+    /// Applies `outcome` and forgets the operation, in one transaction.
+    /// Returns the stored new tokens in order, with the operation's id as
+    /// their `batch_id`.
+    pub fn finish_pending(&self, pending_id: &str, outcome: PendingOutcome) -> Result<Vec<StoredToken>> {
+        let tx = self.conn.unchecked_transaction()?;
+        
+        for id in &outcome.spent {
+            tx.execute(
+                "UPDATE tokens SET status = 'spent', spent_at = ?1 WHERE id = ?2",
+                params![Utc::now().to_rfc3339(), id],
+            )?;
+        }
+        for id in &outcome.released {
+            tx.execute(
+                "UPDATE tokens SET status = 'available' WHERE id = ?1 AND status = 'pending'",
+                params![id],
+            )?;
+        }
+        let stored = outcome.new_tokens
+            .into_iter()
+            .map(|token| self.insert_token(&tx, token, Some(pending_id)))
+            .collect::<Result<Vec<_>>>()?;
+        tx.execute("DELETE FROM pending_operations WHERE id = ?1", params![pending_id])?;
+        
+        tx.commit()?;
+        Ok(stored)
+    }
+
+    /// Serial numbers of every token held, spent or not.
+    pub fn known_serials(&self) -> Result<HashSet<Vec<u8>>> {
+        let mut stmt = self.conn.prepare("SELECT id, token_data, currency, denomination FROM tokens")?;
+        
+        let mut serials = HashSet::new();
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, i64>(3)?)))?;
+        for row in rows {
+            let (id, token_data, currency, denomination) = row?;
+            serials.insert(self.open_token(&id, &currency, denomination, &token_data)?.serial_number);
+        }
+        
+        Ok(serials)
+    }
+
+    /// Claims `count` consecutive derivation indices of `key_id` and returns
+    /// the first. Claimed indices are never handed out again, even if the
+    /// request they were claimed for fails.
+    pub fn reserve_seed_indices(&self, key_id: &str, count: usize) -> Result<u64> {
+        let tx = self.conn.unchecked_transaction()?;
+        
+        let first: i64 = tx.query_row(
+            "SELECT COALESCE((SELECT next_index FROM seed_indices WHERE key_id = ?1), 0)",
+            params![key_id],
+            |row| row.get(0),
+        )?;
+        let next = first + count as i64;
+        tx.execute(
+            "INSERT INTO seed_indices (key_id, next_index) VALUES (?1, ?2)
+             ON CONFLICT (key_id) DO UPDATE SET next_index = excluded.next_index",
+            params![key_id, next],
+        )?;
+        
+        tx.commit()?;
+        Ok(first as u64)
+    }
+
+    /// Makes sure indices of `key_id` below `next_index` are not reused.
+    pub fn advance_seed_index(&self, key_id: &str, next_index: u64) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO seed_indices (key_id, next_index) VALUES (?1, ?2)
+             ON CONFLICT (key_id) DO UPDATE SET next_index = MAX(next_index, excluded.next_index)",
+            params![key_id, next_index as i64],
         )?;
         
-        let mut total = 0u64;
+        Ok(())
+    }
+
+    /// The fingerprint pinned for key set `key_id` of `institution_id`.
+    pub fn pinned_key(&self, institution_id: &str, key_id: &str) -> Result<Option<String>> {
+        Ok(self.conn.query_row(
+            "SELECT fingerprint FROM pinned_keys WHERE institution_id = ?1 AND key_id = ?2",
+            params![institution_id, key_id],
+            |row| row.get(0),
+        ).optional()?)
+    }
+
+    /// Pins `fingerprint` for key set `key_id`, unless it is pinned already.
+    pub fn pin_key(&self, institution_id: &str, key_id: &str, fingerprint: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO pinned_keys (institution_id, key_id, fingerprint, pinned_at) VALUES (?1, ?2, ?3, ?4)",
+            params![institution_id, key_id, fingerprint, Utc::now().to_rfc3339()],
+        )?;
+        
+        Ok(())
+    }
+
+    /// The identity key fingerprint pinned for `institution_id`.
+    pub fn pinned_identity(&self, institution_id: &str) -> Result<Option<String>> {
+        Ok(self.conn.query_row(
+            "SELECT fingerprint FROM pinned_identities WHERE institution_id = ?1",
+            params![institution_id],
+            |row| row.get(0),
+        ).optional()?)
+    }
+
+    /// Pins `fingerprint` as the identity key of `institution_id`, unless
+    /// one is pinned already.
+    pub fn pin_identity(&self, institution_id: &str, fingerprint: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO pinned_identities (institution_id, fingerprint, pinned_at) VALUES (?1, ?2, ?3)",
+            params![institution_id, fingerprint, Utc::now().to_rfc3339()],
+        )?;
+        
+        Ok(())
+    }
+
+    /// Total value of the available tokens in minor units, per currency.
+    /// Works while the wallet is locked.
+    pub fn get_balances(&self) -> Result<BTreeMap<String, u64>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT currency, denomination FROM tokens WHERE status = 'available'"
+        )?;
+        
+        let mut balances = BTreeMap::new();
         let tokens = stmt.query_map([], |row| {
-            let token_json: String = row.get(0)?;
-            Ok(token_json)
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
         })?;
         
         for token_result in tokens {
-            let token_json = token_result?;
-            let token: Token = serde_json::from_str(&token_json)?;
-            total += token.denomination;
+            let (currency, denomination) = token_result?;
+            let denomination = u64::try_from(denomination).map_err(|_| EcashError::InvalidAmount)?;
+            let balance = balances.entry(currency).or_insert(0u64);
+            *balance = balance.checked_add(denomination).ok_or(EcashError::AmountOverflow)?;
         }
         
-        Ok(total)
+        Ok(balances)
     }
 
-    pub fn log_transaction(&self, tx_type: &str, amount: u64, token_count: usize, metadata: Option<String>) -> Result<()> {
+    pub fn log_transaction(&self, tx_type: &str, amount: &Amount, token_count: usize, metadata: Option<String>) -> Result<()> {
         self.conn.execute(
-            "INSERT INTO transactions (id, tx_type, amount, token_count, created_at, metadata) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            "INSERT INTO transactions (id, tx_type, amount, currency, token_count, created_at, metadata) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
             params![
                 Uuid::new_v4().to_string(),
                 tx_type,
-                amount as i64,
+                amount.to_i64()?,
+                amount.currency(),
                 token_count as i64,
                 Utc::now().to_rfc3339(),
                 metadata,
@@ -185,3 +671,128 @@ impl WalletStorage {
         Ok(())
     }
 }
+
+/// Adds `column` to `table` unless it exists; returns whether it was added.
+fn add_column(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<bool> {
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+    Ok(!exists)
+}
+
+fn store_wallet_key(tx: &Transaction, kdf: &KdfParams, wrapped_key: &[u8]) -> Result<()> {
+    tx.execute(
+        "INSERT INTO wallet_key (id, kdf_params, wrapped_key) VALUES (1, ?1, ?2)
+         ON CONFLICT (id) DO UPDATE SET kdf_params = excluded.kdf_params, wrapped_key = excluded.wrapped_key",
+        params![serde_json::to_string(kdf)?, wrapped_key],
+    )?;
+    Ok(())
+}
+
+/// A secret column: plain JSON, or sealed and base64-encoded.
+fn seal_row<T: Serialize>(cipher: Option<&StorageCipher>, aad: &[u8], value: &T) -> Result<String> {
+    let json = zeroize::Zeroizing::new(serde_json::to_vec(value)?);
+    match cipher {
+        Some(cipher) => Ok(BASE64.encode(cipher.seal(aad, &json))),
+        None => Ok(String::from_utf8(json.to_vec()).expect("JSON is UTF-8")),
+    }
+}
+
+/// Binds a sealed token to its row and to the metadata kept in the clear.
+fn token_aad(id: &str, currency: &str, denomination: i64) -> Vec<u8> {
+    format!("ecash-wallet/token/v1\0{}\0{}\0{}", id, currency, denomination).into_bytes()
+}
+
+fn pending_aad(id: &str, kind: &str, idempotency_key: &str) -> Vec<u8> {
+    format!("ecash-wallet/pending/v1\0{}\0{}\0{}", id, kind, idempotency_key).into_bytes()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    
+    /// A database file of its own, deleted when dropped.
+    pub(crate) struct TempDb(pub(crate) String);
+    
+    impl TempDb {
+        pub(crate) fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("ecash-wallet-{}.db", Uuid::new_v4()));
+            Self(path.to_string_lossy().into_owned())
+        }
+    }
+    
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm", "-journal"] {
+                let _ = std::fs::remove_file(format!("{}{}", self.0, suffix));
+            }
+        }
+    }
+    
+    fn token(serial: u8, denomination: u64) -> Token {
+        Token {
+            serial_number: vec![serial; 32],
+            denomination,
+            currency: "USD".to_string(),
+            signature: vec![serial; 64],
+            issued_at: Utc::now(),
+            expires_at: Utc::now() + chrono::Duration::days(1),
+            institution_id: "inst_test".to_string(),
+            key_id: "key_1".to_string(),
+            msg_prefix: vec![],
+        }
+    }
+    
+    fn serials(storage: &WalletStorage) -> Vec<Vec<u8>> {
+        storage.get_available_tokens().unwrap()
+            .into_iter()
+            .map(|stored| stored.token.serial_number)
+            .collect()
+    }
+    
+    #[test]
+    fn test_rekey_keeps_tokens_readable() {
+        let db = TempDb::new();
+        let mut storage = WalletStorage::new(&db.0).unwrap();
+        storage.store_token(token(1, 10)).unwrap();
+        let secure_delete: i64 = storage.conn.query_row("PRAGMA secure_delete", [], |row| row.get(0)).unwrap();
+        assert_eq!(secure_delete, 1);
+        
+        storage.encrypt("first").unwrap();
+        storage.store_token(token(2, 50)).unwrap();
+        let raw: String = storage.conn.query_row("SELECT token_data FROM tokens LIMIT 1", [], |row| row.get(0)).unwrap();
+        assert!(serde_json::from_str::<Token>(&raw).is_err());
+        
+        storage.change_passphrase("first", "second").unwrap();
+        drop(storage);
+        
+        let mut storage = WalletStorage::new(&db.0).unwrap();
+        assert!(matches!(storage.unlock("first"), Err(ClientError::WrongPassphrase)));
+        storage.unlock("second").unwrap();
+        assert_eq!(serials(&storage), vec![vec![1; 32], vec![2; 32]]);
+    }
+    
+    #[test]
+    fn test_locked_wallet_reports_balances_but_not_tokens() {
+        let db = TempDb::new();
+        let mut storage = WalletStorage::new(&db.0).unwrap();
+        storage.store_token(token(1, 10)).unwrap();
+        storage.store_token(token(2, 50)).unwrap();
+        storage.encrypt("passphrase").unwrap();
+        
+        storage.lock();
+        assert!(storage.is_locked());
+        assert!(matches!(storage.get_available_tokens(), Err(ClientError::WalletLocked)));
+        assert!(matches!(storage.known_serials(), Err(ClientError::WalletLocked)));
+        assert!(matches!(storage.store_token(token(3, 10)), Err(ClientError::WalletLocked)));
+        assert_eq!(storage.get_balances().unwrap()["USD"], 60);
+        
+        storage.unlock("passphrase").unwrap();
+        assert_eq!(serials(&storage).len(), 2);
+    }
+}
@@ -1,15 +1,95 @@
-use crate::api::{ApiClient, RedeemRequest, WithdrawRequest};
+use crate::api::{
+    ApiClient, DenominationKeyInfo, ExchangeRequest, PublicKeyResponse, RedeemRequest, RestoreRequest,
+    VerifyRequest, WithdrawRequest,
+};
+use crate::directory::{confirm_key_sets, DirectoryVerifier};
 use crate::error::{ClientError, Result};
-use crate::storage::{StoredToken, WalletStorage};
-use chrono::{DateTime, Utc};
-use ecash_core::{Token, Wallet as CoreWallet};
+use crate::pinning::{key_set_fingerprints, KeyChange, KeyPinPolicy, KeySource};
+use crate::selection::CoinSelector;
+use crate::storage::{PendingOperation, PendingOutcome, PendingRecord, StoredToken, WalletStorage};
+use chrono::{DateTime, Duration, Utc};
+use ecash_core::{
+    decompose, Amount, BlindSignature, BlindedToken, Currency, Decomposition, KeyDirectory, KeyStatus,
+    Token, TokenMetadata, Wallet as CoreWallet, WalletSeed,
+};
 use rsa::RsaPublicKey;
+use std::collections::BTreeMap;
+
+/// Recovery ids asked for per restore request. A key set is scanned until a
+/// whole batch comes back empty, so up to this many indices in a row may be
+/// burned by failed withdrawals without losing the tokens after them.
+const RESTORE_BATCH: u64 = 100;
+
+/// How long after it was begun a withdrawal may still be resent. The server
+/// only remembers idempotency keys for `IDEMPOTENCY_TTL_SECONDS` (a day by
+/// default); resending after that would debit the account a second time.
+const WITHDRAW_REPLAY_WINDOW_HOURS: i64 = 23;
+
+/// How long before its key set stops issuing tokens the wallet stops
+/// withdrawing and exchanging under it, leaving room for clock skew and for
+/// the request itself.
+const KEY_REFRESH_MARGIN_MINUTES: i64 = 60;
+
+/// How often `Wallet::refresh_keys` fetches the keys of a server that
+/// publishes no key directory to say when they change.
+const KEY_REFRESH_INTERVAL_MINUTES: i64 = 60;
+
+/// Outcome of `Wallet::recover_pending`.
+#[derive(Debug, Clone, Default)]
+pub struct RecoverySummary {
+    /// Operations the server had carried out, or carried out when resent,
+    /// whose results are now stored.
+    pub completed: usize,
+    /// Operations the server refused or never received; tokens it has not
+    /// seen spent are available again.
+    pub rolled_back: usize,
+    /// Operations left pending, because the server could not be reached or
+    /// a withdrawal is too old to resend safely.
+    pub unresolved: usize,
+}
+
+enum Resolution {
+    Completed,
+    RolledBack,
+    Unresolved,
+}
+
+/// Outcome of `Wallet::restore`.
+#[derive(Debug, Clone, Default)]
+pub struct RestoreSummary {
+    /// Unspent tokens that were missing from the wallet and are now stored.
+    pub restored: Vec<Token>,
+    /// Tokens re-derived from the seed that the server has already seen
+    /// spent, or that have expired.
+    pub spent: usize,
+    pub expired: usize,
+}
 
 pub struct Wallet {
     api: ApiClient,
     storage: WalletStorage,
-    core_wallet: Option<CoreWallet>,
+    /// One core wallet per currency, holding that currency's keys.
+    core_wallets: BTreeMap<String, CoreWallet>,
+    currencies: BTreeMap<String, Currency>,
+    default_currency: String,
     institution_id: String,
+    /// Set for wallets whose tokens are derived from a seed phrase.
+    seed: Option<WalletSeed>,
+    key_pin_policy: KeyPinPolicy,
+    /// Checked before tokens are issued, if set.
+    key_source: Option<KeySource>,
+    /// Fingerprint of each key set as of the last `initialize`.
+    key_fingerprints: BTreeMap<String, String>,
+    key_changes: Vec<KeyChange>,
+    /// Identity key fingerprint given up front, rather than pinned when the
+    /// first key directory is seen.
+    identity_key: Option<String>,
+    directory_verifier: Option<DirectoryVerifier>,
+    /// The verified key directory as of the last `initialize`, if the
+    /// server publishes one.
+    directory: Option<KeyDirectory>,
+    /// When `refresh_keys` next fetches the keys; `None` before `initialize`.
+    refresh_at: Option<DateTime<Utc>>,
 }
 
 impl Wallet {
@@ -20,69 +100,339 @@ impl Wallet {
         Ok(Self {
             api,
             storage,
-            core_wallet: None,
+            core_wallets: BTreeMap::new(),
+            currencies: BTreeMap::new(),
+            default_currency: String::new(),
             institution_id: String::new(),
+            seed: None,
+            key_pin_policy: KeyPinPolicy::default(),
+            key_source: None,
+            key_fingerprints: BTreeMap::new(),
+            key_changes: Vec::new(),
+            identity_key: None,
+            directory_verifier: None,
+            directory: None,
+            refresh_at: None,
         })
     }
 
+    /// Authenticates withdrawals as the account owning `api_key`.
+    pub fn with_api_key(mut self, api_key: String) -> Self {
+        self.api = self.api.with_api_key(api_key);
+        self
+    }
+
+    /// Derives every token's secrets from `seed`, so that the wallet's
+    /// tokens can be recovered with `restore` if the database is lost.
+    pub fn with_seed(mut self, seed: WalletSeed) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// What `initialize` does when a key set differs from the one pinned.
+    /// Refuses by default.
+    pub fn with_key_pin_policy(mut self, policy: KeyPinPolicy) -> Self {
+        self.key_pin_policy = policy;
+        self
+    }
+
+    /// Compares the key set with `source` before every withdrawal and
+    /// exchange, and refuses to blind tokens under keys it does not list.
+    pub fn with_key_source(mut self, source: KeySource) -> Self {
+        self.key_source = Some(source);
+        self
+    }
+
+    /// Only accepts key directories signed by the identity key with
+    /// `fingerprint` (see `ecash_core::identity_fingerprint`), instead of
+    /// trusting the one the first directory is signed with.
+    pub fn with_identity_key(mut self, fingerprint: String) -> Self {
+        self.identity_key = Some(fingerprint);
+        self
+    }
+
+    /// Pinned key sets the server served different keys for at the last
+    /// `initialize`, under `KeyPinPolicy::Warn`.
+    pub fn key_changes(&self) -> &[KeyChange] {
+        &self.key_changes
+    }
+
+    /// Whether the wallet's tokens are encrypted at rest.
+    pub fn is_encrypted(&self) -> bool {
+        self.storage.is_encrypted()
+    }
+
+    /// An encrypted wallet opens locked: balances can be read, but no token
+    /// can be spent, exchanged or received until it is unlocked.
+    pub fn is_locked(&self) -> bool {
+        self.storage.is_locked()
+    }
+
+    pub fn unlock(&mut self, passphrase: &str) -> Result<()> {
+        self.storage.unlock(passphrase)
+    }
+
+    pub fn lock(&mut self) {
+        self.storage.lock();
+    }
+
+    /// Encrypts the wallet's tokens under `passphrase`. The wallet stays
+    /// unlocked until `lock` or until it is reopened.
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<()> {
+        self.storage.encrypt(passphrase)
+    }
+
+    /// Re-encrypts the wallet's tokens under `new_passphrase`.
+    pub fn change_passphrase(&mut self, old_passphrase: &str, new_passphrase: &str) -> Result<()> {
+        self.storage.change_passphrase(old_passphrase, new_passphrase)
+    }
+
+    /// Fetches the institution's keys and currencies. If the server
+    /// publishes a signed key directory, it is verified and must list every
+    /// key set served.
     pub async fn initialize(&mut self) -> Result<()> {
         let key_response = self.api.get_public_key().await?;
+        self.check_key_pins(&key_response)?;
+        let now = Utc::now();
+        let (directory_verifier, directory) = self.check_key_directory(&key_response, now).await?;
+        let mut public_keys = parse_public_keys(&key_response.public_keys)?;
         
-        // Parse decimal strings to BigUint
-        let n = rsa::BigUint::parse_bytes(key_response.public_key_n.as_bytes(), 10)
-            .ok_or_else(|| ClientError::InvalidResponse("Invalid public key N".to_string()))?;
+        self.currencies = key_response.currencies()
+            .into_iter()
+            .map(|currency| (currency.code.clone(), currency))
+            .collect();
+        self.core_wallets = key_response.currencies()
+            .into_iter()
+            .map(|currency| {
+                let core_wallet = CoreWallet::new(
+                    public_keys.remove(&currency.code).unwrap_or_default(),
+                    key_response.institution_id.clone(),
+                    key_response.key_id.clone(),
+                    currency.code.clone(),
+                )
+                .with_variant(key_response.variant);
+                (currency.code, core_wallet)
+            })
+            .collect();
         
-        let e = rsa::BigUint::parse_bytes(key_response.public_key_e.as_bytes(), 10)
-            .ok_or_else(|| ClientError::InvalidResponse("Invalid public key E".to_string()))?;
+        self.refresh_at = Some(refresh_time(directory.as_ref(), &key_response.key_id, now));
+        self.directory_verifier = directory_verifier;
+        self.directory = directory;
+        self.default_currency = key_response.currency;
+        self.institution_id = key_response.institution_id;
         
-        let public_key = RsaPublicKey::new(n, e)
-            .map_err(|e| ClientError::InvalidResponse(format!("Invalid public key: {}", e)))?;
+        Ok(())
+    }
+
+    /// Runs `initialize` again if the key directory is due to be replaced,
+    /// or the active key set is about to stop issuing tokens, or the wallet
+    /// was never initialized. Returns whether it did. Long-running wallets
+    /// call it before withdrawing or exchanging.
+    pub async fn refresh_keys(&mut self) -> Result<bool> {
+        if self.refresh_at.is_some_and(|refresh_at| Utc::now() < refresh_at) {
+            return Ok(false);
+        }
+        self.initialize().await?;
+        Ok(true)
+    }
+
+    /// The verified key directory, as of the last `initialize`.
+    pub fn key_directory(&self) -> Option<&KeyDirectory> {
+        self.directory.as_ref()
+    }
+
+    /// Fetches and verifies the key directory and checks it against the
+    /// key sets served. The identity key is pinned with the first directory
+    /// seen; a server that stops publishing one once it is pinned is
+    /// refused, like one whose directory is signed by another key.
+    async fn check_key_directory(
+        &self,
+        key_response: &PublicKeyResponse,
+        now: DateTime<Utc>,
+    ) -> Result<(Option<DirectoryVerifier>, Option<KeyDirectory>)> {
+        let institution_id = &key_response.institution_id;
+        let pinned = match &self.identity_key {
+            Some(fingerprint) => Some(fingerprint.clone()),
+            None => self.storage.pinned_identity(institution_id)?,
+        };
         
-        self.core_wallet = Some(CoreWallet::new(
-            public_key,
-            key_response.institution_id.clone(),
-            "USD".to_string(),
-        ));
+        let Some(signed) = self.api.get_key_directory().await? else {
+            if pinned.is_some() {
+                return Err(ClientError::InvalidResponse(format!(
+                    "{} does not publish its signed key directory",
+                    institution_id
+                )));
+            }
+            return Ok((None, None));
+        };
+        let identity_fingerprint = match pinned {
+            Some(fingerprint) => fingerprint,
+            None => signed.identity_fingerprint()?,
+        };
+        let verifier = DirectoryVerifier::new(institution_id.clone(), identity_fingerprint);
         
-        self.institution_id = key_response.institution_id;
+        let directory = verifier.verify_current(&signed, now)?;
+        confirm_key_sets(&directory, key_response)?;
+        self.storage.pin_identity(institution_id, verifier.identity_fingerprint())?;
+        
+        Ok((Some(verifier), Some(directory)))
+    }
+
+    /// Refuses to blind tokens under key set `key_id` if the directory says
+    /// it stops issuing within the refresh margin, or the directory itself
+    /// is out of date. `refresh_keys` clears either.
+    fn check_key_current(&self, key_id: &str) -> Result<()> {
+        let Some(directory) = &self.directory else {
+            return Ok(());
+        };
+        let now = Utc::now();
+        let issuing = directory.entry(key_id).is_some_and(|entry| {
+            entry.status == KeyStatus::Active
+                && entry.issue_until
+                    .is_none_or(|issue_until| now + Duration::minutes(KEY_REFRESH_MARGIN_MINUTES) < issue_until)
+        });
+        
+        if !issuing || now > directory.next_update {
+            return Err(ClientError::KeysOutdated { key_id: key_id.to_string() });
+        }
+        Ok(())
+    }
+
+    /// Pins key sets seen for the first time and looks for pinned ones that
+    /// have changed.
+    fn check_key_pins(&mut self, key_response: &PublicKeyResponse) -> Result<()> {
+        let fingerprints = key_set_fingerprints(key_response)?;
+        let mut changes = Vec::new();
+        
+        for (key_id, served) in &fingerprints {
+            match self.storage.pinned_key(&key_response.institution_id, key_id)? {
+                None => self.storage.pin_key(&key_response.institution_id, key_id, served)?,
+                Some(pinned) if pinned != *served => changes.push(KeyChange {
+                    key_id: key_id.clone(),
+                    pinned,
+                    served: served.clone(),
+                }),
+                Some(_) => {}
+            }
+        }
+        
+        if let (KeyPinPolicy::Refuse, Some(change)) = (self.key_pin_policy, changes.first()) {
+            return Err(ClientError::KeyChanged { key_id: change.key_id.clone() });
+        }
+        self.key_fingerprints = fingerprints;
+        self.key_changes = changes;
         
         Ok(())
     }
 
-    pub async fn withdraw(&self, amount: u64, denomination: u64) -> Result<Vec<Token>> {
-        let core_wallet = self.core_wallet.as_ref()
+    /// Makes sure the key source, if any, publishes key set `key_id` as the
+    /// server served it, so that the tokens about to be blinded under it
+    /// look like every other wallet's.
+    async fn confirm_key_set(&self, key_id: &str) -> Result<()> {
+        let Some(source) = &self.key_source else {
+            return Ok(());
+        };
+        let served = self.key_fingerprints.get(key_id)
             .ok_or_else(|| ClientError::InvalidResponse("Wallet not initialized".to_string()))?;
         
-        let tokens_to_prepare = core_wallet.prepare_withdrawal(amount, denomination)
-            .map_err(ClientError::Core)?;
+        let published = source.fingerprints(&self.institution_id, self.directory_verifier.as_ref()).await?;
+        if published.get(key_id) != Some(served) {
+            return Err(ClientError::KeyNotConfirmed {
+                key_id: key_id.to_string(),
+                checked_against: source.to_string(),
+            });
+        }
+        
+        Ok(())
+    }
+
+    /// The institution's default currency, as of the last `initialize`.
+    pub fn default_currency(&self) -> &str {
+        &self.default_currency
+    }
+
+    /// The currencies the institution issues, as of the last `initialize`.
+    pub fn currencies(&self) -> Vec<String> {
+        self.core_wallets.keys().cloned().collect()
+    }
+
+    /// Parses a decimal amount in major units of `currency`, such as `12.50`.
+    pub fn parse_amount(&self, currency: &str, value: &str) -> Result<Amount> {
+        Ok(Amount::parse(value, self.currency(currency)?)?)
+    }
+
+    fn currency(&self, code: &str) -> Result<&Currency> {
+        if self.currencies.is_empty() {
+            return Err(ClientError::InvalidResponse("Wallet not initialized".to_string()));
+        }
+        self.currencies.get(code)
+            .ok_or_else(|| ClientError::UnsupportedCurrency(code.to_string()))
+    }
+
+    fn ensure_unlocked(&self) -> Result<()> {
+        if self.storage.is_locked() {
+            return Err(ClientError::WalletLocked);
+        }
+        Ok(())
+    }
+
+    fn core_wallet(&self, currency: &str) -> Result<&CoreWallet> {
+        if self.core_wallets.is_empty() {
+            return Err(ClientError::InvalidResponse("Wallet not initialized".to_string()));
+        }
+        self.core_wallets.get(currency)
+            .ok_or_else(|| ClientError::UnsupportedCurrency(currency.to_string()))
+    }
+
+    /// Withdraws tokens worth exactly `amount`, which must be in the
+    /// account's currency, split into its denominations with `strategy`.
+    pub async fn withdraw(&self, amount: &Amount, strategy: Decomposition) -> Result<Vec<Token>> {
+        // Refuse before the account is debited for tokens that could not
+        // be stored.
+        self.ensure_unlocked()?;
+        let amount = self.currency(amount.currency())?.normalize(amount)?;
+        let core_wallet = self.core_wallet(amount.currency())?;
+        
+        // The expiry epoch rolls over daily, so fetch the current one rather
+        // than reusing the value seen at initialization.
+        let expiry_epoch = self.api.get_public_key().await?.expiry_epoch;
         
-        let (blinded_tokens, metadata): (Vec<_>, Vec<_>) = tokens_to_prepare.into_iter().unzip();
+        let denominations = core_wallet.decompose(&amount, strategy)?;
+        self.check_key_current(core_wallet.key_id())?;
+        self.confirm_key_set(core_wallet.key_id()).await?;
+        let (blinded_tokens, metadata) = self.prepare_tokens(core_wallet, &denominations, expiry_epoch)?;
         
         let request = WithdrawRequest {
-            amount,
-            denomination,
+            amount: amount.clone(),
+            expiry_epoch,
             blinded_tokens: blinded_tokens.clone(),
         };
         
-        let response = self.api.withdraw(request).await?;
+        // The blinding factors are the only way to unblind what the server
+        // signs, so they are on disk before the account is debited.
+        let idempotency_key = ApiClient::new_idempotency_key();
+        let operation = PendingOperation::Withdraw {
+            request: request.clone(),
+            metadata: metadata.clone(),
+        };
+        let pending_id = self.storage.begin_pending(&idempotency_key, &operation, &[])?;
         
-        let expires_at = DateTime::parse_from_rfc3339(&response.expires_at)
-            .map_err(|e| ClientError::InvalidResponse(format!("Invalid expires_at: {}", e)))?
-            .with_timezone(&Utc);
+        let response = self.api.withdraw_with_key(request, &idempotency_key).await;
+        let response = self.check_sent(&pending_id, response, &[])?;
         
         let tokens = core_wallet.finalize_withdrawal(
             response.blind_signatures,
             metadata,
-            expires_at,
         ).map_err(ClientError::Core)?;
         
-        for token in &tokens {
-            self.storage.store_token(token.clone())?;
-        }
+        self.storage.finish_pending(&pending_id, PendingOutcome {
+            new_tokens: tokens.clone(),
+            ..Default::default()
+        })?;
         
         self.storage.log_transaction(
             "withdraw",
-            amount,
+            &amount,
             tokens.len(),
             Some(response.transaction_id),
         )?;
@@ -90,45 +440,84 @@ impl Wallet {
         Ok(tokens)
     }
 
-    pub async fn spend(&self, amount: u64) -> Result<String> {
-        let available = self.storage.get_available_tokens()?;
+    /// Pays `amount` with tokens picked by `selector`. Without tokens adding
+    /// up to exactly `amount`, the selected tokens are first exchanged for
+    /// the payment plus change, so nothing is overpaid; the change stays in
+    /// the wallet.
+    pub async fn spend(&self, amount: &Amount, selector: CoinSelector) -> Result<String> {
+        self.ensure_unlocked()?;
+        let currency = self.currency(amount.currency())?;
+        let amount = currency.normalize(amount)?;
+        let available: Vec<StoredToken> = self.storage.get_available_tokens()?
+            .into_iter()
+            .filter(|stored| stored.token.currency == currency.code)
+            .collect();
         
-        if available.is_empty() {
-            return Err(ClientError::NoTokensAvailable);
-        }
+        let now = Utc::now();
+        let spendable = available.iter()
+            .filter(|stored| stored.token.expires_at >= now)
+            .try_fold(currency.amount(0), |total, stored| total.checked_add(&stored.token.amount(currency)?))?;
+        let insufficient = |required: &Amount| ClientError::InsufficientBalance {
+            required: required.clone(),
+            available: spendable.clone(),
+        };
         
-        let mut selected_tokens = Vec::new();
-        let mut total = 0u64;
-        let mut token_ids = Vec::new();
+        let mut selected = selector.select(&available, amount.minor_units(), now)
+            .ok_or_else(|| insufficient(&amount))?;
         
-        for stored_token in available {
-            if total >= amount {
-                break;
-            }
-            total += stored_token.token.denomination;
-            token_ids.push(stored_token.id);
-            selected_tokens.push(stored_token.token);
+        if selected.is_empty() {
+            return Err(ClientError::NoTokensAvailable);
         }
         
-        if total < amount {
-            return Err(ClientError::InsufficientBalance { 
-                required: amount, 
-                available: total 
-            });
+        let total = selected.iter().try_fold(currency.amount(0), |total, stored| total.checked_add(&stored.token.amount(currency)?))?;
+        if total > amount {
+            let keys = self.api.get_public_key().await?;
+            
+            // The change must also cover the exchange fee.
+            let required = amount.checked_add(&currency.amount(keys.exchange_fee))?;
+            selected = selector.select(&available, required.minor_units(), now)
+                .ok_or_else(|| insufficient(&required))?;
+            let total = selected.iter().try_fold(currency.amount(0), |total, stored| total.checked_add(&stored.token.amount(currency)?))?;
+            
+            let denominations = keys.currency(&currency.code)?.denominations;
+            let payment = decompose(amount.minor_units(), &denominations, Decomposition::Greedy)?;
+            let change = decompose(total.checked_sub(&required)?.minor_units(), &denominations, Decomposition::Greedy)?;
+            let outputs: Vec<u64> = payment.iter().chain(&change).copied().collect();
+            
+            let mut exchanged = self.exchange_stored(selected, &outputs, keys.expiry_epoch).await?;
+            exchanged.truncate(payment.len());
+            selected = exchanged;
         }
         
+        let (token_ids, selected_tokens): (Vec<_>, Vec<_>) = selected
+            .into_iter()
+            .map(|stored| (stored.id, stored.token))
+            .unzip();
+        
         let request = RedeemRequest {
             tokens: selected_tokens,
-            merchant_id: Some("self".to_string()),
         };
         
-        let response = self.api.redeem(request).await?;
+        // Marked pending first, so that tokens the server may have accepted
+        // are never offered again before `recover_pending` has checked them.
+        let idempotency_key = ApiClient::new_idempotency_key();
+        let operation = PendingOperation::Redeem {
+            token_ids: token_ids.clone(),
+            amount: amount.clone(),
+        };
+        let pending_id = self.storage.begin_pending(&idempotency_key, &operation, &token_ids)?;
+        
+        let response = self.api.redeem_with_key(request, &idempotency_key).await;
+        let response = self.check_sent(&pending_id, response, &token_ids)?;
         
-        self.storage.mark_tokens_spent(&token_ids)?;
+        self.storage.finish_pending(&pending_id, PendingOutcome {
+            spent: token_ids.clone(),
+            ..Default::default()
+        })?;
         
         self.storage.log_transaction(
             "spend",
-            amount,
+            &amount,
             token_ids.len(),
             Some(response.transaction_id.clone()),
         )?;
@@ -136,8 +525,407 @@ impl Wallet {
         Ok(response.transaction_id)
     }
 
-    pub fn get_balance(&self) -> Result<u64> {
-        self.storage.get_balance()
+    /// Exchanges the available token `token_id` for new tokens of
+    /// `denominations`, which with the server's fee must not be worth more.
+    pub async fn split(&self, token_id: &str, denominations: &[u64]) -> Result<Vec<Token>> {
+        self.ensure_unlocked()?;
+        let input = self.storage.get_available_tokens()?
+            .into_iter()
+            .find(|stored| stored.id == token_id)
+            .ok_or_else(|| ClientError::TokenNotFound(token_id.to_string()))?;
+        
+        let expiry_epoch = self.api.get_public_key().await?.expiry_epoch;
+        let outputs = self.exchange_stored(vec![input], denominations, expiry_epoch).await?;
+        
+        Ok(outputs.into_iter().map(|stored| stored.token).collect())
+    }
+
+    /// Exchanges all available tokens of `currency` for as few tokens as
+    /// their value, less the server's fee, allows. Does nothing if that would
+    /// not reduce the number of tokens.
+    pub async fn consolidate(&self, currency: &str) -> Result<Vec<Token>> {
+        self.ensure_unlocked()?;
+        let currency = self.currency(currency)?;
+        let available: Vec<StoredToken> = self.storage.get_available_tokens()?
+            .into_iter()
+            .filter(|stored| stored.token.currency == currency.code)
+            .collect();
+        let total = available.iter().try_fold(currency.amount(0), |total, stored| {
+            total.checked_add(&stored.token.amount(currency)?)
+        })?;
+        
+        let keys = self.api.get_public_key().await?;
+        let Ok(value) = total.checked_sub(&currency.amount(keys.exchange_fee)) else {
+            return Ok(Vec::new());
+        };
+        let outputs = decompose(value.minor_units(), &keys.currency(&currency.code)?.denominations, Decomposition::Greedy)?;
+        if outputs.len() >= available.len() {
+            return Ok(Vec::new());
+        }
+        
+        let outputs = self.exchange_stored(available, &outputs, keys.expiry_epoch).await?;
+        
+        Ok(outputs.into_iter().map(|stored| stored.token).collect())
+    }
+
+    /// Exchanges available tokens that stop being redeemable within
+    /// `within`, because they expire or their key set does, for new tokens
+    /// under the active key set. A currency's tokens are renewed together,
+    /// and only if they are worth more than the exchange fee. Returns the
+    /// new tokens.
+    pub async fn renew_expiring(&self, within: Duration) -> Result<Vec<Token>> {
+        self.ensure_unlocked()?;
+        let now = Utc::now();
+        let mut expiring: BTreeMap<String, Vec<StoredToken>> = BTreeMap::new();
+        for stored in self.storage.get_available_tokens()? {
+            let redeemable_until = self.redeemable_until(&stored.token);
+            if now < redeemable_until && redeemable_until <= now + within {
+                expiring.entry(stored.token.currency.clone()).or_default().push(stored);
+            }
+        }
+        if expiring.is_empty() {
+            return Ok(Vec::new());
+        }
+        
+        let keys = self.api.get_public_key().await?;
+        let mut renewed = Vec::new();
+        for (code, inputs) in expiring {
+            let currency = self.currency(&code)?;
+            let total = inputs.iter().try_fold(currency.amount(0), |total, stored| {
+                total.checked_add(&stored.token.amount(currency)?)
+            })?;
+            let value = match total.checked_sub(&currency.amount(keys.exchange_fee)) {
+                Ok(value) if !value.is_zero() => value,
+                _ => continue,
+            };
+            
+            let outputs = decompose(value.minor_units(), &keys.currency(&code)?.denominations, Decomposition::Greedy)?;
+            let outputs = self.exchange_stored(inputs, &outputs, keys.expiry_epoch).await?;
+            renewed.extend(outputs.into_iter().map(|stored| stored.token));
+        }
+        
+        Ok(renewed)
+    }
+
+    /// The last instant `token` can be redeemed: its own expiry, or its key
+    /// set's if the directory says that comes first.
+    fn redeemable_until(&self, token: &Token) -> DateTime<Utc> {
+        self.directory.as_ref()
+            .and_then(|directory| directory.entry(&token.key_id))
+            .and_then(|entry| entry.expires_at)
+            .map_or(token.expires_at, |key_expires_at| key_expires_at.min(token.expires_at))
+    }
+
+    /// Exchanges `inputs`, all in one currency, for new tokens of
+    /// `denominations` in that currency, marking the inputs spent and storing
+    /// the new tokens in request order.
+    async fn exchange_stored(
+        &self,
+        inputs: Vec<StoredToken>,
+        denominations: &[u64],
+        expiry_epoch: u64,
+    ) -> Result<Vec<StoredToken>> {
+        let Some(first) = inputs.first() else {
+            return Err(ClientError::NoTokensAvailable);
+        };
+        let core_wallet = self.core_wallet(&first.token.currency)?;
+        
+        self.check_key_current(core_wallet.key_id())?;
+        self.confirm_key_set(core_wallet.key_id()).await?;
+        let (outputs, metadata) = self.prepare_tokens(core_wallet, denominations, expiry_epoch)?;
+        let (token_ids, tokens): (Vec<_>, Vec<_>) = inputs
+            .into_iter()
+            .map(|stored| (stored.id, stored.token))
+            .unzip();
+        
+        let request = ExchangeRequest {
+            inputs: tokens,
+            outputs,
+            expiry_epoch,
+        };
+        
+        let idempotency_key = ApiClient::new_idempotency_key();
+        let operation = PendingOperation::Exchange {
+            input_ids: token_ids.clone(),
+            request: request.clone(),
+            metadata: metadata.clone(),
+        };
+        let pending_id = self.storage.begin_pending(&idempotency_key, &operation, &token_ids)?;
+        
+        let response = self.api.exchange_with_key(request, &idempotency_key).await;
+        let response = self.check_sent(&pending_id, response, &token_ids)?;
+        
+        let new_tokens = core_wallet.finalize_withdrawal(
+            response.blind_signatures,
+            metadata,
+        ).map_err(ClientError::Core)?;
+        
+        let stored = self.storage.finish_pending(&pending_id, PendingOutcome {
+            spent: token_ids.clone(),
+            released: Vec::new(),
+            new_tokens,
+        })?;
+        
+        self.storage.log_transaction(
+            "exchange",
+            &response.input_amount,
+            token_ids.len(),
+            Some(response.transaction_id),
+        )?;
+        
+        Ok(stored)
+    }
+
+    /// Passes on the result of sending pending operation `pending_id`. If
+    /// the server refused it, nothing happened and the operation is dropped,
+    /// releasing `token_ids`. After any other failure the outcome is unknown,
+    /// so the operation stays pending for `recover_pending`.
+    fn check_sent<T>(&self, pending_id: &str, result: Result<T>, token_ids: &[String]) -> Result<T> {
+        if let Err(e) = &result {
+            if e.is_rejection() {
+                self.storage.finish_pending(pending_id, PendingOutcome {
+                    released: token_ids.to_vec(),
+                    ..Default::default()
+                })?;
+            }
+        }
+        result
+    }
+
+    /// Settles the operations a crash or a lost connection left pending;
+    /// run it once the wallet is initialized and unlocked. Withdrawals and
+    /// exchanges are resent with their original idempotency key, so the
+    /// server replays its response if it had already acted. Redemptions are
+    /// not resent: each token is checked, and those the server has not seen
+    /// spent are available again.
+    pub async fn recover_pending(&self) -> Result<RecoverySummary> {
+        self.ensure_unlocked()?;
+        let mut summary = RecoverySummary::default();
+        
+        let pending = self.storage.pending_operations()?;
+        if pending.is_empty() {
+            return Ok(summary);
+        }
+        let key_response = self.api.get_public_key().await?;
+        
+        for record in pending {
+            let resolution = match self.recover_operation(&key_response, record).await {
+                Ok(resolution) => resolution,
+                // Unreachable or failing server: try again next time.
+                Err(ClientError::Http(_) | ClientError::ServerError(_) | ClientError::RateLimited { .. }) => Resolution::Unresolved,
+                Err(e) => return Err(e),
+            };
+            match resolution {
+                Resolution::Completed => summary.completed += 1,
+                Resolution::RolledBack => summary.rolled_back += 1,
+                Resolution::Unresolved => summary.unresolved += 1,
+            }
+        }
+        
+        Ok(summary)
+    }
+
+    async fn recover_operation(&self, key_response: &PublicKeyResponse, record: PendingRecord) -> Result<Resolution> {
+        match record.operation {
+            PendingOperation::Withdraw { request, metadata } => {
+                if Utc::now() - record.created_at >= chrono::Duration::hours(WITHDRAW_REPLAY_WINDOW_HOURS) {
+                    return Ok(Resolution::Unresolved);
+                }
+                let amount = request.amount.clone();
+                let response = match self.api.withdraw_with_key(request, &record.idempotency_key).await {
+                    Err(e) if e.is_rejection() => {
+                        self.storage.finish_pending(&record.id, PendingOutcome::default())?;
+                        return Ok(Resolution::RolledBack);
+                    }
+                    response => response?,
+                };
+                
+                let tokens = finalize_pending(key_response, response.blind_signatures, metadata)?;
+                let count = tokens.len();
+                self.storage.finish_pending(&record.id, PendingOutcome {
+                    new_tokens: tokens,
+                    ..Default::default()
+                })?;
+                self.storage.log_transaction("withdraw", &amount, count, Some(response.transaction_id))?;
+                
+                Ok(Resolution::Completed)
+            }
+            PendingOperation::Exchange { input_ids, request, metadata } => {
+                // Resending is safe even after the server has forgotten the
+                // key: inputs it already accepted are refused as spent.
+                let inputs = request.inputs.clone();
+                let response = match self.api.exchange_with_key(request, &record.idempotency_key).await {
+                    Err(e) if e.is_rejection() => {
+                        self.settle_by_verifying(&record.id, input_ids, inputs).await?;
+                        return Ok(Resolution::RolledBack);
+                    }
+                    response => response?,
+                };
+                
+                let new_tokens = finalize_pending(key_response, response.blind_signatures, metadata)?;
+                self.storage.finish_pending(&record.id, PendingOutcome {
+                    spent: input_ids.clone(),
+                    released: Vec::new(),
+                    new_tokens,
+                })?;
+                self.storage.log_transaction("exchange", &response.input_amount, input_ids.len(), Some(response.transaction_id))?;
+                
+                Ok(Resolution::Completed)
+            }
+            PendingOperation::Redeem { token_ids, amount } => {
+                let tokens = self.storage.get_tokens(&token_ids)?;
+                let spent = self.settle_by_verifying(&record.id, token_ids, tokens).await?;
+                if spent == 0 {
+                    return Ok(Resolution::RolledBack);
+                }
+                // The response, and with it the transaction id, was lost.
+                self.storage.log_transaction("spend", &amount, spent, None)?;
+                
+                Ok(Resolution::Completed)
+            }
+        }
+    }
+
+    /// Ends pending operation `pending_id` by asking the server about each
+    /// of its tokens: those it has seen spent are marked spent, the rest
+    /// are released. Returns how many were spent.
+    async fn settle_by_verifying(&self, pending_id: &str, token_ids: Vec<String>, tokens: Vec<Token>) -> Result<usize> {
+        let mut outcome = PendingOutcome::default();
+        for (id, token) in token_ids.into_iter().zip(tokens) {
+            if self.api.verify(VerifyRequest { token }).await?.spent {
+                outcome.spent.push(id);
+            } else {
+                outcome.released.push(id);
+            }
+        }
+        
+        let spent = outcome.spent.len();
+        self.storage.finish_pending(pending_id, outcome)?;
+        Ok(spent)
+    }
+
+    /// Blinds new tokens of `denominations`, from the seed if there is one.
+    /// Seeded tokens take the next unused indices of the key set, which are
+    /// claimed before anything is sent.
+    fn prepare_tokens(
+        &self,
+        core_wallet: &CoreWallet,
+        denominations: &[u64],
+        expiry_epoch: u64,
+    ) -> Result<(Vec<BlindedToken>, Vec<TokenMetadata>)> {
+        let prepared = match &self.seed {
+            Some(seed) => {
+                let first_index = self.storage.reserve_seed_indices(core_wallet.key_id(), denominations.len())?;
+                core_wallet.prepare_tokens_from_seed(seed, denominations, expiry_epoch, first_index)?
+            }
+            None => core_wallet.prepare_tokens(denominations, expiry_epoch)?,
+        };
+        
+        Ok(prepared.into_iter().unzip())
+    }
+
+    /// Recovers the tokens issued to this wallet's seed, for a wallet whose
+    /// database was lost. Every key set the server still accepts tokens of
+    /// is scanned for issuance records, each token is rebuilt from the seed
+    /// and checked with the server, and unspent tokens the wallet does not
+    /// hold yet are stored. New tokens are derived past the last index
+    /// found.
+    pub async fn restore(&self) -> Result<RestoreSummary> {
+        let seed = self.seed.as_ref().ok_or(ClientError::NoSeed)?;
+        self.ensure_unlocked()?;
+        let key_response = self.api.get_public_key().await?;
+        
+        // Servers that predate `keys` only publish the active key set.
+        let key_sets: Vec<(String, Vec<DenominationKeyInfo>)> = if key_response.keys.is_empty() {
+            vec![(key_response.key_id.clone(), key_response.public_keys.clone())]
+        } else {
+            key_response.keys.iter()
+                .filter(|key| key.status != KeyStatus::Revoked)
+                .map(|key| (key.key_id.clone(), key.public_keys.clone()))
+                .collect()
+        };
+        
+        let mut known_serials = self.storage.known_serials()?;
+        let mut summary = RestoreSummary::default();
+        
+        for (key_id, key_info) in key_sets {
+            let core_wallets = key_set_wallets(&key_response, &key_id, &key_info)?;
+            
+            let mut next_index = None;
+            let mut start = 0;
+            loop {
+                let indices: BTreeMap<Vec<u8>, u64> = (start..start + RESTORE_BATCH)
+                    .map(|index| (seed.token_secrets(&key_id, index).recovery_id, index))
+                    .collect();
+                let request = RestoreRequest {
+                    recovery_ids: indices.keys().cloned().collect(),
+                };
+                
+                let response = self.api.restore(request).await?;
+                let mut found = false;
+                
+                for issued in response.tokens {
+                    let Some(&index) = indices.get(&issued.recovery_id) else {
+                        continue;
+                    };
+                    found = true;
+                    next_index = next_index.max(Some(index + 1));
+                    
+                    let core_wallet = core_wallets.get(&issued.currency)
+                        .ok_or_else(|| ClientError::UnsupportedCurrency(issued.currency.clone()))?;
+                    let token = core_wallet.restore_token(seed, index, &issued)?;
+                    if known_serials.contains(&token.serial_number) {
+                        continue;
+                    }
+                    
+                    let status = self.api.verify(VerifyRequest { token: token.clone() }).await?;
+                    if status.expired {
+                        summary.expired += 1;
+                    } else if status.spent {
+                        summary.spent += 1;
+                    } else if !status.valid {
+                        return Err(ClientError::InvalidResponse(format!(
+                            "Restored token {} does not verify",
+                            token.serial_hex()
+                        )));
+                    } else {
+                        known_serials.insert(token.serial_number.clone());
+                        self.storage.store_token(token.clone())?;
+                        summary.restored.push(token);
+                    }
+                }
+                
+                if !found {
+                    break;
+                }
+                start += RESTORE_BATCH;
+            }
+            
+            if let Some(next_index) = next_index {
+                self.storage.advance_seed_index(&key_id, next_index)?;
+            }
+        }
+        
+        Ok(summary)
+    }
+
+    /// Value of the available tokens of `currency`.
+    pub fn get_balance(&self, currency: &str) -> Result<Amount> {
+        let minor_units = self.storage.get_balances()?.get(currency).copied().unwrap_or(0);
+        Ok(self.currency(currency)?.amount(minor_units))
+    }
+
+    /// Value of the available tokens in each currency held.
+    pub fn get_balances(&self) -> Result<BTreeMap<String, Amount>> {
+        self.storage.get_balances()?
+            .into_iter()
+            .map(|(code, minor_units)| Ok((code.clone(), self.currency(&code)?.amount(minor_units))))
+            .collect()
+    }
+
+    /// Balance of the server-side account withdrawals are debited from.
+    pub async fn get_account_balance(&self) -> Result<Amount> {
+        Ok(self.api.get_account().await?.balance)
     }
 
     pub fn get_available_tokens(&self) -> Result<Vec<StoredToken>> {
@@ -148,3 +936,232 @@ impl Wallet {
         self.api.health_check().await
     }
 }
+
+/// When the keys are next fetched: when the directory is due to be
+/// replaced, or once key set `key_id` is about to stop issuing, whichever
+/// comes first. Without a directory, at a fixed interval.
+fn refresh_time(directory: Option<&KeyDirectory>, key_id: &str, now: DateTime<Utc>) -> DateTime<Utc> {
+    let Some(directory) = directory else {
+        return now + Duration::minutes(KEY_REFRESH_INTERVAL_MINUTES);
+    };
+    directory.entry(key_id)
+        .and_then(|entry| entry.issue_until)
+        .map(|issue_until| issue_until - Duration::minutes(KEY_REFRESH_MARGIN_MINUTES))
+        .map_or(directory.next_update, |stops_issuing| stops_issuing.min(directory.next_update))
+}
+
+/// One core wallet per currency for key set `key_id`, whose public keys
+/// are `keys`.
+fn key_set_wallets(
+    key_response: &PublicKeyResponse,
+    key_id: &str,
+    keys: &[DenominationKeyInfo],
+) -> Result<BTreeMap<String, CoreWallet>> {
+    Ok(parse_public_keys(keys)?
+        .into_iter()
+        .map(|(currency, public_keys)| {
+            let core_wallet = CoreWallet::new(
+                public_keys,
+                key_response.institution_id.clone(),
+                key_id.to_string(),
+                currency.clone(),
+            )
+            .with_variant(key_response.variant);
+            (currency, core_wallet)
+        })
+        .collect())
+}
+
+/// Unblinds the signatures returned for a pending operation. Its tokens may
+/// have been blinded for a key set that has been rotated out since.
+fn finalize_pending(
+    key_response: &PublicKeyResponse,
+    blind_signatures: Vec<BlindSignature>,
+    metadata: Vec<TokenMetadata>,
+) -> Result<Vec<Token>> {
+    let Some(first) = metadata.first() else {
+        return Ok(Vec::new());
+    };
+    
+    let keys = if first.key_id == key_response.key_id {
+        &key_response.public_keys
+    } else {
+        &key_response.keys.iter()
+            .find(|key| key.key_id == first.key_id)
+            .ok_or_else(|| ClientError::InvalidResponse(format!("Key set {} is no longer published", first.key_id)))?
+            .public_keys
+    };
+    let core_wallets = key_set_wallets(key_response, &first.key_id, keys)?;
+    let core_wallet = core_wallets.get(&first.currency)
+        .ok_or_else(|| ClientError::UnsupportedCurrency(first.currency.clone()))?;
+    
+    Ok(core_wallet.finalize_withdrawal(blind_signatures, metadata)?)
+}
+
+/// Groups a key set's public keys by currency and denomination; each
+/// denomination of each currency is signed by its own key.
+pub(crate) fn parse_public_keys(keys: &[DenominationKeyInfo]) -> Result<BTreeMap<String, BTreeMap<u64, RsaPublicKey>>> {
+    let mut public_keys: BTreeMap<String, BTreeMap<u64, RsaPublicKey>> = BTreeMap::new();
+    for key in keys {
+        let public_key = match &key.jwk {
+            Some(jwk) => jwk.to_public_key()
+                .map_err(|_| ClientError::InvalidResponse("Invalid public key JWK".to_string()))?,
+            None => decimal_public_key(key)?,
+        };
+        
+        public_keys.entry(key.currency.clone()).or_default().insert(key.denomination, public_key);
+    }
+    
+    Ok(public_keys)
+}
+
+fn decimal_public_key(key: &DenominationKeyInfo) -> Result<RsaPublicKey> {
+    let n = rsa::BigUint::parse_bytes(key.public_key_n.as_bytes(), 10)
+        .ok_or_else(|| ClientError::InvalidResponse("Invalid public key N".to_string()))?;
+    
+    let e = rsa::BigUint::parse_bytes(key.public_key_e.as_bytes(), 10)
+        .ok_or_else(|| ClientError::InvalidResponse("Invalid public key E".to_string()))?;
+    
+    RsaPublicKey::new(n, e)
+        .map_err(|e| ClientError::InvalidResponse(format!("Invalid public key: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::TempDb;
+    use axum::extract::State;
+    use axum::routing::{get, post};
+    use axum::{Json, Router};
+    use chacha20poly1305::aead::OsRng;
+    use ecash_core::{Institution, InstitutionKey, Jwk, KeyRing, KeyValidity, RsaBssaVariant};
+    use rsa::RsaPrivateKey;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    
+    struct MockServer {
+        institution: Institution,
+        keys: Value,
+    }
+    
+    fn mock_server() -> (MockServer, CoreWallet) {
+        let mut key = InstitutionKey::new("key_1".to_string(), KeyStatus::Active, KeyValidity::unbounded());
+        let mut public_keys = BTreeMap::new();
+        let mut key_infos = Vec::new();
+        for denomination in [10, 50] {
+            let private_key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+            let public_key = private_key.to_public_key();
+            key_infos.push(json!({
+                "currency": "USD",
+                "denomination": denomination,
+                "public_key_n": "",
+                "public_key_e": "",
+                "jwk": Jwk::from_public_key(&public_key),
+            }));
+            public_keys.insert(denomination, public_key);
+            key = key.with_private_key("USD", denomination, private_key);
+        }
+        let mut keyring = KeyRing::new();
+        keyring.insert(key);
+        let currencies = vec![Currency::new("USD", vec![10, 50]).unwrap()];
+        let institution = Institution::new(keyring, "inst_test".to_string(), currencies, 90);
+        
+        let keys = json!({
+            "key_id": "key_1",
+            "institution_id": "inst_test",
+            "currency": "USD",
+            "denominations": [10, 50],
+            "variant": RsaBssaVariant::default(),
+            "expiry_epoch": institution.current_expiry_epoch(),
+            "public_keys": key_infos,
+            "keys": [{ "key_id": "key_1", "status": KeyStatus::Active, "public_keys": key_infos }],
+        });
+        let core_wallet = CoreWallet::new(public_keys, "inst_test".to_string(), "key_1".to_string(), "USD".to_string());
+        
+        (MockServer { institution, keys }, core_wallet)
+    }
+    
+    /// Serves the keys, signs every withdrawal, and reports every token as
+    /// spent. Returns the server's URL.
+    async fn serve(server: MockServer) -> String {
+        let app = Router::new()
+            .route("/api/v1/keys", get(|State(server): State<Arc<MockServer>>| async move {
+                Json(server.keys.clone())
+            }))
+            .route("/api/v1/withdraw", post(|State(server): State<Arc<MockServer>>, Json(request): Json<WithdrawRequest>| async move {
+                let blind_signatures: Vec<BlindSignature> = request.blinded_tokens.iter()
+                    .map(|blinded| server.institution.sign_blinded_token(blinded).unwrap())
+                    .collect();
+                Json(json!({
+                    "blind_signatures": blind_signatures,
+                    "key_id": "key_1",
+                    "expires_at": Utc::now().to_rfc3339(),
+                    "transaction_id": "tx_withdraw",
+                    "balance": request.amount,
+                }))
+            }))
+            .route("/api/v1/verify", post(|| async {
+                Json(json!({ "valid": false, "expired": false, "spent": true, "message": "Token has already been spent" }))
+            }))
+            .with_state(Arc::new(server));
+        
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        url
+    }
+    
+    #[tokio::test]
+    async fn test_recover_pending_resolves_withdraw_and_spend_after_reopening() {
+        let (server, core_wallet) = mock_server();
+        let usd = server.institution.currencies()[0].clone();
+        let expiry_epoch = server.institution.current_expiry_epoch();
+        
+        // Tokens already held, sent for redemption when the wallet stopped.
+        let (blinded, metadata): (Vec<_>, Vec<_>) = core_wallet.prepare_tokens(&[10, 50], expiry_epoch).unwrap().into_iter().unzip();
+        let signatures = blinded.iter()
+            .map(|blinded| server.institution.sign_blinded_token(blinded).unwrap())
+            .collect();
+        let held = core_wallet.finalize_withdrawal(signatures, metadata).unwrap();
+        let held_serials: Vec<Vec<u8>> = held.iter().map(|token| token.serial_number.clone()).collect();
+        
+        let (blinded_tokens, metadata) = core_wallet.prepare_tokens(&[10, 50], expiry_epoch).unwrap().into_iter().unzip();
+        let withdraw = PendingOperation::Withdraw {
+            request: WithdrawRequest {
+                amount: usd.amount(60),
+                expiry_epoch,
+                blinded_tokens,
+            },
+            metadata,
+        };
+        
+        let db = TempDb::new();
+        let url = serve(server).await;
+        {
+            let wallet = Wallet::new(url.clone(), db.0.clone()).unwrap();
+            let token_ids: Vec<String> = held.into_iter()
+                .map(|token| wallet.storage.store_token(token).unwrap().id)
+                .collect();
+            let spend = PendingOperation::Redeem {
+                token_ids: token_ids.clone(),
+                amount: usd.amount(60),
+            };
+            wallet.storage.begin_pending(&ApiClient::new_idempotency_key(), &withdraw, &[]).unwrap();
+            wallet.storage.begin_pending(&ApiClient::new_idempotency_key(), &spend, &token_ids).unwrap();
+            assert!(wallet.storage.get_balances().unwrap().is_empty());
+        }
+        
+        let wallet = Wallet::new(url, db.0.clone()).unwrap();
+        assert_eq!(wallet.storage.pending_operations().unwrap().len(), 2);
+        
+        let summary = wallet.recover_pending().await.unwrap();
+        assert_eq!((summary.completed, summary.rolled_back, summary.unresolved), (2, 0, 0));
+        assert!(wallet.storage.pending_operations().unwrap().is_empty());
+        
+        // The withdrawn tokens are stored; the redeemed ones are spent.
+        let available = wallet.storage.get_available_tokens().unwrap();
+        assert_eq!(available.len(), 2);
+        assert!(available.iter().all(|stored| !held_serials.contains(&stored.token.serial_number)));
+        assert_eq!(wallet.storage.get_balances().unwrap()["USD"], 60);
+    }
+}
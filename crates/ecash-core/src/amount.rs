@@ -0,0 +1,297 @@
+//! Amounts of money: a whole number of a currency's minor unit.
+//!
+//! An `Amount` always knows its currency and exponent, so amounts in
+//! different currencies cannot be added or compared by accident, and every
+//! operation that could overflow is checked. On the wire an amount is a
+//! decimal string in major units with exactly `exponent` fraction digits,
+//! `{"value": "12.50", "currency": "USD"}`, so no precision is lost to
+//! floating point. Databases store the minor units as a signed 64-bit
+//! integer; `to_i64` and `from_i64` convert without truncation.
+
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::currency::Currency;
+use crate::error::{EcashError, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "AmountRepr", into = "AmountRepr")]
+pub struct Amount {
+    minor_units: u64,
+    currency: String,
+    exponent: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AmountRepr {
+    value: String,
+    currency: String,
+}
+
+impl Amount {
+    pub fn new(minor_units: u64, currency: &Currency) -> Self {
+        Self {
+            minor_units,
+            currency: currency.code.clone(),
+            exponent: currency.exponent,
+        }
+    }
+
+    pub fn zero(currency: &Currency) -> Self {
+        Self::new(0, currency)
+    }
+
+    /// Parses a decimal string in major units, such as `"12.5"` for
+    /// 1250 cents. More fraction digits than the currency has are refused
+    /// rather than rounded.
+    pub fn parse(value: &str, currency: &Currency) -> Result<Self> {
+        let (minor_units, exponent) = parse_decimal(value)?;
+        let amount = Self {
+            minor_units,
+            currency: currency.code.clone(),
+            exponent,
+        };
+        amount.rescale(currency.exponent)
+    }
+
+    /// Reads minor units stored in a signed 64-bit database column.
+    pub fn from_i64(minor_units: i64, currency: &Currency) -> Result<Self> {
+        let minor_units = u64::try_from(minor_units).map_err(|_| EcashError::InvalidAmount)?;
+        Ok(Self::new(minor_units, currency))
+    }
+
+    /// The minor units as a signed 64-bit integer, for a database column.
+    pub fn to_i64(&self) -> Result<i64> {
+        i64::try_from(self.minor_units).map_err(|_| EcashError::AmountOverflow)
+    }
+
+    pub fn minor_units(&self) -> u64 {
+        self.minor_units
+    }
+
+    pub fn currency(&self) -> &str {
+        &self.currency
+    }
+
+    pub fn exponent(&self) -> u32 {
+        self.exponent
+    }
+
+    /// Another amount in the same currency.
+    pub fn with_minor_units(&self, minor_units: u64) -> Amount {
+        Self {
+            minor_units,
+            currency: self.currency.clone(),
+            exponent: self.exponent,
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.minor_units == 0
+    }
+
+    pub fn checked_add(&self, other: &Amount) -> Result<Amount> {
+        self.check_same_currency(other)?;
+        self.checked(self.minor_units.checked_add(other.minor_units))
+    }
+
+    /// Fails with `AmountOverflow` if `other` is larger.
+    pub fn checked_sub(&self, other: &Amount) -> Result<Amount> {
+        self.check_same_currency(other)?;
+        self.checked(self.minor_units.checked_sub(other.minor_units))
+    }
+
+    /// Adds up amounts that must all be in `currency`.
+    pub fn sum<'a>(
+        currency: &Currency,
+        amounts: impl IntoIterator<Item = &'a Amount>,
+    ) -> Result<Amount> {
+        amounts
+            .into_iter()
+            .try_fold(Self::zero(currency), |total, amount| {
+                total.checked_add(amount)
+            })
+    }
+
+    /// The same value with `exponent` fraction digits. Adding digits always
+    /// succeeds unless it overflows; dropping them only if they are zero.
+    pub fn rescale(&self, exponent: u32) -> Result<Amount> {
+        let minor_units = match exponent.cmp(&self.exponent) {
+            Ordering::Equal => Some(self.minor_units),
+            Ordering::Greater => 10u64
+                .checked_pow(exponent - self.exponent)
+                .and_then(|factor| self.minor_units.checked_mul(factor)),
+            Ordering::Less => {
+                let factor = 10u64
+                    .checked_pow(self.exponent - exponent)
+                    .ok_or(EcashError::InvalidAmount)?;
+                if !self.minor_units.is_multiple_of(factor) {
+                    return Err(EcashError::InvalidAmount);
+                }
+                Some(self.minor_units / factor)
+            }
+        };
+
+        Ok(Self {
+            minor_units: minor_units.ok_or(EcashError::AmountOverflow)?,
+            currency: self.currency.clone(),
+            exponent,
+        })
+    }
+
+    fn check_same_currency(&self, other: &Amount) -> Result<()> {
+        if self.currency != other.currency || self.exponent != other.exponent {
+            return Err(EcashError::CurrencyMismatch);
+        }
+        Ok(())
+    }
+
+    fn checked(&self, minor_units: Option<u64>) -> Result<Amount> {
+        Ok(self.with_minor_units(minor_units.ok_or(EcashError::AmountOverflow)?))
+    }
+
+    /// The value in major units, with exactly `exponent` fraction digits.
+    fn decimal(&self) -> String {
+        let digits = format!(
+            "{:0>width$}",
+            self.minor_units,
+            width = self.exponent as usize + 1
+        );
+        let (whole, fraction) = digits.split_at(digits.len() - self.exponent as usize);
+        if fraction.is_empty() {
+            whole.to_string()
+        } else {
+            format!("{}.{}", whole, fraction)
+        }
+    }
+}
+
+/// Amounts in different currencies are unordered.
+impl PartialOrd for Amount {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.check_same_currency(other).ok()?;
+        Some(self.minor_units.cmp(&other.minor_units))
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.decimal(), self.currency)
+    }
+}
+
+impl From<Amount> for AmountRepr {
+    fn from(amount: Amount) -> Self {
+        Self {
+            value: amount.decimal(),
+            currency: amount.currency,
+        }
+    }
+}
+
+impl TryFrom<AmountRepr> for Amount {
+    type Error = EcashError;
+
+    /// The exponent is taken from the number of fraction digits; use
+    /// `Currency::normalize` before mixing the result with other amounts.
+    fn try_from(repr: AmountRepr) -> Result<Self> {
+        let (minor_units, exponent) = parse_decimal(&repr.value)?;
+        Ok(Self {
+            minor_units,
+            currency: repr.currency,
+            exponent,
+        })
+    }
+}
+
+/// Splits a plain decimal such as `"12.50"` into minor units and the number
+/// of fraction digits.
+fn parse_decimal(value: &str) -> Result<(u64, u32)> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if whole.is_empty()
+        || (value.contains('.') && fraction.is_empty())
+        || !whole
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+    {
+        return Err(EcashError::InvalidAmount);
+    }
+
+    let exponent = u32::try_from(fraction.len()).map_err(|_| EcashError::InvalidAmount)?;
+    let minor_units = format!("{}{}", whole, fraction)
+        .parse::<u64>()
+        .map_err(|_| EcashError::AmountOverflow)?;
+    Ok((minor_units, exponent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usd() -> Currency {
+        Currency::new("USD", vec![10, 50]).unwrap()
+    }
+
+    #[test]
+    fn test_amounts_are_checked_and_formatted_in_major_units() {
+        let usd = usd();
+        let jpy = Currency::new("JPY", vec![100]).unwrap();
+
+        let price = Amount::parse("12.5", &usd).unwrap();
+        assert_eq!(price.minor_units(), 1250);
+        assert_eq!(price.to_string(), "12.50 USD");
+        assert_eq!(Amount::new(5, &usd).to_string(), "0.05 USD");
+        assert_eq!(Amount::new(500, &jpy).to_string(), "500 JPY");
+        assert!(matches!(
+            Amount::parse("12.505", &usd),
+            Err(EcashError::InvalidAmount)
+        ));
+        assert!(Amount::parse("-1", &usd).is_err());
+        assert!(Amount::parse("1.", &usd).is_err());
+
+        let total = Amount::sum(&usd, [&price, &Amount::new(50, &usd)]).unwrap();
+        assert_eq!(total.minor_units(), 1300);
+        assert!(total > price);
+        assert!(matches!(
+            price.checked_sub(&total),
+            Err(EcashError::AmountOverflow)
+        ));
+        assert!(matches!(
+            Amount::new(u64::MAX, &usd).checked_add(&Amount::new(1, &usd)),
+            Err(EcashError::AmountOverflow)
+        ));
+        assert!(matches!(
+            price.checked_add(&Amount::new(1, &jpy)),
+            Err(EcashError::CurrencyMismatch)
+        ));
+        assert_eq!(price.partial_cmp(&Amount::new(1, &jpy)), None);
+
+        assert_eq!(price.to_i64().unwrap(), 1250);
+        assert!(Amount::new(u64::MAX, &usd).to_i64().is_err());
+        assert!(Amount::from_i64(-1, &usd).is_err());
+    }
+
+    #[test]
+    fn test_amount_serializes_as_decimal_string() {
+        let usd = usd();
+        let amount = Amount::new(1250, &usd);
+
+        let json = serde_json::to_value(&amount).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"value": "12.50", "currency": "USD"})
+        );
+        assert_eq!(serde_json::from_value::<Amount>(json).unwrap(), amount);
+
+        let whole: Amount =
+            serde_json::from_value(serde_json::json!({"value": "12", "currency": "USD"})).unwrap();
+        assert_eq!(whole.exponent(), 0);
+        assert_eq!(usd.normalize(&whole).unwrap(), Amount::new(1200, &usd));
+        assert!(serde_json::from_value::<Amount>(
+            serde_json::json!({"value": "1e3", "currency": "USD"})
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,151 @@
+//! ISO 4217 currencies and the denominations issued in each.
+//!
+//! Amounts and denominations are integers in a currency's minor unit; the
+//! exponent says how many of those make a major unit (`2` for cents in USD,
+//! `0` for JPY).
+
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::error::{EcashError, Result};
+
+/// Minor-unit exponents of the ISO 4217 currencies that differ from the
+/// usual two digits, plus the common two-digit ones.
+const EXPONENTS: &[(&str, u32)] = &[
+    ("AUD", 2),
+    ("BHD", 3),
+    ("BRL", 2),
+    ("CAD", 2),
+    ("CHF", 2),
+    ("CLP", 0),
+    ("CNY", 2),
+    ("CZK", 2),
+    ("DKK", 2),
+    ("EUR", 2),
+    ("GBP", 2),
+    ("HKD", 2),
+    ("HUF", 2),
+    ("IDR", 2),
+    ("INR", 2),
+    ("IQD", 3),
+    ("ISK", 0),
+    ("JOD", 3),
+    ("JPY", 0),
+    ("KRW", 0),
+    ("KWD", 3),
+    ("LYD", 3),
+    ("MXN", 2),
+    ("NOK", 2),
+    ("NZD", 2),
+    ("OMR", 3),
+    ("PLN", 2),
+    ("SEK", 2),
+    ("SGD", 2),
+    ("TND", 3),
+    ("UGX", 0),
+    ("USD", 2),
+    ("VND", 0),
+    ("XAF", 0),
+    ("XOF", 0),
+    ("ZAR", 2),
+];
+
+/// The minor-unit exponent of a known ISO 4217 currency.
+pub fn iso4217_exponent(code: &str) -> Option<u32> {
+    EXPONENTS
+        .iter()
+        .find(|(known, _)| *known == code)
+        .map(|(_, exponent)| *exponent)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Currency {
+    /// Three-letter ISO 4217 code, such as `USD`.
+    pub code: String,
+    /// Digits of the minor unit.
+    pub exponent: u32,
+    /// Denominations issued, in minor units, smallest first.
+    pub denominations: Vec<u64>,
+}
+
+impl Currency {
+    /// A currency from the ISO 4217 table, with its standard exponent.
+    pub fn new(code: &str, denominations: Vec<u64>) -> Result<Self> {
+        let exponent = iso4217_exponent(code).ok_or(EcashError::InvalidCurrency)?;
+        Self::with_exponent(code, exponent, denominations)
+    }
+
+    /// A currency not in the built-in table, or one used with a
+    /// non-standard exponent. The code must still look like an ISO 4217
+    /// code.
+    pub fn with_exponent(code: &str, exponent: u32, mut denominations: Vec<u64>) -> Result<Self> {
+        if code.len() != 3 || !code.bytes().all(|b| b.is_ascii_uppercase()) {
+            return Err(EcashError::InvalidCurrency);
+        }
+        if denominations.is_empty() || denominations.contains(&0) {
+            return Err(EcashError::InvalidDenomination);
+        }
+        denominations.sort_unstable();
+        denominations.dedup();
+
+        Ok(Self {
+            code: code.to_string(),
+            exponent,
+            denominations,
+        })
+    }
+
+    pub fn has_denomination(&self, denomination: u64) -> bool {
+        self.denominations.contains(&denomination)
+    }
+
+    /// `minor_units` of this currency as an `Amount`.
+    pub fn amount(&self, minor_units: u64) -> Amount {
+        Amount::new(minor_units, self)
+    }
+
+    /// Checks that `amount` is in this currency and rescales it to this
+    /// currency's exponent, as amounts received from outside must be.
+    pub fn normalize(&self, amount: &Amount) -> Result<Amount> {
+        if amount.currency() != self.code {
+            return Err(EcashError::CurrencyMismatch);
+        }
+        amount.rescale(self.exponent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_currency_codes_and_exponents() {
+        let usd = Currency::new("USD", vec![100, 10, 50, 10]).unwrap();
+        assert_eq!(usd.exponent, 2);
+        assert_eq!(usd.denominations, vec![10, 50, 100]);
+        assert!(usd.has_denomination(50));
+        assert!(!usd.has_denomination(20));
+
+        assert_eq!(Currency::new("JPY", vec![100]).unwrap().exponent, 0);
+        assert_eq!(Currency::new("KWD", vec![100]).unwrap().exponent, 3);
+
+        assert!(matches!(
+            Currency::new("usd", vec![10]),
+            Err(EcashError::InvalidCurrency)
+        ));
+        assert!(matches!(
+            Currency::new("XYZ", vec![10]),
+            Err(EcashError::InvalidCurrency)
+        ));
+        assert_eq!(
+            Currency::with_exponent("XYZ", 4, vec![10])
+                .unwrap()
+                .exponent,
+            4
+        );
+        assert!(matches!(
+            Currency::new("EUR", vec![]),
+            Err(EcashError::InvalidDenomination)
+        ));
+    }
+}
@@ -0,0 +1,121 @@
+//! Decomposition of an amount into tokens of the institution's
+//! denominations.
+//!
+//! Every denomination is signed by its own key, so the denominations a
+//! wallet holds are visible to the institution when it withdraws and spends
+//! them. [`Decomposition::Greedy`] uses as few tokens as possible, which is
+//! cheapest. [`Decomposition::PrivacyAware`] trades more tokens for smaller,
+//! more common ones: they blend into a larger anonymity set, and later
+//! payments can more often be made exactly, without an exchange for change.
+
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+
+use crate::error::{EcashError, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decomposition {
+    /// Largest denominations first. The fewest tokens for canonical
+    /// denomination sets such as 1-2-5 or 1-5-10 series.
+    #[default]
+    Greedy,
+    /// Starts from the greedy decomposition and keeps splitting its largest
+    /// tokens into smaller denominations while the total stays within
+    /// `max_tokens`.
+    PrivacyAware { max_tokens: usize },
+}
+
+/// Splits `amount` into tokens of `denominations`, largest first. Fails with
+/// [`EcashError::AmountNotRepresentable`] if the strategy cannot make the
+/// amount exactly.
+pub fn decompose(amount: u64, denominations: &[u64], strategy: Decomposition) -> Result<Vec<u64>> {
+    let mut denominations: Vec<u64> = denominations.iter().copied().filter(|d| *d > 0).collect();
+    denominations.sort_unstable_by(|a, b| b.cmp(a));
+    denominations.dedup();
+
+    let mut tokens = greedy(amount, &denominations).ok_or(EcashError::AmountNotRepresentable)?;
+
+    if let Decomposition::PrivacyAware { max_tokens } = strategy {
+        while let Some((index, parts)) = next_split(&tokens, &denominations, max_tokens) {
+            tokens.splice(index..=index, parts);
+        }
+        tokens.sort_unstable_by(|a, b| b.cmp(a));
+    }
+
+    Ok(tokens)
+}
+
+/// `denominations` must be sorted largest first.
+fn greedy(amount: u64, denominations: &[u64]) -> Option<Vec<u64>> {
+    let mut remaining = amount;
+    let mut tokens = Vec::new();
+    for &denomination in denominations {
+        let count = remaining / denomination;
+        tokens.extend(std::iter::repeat_n(denomination, count as usize));
+        remaining -= count * denomination;
+    }
+    (remaining == 0).then_some(tokens)
+}
+
+/// The largest token that can be split into smaller denominations without
+/// exceeding `max_tokens` in total, with its parts.
+fn next_split(
+    tokens: &[u64],
+    denominations: &[u64],
+    max_tokens: usize,
+) -> Option<(usize, Vec<u64>)> {
+    let mut candidates: Vec<(usize, u64)> = tokens.iter().copied().enumerate().collect();
+    candidates.sort_by_key(|(_, token)| Reverse(*token));
+    candidates.dedup_by_key(|(_, token)| *token);
+
+    candidates.into_iter().find_map(|(index, token)| {
+        let smaller: Vec<u64> = denominations
+            .iter()
+            .copied()
+            .filter(|d| *d < token)
+            .collect();
+        greedy(token, &smaller)
+            .filter(|parts| tokens.len() - 1 + parts.len() <= max_tokens)
+            .map(|parts| (index, parts))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DENOMINATIONS: &[u64] = &[10, 50, 100, 500, 1000];
+
+    #[test]
+    fn test_greedy_uses_largest_denominations() {
+        assert_eq!(
+            decompose(1_670, DENOMINATIONS, Decomposition::Greedy).unwrap(),
+            vec![1000, 500, 100, 50, 10, 10]
+        );
+        assert!(decompose(0, DENOMINATIONS, Decomposition::Greedy)
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            decompose(175, DENOMINATIONS, Decomposition::Greedy),
+            Err(EcashError::AmountNotRepresentable)
+        ));
+    }
+
+    #[test]
+    fn test_privacy_aware_splits_large_tokens_within_budget() {
+        let strategy = Decomposition::PrivacyAware { max_tokens: 8 };
+        let tokens = decompose(1_000, DENOMINATIONS, strategy).unwrap();
+
+        assert_eq!(tokens.iter().sum::<u64>(), 1_000);
+        assert!(tokens.len() <= 8);
+        assert_eq!(tokens, vec![500, 100, 100, 100, 50, 50, 50, 50]);
+
+        // Without room to split, it is the greedy decomposition.
+        let strategy = Decomposition::PrivacyAware { max_tokens: 1 };
+        assert_eq!(
+            decompose(1_000, DENOMINATIONS, strategy).unwrap(),
+            vec![1000]
+        );
+    }
+}
@@ -0,0 +1,251 @@
+//! Signed directories of an institution's key sets.
+//!
+//! The keys served on `/api/v1/keys` are only as authentic as the TLS
+//! connection they came over. A key directory lists every key set with its
+//! denominations and validity window, and is signed with the institution's
+//! long-term identity key, so a wallet that has pinned the identity key can
+//! check keys it was served, or was handed by anyone else, against it.
+
+use chrono::{DateTime, Utc};
+use rand::rngs::OsRng;
+use rsa::pkcs8::{DecodePublicKey, EncodePublicKey, LineEnding};
+use rsa::pss::{BlindedSigningKey, Signature, VerifyingKey};
+use rsa::signature::{RandomizedSigner, SignatureEncoding, Verifier};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::error::{EcashError, Result};
+use crate::keyring::KeyStatus;
+use crate::protocol::Institution;
+
+/// Prepended to the directory before signing, so the signature cannot be
+/// passed off as one over anything else the identity key signs.
+const SIGNATURE_CONTEXT: &[u8] = b"ecash-key-directory/v1\0";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyDirectory {
+    pub institution_id: String,
+    pub issued_at: DateTime<Utc>,
+    /// When the next directory is published. Until then this one is
+    /// current; afterwards it still shows which keys the institution
+    /// published, but not whether any have since been retired.
+    pub next_update: DateTime<Utc>,
+    pub keys: Vec<DirectoryEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirectoryEntry {
+    pub key_id: String,
+    pub status: KeyStatus,
+    pub created_at: DateTime<Utc>,
+    /// Last instant at which tokens of the key set are redeemable.
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Last instant at which the key set issues tokens; see
+    /// [`Institution::issue_until`].
+    pub issue_until: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    /// Denominations by currency, each with a key of its own.
+    pub denominations: BTreeMap<String, Vec<u64>>,
+    /// See [`crate::key_set_fingerprint`].
+    pub fingerprint: String,
+}
+
+impl KeyDirectory {
+    /// The directory of every key set `institution` holds.
+    pub fn new(
+        institution: &Institution,
+        issued_at: DateTime<Utc>,
+        next_update: DateTime<Utc>,
+    ) -> Self {
+        let keys = institution
+            .keys()
+            .iter()
+            .map(|key| {
+                let mut denominations: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+                for (currency, denomination, _) in key.public_keys() {
+                    denominations
+                        .entry(currency.to_string())
+                        .or_default()
+                        .push(denomination);
+                }
+                let validity = key.validity();
+
+                DirectoryEntry {
+                    key_id: key.key_id().to_string(),
+                    status: key.status(),
+                    created_at: validity.created_at,
+                    expires_at: validity.expires_at,
+                    issue_until: institution.issue_until(key),
+                    revoked_at: validity.revoked_at,
+                    denominations,
+                    fingerprint: key.fingerprint(),
+                }
+            })
+            .collect();
+
+        Self {
+            institution_id: institution.institution_id().to_string(),
+            issued_at,
+            next_update,
+            keys,
+        }
+    }
+
+    pub fn entry(&self, key_id: &str) -> Option<&DirectoryEntry> {
+        self.keys.iter().find(|entry| entry.key_id == key_id)
+    }
+}
+
+/// A [`KeyDirectory`] with its signature. The directory is kept as the
+/// exact JSON that was signed, so verifying does not depend on how it would
+/// be serialized again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedKeyDirectory {
+    pub directory: String,
+    /// The identity public key as SPKI PEM.
+    pub identity_key: String,
+    /// Hex RSASSA-PSS signature with SHA-256.
+    pub signature: String,
+}
+
+impl SignedKeyDirectory {
+    pub fn sign(directory: &KeyDirectory, identity_key: &RsaPrivateKey) -> Result<Self> {
+        let json = serde_json::to_string(directory).map_err(|_| EcashError::SerializationError)?;
+        let signature = BlindedSigningKey::<Sha256>::new(identity_key.clone())
+            .try_sign_with_rng(&mut OsRng, &signed_message(&json))
+            .map_err(|_| EcashError::CryptoError)?;
+        let identity_key = RsaPublicKey::from(identity_key)
+            .to_public_key_pem(LineEnding::LF)
+            .map_err(|_| EcashError::InvalidKey)?;
+
+        Ok(Self {
+            directory: json,
+            identity_key,
+            signature: hex::encode(signature.to_bytes()),
+        })
+    }
+
+    /// The directory, if the signature is valid under the enclosed identity
+    /// key. Whether that key is the institution's is for the caller to check
+    /// against [`Self::identity_fingerprint`].
+    pub fn verify(&self) -> Result<KeyDirectory> {
+        let signature = hex::decode(&self.signature)
+            .ok()
+            .and_then(|bytes| Signature::try_from(bytes.as_slice()).ok())
+            .ok_or(EcashError::InvalidSignature)?;
+        VerifyingKey::<Sha256>::new(self.public_key()?)
+            .verify(&signed_message(&self.directory), &signature)
+            .map_err(|_| EcashError::InvalidSignature)?;
+
+        serde_json::from_str(&self.directory).map_err(|_| EcashError::SerializationError)
+    }
+
+    pub fn identity_fingerprint(&self) -> Result<String> {
+        identity_fingerprint(&self.public_key()?)
+    }
+
+    fn public_key(&self) -> Result<RsaPublicKey> {
+        RsaPublicKey::from_public_key_pem(&self.identity_key).map_err(|_| EcashError::InvalidKey)
+    }
+}
+
+/// Hex SHA-256 of an identity key's SPKI DER encoding, which is what
+/// wallets pin.
+pub fn identity_fingerprint(public_key: &RsaPublicKey) -> Result<String> {
+    let der = public_key
+        .to_public_key_der()
+        .map_err(|_| EcashError::InvalidKey)?;
+    Ok(hex::encode(Sha256::digest(der.as_bytes())))
+}
+
+fn signed_message(json: &str) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, json.as_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::Currency;
+    use crate::keyring::{InstitutionKey, KeyRing, KeyValidity};
+    use chrono::Duration;
+
+    fn institution(expires_at: Option<DateTime<Utc>>) -> Institution {
+        let validity = KeyValidity {
+            expires_at,
+            ..KeyValidity::unbounded()
+        };
+        let key = [10, 50].into_iter().fold(
+            InstitutionKey::new("key_1".to_string(), KeyStatus::Active, validity),
+            |key, denomination| {
+                let private_key = RsaPrivateKey::new(&mut OsRng, 512).unwrap();
+                key.with_public_key("USD", denomination, RsaPublicKey::from(&private_key))
+            },
+        );
+        let mut keys = KeyRing::new();
+        keys.insert(key);
+
+        Institution::new(
+            keys,
+            "test-institution".to_string(),
+            vec![Currency::new("USD", vec![10, 50]).unwrap()],
+            30,
+        )
+    }
+
+    #[test]
+    fn test_signed_directory_round_trip() {
+        let now = Utc::now();
+        let expires_at = now + Duration::days(90);
+        let institution = institution(Some(expires_at));
+        let directory = KeyDirectory::new(&institution, now, now + Duration::hours(1));
+
+        let entry = directory.entry("key_1").unwrap();
+        assert_eq!(entry.denominations["USD"], vec![10, 50]);
+        assert_eq!(
+            entry.fingerprint,
+            institution.keys().get("key_1").unwrap().fingerprint()
+        );
+        assert_eq!(entry.issue_until, Some(expires_at - Duration::days(31)));
+
+        let identity_key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+        let signed = SignedKeyDirectory::sign(&directory, &identity_key).unwrap();
+        assert_eq!(signed.verify().unwrap(), directory);
+        assert_eq!(
+            signed.identity_fingerprint().unwrap(),
+            identity_fingerprint(&RsaPublicKey::from(&identity_key)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_signed_directory_rejects_tampering() {
+        let now = Utc::now();
+        let directory = KeyDirectory::new(&institution(None), now, now + Duration::hours(1));
+        let identity_key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+        let signed = SignedKeyDirectory::sign(&directory, &identity_key).unwrap();
+
+        let mut altered = signed.clone();
+        altered.directory = altered.directory.replace("key_1", "key_2");
+        assert!(matches!(
+            altered.verify(),
+            Err(EcashError::InvalidSignature)
+        ));
+
+        // Re-signing under another key verifies, but under a different
+        // identity, which is what pinning catches.
+        let other_key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+        let mut substituted = SignedKeyDirectory::sign(&directory, &other_key).unwrap();
+        assert!(substituted.verify().is_ok());
+        assert_ne!(
+            substituted.identity_fingerprint().unwrap(),
+            signed.identity_fingerprint().unwrap()
+        );
+
+        substituted.identity_key = signed.identity_key.clone();
+        assert!(matches!(
+            substituted.verify(),
+            Err(EcashError::InvalidSignature)
+        ));
+    }
+}
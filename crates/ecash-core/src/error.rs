@@ -11,17 +11,65 @@ pub enum EcashError {
     #[error("Token expired")]
     TokenExpired,
 
+    #[error("Invalid expiry epoch")]
+    InvalidExpiryEpoch,
+
     #[error("Invalid denomination")]
     InvalidDenomination,
 
+    #[error("Invalid ISO 4217 currency code")]
+    InvalidCurrency,
+
+    #[error("Currency not issued by this institution")]
+    UnsupportedCurrency,
+
     #[error("Serialization error")]
     SerializationError,
 
     #[error("Invalid key")]
     InvalidKey,
 
+    #[error("Unknown key")]
+    UnknownKey,
+
+    #[error("Key is not accepting withdrawals")]
+    KeyNotActive,
+
+    #[error("Key is revoked or expired")]
+    KeyNotValid,
+
     #[error("Blinding failed")]
     BlindingFailed,
+
+    #[error("Invalid amount")]
+    InvalidAmount,
+
+    #[error("Amount out of range")]
+    AmountOverflow,
+
+    #[error("Amounts are in different currencies")]
+    CurrencyMismatch,
+
+    #[error("Amount cannot be made from the available denominations")]
+    AmountNotRepresentable,
+
+    #[error("Outputs and fee exceed the value of the inputs")]
+    ExchangeValueExceeded,
+
+    #[error("Invalid seed phrase")]
+    InvalidMnemonic,
+
+    #[error("Issued token does not match the wallet seed")]
+    RecoveryMismatch,
+
+    #[error("Invalid input")]
+    InvalidInput,
+
+    #[error("Not enough valid signature shares")]
+    InsufficientShares,
+
+    #[error("Message encoding failed")]
+    EncodingError,
 }
 
 pub type Result<T> = std::result::Result<T, EcashError>;
@@ -0,0 +1,121 @@
+//! Exchange of tokens for new ones of at most the same value.
+//!
+//! A wallet gives up `inputs` and sends blinded `outputs`, exactly as in a
+//! withdrawal, and the institution signs the outputs if their total plus its
+//! fee does not exceed the value of the inputs. Any value left over is
+//! forfeited. This is how a wallet splits a token to make change,
+//! consolidates small tokens, or refreshes tokens before they expire; since
+//! the outputs are blinded, the new tokens cannot be linked to the inputs.
+
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::error::{EcashError, Result};
+use crate::token::{BlindSignature, BlindedToken, Token};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExchangeRequest {
+    pub inputs: Vec<Token>,
+    pub outputs: Vec<BlindedToken>,
+    /// Expiry epoch bound into every output.
+    pub expiry_epoch: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExchangeResponse {
+    /// One per output, in request order.
+    pub blind_signatures: Vec<BlindSignature>,
+    pub key_id: String,
+    pub expires_at: String,
+    pub input_amount: Amount,
+    pub output_amount: Amount,
+    pub fee: Amount,
+    pub transaction_id: String,
+}
+
+impl ExchangeRequest {
+    /// The value of the inputs, all of which must be in `currency`.
+    pub fn input_amount(&self, currency: &Currency) -> Result<Amount> {
+        self.inputs
+            .iter()
+            .try_fold(currency.amount(0), |total, token| {
+                total.checked_add(&token.amount(currency)?)
+            })
+    }
+
+    /// The value of the outputs, all of which must be in `currency`.
+    pub fn output_amount(&self, currency: &Currency) -> Result<Amount> {
+        self.outputs
+            .iter()
+            .try_fold(currency.amount(0), |total, output| {
+                total.checked_add(&output.amount(currency)?)
+            })
+    }
+
+    /// Checks that the inputs cover the outputs plus `fee`.
+    pub fn check_value(&self, currency: &Currency, fee: &Amount) -> Result<()> {
+        let required = self.output_amount(currency)?.checked_add(fee)?;
+        if required > self.input_amount(currency)? {
+            return Err(EcashError::ExchangeValueExceeded);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn input(denomination: u64) -> Token {
+        Token::new(
+            vec![0; 32],
+            denomination,
+            "USD".to_string(),
+            vec![],
+            Utc::now(),
+            "inst_test".to_string(),
+            "key_001".to_string(),
+        )
+    }
+
+    fn output(denomination: u64) -> BlindedToken {
+        BlindedToken {
+            blinded_message: vec![],
+            denomination,
+            currency: "USD".to_string(),
+            key_id: "key_001".to_string(),
+            recovery_id: None,
+        }
+    }
+
+    #[test]
+    fn test_outputs_and_fee_must_be_covered_by_inputs() {
+        let usd = Currency::new("USD", vec![10, 50, 100]).unwrap();
+        let request = ExchangeRequest {
+            inputs: vec![input(100)],
+            outputs: vec![output(50), output(10), output(10), output(10)],
+            expiry_epoch: 20_000,
+        };
+
+        assert_eq!(request.input_amount(&usd).unwrap().minor_units(), 100);
+        assert_eq!(request.output_amount(&usd).unwrap().minor_units(), 80);
+        assert!(request.check_value(&usd, &usd.amount(0)).is_ok());
+        assert!(request.check_value(&usd, &usd.amount(20)).is_ok());
+        assert!(matches!(
+            request.check_value(&usd, &usd.amount(21)),
+            Err(EcashError::ExchangeValueExceeded)
+        ));
+        assert!(matches!(
+            request.check_value(&usd, &usd.amount(u64::MAX)),
+            Err(EcashError::AmountOverflow)
+        ));
+
+        let eur = Currency::new("EUR", vec![10]).unwrap();
+        assert!(matches!(
+            request.input_amount(&eur),
+            Err(EcashError::CurrencyMismatch)
+        ));
+    }
+}
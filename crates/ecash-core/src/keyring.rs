@@ -0,0 +1,291 @@
+//! Institution signing keys and their rotation states.
+//!
+//! A key is `active` while it issues new tokens, `redeem_only` during the
+//! grace period after rotation in which its outstanding tokens can still be
+//! redeemed, and `revoked` once nothing signed by it may be accepted. Only
+//! active keys hold private keys; verification needs the public keys alone.
+
+use chrono::{DateTime, Utc};
+use rsa::traits::PublicKeyParts;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::crypto::BlindSigner;
+use crate::error::{EcashError, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyStatus {
+    Active,
+    RedeemOnly,
+    Revoked,
+}
+
+impl KeyStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyStatus::Active => "active",
+            KeyStatus::RedeemOnly => "redeem_only",
+            KeyStatus::Revoked => "revoked",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "active" => Ok(KeyStatus::Active),
+            "redeem_only" => Ok(KeyStatus::RedeemOnly),
+            "revoked" => Ok(KeyStatus::Revoked),
+            _ => Err(EcashError::InvalidKey),
+        }
+    }
+}
+
+impl std::fmt::Display for KeyStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyValidity {
+    pub created_at: DateTime<Utc>,
+    /// Last instant at which tokens signed by the key are redeemable.
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl KeyValidity {
+    pub fn unbounded() -> Self {
+        Self {
+            created_at: Utc::now(),
+            expires_at: None,
+            revoked_at: None,
+        }
+    }
+}
+
+struct DenominationKey {
+    public_key: RsaPublicKey,
+    signer: Option<BlindSigner>,
+}
+
+/// One key generation, identified by `key_id`, holding a distinct RSA key
+/// per (currency, denomination) so the value of a token is fixed by the key
+/// that signed it.
+pub struct InstitutionKey {
+    key_id: String,
+    status: KeyStatus,
+    validity: KeyValidity,
+    denominations: BTreeMap<(String, u64), DenominationKey>,
+}
+
+impl InstitutionKey {
+    pub fn new(key_id: String, status: KeyStatus, validity: KeyValidity) -> Self {
+        Self {
+            key_id,
+            status,
+            validity,
+            denominations: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a denomination key able to sign. Only active keys should be
+    /// given private keys; the others merely verify.
+    pub fn with_private_key(
+        mut self,
+        currency: &str,
+        denomination: u64,
+        private_key: RsaPrivateKey,
+    ) -> Self {
+        let signer = BlindSigner::from_keys(private_key);
+        self.denominations.insert(
+            (currency.to_string(), denomination),
+            DenominationKey {
+                public_key: signer.public_key().clone(),
+                signer: Some(signer),
+            },
+        );
+        self
+    }
+
+    pub fn with_public_key(
+        mut self,
+        currency: &str,
+        denomination: u64,
+        public_key: RsaPublicKey,
+    ) -> Self {
+        self.denominations.insert(
+            (currency.to_string(), denomination),
+            DenominationKey {
+                public_key,
+                signer: None,
+            },
+        );
+        self
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    pub fn status(&self) -> KeyStatus {
+        self.status
+    }
+
+    pub fn validity(&self) -> &KeyValidity {
+        &self.validity
+    }
+
+    pub fn public_key(&self, currency: &str, denomination: u64) -> Option<&RsaPublicKey> {
+        self.denominations
+            .get(&(currency.to_string(), denomination))
+            .map(|key| &key.public_key)
+    }
+
+    /// All denomination keys as `(currency, denomination, public_key)`.
+    pub fn public_keys(&self) -> impl Iterator<Item = (&str, u64, &RsaPublicKey)> {
+        self.denominations
+            .iter()
+            .map(|((currency, denomination), key)| {
+                (currency.as_str(), *denomination, &key.public_key)
+            })
+    }
+
+    /// See [`key_set_fingerprint`].
+    pub fn fingerprint(&self) -> String {
+        key_set_fingerprint(&self.key_id, self.public_keys())
+    }
+
+    pub(crate) fn signer(&self, currency: &str, denomination: u64) -> Option<&BlindSigner> {
+        self.denominations
+            .get(&(currency.to_string(), denomination))
+            .and_then(|key| key.signer.as_ref())
+    }
+
+    pub fn is_revoked(&self, now: DateTime<Utc>) -> bool {
+        self.status == KeyStatus::Revoked || self.validity.revoked_at.is_some_and(|at| at <= now)
+    }
+
+    /// Whether the key may issue tokens that stay redeemable until
+    /// `token_expiry`.
+    pub fn can_sign(&self, now: DateTime<Utc>, token_expiry: DateTime<Utc>) -> bool {
+        self.status == KeyStatus::Active
+            && !self.is_revoked(now)
+            && self.validity.expires_at.is_none_or(|at| token_expiry <= at)
+    }
+
+    pub fn can_verify(&self, now: DateTime<Utc>) -> bool {
+        !self.is_revoked(now) && self.validity.expires_at.is_none_or(|at| now <= at)
+    }
+}
+
+/// Hex SHA-256 of a key set's id and public keys, as
+/// `(currency, denomination, public_key)`. Clients pin it to notice a key
+/// set being swapped, and compare it with other sources to notice being
+/// served keys other wallets are not. The keys are hashed in currency and
+/// denomination order, so the order they are listed in does not matter.
+pub fn key_set_fingerprint<'a>(
+    key_id: &str,
+    public_keys: impl IntoIterator<Item = (&'a str, u64, &'a RsaPublicKey)>,
+) -> String {
+    let mut public_keys: Vec<_> = public_keys.into_iter().collect();
+    public_keys.sort_by_key(|(currency, denomination, _)| (*currency, *denomination));
+
+    let mut hasher = Sha256::new();
+    hasher.update(b"ecash-key-set/v1");
+    let mut field = |bytes: &[u8]| {
+        hasher.update((bytes.len() as u32).to_be_bytes());
+        hasher.update(bytes);
+    };
+    field(key_id.as_bytes());
+    for (currency, denomination, public_key) in public_keys {
+        field(currency.as_bytes());
+        field(&denomination.to_be_bytes());
+        field(&public_key.n().to_bytes_be());
+        field(&public_key.e().to_bytes_be());
+    }
+
+    hex::encode(hasher.finalize())
+}
+
+#[derive(Default)]
+pub struct KeyRing {
+    keys: Vec<InstitutionKey>,
+}
+
+impl KeyRing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a key, replacing any existing key with the same id. Later keys
+    /// take precedence for signing.
+    pub fn insert(&mut self, key: InstitutionKey) {
+        self.keys.retain(|existing| existing.key_id != key.key_id);
+        self.keys.push(key);
+    }
+
+    pub fn get(&self, key_id: &str) -> Option<&InstitutionKey> {
+        self.keys.iter().find(|key| key.key_id == key_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &InstitutionKey> {
+        self.keys.iter()
+    }
+
+    /// The most recently added key that can sign tokens expiring at
+    /// `token_expiry`.
+    pub fn signing_key(
+        &self,
+        now: DateTime<Utc>,
+        token_expiry: DateTime<Utc>,
+    ) -> Option<&InstitutionKey> {
+        self.keys
+            .iter()
+            .rev()
+            .find(|key| key.can_sign(now, token_expiry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_fingerprint_covers_every_key_and_ignores_order() {
+        let keys: Vec<RsaPublicKey> = (0..3)
+            .map(|_| RsaPublicKey::from(&RsaPrivateKey::new(&mut OsRng, 512).unwrap()))
+            .collect();
+        let key_set = |key_id: &str, keys: &[&RsaPublicKey]| {
+            keys.iter().zip([10, 50]).fold(
+                InstitutionKey::new(
+                    key_id.to_string(),
+                    KeyStatus::Active,
+                    KeyValidity::unbounded(),
+                ),
+                |key_set, (public_key, denomination)| {
+                    key_set.with_public_key("USD", denomination, (*public_key).clone())
+                },
+            )
+        };
+
+        let fingerprint = key_set("key_1", &[&keys[0], &keys[1]]).fingerprint();
+        assert_eq!(fingerprint.len(), 64);
+
+        let listed_in_reverse =
+            key_set_fingerprint("key_1", [("USD", 50, &keys[1]), ("USD", 10, &keys[0])]);
+        assert_eq!(listed_in_reverse, fingerprint);
+
+        assert_ne!(
+            key_set("key_1", &[&keys[0], &keys[2]]).fingerprint(),
+            fingerprint
+        );
+        assert_ne!(
+            key_set("key_2", &[&keys[0], &keys[1]]).fingerprint(),
+            fingerprint
+        );
+    }
+}
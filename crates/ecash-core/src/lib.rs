@@ -1,9 +1,29 @@
+pub mod amount;
 pub mod crypto;
+pub mod currency;
+pub mod decomposition;
+pub mod directory;
 pub mod error;
+pub mod exchange;
+pub mod keyformat;
+pub mod keyring;
+pub mod message;
 pub mod protocol;
+pub mod recovery;
+pub mod threshold;
 pub mod token;
 
-pub use crypto::{BlindSigner, BlindUser};
+pub use amount::Amount;
+pub use crypto::{BlindSigner, BlindUser, RsaBssaVariant};
+pub use currency::{iso4217_exponent, Currency};
+pub use decomposition::{decompose, Decomposition};
+pub use directory::{identity_fingerprint, DirectoryEntry, KeyDirectory, SignedKeyDirectory};
 pub use error::{EcashError, Result};
+pub use exchange::{ExchangeRequest, ExchangeResponse};
+pub use keyformat::{Jwk, PrivateKeyFormat, PublicKeyFormat};
+pub use keyring::{key_set_fingerprint, InstitutionKey, KeyRing, KeyStatus, KeyValidity};
+pub use message::TokenMessage;
 pub use protocol::{Institution, Wallet};
+pub use recovery::{IssuedToken, TokenSecrets, WalletSeed};
+pub use threshold::{KeyShare, PartialSignature, ThresholdPublicKey};
 pub use token::{BlindSignature, BlindedToken, Token, TokenMetadata};
@@ -1,47 +1,81 @@
 use chrono::{DateTime, Duration, Utc};
-use num_bigint::BigUint;
 use rand::Rng;
-use rsa::RsaPrivateKey;
+use rsa::RsaPublicKey;
+use std::collections::BTreeMap;
 
-use crate::crypto::{BlindSigner, BlindUser};
+use crate::amount::Amount;
+use crate::crypto::{BlindUser, RsaBssaVariant};
+use crate::currency::Currency;
+use crate::decomposition::{self, Decomposition};
 use crate::error::{EcashError, Result};
+use crate::keyring::{InstitutionKey, KeyRing};
+use crate::message::{self, TokenMessage};
+use crate::recovery::{IssuedToken, TokenSecrets, WalletSeed};
 use crate::token::{BlindSignature, BlindedToken, Token, TokenMetadata};
 
 pub struct Institution {
-    signer: BlindSigner,
+    keys: KeyRing,
+    variant: RsaBssaVariant,
     institution_id: String,
-    key_id: String,
-    denominations: Vec<u64>,
+    currencies: Vec<Currency>,
     default_expiry: Duration,
 }
 
 impl Institution {
+    /// `currencies` are the currencies tokens are issued in, each with its
+    /// own denominations. The first is the institution's default.
     pub fn new(
-        private_key: RsaPrivateKey,
+        keys: KeyRing,
         institution_id: String,
-        key_id: String,
-        denominations: Vec<u64>,
+        currencies: Vec<Currency>,
         default_expiry_days: i64,
     ) -> Self {
         Self {
-            signer: BlindSigner::from_keys(private_key),
+            keys,
+            variant: RsaBssaVariant::default(),
             institution_id,
-            key_id,
-            denominations,
+            currencies,
             default_expiry: Duration::days(default_expiry_days),
         }
     }
 
+    pub fn with_variant(mut self, variant: RsaBssaVariant) -> Self {
+        self.variant = variant;
+        self
+    }
+
     pub fn institution_id(&self) -> &str {
         &self.institution_id
     }
 
-    pub fn public_key(&self) -> &rsa::RsaPublicKey {
-        self.signer.public_key()
+    pub fn variant(&self) -> RsaBssaVariant {
+        self.variant
+    }
+
+    pub fn keys(&self) -> &KeyRing {
+        &self.keys
+    }
+
+    /// The key new withdrawals are signed with, if any key may currently
+    /// issue tokens that stay redeemable for the full expiry period.
+    pub fn signing_key(&self) -> Option<&InstitutionKey> {
+        let token_expiry = self.expiry_time(self.current_expiry_epoch()).ok()?;
+        self.keys.signing_key(Utc::now(), token_expiry)
     }
 
-    pub fn validate_denomination(&self, denomination: u64) -> Result<()> {
-        if self.denominations.contains(&denomination) {
+    pub fn currencies(&self) -> &[Currency] {
+        &self.currencies
+    }
+
+    pub fn currency(&self, code: &str) -> Result<&Currency> {
+        self.currencies
+            .iter()
+            .find(|currency| currency.code == code)
+            .ok_or(EcashError::UnsupportedCurrency)
+    }
+
+    pub fn validate_denomination(&self, currency: &str, denomination: u64) -> Result<()> {
+        if self.currency(currency)?.has_denomination(denomination) {
             Ok(())
         } else {
             Err(EcashError::InvalidDenomination)
@@ -49,14 +83,23 @@ impl Institution {
     }
 
     pub fn sign_blinded_token(&self, blinded: &BlindedToken) -> Result<BlindSignature> {
-        self.validate_denomination(blinded.denomination)?;
+        self.validate_denomination(&blinded.currency, blinded.denomination)?;
 
-        let blinded_msg = BigUint::from_bytes_be(&blinded.blinded_message);
-        let signature = self.signer.sign_blinded(&blinded_msg)?;
+        let key = self.signing_key().ok_or(EcashError::KeyNotActive)?;
+        if key.key_id() != blinded.key_id {
+            return Err(EcashError::KeyNotActive);
+        }
+        // The denomination selects the key, so a blinded message cannot be
+        // signed as worth more than the key it was blinded for.
+        let signer = key
+            .signer(&blinded.currency, blinded.denomination)
+            .ok_or(EcashError::InvalidDenomination)?;
+
+        let signature = signer.sign_blinded(&blinded.blinded_message)?;
 
         Ok(BlindSignature {
-            signature: signature.to_bytes_be(),
-            key_id: self.key_id.clone(),
+            signature,
+            key_id: key.key_id().to_string(),
         })
     }
 
@@ -65,91 +108,244 @@ impl Institution {
             return Ok(false);
         }
 
-        self.validate_denomination(token.denomination)?;
+        self.validate_denomination(&token.currency, token.denomination)?;
 
-        let user = BlindUser::new(self.signer.public_key().clone());
-        let message = Self::construct_message(
-            &token.serial_number,
-            token.denomination,
-            &token.currency,
-            &token.issued_at,
-        );
+        let key = self.keys.get(&token.key_id).ok_or(EcashError::UnknownKey)?;
+        if !key.can_verify(Utc::now()) {
+            return Err(EcashError::KeyNotValid);
+        }
+
+        let message = TokenMessage::from_token(token)?;
+        if message.expiry_epoch > self.current_expiry_epoch() {
+            return Ok(false);
+        }
+
+        let public_key = key
+            .public_key(&token.currency, token.denomination)
+            .ok_or(EcashError::InvalidDenomination)?;
+        let user = BlindUser::new(public_key.clone(), self.variant);
+        let prepared = self.variant.prepare(&token.msg_prefix, &message.encode())?;
 
-        let signature = BigUint::from_bytes_be(&token.signature);
-        Ok(user.verify_signature(&message, &signature))
+        Ok(user.verify_signature(&prepared, &token.signature))
     }
 
-    pub fn expiry_time(&self) -> DateTime<Utc> {
-        Utc::now() + self.default_expiry
+    /// The expiry epoch wallets should bind into tokens withdrawn now.
+    pub fn current_expiry_epoch(&self) -> u64 {
+        message::expiry_epoch_at(&(Utc::now() + self.default_expiry))
     }
 
-    fn construct_message(
-        serial: &[u8],
-        denomination: u64,
-        currency: &str,
-        issued_at: &DateTime<Utc>,
-    ) -> Vec<u8> {
-        let mut message = Vec::new();
-        message.extend_from_slice(serial);
-        message.extend_from_slice(&denomination.to_be_bytes());
-        message.extend_from_slice(currency.as_bytes());
-        message.extend_from_slice(&issued_at.timestamp().to_be_bytes());
-        message
+    /// Accepts the current epoch and the one before it, so a wallet that
+    /// fetched the epoch just before a boundary can still withdraw.
+    pub fn validate_expiry_epoch(&self, expiry_epoch: u64) -> Result<()> {
+        let current = self.current_expiry_epoch();
+        if expiry_epoch == current || expiry_epoch + 1 == current {
+            Ok(())
+        } else {
+            Err(EcashError::InvalidExpiryEpoch)
+        }
+    }
+
+    pub fn expiry_time(&self, expiry_epoch: u64) -> Result<DateTime<Utc>> {
+        message::epoch_expiry(expiry_epoch)
+    }
+
+    /// Last instant at which `key` can still issue tokens, which must stay
+    /// redeemable for the full expiry period rounded up to the next epoch.
+    /// `None` if the key has no expiry.
+    pub fn issue_until(&self, key: &InstitutionKey) -> Option<DateTime<Utc>> {
+        key.validity().expires_at.map(|expires_at| {
+            expires_at - self.default_expiry - Duration::seconds(message::EXPIRY_EPOCH_SECONDS)
+        })
     }
 }
 
 pub struct Wallet {
-    user: BlindUser,
+    public_keys: BTreeMap<u64, RsaPublicKey>,
+    variant: RsaBssaVariant,
     institution_id: String,
+    key_id: String,
     currency: String,
 }
 
 impl Wallet {
-    pub fn new(public_key: rsa::RsaPublicKey, institution_id: String, currency: String) -> Self {
+    /// `public_keys` maps each denomination of `currency` to the key that
+    /// signs it under `key_id`.
+    pub fn new(
+        public_keys: BTreeMap<u64, RsaPublicKey>,
+        institution_id: String,
+        key_id: String,
+        currency: String,
+    ) -> Self {
         Self {
-            user: BlindUser::new(public_key),
+            public_keys,
+            variant: RsaBssaVariant::default(),
             institution_id,
+            key_id,
             currency,
         }
     }
 
+    pub fn with_variant(mut self, variant: RsaBssaVariant) -> Self {
+        self.variant = variant;
+        self
+    }
+
+    fn user(&self, denomination: u64) -> Result<BlindUser> {
+        let public_key = self
+            .public_keys
+            .get(&denomination)
+            .ok_or(EcashError::InvalidDenomination)?;
+        Ok(BlindUser::new(public_key.clone(), self.variant))
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// The denominations this wallet has keys for, smallest first.
+    pub fn denominations(&self) -> Vec<u64> {
+        self.public_keys.keys().copied().collect()
+    }
+
+    /// Splits `amount`, which must be in this wallet's currency, into its
+    /// denominations.
+    pub fn decompose(&self, amount: &Amount, strategy: Decomposition) -> Result<Vec<u64>> {
+        if amount.currency() != self.currency {
+            return Err(EcashError::CurrencyMismatch);
+        }
+        decomposition::decompose(amount.minor_units(), &self.denominations(), strategy)
+    }
+
+    /// Blinds tokens worth exactly `amount`, decomposed with `strategy`.
     pub fn prepare_withdrawal(
         &self,
-        amount: u64,
-        denomination: u64,
+        amount: &Amount,
+        strategy: Decomposition,
+        expiry_epoch: u64,
     ) -> Result<Vec<(BlindedToken, TokenMetadata)>> {
-        let count = amount.div_ceil(denomination);
-        let mut tokens = Vec::new();
+        self.prepare_tokens(&self.decompose(amount, strategy)?, expiry_epoch)
+    }
 
-        for _ in 0..count {
-            let serial = Self::generate_serial();
-            let message = Self::construct_message_for_serial(&serial, denomination, &self.currency);
+    /// Blinds one new token per entry of `denominations`, for a withdrawal
+    /// or the outputs of an exchange.
+    pub fn prepare_tokens(
+        &self,
+        denominations: &[u64],
+        expiry_epoch: u64,
+    ) -> Result<Vec<(BlindedToken, TokenMetadata)>> {
+        denominations
+            .iter()
+            .map(|&denomination| self.prepare_token(denomination, expiry_epoch, None))
+            .collect()
+    }
 
-            let (blinded, blinding_factor) = self.user.blind_message(&message)?;
+    /// Like `prepare_tokens`, but derives each token's secrets from `seed`
+    /// at consecutive indices starting with `first_index`, so that the
+    /// tokens can be restored from the seed alone.
+    pub fn prepare_tokens_from_seed(
+        &self,
+        seed: &WalletSeed,
+        denominations: &[u64],
+        expiry_epoch: u64,
+        first_index: u64,
+    ) -> Result<Vec<(BlindedToken, TokenMetadata)>> {
+        denominations
+            .iter()
+            .zip(first_index..)
+            .map(|(&denomination, index)| {
+                let secrets = seed.token_secrets(&self.key_id, index);
+                self.prepare_token(denomination, expiry_epoch, Some(&secrets))
+            })
+            .collect()
+    }
 
-            tokens.push((
-                BlindedToken {
-                    blinded_message: blinded.to_bytes_be(),
-                    denomination,
-                    currency: self.currency.clone(),
-                },
-                TokenMetadata {
-                    serial_number: serial,
-                    blinding_factor: blinding_factor.to_bytes_be(),
-                    denomination,
-                    currency: self.currency.clone(),
-                },
-            ));
+    /// Rebuilds the token at `index` of `seed` from what the institution
+    /// stored when it was issued. Fails with `RecoveryMismatch` unless the
+    /// re-derived blinded message is the one that was signed.
+    pub fn restore_token(
+        &self,
+        seed: &WalletSeed,
+        index: u64,
+        issued: &IssuedToken,
+    ) -> Result<Token> {
+        if issued.key_id != self.key_id {
+            return Err(EcashError::InvalidKey);
+        }
+        if issued.currency != self.currency {
+            return Err(EcashError::CurrencyMismatch);
         }
 
-        Ok(tokens)
+        let secrets = seed.token_secrets(&self.key_id, index);
+        if secrets.recovery_id != issued.recovery_id {
+            return Err(EcashError::RecoveryMismatch);
+        }
+        let (blinded, metadata) =
+            self.prepare_token(issued.denomination, issued.expiry_epoch, Some(&secrets))?;
+        if blinded.blinded_message != issued.blinded_message {
+            return Err(EcashError::RecoveryMismatch);
+        }
+
+        let blind_signature = BlindSignature {
+            signature: issued.blind_signature.clone(),
+            key_id: issued.key_id.clone(),
+        };
+        let mut tokens = self.finalize_withdrawal(vec![blind_signature], vec![metadata])?;
+        Ok(tokens.remove(0))
+    }
+
+    /// Blinds one token, with fresh randomness unless `secrets` is given.
+    fn prepare_token(
+        &self,
+        denomination: u64,
+        expiry_epoch: u64,
+        secrets: Option<&TokenSecrets>,
+    ) -> Result<(BlindedToken, TokenMetadata)> {
+        let user = self.user(denomination)?;
+        let (serial, msg_prefix) = match secrets {
+            Some(secrets) if self.variant.is_randomized() => {
+                (secrets.serial_number.clone(), secrets.msg_prefix.clone())
+            }
+            Some(secrets) => (secrets.serial_number.clone(), Vec::new()),
+            None => (Self::generate_serial(), self.variant.generate_msg_prefix()),
+        };
+        let message = TokenMessage {
+            serial_number: serial.clone(),
+            denomination,
+            currency: self.currency.clone(),
+            key_id: self.key_id.clone(),
+            expiry_epoch,
+        };
+        let prepared = self.variant.prepare(&msg_prefix, &message.encode())?;
+
+        let (blinded, blinding_factor) = match secrets {
+            Some(secrets) => secrets.blind(&user, &prepared)?,
+            None => user.blind_message(&prepared)?,
+        };
+
+        Ok((
+            BlindedToken {
+                blinded_message: blinded,
+                denomination,
+                currency: self.currency.clone(),
+                key_id: self.key_id.clone(),
+                recovery_id: secrets.map(|secrets| secrets.recovery_id.clone()),
+            },
+            TokenMetadata {
+                serial_number: serial,
+                blinding_factor,
+                msg_prefix,
+                denomination,
+                currency: self.currency.clone(),
+                key_id: self.key_id.clone(),
+                expiry_epoch,
+            },
+        ))
     }
 
     pub fn finalize_withdrawal(
         &self,
         blind_signatures: Vec<BlindSignature>,
         metadata: Vec<TokenMetadata>,
-        expires_at: DateTime<Utc>,
     ) -> Result<Vec<Token>> {
         if blind_signatures.len() != metadata.len() {
             return Err(EcashError::CryptoError);
@@ -158,32 +354,38 @@ impl Wallet {
         let mut tokens = Vec::new();
 
         for (blind_sig, meta) in blind_signatures.into_iter().zip(metadata) {
-            let blind_signature = BigUint::from_bytes_be(&blind_sig.signature);
-            let blinding_factor = BigUint::from_bytes_be(&meta.blinding_factor);
-
-            let signature = self
-                .user
-                .unblind_signature(&blind_signature, &blinding_factor)?;
-
-            let message = Self::construct_message_for_serial(
-                &meta.serial_number,
-                meta.denomination,
-                &meta.currency,
-            );
-
-            if !self.user.verify_signature(&message, &signature) {
-                return Err(EcashError::InvalidSignature);
+            if blind_sig.key_id != meta.key_id {
+                return Err(EcashError::InvalidKey);
             }
 
-            tokens.push(Token::new(
-                meta.serial_number,
-                meta.denomination,
-                meta.currency,
-                signature.to_bytes_be(),
-                expires_at,
-                self.institution_id.clone(),
-                blind_sig.key_id,
-            ));
+            let message = TokenMessage {
+                serial_number: meta.serial_number.clone(),
+                denomination: meta.denomination,
+                currency: meta.currency.clone(),
+                key_id: meta.key_id.clone(),
+                expiry_epoch: meta.expiry_epoch,
+            };
+            let prepared = self.variant.prepare(&meta.msg_prefix, &message.encode())?;
+            let expires_at = message::epoch_expiry(meta.expiry_epoch)?;
+
+            let signature = self.user(meta.denomination)?.finalize(
+                &prepared,
+                &blind_sig.signature,
+                &meta.blinding_factor,
+            )?;
+
+            tokens.push(
+                Token::new(
+                    meta.serial_number,
+                    meta.denomination,
+                    meta.currency,
+                    signature,
+                    expires_at,
+                    self.institution_id.clone(),
+                    meta.key_id,
+                )
+                .with_msg_prefix(meta.msg_prefix),
+            );
         }
 
         Ok(tokens)
@@ -195,15 +397,6 @@ impl Wallet {
         rng.fill(&mut serial[..]);
         serial
     }
-
-    fn construct_message_for_serial(serial: &[u8], denomination: u64, currency: &str) -> Vec<u8> {
-        let mut message = Vec::new();
-        message.extend_from_slice(serial);
-        message.extend_from_slice(&denomination.to_be_bytes());
-        message.extend_from_slice(currency.as_bytes());
-        message.extend_from_slice(&Utc::now().timestamp().to_be_bytes());
-        message
-    }
 }
 
 #[cfg(test)]
@@ -212,23 +405,64 @@ mod tests {
     use rand::thread_rng;
     use rsa::RsaPrivateKey;
 
-    #[test]
-    fn test_full_withdrawal_flow() {
+    use crate::keyring::{KeyStatus, KeyValidity};
+
+    /// A key set with fresh keys for each denomination, plus the public keys
+    /// a wallet needs to use it.
+    fn key_set(
+        key_id: &str,
+        denominations: &[u64],
+    ) -> (InstitutionKey, BTreeMap<u64, RsaPublicKey>) {
         let mut rng = thread_rng();
-        let private_key = RsaPrivateKey::new(&mut rng, 2048).unwrap();
-        let public_key = private_key.to_public_key();
+        let mut key = InstitutionKey::new(
+            key_id.to_string(),
+            KeyStatus::Active,
+            KeyValidity::unbounded(),
+        );
+        let mut public_keys = BTreeMap::new();
+        for &denomination in denominations {
+            let private_key = RsaPrivateKey::new(&mut rng, 2048).unwrap();
+            public_keys.insert(denomination, private_key.to_public_key());
+            key = key.with_private_key("USD", denomination, private_key);
+        }
+        (key, public_keys)
+    }
+
+    /// The same key set as seen after rotation: public keys only.
+    fn verify_only(
+        key_id: &str,
+        public_keys: &BTreeMap<u64, RsaPublicKey>,
+        status: KeyStatus,
+        validity: KeyValidity,
+    ) -> InstitutionKey {
+        public_keys.iter().fold(
+            InstitutionKey::new(key_id.to_string(), status, validity),
+            |key, (&denomination, public_key)| {
+                key.with_public_key("USD", denomination, public_key.clone())
+            },
+        )
+    }
 
-        let institution = Institution::new(
-            private_key,
+    fn wallet(key_id: &str, public_keys: BTreeMap<u64, RsaPublicKey>) -> Wallet {
+        Wallet::new(
+            public_keys,
             "inst_test".to_string(),
-            "key_001".to_string(),
-            vec![10, 50, 100],
-            90,
-        );
+            key_id.to_string(),
+            "USD".to_string(),
+        )
+    }
 
-        let wallet = Wallet::new(public_key, "inst_test".to_string(), "USD".to_string());
+    fn usd(denominations: &[u64]) -> Vec<Currency> {
+        vec![Currency::new("USD", denominations.to_vec()).unwrap()]
+    }
 
-        let tokens_to_prepare = wallet.prepare_withdrawal(100, 50).unwrap();
+    fn withdraw(institution: &Institution, wallet: &Wallet, denomination: u64) -> Vec<Token> {
+        let tokens_to_prepare = wallet
+            .prepare_tokens(
+                &vec![denomination; (100 / denomination) as usize],
+                institution.current_expiry_epoch(),
+            )
+            .unwrap();
         let (blinded_tokens, metadata): (Vec<_>, Vec<_>) = tokens_to_prepare.into_iter().unzip();
 
         let blind_signatures: Vec<_> = blinded_tokens
@@ -236,12 +470,264 @@ mod tests {
             .map(|bt| institution.sign_blinded_token(bt).unwrap())
             .collect();
 
+        wallet
+            .finalize_withdrawal(blind_signatures, metadata)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_full_withdrawal_flow() {
+        let (key, public_keys) = key_set("key_001", &[10, 50]);
+        let mut keys = KeyRing::new();
+        keys.insert(key);
+        let institution = Institution::new(keys, "inst_test".to_string(), usd(&[10, 50]), 90);
+        let wallet = wallet("key_001", public_keys);
+
+        for denomination in [10, 50] {
+            for token in &withdraw(&institution, &wallet, denomination) {
+                assert!(institution.verify_token(token).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_prepare_tokens_of_mixed_denominations() {
+        let (key, public_keys) = key_set("key_001", &[10, 50]);
+        let mut keys = KeyRing::new();
+        keys.insert(key);
+        let institution = Institution::new(keys, "inst_test".to_string(), usd(&[10, 50]), 90);
+        let wallet = wallet("key_001", public_keys);
+
+        let (blinded_tokens, metadata): (Vec<_>, Vec<_>) = wallet
+            .prepare_tokens(&[50, 10, 10], institution.current_expiry_epoch())
+            .unwrap()
+            .into_iter()
+            .unzip();
+        let blind_signatures = blinded_tokens
+            .iter()
+            .map(|bt| institution.sign_blinded_token(bt).unwrap())
+            .collect();
+
+        let tokens = wallet
+            .finalize_withdrawal(blind_signatures, metadata)
+            .unwrap();
+        let denominations: Vec<u64> = tokens.iter().map(|t| t.denomination).collect();
+        assert_eq!(denominations, vec![50, 10, 10]);
+        assert!(tokens
+            .iter()
+            .all(|token| institution.verify_token(token).unwrap()));
+        assert!(wallet.prepare_tokens(&[10, 20], 0).is_err());
+
+        // Withdrawals are decomposed exactly rather than rounded up.
+        let usd = &institution.currencies()[0];
+        let prepared = wallet
+            .prepare_withdrawal(&usd.amount(170), Decomposition::Greedy, 0)
+            .unwrap();
+        let denominations: Vec<u64> = prepared.iter().map(|(bt, _)| bt.denomination).collect();
+        assert_eq!(denominations, vec![50, 50, 50, 10, 10]);
+        assert!(matches!(
+            wallet.prepare_withdrawal(&usd.amount(175), Decomposition::Greedy, 0),
+            Err(EcashError::AmountNotRepresentable)
+        ));
+        let yen = Currency::new("JPY", vec![10]).unwrap();
+        assert!(matches!(
+            wallet.prepare_withdrawal(&yen.amount(100), Decomposition::Greedy, 0),
+            Err(EcashError::CurrencyMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_tokens_are_issued_per_currency() {
+        let (usd_key, _) = key_set("key_001", &[100]);
+        let private_key = RsaPrivateKey::new(&mut thread_rng(), 2048).unwrap();
+        let yen_public_key = private_key.to_public_key();
+        let mut keys = KeyRing::new();
+        keys.insert(usd_key.with_private_key("JPY", 100, private_key));
+        let currencies = vec![
+            Currency::new("USD", vec![100]).unwrap(),
+            Currency::new("JPY", vec![100]).unwrap(),
+        ];
+        let institution = Institution::new(keys, "inst_test".to_string(), currencies, 90);
+        let yen_wallet = Wallet::new(
+            BTreeMap::from([(100, yen_public_key)]),
+            "inst_test".to_string(),
+            "key_001".to_string(),
+            "JPY".to_string(),
+        );
+
+        let mut token = withdraw(&institution, &yen_wallet, 100).remove(0);
+        assert_eq!(token.currency, "JPY");
+        assert!(institution.verify_token(&token).unwrap());
+
+        // The currency selects the key just as the denomination does.
+        token.currency = "USD".to_string();
+        assert!(!institution.verify_token(&token).unwrap());
+
+        token.currency = "EUR".to_string();
+        assert!(matches!(
+            institution.verify_token(&token),
+            Err(EcashError::UnsupportedCurrency)
+        ));
+    }
+
+    #[test]
+    fn test_denomination_is_bound_to_key() {
+        let (key, public_keys) = key_set("key_001", &[10, 50]);
+        let mut keys = KeyRing::new();
+        keys.insert(key);
+        let institution = Institution::new(keys, "inst_test".to_string(), usd(&[10, 50]), 90);
+        let wallet = wallet("key_001", public_keys);
+
+        // Relabelling a valid token does not make it verify under another
+        // denomination's key.
+        let mut token = withdraw(&institution, &wallet, 10).remove(0);
+        token.denomination = 50;
+        assert!(!institution.verify_token(&token).unwrap());
+
+        // A message blinded for the 10 key but submitted as a 50 is signed
+        // with the 50 key, and the result does not unblind to a valid token.
+        // It is refused outright if it is not below the 50 key's modulus.
+        let (mut blinded, mut metadata) = wallet
+            .prepare_tokens(&[10], institution.current_expiry_epoch())
+            .unwrap()
+            .remove(0);
+        blinded.denomination = 50;
+        match institution.sign_blinded_token(&blinded) {
+            Ok(signature) => {
+                metadata.denomination = 50;
+                assert!(wallet
+                    .finalize_withdrawal(vec![signature], vec![metadata])
+                    .is_err());
+            }
+            Err(error) => assert!(matches!(error, EcashError::InvalidInput)),
+        }
+
+        blinded.denomination = 100;
+        assert!(matches!(
+            institution.sign_blinded_token(&blinded),
+            Err(EcashError::InvalidDenomination)
+        ));
+    }
+
+    #[test]
+    fn test_key_rotation_keeps_outstanding_tokens_redeemable() {
+        let (old_key, old_public) = key_set("key_q1", &[50]);
+        let (new_key, new_public) = key_set("key_q2", &[50]);
+
+        let mut keys = KeyRing::new();
+        keys.insert(old_key);
+        let before = Institution::new(keys, "inst_test".to_string(), usd(&[50]), 90);
+        let old_wallet = wallet("key_q1", old_public.clone());
+        let old_tokens = withdraw(&before, &old_wallet, 50);
+
+        // Rotate: the old key becomes redeem-only with a grace period that
+        // covers its outstanding tokens.
+        let grace = KeyValidity {
+            expires_at: Some(Utc::now() + Duration::days(91)),
+            ..KeyValidity::unbounded()
+        };
+        let mut keys = KeyRing::new();
+        keys.insert(verify_only(
+            "key_q1",
+            &old_public,
+            KeyStatus::RedeemOnly,
+            grace,
+        ));
+        keys.insert(new_key);
+        let after = Institution::new(keys, "inst_test".to_string(), usd(&[50]), 90);
+
+        assert_eq!(after.signing_key().unwrap().key_id(), "key_q2");
+        assert!(after.verify_token(&old_tokens[0]).unwrap());
+
+        let (blinded, _) = old_wallet
+            .prepare_tokens(&[50], after.current_expiry_epoch())
+            .unwrap()
+            .remove(0);
+        assert!(matches!(
+            after.sign_blinded_token(&blinded),
+            Err(EcashError::KeyNotActive)
+        ));
+
+        let new_wallet = wallet("key_q2", new_public);
+        assert!(after
+            .verify_token(&withdraw(&after, &new_wallet, 50)[0])
+            .unwrap());
+
+        // Once revoked, the old key's tokens are refused outright.
+        let mut keys = KeyRing::new();
+        keys.insert(verify_only(
+            "key_q1",
+            &old_public,
+            KeyStatus::Revoked,
+            KeyValidity::unbounded(),
+        ));
+        let revoked = Institution::new(keys, "inst_test".to_string(), usd(&[50]), 90);
+        assert!(matches!(
+            revoked.verify_token(&old_tokens[0]),
+            Err(EcashError::KeyNotValid)
+        ));
+
+        let mut unknown = old_tokens[0].clone();
+        unknown.key_id = "key_q9".to_string();
+        assert!(matches!(
+            after.verify_token(&unknown),
+            Err(EcashError::UnknownKey)
+        ));
+    }
+
+    #[test]
+    fn test_seeded_tokens_are_restored_from_issuance_records() {
+        let (key, public_keys) = key_set("key_001", &[10, 50]);
+        let mut keys = KeyRing::new();
+        keys.insert(key);
+        let institution = Institution::new(keys, "inst_test".to_string(), usd(&[10, 50]), 90);
+        let wallet = wallet("key_001", public_keys);
+        let seed = WalletSeed::from_mnemonic(&WalletSeed::generate_mnemonic(), "").unwrap();
+        let expiry_epoch = institution.current_expiry_epoch();
+
+        let prepared = wallet
+            .prepare_tokens_from_seed(&seed, &[50, 10], expiry_epoch, 7)
+            .unwrap();
+        let (blinded_tokens, metadata): (Vec<_>, Vec<_>) = prepared.into_iter().unzip();
+        let issued: Vec<_> = blinded_tokens
+            .iter()
+            .map(|blinded| IssuedToken {
+                recovery_id: blinded.recovery_id.clone().unwrap(),
+                blinded_message: blinded.blinded_message.clone(),
+                blind_signature: institution.sign_blinded_token(blinded).unwrap().signature,
+                denomination: blinded.denomination,
+                currency: blinded.currency.clone(),
+                key_id: blinded.key_id.clone(),
+                expiry_epoch,
+            })
+            .collect();
+        let blind_signatures = issued
+            .iter()
+            .map(|issued| BlindSignature {
+                signature: issued.blind_signature.clone(),
+                key_id: issued.key_id.clone(),
+            })
+            .collect();
         let tokens = wallet
-            .finalize_withdrawal(blind_signatures, metadata, institution.expiry_time())
+            .finalize_withdrawal(blind_signatures, metadata)
             .unwrap();
 
-        for token in &tokens {
-            assert!(institution.verify_token(token).unwrap());
+        // The seed alone reproduces both tokens, byte for byte.
+        for (index, (issued, token)) in (7..).zip(issued.iter().zip(&tokens)) {
+            let restored = wallet.restore_token(&seed, index, issued).unwrap();
+            assert_eq!(restored.serial_number, token.serial_number);
+            assert_eq!(restored.signature, token.signature);
+            assert!(institution.verify_token(&restored).unwrap());
         }
+
+        assert!(matches!(
+            wallet.restore_token(&seed, 8, &issued[0]),
+            Err(EcashError::RecoveryMismatch)
+        ));
+        let other = WalletSeed::from_mnemonic(&WalletSeed::generate_mnemonic(), "").unwrap();
+        assert!(matches!(
+            wallet.restore_token(&other, 7, &issued[0]),
+            Err(EcashError::RecoveryMismatch)
+        ));
     }
 }
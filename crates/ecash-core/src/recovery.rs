@@ -0,0 +1,220 @@
+//! Deterministic token secrets derived from a BIP-39 seed phrase.
+//!
+//! A seeded wallet derives each token's serial, message prefix, PSS salt and
+//! blinding factor from its master seed with HKDF-SHA256, along the path
+//! `(key_id, index)`. It also derives a recovery id, which is sent with the
+//! blinded token and stored by the institution next to the blind signature.
+//! A wallet restored from the phrase alone walks the indices of each key
+//! set, fetches the issuance records stored under their recovery ids,
+//! re-derives the blinded messages and unblinds the stored signatures.
+//!
+//! A recovery id is never shown at redemption, so it does not link a spent
+//! token to its issuance. A restore request does tell the institution which
+//! issuances belong to one wallet.
+
+use bip39::Mnemonic;
+use hkdf::Hkdf;
+use rand::RngCore;
+use rsa::traits::PublicKeyParts;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::crypto::BlindUser;
+use crate::error::{EcashError, Result};
+
+const HKDF_SALT: &[u8] = b"ecash-protocol/wallet-seed/v1";
+
+/// 256 bits of entropy, a 24-word phrase.
+const MNEMONIC_ENTROPY_LEN: usize = 32;
+
+const SERIAL_LEN: usize = 32;
+const MSG_PREFIX_LEN: usize = 32;
+const SALT_LEN: usize = 48;
+pub const RECOVERY_ID_LEN: usize = 32;
+
+/// Derived beyond the modulus length so that reducing the blinding factor
+/// modulo `n` is unbiased to within 2^-128.
+const BLINDING_EXTRA_LEN: usize = 16;
+
+/// Blinding factors tried before giving up; each fails with negligible
+/// probability.
+const MAX_BLINDING_ATTEMPTS: u32 = 8;
+
+/// The 64-byte BIP-39 seed of a wallet.
+#[derive(Clone)]
+pub struct WalletSeed {
+    hkdf: Hkdf<Sha256>,
+}
+
+impl WalletSeed {
+    /// A new random 24-word English phrase.
+    pub fn generate_mnemonic() -> String {
+        let mut entropy = [0u8; MNEMONIC_ENTROPY_LEN];
+        rand::thread_rng().fill_bytes(&mut entropy);
+        Mnemonic::from_entropy(&entropy)
+            .expect("32 bytes is a valid entropy length")
+            .to_string()
+    }
+
+    /// Checks the phrase's words and checksum and derives its seed. The
+    /// passphrase is BIP-39's optional extra word; an empty one is allowed.
+    pub fn from_mnemonic(phrase: &str, passphrase: &str) -> Result<Self> {
+        let mnemonic = Mnemonic::parse(phrase).map_err(|_| EcashError::InvalidMnemonic)?;
+        Ok(Self::from_seed(&mnemonic.to_seed(passphrase)))
+    }
+
+    pub fn from_seed(seed: &[u8; 64]) -> Self {
+        Self {
+            hkdf: Hkdf::new(Some(HKDF_SALT), seed),
+        }
+    }
+
+    /// The secrets of the token at `index` of key set `key_id`.
+    pub fn token_secrets(&self, key_id: &str, index: u64) -> TokenSecrets {
+        let mut path = Vec::with_capacity(4 + key_id.len() + 8);
+        path.extend_from_slice(&(key_id.len() as u32).to_be_bytes());
+        path.extend_from_slice(key_id.as_bytes());
+        path.extend_from_slice(&index.to_be_bytes());
+
+        TokenSecrets {
+            serial_number: expand(&self.hkdf, &path, b"serial", SERIAL_LEN),
+            msg_prefix: expand(&self.hkdf, &path, b"msg-prefix", MSG_PREFIX_LEN),
+            salt: expand(&self.hkdf, &path, b"salt", SALT_LEN),
+            recovery_id: expand(&self.hkdf, &path, b"recovery-id", RECOVERY_ID_LEN),
+            hkdf: self.hkdf.clone(),
+            path,
+        }
+    }
+}
+
+impl std::fmt::Debug for WalletSeed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("WalletSeed(..)")
+    }
+}
+
+/// Everything a wallet would otherwise draw at random for one token.
+pub struct TokenSecrets {
+    hkdf: Hkdf<Sha256>,
+    path: Vec<u8>,
+    pub serial_number: Vec<u8>,
+    /// Used by the randomized RSABSSA variant only.
+    pub msg_prefix: Vec<u8>,
+    pub salt: Vec<u8>,
+    pub recovery_id: Vec<u8>,
+}
+
+impl TokenSecrets {
+    /// RFC 9474 `Blind` with the derived salt and the first derived
+    /// blinding factor that is invertible modulo `n`.
+    pub fn blind(&self, user: &BlindUser, prepared_message: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let len = user.public_key().size() + BLINDING_EXTRA_LEN;
+        for attempt in 0..MAX_BLINDING_ATTEMPTS {
+            let mut label = b"blinding-factor/".to_vec();
+            label.extend_from_slice(&attempt.to_be_bytes());
+
+            match user.blind_message_with(
+                prepared_message,
+                &self.salt,
+                &expand(&self.hkdf, &self.path, &label, len),
+            ) {
+                Err(EcashError::BlindingFailed) => continue,
+                result => return result,
+            }
+        }
+        Err(EcashError::BlindingFailed)
+    }
+}
+
+/// HKDF-Expand with `label` and the token's path as info.
+fn expand(hkdf: &Hkdf<Sha256>, path: &[u8], label: &[u8], len: usize) -> Vec<u8> {
+    let mut info = Vec::with_capacity(label.len() + 1 + path.len());
+    info.extend_from_slice(label);
+    info.push(0);
+    info.extend_from_slice(path);
+
+    let mut out = vec![0u8; len];
+    hkdf.expand(&info, &mut out)
+        .expect("output is far below the HKDF limit");
+    out
+}
+
+/// What the institution stored when it signed a blinded token that carried
+/// a recovery id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IssuedToken {
+    pub recovery_id: Vec<u8>,
+    pub blinded_message: Vec<u8>,
+    pub blind_signature: Vec<u8>,
+    pub denomination: u64,
+    pub currency: String,
+    pub key_id: String,
+    pub expiry_epoch: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PHRASE: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    #[test]
+    fn test_secrets_are_derived_per_key_and_index() {
+        let seed = WalletSeed::from_mnemonic(PHRASE, "").unwrap();
+        let again = WalletSeed::from_mnemonic(PHRASE, "").unwrap();
+        let other = WalletSeed::from_mnemonic(PHRASE, "TREZOR").unwrap();
+
+        let first = seed.token_secrets("key_001", 0);
+        assert_eq!(
+            first.serial_number,
+            again.token_secrets("key_001", 0).serial_number
+        );
+        assert_eq!(first.serial_number.len(), SERIAL_LEN);
+        assert_eq!(first.salt.len(), SALT_LEN);
+        assert_ne!(
+            first.serial_number,
+            seed.token_secrets("key_001", 1).serial_number
+        );
+        assert_ne!(
+            first.serial_number,
+            seed.token_secrets("key_002", 0).serial_number
+        );
+        assert_ne!(
+            first.serial_number,
+            other.token_secrets("key_001", 0).serial_number
+        );
+        assert_ne!(first.serial_number, first.recovery_id);
+
+        // The phrase maps to the standard BIP-39 seed.
+        let bip39_seed: [u8; 64] = hex::decode(
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04",
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+        assert_eq!(
+            WalletSeed::from_seed(&bip39_seed)
+                .token_secrets("key_001", 0)
+                .serial_number,
+            other.token_secrets("key_001", 0).serial_number
+        );
+    }
+
+    #[test]
+    fn test_mnemonic_round_trip_and_checksum() {
+        let phrase = WalletSeed::generate_mnemonic();
+        assert_eq!(phrase.split_whitespace().count(), 24);
+        assert!(WalletSeed::from_mnemonic(&phrase, "").is_ok());
+
+        // The last word of a valid 12-word phrase carries its checksum.
+        let broken = PHRASE.replace("about", "abandon");
+        assert!(matches!(
+            WalletSeed::from_mnemonic(&broken, ""),
+            Err(EcashError::InvalidMnemonic)
+        ));
+        assert!(matches!(
+            WalletSeed::from_mnemonic("not a seed phrase", ""),
+            Err(EcashError::InvalidMnemonic)
+        ));
+    }
+}
@@ -0,0 +1,670 @@
+//! Threshold RSA blind signatures (Shoup, "Practical Threshold Signatures",
+//! EUROCRYPT 2000).
+//!
+//! A dealer splits the private exponent of a key built from safe primes
+//! into `signers` shares, any `threshold` of which can sign. Each signer
+//! raises a blinded message to its share and proves, against its public
+//! verification key, that it did so correctly. A combiner checks the
+//! proofs, discards bad shares and assembles the signature from
+//! `threshold` good ones. The result is the ordinary RSA signature
+//! `blinded_message^d mod n`, so wallets finalize it with [`BlindUser`]
+//! as if a [`BlindSigner`] had produced it.
+//!
+//! [`BlindUser`]: crate::BlindUser
+//! [`BlindSigner`]: crate::BlindSigner
+
+use num_bigint::{BigInt, BigUint, Sign};
+use num_traits::{One, Signed, Zero};
+use rand::{thread_rng, RngCore};
+use rsa::traits::{PrivateKeyParts, PublicKeyParts};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::{EcashError, Result};
+
+/// Public exponent of dealt keys. Shoup's scheme needs a prime larger than
+/// the number of signers.
+const PUBLIC_EXPONENT: u64 = 65537;
+
+/// Prepended to the transcript hashed into share proofs.
+const PROOF_CONTEXT: &[u8] = b"ecash-threshold-share-proof/v1\0";
+
+/// Bits of the proof challenge, the SHA-256 output length.
+const CHALLENGE_BITS: usize = 256;
+
+const MILLER_RABIN_ROUNDS: usize = 32;
+
+/// Primes below this bound are sieved out before Miller-Rabin.
+const SIEVE_BOUND: u64 = 8192;
+
+/// What a combiner needs: the RSA public key, the threshold, and the
+/// verification keys that share proofs are checked against. Integers are
+/// unsigned big-endian.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThresholdPublicKey {
+    pub n: Vec<u8>,
+    pub e: Vec<u8>,
+    /// Number of shares needed to sign.
+    pub threshold: usize,
+    /// Number of shares dealt.
+    pub signers: usize,
+    /// A random square modulo `n`.
+    pub verification_base: Vec<u8>,
+    /// `verification_base` raised to each share, in share order.
+    pub verification_keys: Vec<Vec<u8>>,
+}
+
+/// One signer's share of the private exponent, with the public key so the
+/// signer can prove its partial signatures. Keep it as secret as a private
+/// key.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeyShare {
+    /// 1-based index of the share.
+    pub index: usize,
+    pub share: Vec<u8>,
+    pub public_key: ThresholdPublicKey,
+}
+
+/// A signer's contribution to a blind signature: the blinded message raised
+/// to `2 * signers! * share`, and a proof that the share used is the one
+/// behind the signer's verification key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartialSignature {
+    pub index: usize,
+    pub signature: Vec<u8>,
+    pub challenge: Vec<u8>,
+    pub response: Vec<u8>,
+}
+
+/// Generates a key of `bits` bits from safe primes and deals it into
+/// `signers` shares, any `threshold` of which sign. The full private key
+/// is not kept.
+///
+/// Finding safe primes takes far longer than ordinary RSA key generation;
+/// dealing a 3072-bit key takes tens of seconds, sometimes minutes.
+pub fn deal(
+    bits: usize,
+    threshold: usize,
+    signers: usize,
+) -> Result<(ThresholdPublicKey, Vec<KeyShare>)> {
+    check_parameters(threshold, signers)?;
+    if bits < 512 || !bits.is_multiple_of(2) {
+        return Err(EcashError::InvalidInput);
+    }
+
+    let p = safe_prime(bits / 2);
+    let mut q = safe_prime(bits / 2);
+    while q == p {
+        q = safe_prime(bits / 2);
+    }
+    deal_primes(&p, &q, threshold, signers)
+}
+
+/// Deals an existing key, which must be the product of two safe primes;
+/// keys from [`RsaPrivateKey::new`] are not.
+pub fn deal_key(
+    private_key: &RsaPrivateKey,
+    threshold: usize,
+    signers: usize,
+) -> Result<(ThresholdPublicKey, Vec<KeyShare>)> {
+    check_parameters(threshold, signers)?;
+    let [p, q] = private_key.primes() else {
+        return Err(EcashError::InvalidKey);
+    };
+    if private_key.e() != &rsa::BigUint::from(PUBLIC_EXPONENT) {
+        return Err(EcashError::InvalidKey);
+    }
+
+    let (p, q) = (to_biguint(p), to_biguint(q));
+    for prime in [&p, &q] {
+        if !is_probable_prime(&(prime >> 1u32)) {
+            return Err(EcashError::InvalidKey);
+        }
+    }
+    deal_primes(&p, &q, threshold, signers)
+}
+
+fn check_parameters(threshold: usize, signers: usize) -> Result<()> {
+    if threshold == 0 || threshold > signers || signers as u64 >= PUBLIC_EXPONENT {
+        return Err(EcashError::InvalidInput);
+    }
+    Ok(())
+}
+
+fn deal_primes(
+    p: &BigUint,
+    q: &BigUint,
+    threshold: usize,
+    signers: usize,
+) -> Result<(ThresholdPublicKey, Vec<KeyShare>)> {
+    let n = p * q;
+    // The squares modulo n form a cyclic group of order m = p'q'.
+    let m = (p >> 1u32) * (q >> 1u32);
+    let e = BigUint::from(PUBLIC_EXPONENT);
+    let d = mod_inverse(&e, &m).ok_or(EcashError::CryptoError)?;
+
+    // f(0) = d; share i is f(i) mod m.
+    let mut coefficients = vec![d];
+    for _ in 1..threshold {
+        coefficients.push(random_below(&m));
+    }
+    let shares: Vec<BigUint> = (1..=signers)
+        .map(|i| {
+            let x = BigUint::from(i);
+            coefficients
+                .iter()
+                .rev()
+                .fold(BigUint::zero(), |acc, coefficient| {
+                    (acc * &x + coefficient) % &m
+                })
+        })
+        .collect();
+
+    let root = random_unit(&n);
+    let verification_base = root.modpow(&BigUint::from(2u32), &n);
+    let public_key = ThresholdPublicKey {
+        n: n.to_bytes_be(),
+        e: e.to_bytes_be(),
+        threshold,
+        signers,
+        verification_base: verification_base.to_bytes_be(),
+        verification_keys: shares
+            .iter()
+            .map(|share| verification_base.modpow(share, &n).to_bytes_be())
+            .collect(),
+    };
+    let key_shares = shares
+        .iter()
+        .enumerate()
+        .map(|(i, share)| KeyShare {
+            index: i + 1,
+            share: share.to_bytes_be(),
+            public_key: public_key.clone(),
+        })
+        .collect();
+
+    Ok((public_key, key_shares))
+}
+
+impl ThresholdPublicKey {
+    pub fn public_key(&self) -> Result<RsaPublicKey> {
+        RsaPublicKey::new(
+            rsa::BigUint::from_bytes_be(&self.n),
+            rsa::BigUint::from_bytes_be(&self.e),
+        )
+        .map_err(|_| EcashError::InvalidKey)
+    }
+
+    /// Whether `partial` is a correct share of the signature over
+    /// `blinded_message` by the signer it names.
+    pub fn verify_partial(&self, blinded_message: &[u8], partial: &PartialSignature) -> bool {
+        let Some(verification_key) = partial
+            .index
+            .checked_sub(1)
+            .and_then(|i| self.verification_keys.get(i))
+        else {
+            return false;
+        };
+        let (Ok(n), Ok(x)) = (self.modulus(), self.message(blinded_message)) else {
+            return false;
+        };
+        let x_i = BigUint::from_bytes_be(&partial.signature);
+        let challenge = BigUint::from_bytes_be(&partial.challenge);
+        let response = BigUint::from_bytes_be(&partial.response);
+        if x_i.is_zero() || x_i >= n || challenge.bits() > CHALLENGE_BITS as u64 {
+            return false;
+        }
+
+        let v = BigUint::from_bytes_be(&self.verification_base);
+        let v_i = BigUint::from_bytes_be(verification_key);
+        let x_tilde = x.modpow(&(BigUint::from(4u32) * self.delta()), &n);
+        let x_i_squared = x_i.modpow(&BigUint::from(2u32), &n);
+
+        // v^z * v_i^-c and x~^z * x_i^-2c recover the prover's commitments.
+        let (Some(v_i_inverse), Some(x_i_inverse)) =
+            (mod_inverse(&v_i, &n), mod_inverse(&x_i_squared, &n))
+        else {
+            return false;
+        };
+        let v_commitment = v.modpow(&response, &n) * v_i_inverse.modpow(&challenge, &n) % &n;
+        let x_commitment = x_tilde.modpow(&response, &n) * x_i_inverse.modpow(&challenge, &n) % &n;
+
+        let expected = proof_challenge(
+            &n,
+            [
+                &v,
+                &x_tilde,
+                &v_i,
+                &x_i_squared,
+                &v_commitment,
+                &x_commitment,
+            ],
+        );
+        expected == challenge
+    }
+
+    /// Assembles the blind signature over `blinded_message` from the
+    /// partial signatures. Partials that fail their proof, and repeats of
+    /// a signer, are skipped; fails with
+    /// [`EcashError::InsufficientShares`] if fewer than `threshold` remain.
+    pub fn combine(
+        &self,
+        blinded_message: &[u8],
+        partials: &[PartialSignature],
+    ) -> Result<Vec<u8>> {
+        let n = self.modulus()?;
+        let e = BigUint::from_bytes_be(&self.e);
+        let x = self.message(blinded_message)?;
+
+        let mut valid: Vec<&PartialSignature> = Vec::with_capacity(self.threshold);
+        for partial in partials {
+            if valid.len() == self.threshold {
+                break;
+            }
+            if valid.iter().all(|chosen| chosen.index != partial.index)
+                && self.verify_partial(blinded_message, partial)
+            {
+                valid.push(partial);
+            }
+        }
+        if valid.len() < self.threshold {
+            return Err(EcashError::InsufficientShares);
+        }
+
+        // w = prod x_j^(2 * lambda_j), where lambda_j = delta times the
+        // Lagrange coefficient at 0 is an integer. Then w^e = x^(4 delta^2).
+        let delta = BigInt::from_biguint(Sign::Plus, self.delta());
+        let mut w = BigUint::one();
+        for partial in &valid {
+            let others = valid
+                .iter()
+                .map(|other| other.index)
+                .filter(|&index| index != partial.index);
+            let (numerator, denominator) = others.fold(
+                (delta.clone(), BigInt::one()),
+                |(numerator, denominator), index| {
+                    (
+                        numerator * BigInt::from(index),
+                        denominator * (BigInt::from(index) - BigInt::from(partial.index)),
+                    )
+                },
+            );
+            let lambda = numerator / denominator;
+
+            let x_j = BigUint::from_bytes_be(&partial.signature);
+            w = w * signed_pow(&x_j, &(lambda * 2), &n)? % &n;
+        }
+
+        // e and e' = 4 delta^2 are coprime: with a e' + b e = 1,
+        // y = w^a x^b satisfies y^e = x^(a e' + b e) = x.
+        let e_prime = BigInt::from(4) * &delta * &delta;
+        let (gcd, a, b) = extended_gcd(&e_prime, &BigInt::from_biguint(Sign::Plus, e.clone()));
+        if !gcd.is_one() {
+            return Err(EcashError::CryptoError);
+        }
+        let y = signed_pow(&w, &a, &n)? * signed_pow(&x, &b, &n)? % &n;
+
+        if y.modpow(&e, &n) != x {
+            return Err(EcashError::CryptoError);
+        }
+        int_to_bytes(&y, blinded_message.len())
+    }
+
+    fn modulus(&self) -> Result<BigUint> {
+        let n = BigUint::from_bytes_be(&self.n);
+        if n.bits() < 512 {
+            return Err(EcashError::InvalidKey);
+        }
+        Ok(n)
+    }
+
+    fn modulus_len(&self) -> usize {
+        BigUint::from_bytes_be(&self.n).bits().div_ceil(8) as usize
+    }
+
+    /// The blinded message as an integer, checked as in
+    /// [`crate::BlindSigner::sign_blinded`].
+    fn message(&self, blinded_message: &[u8]) -> Result<BigUint> {
+        if blinded_message.len() != self.modulus_len() {
+            return Err(EcashError::InvalidInput);
+        }
+        let x = BigUint::from_bytes_be(blinded_message);
+        if x.is_zero() || x >= BigUint::from_bytes_be(&self.n) {
+            return Err(EcashError::InvalidInput);
+        }
+        Ok(x)
+    }
+
+    /// delta = signers!, which clears the denominators of every Lagrange
+    /// coefficient.
+    fn delta(&self) -> BigUint {
+        (1..=self.signers).fold(BigUint::one(), |acc, i| acc * BigUint::from(i))
+    }
+}
+
+impl KeyShare {
+    /// This signer's partial signature over a blinded message, as sent in
+    /// [`crate::BlindedToken::blinded_message`].
+    pub fn sign_partial(&self, blinded_message: &[u8]) -> Result<PartialSignature> {
+        let public_key = &self.public_key;
+        let n = public_key.modulus()?;
+        let x = public_key.message(blinded_message)?;
+        let share = BigUint::from_bytes_be(&self.share);
+        let delta = public_key.delta();
+        let v = BigUint::from_bytes_be(&public_key.verification_base);
+        let v_i = self
+            .index
+            .checked_sub(1)
+            .and_then(|i| public_key.verification_keys.get(i))
+            .map(|key| BigUint::from_bytes_be(key))
+            .ok_or(EcashError::InvalidKey)?;
+
+        let x_i = x.modpow(&(BigUint::from(2u32) * &delta * &share), &n);
+
+        // Proves log_v(v_i) = log_x~(x_i^2) without revealing the share.
+        let x_tilde = x.modpow(&(BigUint::from(4u32) * &delta), &n);
+        let x_i_squared = x_i.modpow(&BigUint::from(2u32), &n);
+        let r = random_bits(n.bits() as usize + 2 * CHALLENGE_BITS);
+        let challenge = proof_challenge(
+            &n,
+            [
+                &v,
+                &x_tilde,
+                &v_i,
+                &x_i_squared,
+                &v.modpow(&r, &n),
+                &x_tilde.modpow(&r, &n),
+            ],
+        );
+        let response = &share * &challenge + r;
+
+        Ok(PartialSignature {
+            index: self.index,
+            signature: int_to_bytes(&x_i, blinded_message.len())?,
+            challenge: challenge.to_bytes_be(),
+            response: response.to_bytes_be(),
+        })
+    }
+}
+
+fn proof_challenge(n: &BigUint, values: [&BigUint; 6]) -> BigUint {
+    let len = n.bits().div_ceil(8) as usize;
+    let mut hasher = Sha256::new();
+    hasher.update(PROOF_CONTEXT);
+    hasher.update(n.to_bytes_be());
+    for value in values {
+        let bytes = value.to_bytes_be();
+        hasher.update(vec![0u8; len.saturating_sub(bytes.len())]);
+        hasher.update(bytes);
+    }
+    BigUint::from_bytes_be(&hasher.finalize())
+}
+
+/// `base^exponent mod n`, inverting `base` for a negative exponent.
+fn signed_pow(base: &BigUint, exponent: &BigInt, n: &BigUint) -> Result<BigUint> {
+    let magnitude = exponent.magnitude();
+    if exponent.is_negative() {
+        let inverse = mod_inverse(base, n).ok_or(EcashError::CryptoError)?;
+        Ok(inverse.modpow(magnitude, n))
+    } else {
+        Ok(base.modpow(magnitude, n))
+    }
+}
+
+fn mod_inverse(a: &BigUint, n: &BigUint) -> Option<BigUint> {
+    let n_int = BigInt::from_biguint(Sign::Plus, n.clone());
+    let (gcd, x, _) = extended_gcd(&BigInt::from_biguint(Sign::Plus, a % n), &n_int);
+    if !gcd.is_one() {
+        return None;
+    }
+    (((x % &n_int) + &n_int) % &n_int).to_biguint()
+}
+
+/// (g, x, y) with a x + b y = g = gcd(a, b).
+fn extended_gcd(a: &BigInt, b: &BigInt) -> (BigInt, BigInt, BigInt) {
+    let (mut old_r, mut r) = (a.clone(), b.clone());
+    let (mut old_x, mut x) = (BigInt::one(), BigInt::zero());
+    let (mut old_y, mut y) = (BigInt::zero(), BigInt::one());
+    while !r.is_zero() {
+        let quotient = &old_r / &r;
+        (old_r, r) = (r.clone(), old_r - &quotient * r);
+        (old_x, x) = (x.clone(), old_x - &quotient * x);
+        (old_y, y) = (y.clone(), old_y - &quotient * y);
+    }
+    (old_r, old_x, old_y)
+}
+
+/// A prime p of exactly `bits` bits with (p - 1) / 2 also prime.
+fn safe_prime(bits: usize) -> BigUint {
+    let small_primes = small_primes();
+    loop {
+        // The top two bits are set so the product of two such primes has
+        // exactly twice as many bits.
+        let mut candidate = random_bits(bits - 1);
+        candidate |= BigUint::from(3u32) << (bits - 3);
+        candidate |= BigUint::one();
+
+        let mut residues: Vec<u64> = small_primes
+            .iter()
+            .map(|&prime| (&candidate % prime).iter_u64_digits().next().unwrap_or(0))
+            .collect();
+        // Walk q = candidate + 2k, skipping any q or 2q + 1 with a small
+        // factor, until the walk would spill over the bit length.
+        for _ in 0..(1 << 16) {
+            let sieved = small_primes
+                .iter()
+                .zip(&residues)
+                .all(|(&prime, &residue)| residue != 0 && (2 * residue + 1) % prime != 0);
+            if sieved {
+                let p = (&candidate << 1u32) + 1u32;
+                if is_probable_prime(&candidate) && is_probable_prime(&p) {
+                    return p;
+                }
+            }
+            candidate += 2u32;
+            for (residue, &prime) in residues.iter_mut().zip(&small_primes) {
+                *residue = (*residue + 2) % prime;
+            }
+        }
+    }
+}
+
+fn small_primes() -> Vec<u64> {
+    let mut composite = vec![false; SIEVE_BOUND as usize];
+    let mut primes = Vec::new();
+    for i in 3..SIEVE_BOUND {
+        if !composite[i as usize] {
+            primes.push(i);
+            for multiple in (i * i..SIEVE_BOUND).step_by(i as usize) {
+                composite[multiple as usize] = true;
+            }
+        }
+    }
+    primes
+}
+
+fn is_probable_prime(n: &BigUint) -> bool {
+    let two = BigUint::from(2u32);
+    if n < &BigUint::from(4u32) {
+        return n >= &two;
+    }
+    if !n.bit(0) {
+        return false;
+    }
+
+    let n_minus_one = n - 1u32;
+    let s = n_minus_one.trailing_zeros().unwrap_or(0);
+    let d = &n_minus_one >> s;
+    let n_minus_three = n - 3u32;
+
+    (0..MILLER_RABIN_ROUNDS).all(|round| {
+        // Base 2 first: it rejects almost every composite cheaply.
+        let a = if round == 0 {
+            two.clone()
+        } else {
+            random_below(&n_minus_three) + 2u32
+        };
+        let mut x = a.modpow(&d, n);
+        if x.is_one() || x == n_minus_one {
+            return true;
+        }
+        for _ in 1..s {
+            x = x.modpow(&two, n);
+            if x == n_minus_one {
+                return true;
+            }
+        }
+        false
+    })
+}
+
+fn random_bits(bits: usize) -> BigUint {
+    let mut bytes = vec![0u8; bits.div_ceil(8)];
+    thread_rng().fill_bytes(&mut bytes);
+    let value = BigUint::from_bytes_be(&bytes);
+    value >> (bytes.len() * 8 - bits)
+}
+
+/// Uniform in [0, bound).
+fn random_below(bound: &BigUint) -> BigUint {
+    loop {
+        let value = random_bits(bound.bits() as usize);
+        if &value < bound {
+            return value;
+        }
+    }
+}
+
+/// Uniform among the integers in [1, n) coprime to n.
+fn random_unit(n: &BigUint) -> BigUint {
+    loop {
+        let value = random_below(n);
+        if !value.is_zero() && mod_inverse(&value, n).is_some() {
+            return value;
+        }
+    }
+}
+
+fn to_biguint(value: &rsa::BigUint) -> BigUint {
+    BigUint::from_bytes_be(&value.to_bytes_be())
+}
+
+/// I2OSP: big-endian encoding left-padded to exactly `len` bytes.
+fn int_to_bytes(value: &BigUint, len: usize) -> Result<Vec<u8>> {
+    let bytes = value.to_bytes_be();
+    if bytes.len() > len {
+        return Err(EcashError::InvalidInput);
+    }
+    let mut out = vec![0u8; len - bytes.len()];
+    out.extend_from_slice(&bytes);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{BlindUser, RsaBssaVariant};
+
+    fn blind(public_key: &ThresholdPublicKey) -> (BlindUser, Vec<u8>, Vec<u8>, Vec<u8>) {
+        let user = BlindUser::new(
+            public_key.public_key().unwrap(),
+            RsaBssaVariant::Sha384PssRandomized,
+        );
+        let msg_prefix = user.variant().generate_msg_prefix();
+        let prepared = user
+            .variant()
+            .prepare(&msg_prefix, b"threshold token")
+            .unwrap();
+        let (blinded, inverse) = user.blind_message(&prepared).unwrap();
+        (user, prepared, blinded, inverse)
+    }
+
+    #[test]
+    fn test_any_threshold_of_shares_signs() {
+        let (public_key, shares) = deal(1024, 3, 5).unwrap();
+        let (user, prepared, blinded, inverse) = blind(&public_key);
+
+        let partials: Vec<PartialSignature> = shares
+            .iter()
+            .map(|share| share.sign_partial(&blinded).unwrap())
+            .collect();
+        for partial in &partials {
+            assert!(public_key.verify_partial(&blinded, partial));
+        }
+
+        let mut signatures = Vec::new();
+        for subset in [[0, 1, 2], [0, 2, 4], [1, 3, 4], [4, 3, 0]] {
+            let chosen: Vec<PartialSignature> =
+                subset.iter().map(|&i| partials[i].clone()).collect();
+            let blind_signature = public_key.combine(&blinded, &chosen).unwrap();
+            let signature = user
+                .finalize(&prepared, &blind_signature, &inverse)
+                .unwrap();
+            assert!(user.verify_signature(&prepared, &signature));
+            signatures.push(blind_signature);
+        }
+        // RSA signatures are unique, whichever signers took part.
+        signatures.dedup();
+        assert_eq!(signatures.len(), 1);
+
+        assert!(matches!(
+            public_key.combine(&blinded, &partials[..2]),
+            Err(EcashError::InsufficientShares)
+        ));
+        let repeated = vec![
+            partials[0].clone(),
+            partials[0].clone(),
+            partials[1].clone(),
+        ];
+        assert!(matches!(
+            public_key.combine(&blinded, &repeated),
+            Err(EcashError::InsufficientShares)
+        ));
+    }
+
+    #[test]
+    fn test_combine_skips_bad_partials() {
+        let (public_key, shares) = deal(1024, 2, 3).unwrap();
+        let (_, _, blinded, _) = blind(&public_key);
+        let (_, _, other_blinded, _) = blind(&public_key);
+
+        let mut forged = shares[0].sign_partial(&blinded).unwrap();
+        forged.signature = shares[0].sign_partial(&other_blinded).unwrap().signature;
+        assert!(!public_key.verify_partial(&blinded, &forged));
+
+        // A signer claiming another's index fails that signer's proof.
+        let mut impostor = shares[1].sign_partial(&blinded).unwrap();
+        impostor.index = 3;
+        assert!(!public_key.verify_partial(&blinded, &impostor));
+
+        let honest = shares[2].sign_partial(&blinded).unwrap();
+        assert!(matches!(
+            public_key.combine(&blinded, &[forged.clone(), impostor, honest.clone()]),
+            Err(EcashError::InsufficientShares)
+        ));
+
+        let second = shares[1].sign_partial(&blinded).unwrap();
+        let blind_signature = public_key
+            .combine(&blinded, &[forged, honest, second])
+            .unwrap();
+        let public = public_key.public_key().unwrap();
+        assert_eq!(
+            BigUint::from_bytes_be(&blind_signature)
+                .modpow(&to_biguint(public.e()), &to_biguint(public.n())),
+            BigUint::from_bytes_be(&blinded)
+        );
+    }
+
+    #[test]
+    fn test_deal_rejects_bad_parameters() {
+        assert!(matches!(deal(1024, 0, 3), Err(EcashError::InvalidInput)));
+        assert!(matches!(deal(1024, 4, 3), Err(EcashError::InvalidInput)));
+
+        let private_key = RsaPrivateKey::new(&mut thread_rng(), 1024).unwrap();
+        assert!(matches!(
+            deal_key(&private_key, 2, 3),
+            Err(EcashError::InvalidKey)
+        ));
+    }
+}
@@ -1,6 +1,10 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::error::{EcashError, Result};
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Token {
     pub serial_number: Vec<u8>,
@@ -11,6 +15,8 @@ pub struct Token {
     pub expires_at: DateTime<Utc>,
     pub institution_id: String,
     pub key_id: String,
+    #[serde(default)]
+    pub msg_prefix: Vec<u8>,
 }
 
 impl Token {
@@ -32,9 +38,15 @@ impl Token {
             expires_at,
             institution_id,
             key_id,
+            msg_prefix: Vec::new(),
         }
     }
 
+    pub fn with_msg_prefix(mut self, msg_prefix: Vec<u8>) -> Self {
+        self.msg_prefix = msg_prefix;
+        self
+    }
+
     pub fn is_expired(&self) -> bool {
         Utc::now() > self.expires_at
     }
@@ -42,6 +54,11 @@ impl Token {
     pub fn serial_hex(&self) -> String {
         hex::encode(&self.serial_number)
     }
+
+    /// The token's value, which must be in `currency`.
+    pub fn amount(&self, currency: &Currency) -> Result<Amount> {
+        denomination_amount(self.denomination, &self.currency, currency)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -49,6 +66,25 @@ pub struct BlindedToken {
     pub blinded_message: Vec<u8>,
     pub denomination: u64,
     pub currency: String,
+    pub key_id: String,
+    /// Set by seeded wallets so the institution keeps the signature for a
+    /// later restore; see `recovery`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recovery_id: Option<Vec<u8>>,
+}
+
+impl BlindedToken {
+    /// The value the token will have once signed.
+    pub fn amount(&self, currency: &Currency) -> Result<Amount> {
+        denomination_amount(self.denomination, &self.currency, currency)
+    }
+}
+
+fn denomination_amount(denomination: u64, code: &str, currency: &Currency) -> Result<Amount> {
+    if code != currency.code {
+        return Err(EcashError::CurrencyMismatch);
+    }
+    Ok(currency.amount(denomination))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -57,10 +93,15 @@ pub struct BlindSignature {
     pub key_id: String,
 }
 
-#[derive(Debug, Clone)]
+/// The wallet's secrets for a blinded token, needed to unblind its
+/// signature.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TokenMetadata {
     pub serial_number: Vec<u8>,
     pub blinding_factor: Vec<u8>,
+    pub msg_prefix: Vec<u8>,
     pub denomination: u64,
     pub currency: String,
+    pub key_id: String,
+    pub expiry_epoch: u64,
 }
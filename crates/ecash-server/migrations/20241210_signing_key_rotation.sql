@@ -0,0 +1,8 @@
+-- Key rotation: 'active' keys sign and redeem, 'redeem_only' keys redeem until
+-- expires_at, 'revoked' keys are refused.
+ALTER TABLE signing_keys DROP CONSTRAINT IF EXISTS signing_keys_status_check;
+ALTER TABLE signing_keys
+    ADD CONSTRAINT signing_keys_status_check
+    CHECK (status IN ('active', 'redeem_only', 'revoked'));
+
+CREATE INDEX IF NOT EXISTS idx_signing_keys_institution_id ON signing_keys(institution_id);
@@ -0,0 +1,10 @@
+-- Each key set (key_id) holds one RSA key per (currency, denomination), so the
+-- denomination of a token is fixed by the key that signed it. Rows created
+-- before this migration have no denomination and are no longer loaded.
+ALTER TABLE signing_keys ADD COLUMN IF NOT EXISTS currency VARCHAR(10) NOT NULL DEFAULT 'USD';
+ALTER TABLE signing_keys ADD COLUMN IF NOT EXISTS denomination BIGINT;
+
+ALTER TABLE signing_keys DROP CONSTRAINT IF EXISTS signing_keys_key_id_key;
+ALTER TABLE signing_keys DROP CONSTRAINT IF EXISTS signing_keys_denomination_key;
+ALTER TABLE signing_keys
+    ADD CONSTRAINT signing_keys_denomination_key UNIQUE (key_id, currency, denomination);
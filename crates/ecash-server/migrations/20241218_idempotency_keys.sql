@@ -0,0 +1,15 @@
+-- Responses of withdraw and redeem requests sent with an Idempotency-Key.
+-- `response` is NULL while the original request is in flight and is written
+-- in the same transaction as the request's effects.
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    endpoint VARCHAR(50) NOT NULL,
+    idempotency_key VARCHAR(255) NOT NULL,
+    request_hash BYTEA NOT NULL,
+    response JSONB,
+    locked_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (endpoint, idempotency_key)
+);
+
+CREATE INDEX IF NOT EXISTS idx_idempotency_keys_expires_at ON idempotency_keys(expires_at);
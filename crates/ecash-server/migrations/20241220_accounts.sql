@@ -0,0 +1,28 @@
+-- Accounts whose balances withdrawals are debited from, and the API keys
+-- they authenticate with. Only SHA-256 hashes of API keys are stored.
+CREATE TABLE IF NOT EXISTS accounts (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    name VARCHAR(255) NOT NULL,
+    currency VARCHAR(10) NOT NULL,
+    balance BIGINT NOT NULL DEFAULT 0 CONSTRAINT accounts_balance_check CHECK (balance >= 0),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS api_keys (
+    key_hash BYTEA PRIMARY KEY,
+    account_id UUID NOT NULL REFERENCES accounts(id),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_api_keys_account_id ON api_keys(account_id);
+
+ALTER TABLE transactions ADD COLUMN IF NOT EXISTS account_id UUID REFERENCES accounts(id);
+CREATE INDEX IF NOT EXISTS idx_transactions_account_id ON transactions(account_id);
+
+-- Idempotency keys are chosen by clients, so scope them to the account that
+-- sent them.
+ALTER TABLE idempotency_keys ADD COLUMN IF NOT EXISTS scope VARCHAR(255) NOT NULL DEFAULT '';
+ALTER TABLE idempotency_keys DROP CONSTRAINT IF EXISTS idempotency_keys_pkey;
+ALTER TABLE idempotency_keys ADD PRIMARY KEY (endpoint, scope, idempotency_key);
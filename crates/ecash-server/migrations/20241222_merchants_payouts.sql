@@ -0,0 +1,28 @@
+-- Merchant accounts are credited by redemptions and can settle their balance
+-- through payouts.
+ALTER TABLE accounts ADD COLUMN IF NOT EXISTS kind VARCHAR(20) NOT NULL DEFAULT 'customer';
+ALTER TABLE accounts DROP CONSTRAINT IF EXISTS accounts_kind_check;
+ALTER TABLE accounts
+    ADD CONSTRAINT accounts_kind_check CHECK (kind IN ('customer', 'merchant'));
+
+-- A payout debits the balance when created as 'pending'; a 'failed' payout
+-- has been credited back.
+CREATE TABLE IF NOT EXISTS payouts (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    account_id UUID NOT NULL REFERENCES accounts(id),
+    amount BIGINT NOT NULL CONSTRAINT payouts_amount_check CHECK (amount > 0),
+    currency VARCHAR(10) NOT NULL,
+    destination VARCHAR(255) NOT NULL,
+    adapter VARCHAR(50) NOT NULL,
+    status VARCHAR(20) NOT NULL DEFAULT 'pending'
+        CONSTRAINT payouts_status_check CHECK (status IN ('pending', 'completed', 'failed')),
+    reference VARCHAR(255),
+    error_message TEXT,
+    idempotency_key VARCHAR(255),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT payouts_idempotency_key UNIQUE (account_id, idempotency_key)
+);
+
+CREATE INDEX IF NOT EXISTS idx_payouts_account_id ON payouts(account_id);
+CREATE INDEX IF NOT EXISTS idx_transactions_account_created ON transactions(account_id, created_at);
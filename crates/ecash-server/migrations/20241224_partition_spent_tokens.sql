@@ -0,0 +1,39 @@
+-- Spent serials are partitioned by the expiry epoch bound into each token's
+-- signature (days since the Unix epoch). Once every token of a partition has
+-- expired none of its serials can be redeemed again, and the server drops
+-- the partition. Partitions are created by the server as they are needed.
+ALTER TABLE tokens RENAME TO tokens_unpartitioned;
+DROP INDEX IF EXISTS idx_tokens_serial_hex;
+DROP INDEX IF EXISTS idx_tokens_status;
+DROP INDEX IF EXISTS idx_tokens_redeemed_at;
+
+CREATE TABLE tokens (
+    serial_number BYTEA NOT NULL,
+    serial_hex VARCHAR(64) NOT NULL,
+    expiry_epoch BIGINT NOT NULL,
+    key_id VARCHAR(255),
+    denomination BIGINT NOT NULL,
+    currency VARCHAR(10) NOT NULL,
+    status VARCHAR(20) NOT NULL DEFAULT 'redeemed',
+    redeemed_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    merchant_id VARCHAR(255),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (expiry_epoch, serial_hex)
+) PARTITION BY RANGE (expiry_epoch);
+
+-- Serials recorded before partitioning have no known expiry. They are kept
+-- under epoch 0, checked for every token, and deleted once they are older
+-- than any token can live.
+CREATE TABLE tokens_legacy PARTITION OF tokens FOR VALUES FROM (0) TO (1);
+
+INSERT INTO tokens
+    (serial_number, serial_hex, expiry_epoch, denomination, currency, status,
+     redeemed_at, merchant_id, created_at)
+SELECT serial_number, serial_hex, 0, denomination, currency, status,
+       redeemed_at, merchant_id, created_at
+FROM tokens_unpartitioned;
+
+DROP TABLE tokens_unpartitioned;
+
+CREATE INDEX idx_tokens_serial_hex ON tokens(serial_hex);
+CREATE INDEX idx_tokens_redeemed_at ON tokens(redeemed_at);
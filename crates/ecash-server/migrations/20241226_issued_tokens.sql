@@ -0,0 +1,16 @@
+-- Blind signatures on tokens from seeded wallets, kept under the recovery id
+-- the wallet derived for each token so that a wallet restored from its seed
+-- phrase can fetch and unblind them again. Tokens without a recovery id are
+-- not recorded.
+CREATE TABLE IF NOT EXISTS issued_tokens (
+    recovery_id BYTEA PRIMARY KEY,
+    blinded_message BYTEA NOT NULL,
+    blind_signature BYTEA NOT NULL,
+    denomination BIGINT NOT NULL,
+    currency VARCHAR(10) NOT NULL,
+    key_id VARCHAR(255) NOT NULL,
+    expiry_epoch BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_issued_tokens_expiry_epoch ON issued_tokens(expiry_epoch);
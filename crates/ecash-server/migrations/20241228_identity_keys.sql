@@ -0,0 +1,9 @@
+-- The long-term key each institution signs its key directory with. Wallets
+-- pin its public key, so unlike signing keys it is never rotated. The
+-- private key is an encrypted PKCS#8 document, as in signing_keys.
+CREATE TABLE IF NOT EXISTS identity_keys (
+    institution_id VARCHAR(255) PRIMARY KEY,
+    public_key_pem TEXT NOT NULL,
+    private_key_encrypted BYTEA NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -8,17 +8,59 @@
 -- Enable UUID extension
 CREATE EXTENSION IF NOT EXISTS "uuid-ossp";
 
--- Tokens table for tracking spent serials
+-- Spent serials, partitioned by the expiry epoch bound into each token
+-- (days since the Unix epoch). The server creates the partitions
+-- tokens_epochs_<start>_<end> as needed and drops them once expired.
 CREATE TABLE IF NOT EXISTS tokens (
-    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
-    serial_number BYTEA NOT NULL UNIQUE,
-    serial_hex VARCHAR(64) NOT NULL UNIQUE,
+    serial_number BYTEA NOT NULL,
+    serial_hex VARCHAR(64) NOT NULL,
+    expiry_epoch BIGINT NOT NULL,
+    key_id VARCHAR(255),
     denomination BIGINT NOT NULL,
     currency VARCHAR(10) NOT NULL,
     status VARCHAR(20) NOT NULL DEFAULT 'redeemed',
     redeemed_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
     merchant_id VARCHAR(255),
-    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (expiry_epoch, serial_hex)
+) PARTITION BY RANGE (expiry_epoch);
+
+-- Accounts funding withdrawals
+CREATE TABLE IF NOT EXISTS accounts (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    name VARCHAR(255) NOT NULL,
+    kind VARCHAR(20) NOT NULL DEFAULT 'customer'
+        CONSTRAINT accounts_kind_check CHECK (kind IN ('customer', 'merchant')),
+    currency VARCHAR(10) NOT NULL,
+    balance BIGINT NOT NULL DEFAULT 0 CONSTRAINT accounts_balance_check CHECK (balance >= 0),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- API keys (SHA-256 hashes only)
+CREATE TABLE IF NOT EXISTS api_keys (
+    key_hash BYTEA PRIMARY KEY,
+    account_id UUID NOT NULL REFERENCES accounts(id),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    revoked_at TIMESTAMPTZ
+);
+
+-- Merchant payouts
+CREATE TABLE IF NOT EXISTS payouts (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    account_id UUID NOT NULL REFERENCES accounts(id),
+    amount BIGINT NOT NULL CONSTRAINT payouts_amount_check CHECK (amount > 0),
+    currency VARCHAR(10) NOT NULL,
+    destination VARCHAR(255) NOT NULL,
+    adapter VARCHAR(50) NOT NULL,
+    status VARCHAR(20) NOT NULL DEFAULT 'pending'
+        CONSTRAINT payouts_status_check CHECK (status IN ('pending', 'completed', 'failed')),
+    reference VARCHAR(255),
+    error_message TEXT,
+    idempotency_key VARCHAR(255),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT payouts_idempotency_key UNIQUE (account_id, idempotency_key)
 );
 
 -- Transactions audit log
@@ -33,30 +75,74 @@ CREATE TABLE IF NOT EXISTS transactions (
     status VARCHAR(20) NOT NULL,
     error_message TEXT,
     request_data JSONB,
+    account_id UUID REFERENCES accounts(id),
     created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
 );
 
 -- Keys table for key rotation
 CREATE TABLE IF NOT EXISTS signing_keys (
     id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
-    key_id VARCHAR(255) NOT NULL UNIQUE,
+    key_id VARCHAR(255) NOT NULL,
     institution_id VARCHAR(255) NOT NULL,
+    currency VARCHAR(10) NOT NULL DEFAULT 'USD',
+    denomination BIGINT,
     public_key_pem TEXT NOT NULL,
     private_key_encrypted BYTEA,
-    status VARCHAR(20) NOT NULL DEFAULT 'active',
+    status VARCHAR(20) NOT NULL DEFAULT 'active'
+        CONSTRAINT signing_keys_status_check CHECK (status IN ('active', 'redeem_only', 'revoked')),
     created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
     expires_at TIMESTAMPTZ,
-    revoked_at TIMESTAMPTZ
+    revoked_at TIMESTAMPTZ,
+    CONSTRAINT signing_keys_denomination_key UNIQUE (key_id, currency, denomination)
+);
+
+-- Stored responses for requests sent with an Idempotency-Key
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    endpoint VARCHAR(50) NOT NULL,
+    scope VARCHAR(255) NOT NULL DEFAULT '',
+    idempotency_key VARCHAR(255) NOT NULL,
+    request_hash BYTEA NOT NULL,
+    response JSONB,
+    locked_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (endpoint, scope, idempotency_key)
+);
+
+-- Blind signatures on tokens from seeded wallets, by recovery id
+CREATE TABLE IF NOT EXISTS issued_tokens (
+    recovery_id BYTEA PRIMARY KEY,
+    blinded_message BYTEA NOT NULL,
+    blind_signature BYTEA NOT NULL,
+    denomination BIGINT NOT NULL,
+    currency VARCHAR(10) NOT NULL,
+    key_id VARCHAR(255) NOT NULL,
+    expiry_epoch BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Long-term key each institution signs its key directory with
+CREATE TABLE IF NOT EXISTS identity_keys (
+    institution_id VARCHAR(255) PRIMARY KEY,
+    public_key_pem TEXT NOT NULL,
+    private_key_encrypted BYTEA NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
 );
 
 -- Indexes
 CREATE INDEX idx_tokens_serial_hex ON tokens(serial_hex);
-CREATE INDEX idx_tokens_status ON tokens(status);
 CREATE INDEX idx_tokens_redeemed_at ON tokens(redeemed_at);
 CREATE INDEX idx_transactions_type ON transactions(transaction_type);
 CREATE INDEX idx_transactions_created_at ON transactions(created_at);
 CREATE INDEX idx_signing_keys_key_id ON signing_keys(key_id);
 CREATE INDEX idx_signing_keys_status ON signing_keys(status);
+CREATE INDEX idx_signing_keys_institution_id ON signing_keys(institution_id);
+CREATE INDEX idx_api_keys_account_id ON api_keys(account_id);
+CREATE INDEX idx_transactions_account_id ON transactions(account_id);
+CREATE INDEX idx_transactions_account_created ON transactions(account_id, created_at);
+CREATE INDEX idx_payouts_account_id ON payouts(account_id);
+CREATE INDEX idx_idempotency_keys_expires_at ON idempotency_keys(expires_at);
+CREATE INDEX idx_issued_tokens_expiry_epoch ON issued_tokens(expiry_epoch);
 
 -- Grant permissions
 GRANT ALL PRIVILEGES ON ALL TABLES IN SCHEMA public TO ecash_user;
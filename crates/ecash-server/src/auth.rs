@@ -0,0 +1,158 @@
+//! API-key authentication.
+//!
+//! Clients send their key as `Authorization: Bearer <key>` or `X-API-Key:
+//! <key>`. Keys are random strings handed out once at account creation; only
+//! their SHA-256 hash is stored.
+
+use crate::error::{ApiError, ApiResult};
+use crate::models::AccountKind;
+use crate::state::AppState;
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap};
+use axum::middleware::Next;
+use axum::response::Response;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+const API_KEY_HEADER: &str = "x-api-key";
+const API_KEY_PREFIX: &str = "ek_";
+
+/// The account a request was authenticated as, added to the request
+/// extensions by [`require_account`].
+#[derive(Debug, Clone)]
+pub struct AuthenticatedAccount {
+    pub account_id: Uuid,
+    pub kind: AccountKind,
+    /// The currency the account's balance is held in.
+    pub currency: String,
+}
+
+impl AuthenticatedAccount {
+    pub fn require_merchant(&self) -> ApiResult<()> {
+        match self.kind {
+            AccountKind::Merchant => Ok(()),
+            AccountKind::Customer => Err(ApiError::Forbidden(
+                "A merchant account is required".to_string(),
+            )),
+        }
+    }
+}
+
+pub async fn require_account(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> ApiResult<Response> {
+    let key = credential(request.headers()).ok_or(ApiError::Unauthorized)?;
+    let account = authenticate(&state, key).await?;
+
+    request.extensions_mut().insert(account);
+    Ok(next.run(request).await)
+}
+
+/// Like [`require_account`] for routes open to anonymous clients: requests
+/// without credentials pass unauthenticated, but invalid credentials are
+/// still refused.
+pub async fn optional_account(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> ApiResult<Response> {
+    if let Some(key) = credential(request.headers()) {
+        let account = authenticate(&state, key).await?;
+        request.extensions_mut().insert(account);
+    }
+    Ok(next.run(request).await)
+}
+
+async fn authenticate(state: &AppState, key: &str) -> ApiResult<AuthenticatedAccount> {
+    let (account_id, kind, currency) = state
+        .db
+        .find_account_by_api_key(&hash_api_key(key))
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+    let kind = AccountKind::parse(&kind)
+        .ok_or_else(|| ApiError::Internal(format!("Account {} has unknown kind", account_id)))?;
+
+    Ok(AuthenticatedAccount {
+        account_id,
+        kind,
+        currency,
+    })
+}
+
+pub async fn require_admin(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> ApiResult<Response> {
+    let admin_key = state
+        .config
+        .auth
+        .admin_api_key
+        .as_ref()
+        .ok_or(ApiError::Unauthorized)?;
+    let key = credential(request.headers()).ok_or(ApiError::Unauthorized)?;
+
+    // Comparing digests keeps the comparison time independent of where the
+    // keys first differ.
+    if hash_api_key(key) != hash_api_key(admin_key.expose()) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    Ok(next.run(request).await)
+}
+
+pub fn credential(headers: &HeaderMap) -> Option<&str> {
+    if let Some(value) = headers.get(header::AUTHORIZATION) {
+        return value.to_str().ok()?.strip_prefix("Bearer ");
+    }
+    headers.get(API_KEY_HEADER)?.to_str().ok()
+}
+
+pub fn generate_api_key() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("{}{}", API_KEY_PREFIX, hex::encode(bytes))
+}
+
+pub fn hash_api_key(key: &str) -> Vec<u8> {
+    Sha256::digest(key.as_bytes()).to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_credential_from_either_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(credential(&headers), None);
+
+        headers.insert(API_KEY_HEADER, HeaderValue::from_static("ek_abc"));
+        assert_eq!(credential(&headers), Some("ek_abc"));
+
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer ek_def"),
+        );
+        assert_eq!(credential(&headers), Some("ek_def"));
+
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Basic ek_def"),
+        );
+        assert_eq!(credential(&headers), None);
+    }
+
+    #[test]
+    fn test_generated_keys_are_unique() {
+        let a = generate_api_key();
+        let b = generate_api_key();
+        assert!(a.starts_with(API_KEY_PREFIX));
+        assert_ne!(a, b);
+        assert_ne!(hash_api_key(&a), hash_api_key(&b));
+    }
+}
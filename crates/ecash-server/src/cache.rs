@@ -1,41 +1,94 @@
 use crate::error::ApiResult;
-use redis::{aio::ConnectionManager, AsyncCommands};
+use crate::metrics::Metrics;
+use redis::{aio::ConnectionManager, AsyncCommands, Script};
+use std::sync::Arc;
+
+/// Sets every key in KEYS, each with the TTL in seconds at the same position
+/// in ARGV, or none of them if any already exists. Runs atomically on the
+/// server, so no other client sees a partially marked batch.
+const MARK_ALL_SPENT: &str = r#"
+for _, key in ipairs(KEYS) do
+    if redis.call('EXISTS', key) == 1 then
+        return 0
+    end
+end
+for i, key in ipairs(KEYS) do
+    redis.call('SET', key, '1', 'NX', 'EX', ARGV[i])
+end
+return 1
+"#;
+
+/// Increments KEYS[1], starting its TTL of ARGV[1] seconds on first use, and
+/// returns the new count.
+const INCREMENT_WINDOW: &str = r#"
+local count = redis.call('INCR', KEYS[1])
+if count == 1 then
+    redis.call('EXPIRE', KEYS[1], ARGV[1])
+end
+return count
+"#;
 
 pub struct RedisCache {
     client: ConnectionManager,
+    metrics: Arc<Metrics>,
 }
 
 impl RedisCache {
-    pub async fn new(redis_url: &str) -> ApiResult<Self> {
+    pub async fn new(redis_url: &str, metrics: Arc<Metrics>) -> ApiResult<Self> {
         let client = redis::Client::open(redis_url)?;
         let manager = ConnectionManager::new(client).await?;
 
-        Ok(Self { client: manager })
+        Ok(Self {
+            client: manager,
+            metrics,
+        })
     }
 
-    pub async fn check_and_mark_spent(
-        &self,
-        serial_hex: &str,
-        ttl_seconds: i64,
-    ) -> ApiResult<bool> {
-        let key = format!("spent:{}", serial_hex);
+    /// Marks all serials spent in one step, each kept for its own TTL.
+    /// Returns `false`, changing nothing, if any of them is already marked.
+    pub async fn mark_all_spent(&self, serials: &[(String, i64)]) -> ApiResult<bool> {
+        let _timer = self.metrics.redis_timer("mark_all_spent");
+        let script = Script::new(MARK_ALL_SPENT);
+        let mut invocation = script.prepare_invoke();
+        for (serial_hex, ttl_seconds) in serials {
+            invocation.key(format!("spent:{}", serial_hex));
+            invocation.arg(*ttl_seconds);
+        }
 
-        let was_set: bool = self.client.clone().set_nx(&key, "1").await?;
+        let marked: i64 = invocation.invoke_async(&mut self.client.clone()).await?;
+        Ok(marked == 1)
+    }
 
-        if was_set {
-            let _: () = self.client.clone().expire(&key, ttl_seconds).await?;
-            Ok(true)
-        } else {
-            Ok(false)
-        }
+    /// Undoes [`mark_all_spent`](Self::mark_all_spent) when the batch could
+    /// not be committed to the database.
+    pub async fn unmark_spent(&self, serials_hex: &[String]) -> ApiResult<()> {
+        let _timer = self.metrics.redis_timer("unmark_spent");
+        let keys: Vec<String> = serials_hex
+            .iter()
+            .map(|serial_hex| format!("spent:{}", serial_hex))
+            .collect();
+        let _: () = self.client.clone().del(keys).await?;
+        Ok(())
     }
 
     pub async fn is_token_spent(&self, serial_hex: &str) -> ApiResult<bool> {
+        let _timer = self.metrics.redis_timer("is_token_spent");
         let key = format!("spent:{}", serial_hex);
         let exists: bool = self.client.clone().exists(&key).await?;
         Ok(exists)
     }
 
+    /// Counts one more request in a rate-limit window.
+    pub async fn increment_window(&self, key: &str, ttl_seconds: i64) -> ApiResult<u64> {
+        let _timer = self.metrics.redis_timer("increment_window");
+        let count: u64 = Script::new(INCREMENT_WINDOW)
+            .key(key)
+            .arg(ttl_seconds)
+            .invoke_async(&mut self.client.clone())
+            .await?;
+        Ok(count)
+    }
+
     pub async fn health_check(&self) -> ApiResult<()> {
         use redis::cmd;
         let mut conn = self.client.clone();
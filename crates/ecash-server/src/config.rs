@@ -1,3 +1,5 @@
+use chrono::{DateTime, Utc};
+use ecash_core::Currency;
 use serde::Deserialize;
 use std::env;
 
@@ -6,7 +8,13 @@ pub struct Config {
     pub server: ServerConfig,
     pub database: DatabaseConfig,
     pub redis: RedisConfig,
+    pub spent: SpentStoreConfig,
     pub institution: InstitutionConfig,
+    pub keys: KeyConfig,
+    pub idempotency: IdempotencyConfig,
+    pub auth: AuthConfig,
+    pub payouts: PayoutConfig,
+    pub rate_limits: RateLimitConfig,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -23,7 +31,22 @@ pub struct DatabaseConfig {
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct RedisConfig {
-    pub url: String,
+    /// Needed by the `redis` and `layered` spent stores and the `redis` rate
+    /// limit backend; the server runs without Redis otherwise.
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpentStoreConfig {
+    /// `memory`, `redis`, `postgres` or `layered` (Redis in front of Postgres).
+    pub backend: String,
+    /// Expiry epochs (days) covered by each partition of the `tokens` table.
+    pub partition_days: u64,
+    /// How long after their tokens expire spent serials are still kept, to
+    /// absorb clock skew between replicas.
+    pub prune_margin_days: i64,
+    /// How often expired serials are pruned; `0` disables pruning.
+    pub prune_interval_seconds: u64,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -31,17 +54,107 @@ pub struct InstitutionConfig {
     pub institution_id: String,
     pub key_id: String,
     pub token_expiry_days: i64,
-    pub denominations: Vec<u64>,
+    /// Currencies issued, each with its denominations. The first is the
+    /// default for new accounts.
+    pub currencies: Vec<Currency>,
+    /// Value kept from the inputs of every exchange, in the minor unit of
+    /// the exchanged currency.
+    pub exchange_fee: u64,
+    /// Most blinded tokens signed for one withdrawal or exchange.
+    pub max_withdraw_tokens: usize,
+}
+
+impl InstitutionConfig {
+    pub fn default_currency(&self) -> &Currency {
+        &self.currencies[0]
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeyConfig {
+    /// Directory of PKCS#8 or PKCS#1 PEM files, one per denomination, named
+    /// `<key_id>_<currency>_<denomination>.pem`. When unset the keys are kept
+    /// encrypted in the `signing_keys` table.
+    pub private_key_dir: Option<String>,
+    /// Passphrase the key-encryption key is derived from.
+    pub encryption_secret: Option<Secret>,
+    /// Create missing denomination keys and the identity key. Never set
+    /// implicitly.
+    pub generate: bool,
+    pub bits: usize,
+    /// When the key set read from `private_key_dir` stops being redeemable.
+    /// Database key sets use their `expires_at` column instead.
+    pub expires_at: Option<DateTime<Utc>>,
+    /// How long a signed key directory is current before clients should
+    /// fetch a new one.
+    pub directory_ttl_seconds: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthConfig {
+    /// Bearer token for the `/api/v1/admin` routes, which are disabled
+    /// when unset.
+    pub admin_api_key: Option<Secret>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdempotencyConfig {
+    /// How long a stored response is replayed for retries with the same key.
+    pub ttl_seconds: i64,
+    /// After this long a claim whose request never committed may be retried.
+    pub lock_timeout_seconds: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PayoutConfig {
+    /// `file` appends payouts to `file_path`; `mock` only logs them.
+    pub adapter: String,
+    pub file_path: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitConfig {
+    /// `redis` shares counters between replicas, counting per process while
+    /// Redis is unreachable; `memory` always counts per process.
+    pub backend: String,
+    pub window_seconds: u64,
+    /// Requests per window for each route; `0` disables the limit.
+    pub withdraw: u32,
+    pub redeem: u32,
+    pub verify: u32,
+    pub exchange: u32,
+    pub restore: u32,
+    /// Count anonymous clients by the first `X-Forwarded-For` address. Only
+    /// safe behind a proxy that sets it.
+    pub trust_forwarded_for: bool,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(***)")
+    }
 }
 
 impl Config {
     pub fn from_env() -> anyhow::Result<Self> {
         dotenvy::dotenv().ok();
 
-        let denominations: Vec<u64> = env::var("DENOMINATIONS")?
-            .split(',')
-            .filter_map(|s| s.trim().parse().ok())
-            .collect();
+        let currencies = match env::var("CURRENCIES").ok().filter(|s| !s.trim().is_empty()) {
+            Some(spec) => parse_currencies(&spec)?,
+            None => vec![Currency::new(
+                &env::var("CURRENCY").unwrap_or_else(|_| "USD".to_string()),
+                parse_denominations(&env::var("DENOMINATIONS")?)?,
+            )?],
+        };
 
         Ok(Self {
             server: ServerConfig {
@@ -57,7 +170,19 @@ impl Config {
                     .parse()?,
             },
             redis: RedisConfig {
-                url: env::var("REDIS_URL")?,
+                url: env::var("REDIS_URL").ok().filter(|url| !url.is_empty()),
+            },
+            spent: SpentStoreConfig {
+                backend: env::var("SPENT_STORE").unwrap_or_else(|_| "layered".to_string()),
+                partition_days: env::var("SPENT_PARTITION_DAYS")
+                    .unwrap_or_else(|_| "7".to_string())
+                    .parse()?,
+                prune_margin_days: env::var("SPENT_PRUNE_MARGIN_DAYS")
+                    .unwrap_or_else(|_| "7".to_string())
+                    .parse()?,
+                prune_interval_seconds: env::var("SPENT_PRUNE_INTERVAL_SECONDS")
+                    .unwrap_or_else(|_| "3600".to_string())
+                    .parse()?,
             },
             institution: InstitutionConfig {
                 institution_id: env::var("INSTITUTION_ID")?,
@@ -65,8 +190,134 @@ impl Config {
                 token_expiry_days: env::var("TOKEN_EXPIRY_DAYS")
                     .unwrap_or_else(|_| "90".to_string())
                     .parse()?,
-                denominations,
+                currencies,
+                exchange_fee: env::var("EXCHANGE_FEE")
+                    .unwrap_or_else(|_| "0".to_string())
+                    .parse()?,
+                max_withdraw_tokens: env::var("MAX_WITHDRAW_TOKENS")
+                    .unwrap_or_else(|_| "100".to_string())
+                    .parse()?,
+            },
+            keys: KeyConfig {
+                private_key_dir: env::var("SIGNING_KEY_DIR").ok(),
+                encryption_secret: env::var("KEY_ENCRYPTION_SECRET").ok().map(Secret),
+                generate: env::var("GENERATE_SIGNING_KEY")
+                    .map(|v| v == "true" || v == "1")
+                    .unwrap_or(false),
+                bits: env::var("SIGNING_KEY_BITS")
+                    .unwrap_or_else(|_| "3072".to_string())
+                    .parse()?,
+                expires_at: env::var("SIGNING_KEY_EXPIRES_AT")
+                    .ok()
+                    .map(|at| DateTime::parse_from_rfc3339(&at).map(|at| at.with_timezone(&Utc)))
+                    .transpose()?,
+                directory_ttl_seconds: env::var("KEY_DIRECTORY_TTL_SECONDS")
+                    .unwrap_or_else(|_| "3600".to_string())
+                    .parse()?,
+            },
+            idempotency: IdempotencyConfig {
+                ttl_seconds: env::var("IDEMPOTENCY_TTL_SECONDS")
+                    .unwrap_or_else(|_| "86400".to_string())
+                    .parse()?,
+                lock_timeout_seconds: env::var("IDEMPOTENCY_LOCK_TIMEOUT_SECONDS")
+                    .unwrap_or_else(|_| "60".to_string())
+                    .parse()?,
+            },
+            auth: AuthConfig {
+                admin_api_key: env::var("ADMIN_API_KEY")
+                    .ok()
+                    .filter(|key| !key.is_empty())
+                    .map(Secret),
+            },
+            payouts: PayoutConfig {
+                adapter: env::var("PAYOUT_ADAPTER").unwrap_or_else(|_| "file".to_string()),
+                file_path: env::var("PAYOUT_FILE_PATH")
+                    .unwrap_or_else(|_| "payouts.jsonl".to_string()),
+            },
+            rate_limits: RateLimitConfig {
+                backend: env::var("RATE_LIMIT_BACKEND").unwrap_or_else(|_| "redis".to_string()),
+                window_seconds: env::var("RATE_LIMIT_WINDOW_SECONDS")
+                    .unwrap_or_else(|_| "60".to_string())
+                    .parse()?,
+                withdraw: env::var("RATE_LIMIT_WITHDRAW")
+                    .unwrap_or_else(|_| "10".to_string())
+                    .parse()?,
+                redeem: env::var("RATE_LIMIT_REDEEM")
+                    .unwrap_or_else(|_| "100".to_string())
+                    .parse()?,
+                verify: env::var("RATE_LIMIT_VERIFY")
+                    .unwrap_or_else(|_| "1000".to_string())
+                    .parse()?,
+                exchange: env::var("RATE_LIMIT_EXCHANGE")
+                    .unwrap_or_else(|_| "100".to_string())
+                    .parse()?,
+                restore: env::var("RATE_LIMIT_RESTORE")
+                    .unwrap_or_else(|_| "60".to_string())
+                    .parse()?,
+                trust_forwarded_for: env::var("RATE_LIMIT_TRUST_FORWARDED_FOR")
+                    .map(|v| v == "true" || v == "1")
+                    .unwrap_or(false),
             },
         })
     }
 }
+
+/// Parses `CURRENCIES`: `;`-separated `CODE=denominations` entries, such as
+/// `USD=10,50,100;JPY=100,500`. A code outside the built-in ISO 4217 table
+/// needs its exponent, as in `XTS:2=10,50`.
+fn parse_currencies(spec: &str) -> anyhow::Result<Vec<Currency>> {
+    let mut currencies: Vec<Currency> = Vec::new();
+
+    for entry in spec.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let (code, denominations) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("CURRENCIES entry {:?} has no '='", entry))?;
+        let denominations = parse_denominations(denominations)?;
+        let currency = match code.trim().split_once(':') {
+            Some((code, exponent)) => {
+                Currency::with_exponent(code, exponent.parse()?, denominations)
+            }
+            None => Currency::new(code.trim(), denominations),
+        }
+        .map_err(|e| anyhow::anyhow!("CURRENCIES entry {:?}: {}", entry, e))?;
+
+        if currencies.iter().any(|known| known.code == currency.code) {
+            anyhow::bail!("CURRENCIES lists {} twice", currency.code);
+        }
+        currencies.push(currency);
+    }
+
+    if currencies.is_empty() {
+        anyhow::bail!("CURRENCIES lists no currency");
+    }
+    Ok(currencies)
+}
+
+fn parse_denominations(list: &str) -> anyhow::Result<Vec<u64>> {
+    list.split(',')
+        .map(|s| s.trim().parse::<u64>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| anyhow::anyhow!("Invalid denomination in {:?}: {}", list, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_currencies() {
+        let currencies = parse_currencies("USD=10,50,100; JPY=500,100;XTS:3=1").unwrap();
+
+        assert_eq!(currencies.len(), 3);
+        assert_eq!(currencies[0].code, "USD");
+        assert_eq!(currencies[0].exponent, 2);
+        assert_eq!(currencies[1].denominations, vec![100, 500]);
+        assert_eq!(currencies[1].exponent, 0);
+        assert_eq!(currencies[2].exponent, 3);
+
+        assert!(parse_currencies("USD=10;USD=50").is_err());
+        assert!(parse_currencies("XTS=10").is_err());
+        assert!(parse_currencies("USD=10,ten").is_err());
+        assert!(parse_currencies("").is_err());
+    }
+}
@@ -1,86 +1,825 @@
-use crate::error::ApiResult;
-use crate::models::{TokenRecord, TransactionRecord};
-use sqlx::PgPool;
+use crate::error::{ApiError, ApiResult};
+use crate::metrics::Metrics;
+use crate::models::{
+    AccountKind, AccountRecord, IdempotencyRecord, IssuedTokenRecord, PayoutRecord,
+    SigningKeyRecord, TransactionRecord,
+};
+use chrono::{DateTime, Utc};
+use ecash_core::{Amount, IssuedToken};
+use sqlx::{PgConnection, PgExecutor, PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use uuid::Uuid;
+
+pub struct SpentToken<'a> {
+    pub serial_number: &'a [u8],
+    pub serial_hex: String,
+    pub expiry_epoch: u64,
+    pub key_id: &'a str,
+    pub denomination: u64,
+    pub currency: &'a str,
+}
+
+/// Partition of the `tokens` table holding the serials of expiry epochs
+/// `start..end`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenPartition {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl TokenPartition {
+    pub fn name(&self) -> String {
+        format!("tokens_epochs_{}_{}", self.start, self.end)
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        let (start, end) = name.strip_prefix("tokens_epochs_")?.split_once('_')?;
+        Some(Self {
+            start: start.parse().ok()?,
+            end: end.parse().ok()?,
+        })
+    }
+}
 
 pub struct TransactionLog<'a> {
     pub transaction_type: &'a str,
-    pub amount: u64,
+    pub amount: &'a Amount,
     pub denomination: u64,
     pub token_count: usize,
     pub institution_id: &'a str,
     pub key_id: &'a str,
     pub status: &'a str,
     pub error_message: Option<String>,
+    pub account_id: Option<Uuid>,
+}
+
+pub struct NewPayout<'a> {
+    pub account_id: Uuid,
+    pub amount: &'a Amount,
+    pub destination: &'a str,
+    pub adapter: &'a str,
+    pub idempotency_key: Option<&'a str>,
 }
 
 pub struct Database {
     pub pool: PgPool,
+    metrics: Arc<Metrics>,
 }
 
 impl Database {
-    pub fn new(pool: PgPool) -> Self {
-        Self { pool }
+    pub fn new(pool: PgPool, metrics: Arc<Metrics>) -> Self {
+        Self { pool, metrics }
     }
 
-    pub async fn check_token_spent(&self, serial_hex: &str) -> ApiResult<bool> {
+    /// Serials recorded before the table was partitioned have expiry epoch
+    /// `0` and are checked for every token.
+    pub async fn check_token_spent(&self, serial_hex: &str, expiry_epoch: u64) -> ApiResult<bool> {
+        let _timer = self.metrics.postgres_timer("check_token_spent");
         let result = sqlx::query_scalar::<_, bool>(
-            "SELECT EXISTS(SELECT 1 FROM tokens WHERE serial_hex = $1)",
+            "SELECT EXISTS(SELECT 1 FROM tokens WHERE serial_hex = $1 AND expiry_epoch IN ($2, 0))",
         )
         .bind(serial_hex)
+        .bind(bigint(expiry_epoch)?)
         .fetch_one(&self.pool)
         .await?;
 
         Ok(result)
     }
 
-    pub async fn mark_token_spent(
+    /// Records a batch of spent tokens in one transaction. Returns `false`,
+    /// recording nothing, if any serial is already recorded. The partitions
+    /// for the tokens' expiry epochs must exist.
+    pub async fn mark_tokens_spent(&self, tokens: &[SpentToken<'_>]) -> ApiResult<bool> {
+        let _timer = self.metrics.postgres_timer("mark_tokens_spent");
+        let mut tx = self.pool.begin().await?;
+
+        if !insert_spent_tokens(&mut tx, tokens).await? {
+            return Ok(false);
+        }
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    pub async fn unmark_tokens_spent(&self, tokens: &[SpentToken<'_>]) -> ApiResult<()> {
+        let _timer = self.metrics.postgres_timer("unmark_tokens_spent");
+        let epochs = tokens
+            .iter()
+            .map(|t| bigint(t.expiry_epoch))
+            .collect::<ApiResult<Vec<_>>>()?;
+        let serials_hex: Vec<&str> = tokens.iter().map(|t| t.serial_hex.as_str()).collect();
+        sqlx::query(
+            r#"
+            DELETE FROM tokens
+            WHERE (expiry_epoch, serial_hex) IN (SELECT * FROM UNNEST($1::BIGINT[], $2::TEXT[]))
+            "#,
+        )
+        .bind(&epochs)
+        .bind(&serials_hex)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Creates the partition unless it exists. Safe to race with other
+    /// replicas creating the same partition.
+    pub async fn create_token_partition(&self, partition: &TokenPartition) -> ApiResult<()> {
+        let _timer = self.metrics.postgres_timer("create_token_partition");
+        let created = sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {} PARTITION OF tokens FOR VALUES FROM ({}) TO ({})",
+            partition.name(),
+            partition.start,
+            partition.end
+        ))
+        .execute(&self.pool)
+        .await;
+
+        match created {
+            Ok(_) => Ok(()),
+            Err(error) => {
+                // Concurrent creation fails on the catalog's unique indexes
+                // instead of being skipped by IF NOT EXISTS.
+                let exists = sqlx::query_scalar::<_, bool>("SELECT to_regclass($1) IS NOT NULL")
+                    .bind(partition.name())
+                    .fetch_one(&self.pool)
+                    .await?;
+                if exists {
+                    Ok(())
+                } else {
+                    Err(error.into())
+                }
+            }
+        }
+    }
+
+    /// Lists the epoch partitions of the `tokens` table.
+    pub async fn list_token_partitions(&self) -> ApiResult<Vec<TokenPartition>> {
+        let _timer = self.metrics.postgres_timer("list_token_partitions");
+        let names = sqlx::query_scalar::<_, String>(
+            r#"
+            SELECT child.relname::TEXT
+            FROM pg_inherits
+            JOIN pg_class parent ON parent.oid = pg_inherits.inhparent
+            JOIN pg_class child ON child.oid = pg_inherits.inhrelid
+            WHERE parent.relname = 'tokens'
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(names
+            .iter()
+            .filter_map(|name| TokenPartition::parse(name))
+            .collect())
+    }
+
+    pub async fn drop_token_partition(&self, partition: &TokenPartition) -> ApiResult<()> {
+        let _timer = self.metrics.postgres_timer("drop_token_partition");
+        sqlx::query(&format!("DROP TABLE IF EXISTS {}", partition.name()))
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Deletes serials recorded before partitioning that were redeemed
+    /// before `before`. Returns how many were deleted.
+    pub async fn delete_legacy_tokens(&self, before: DateTime<Utc>) -> ApiResult<u64> {
+        let _timer = self.metrics.postgres_timer("delete_legacy_tokens");
+        let result = sqlx::query("DELETE FROM tokens WHERE expiry_epoch = 0 AND redeemed_at < $1")
+            .bind(before)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn begin(&self) -> ApiResult<Transaction<'static, Postgres>> {
+        Ok(self.pool.begin().await?)
+    }
+
+    pub async fn get_signing_key(
         &self,
-        serial_number: Vec<u8>,
-        serial_hex: String,
+        key_id: &str,
+        currency: &str,
         denomination: u64,
+    ) -> ApiResult<Option<SigningKeyRecord>> {
+        let record = sqlx::query_as::<_, SigningKeyRecord>(
+            r#"
+            SELECT id, key_id, institution_id, currency, denomination, public_key_pem,
+                   private_key_encrypted,
+                   status, created_at, expires_at, revoked_at
+            FROM signing_keys
+            WHERE key_id = $1 AND currency = $2 AND denomination = $3
+            "#,
+        )
+        .bind(key_id)
+        .bind(currency)
+        .bind(bigint(denomination)?)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(record)
+    }
+
+    pub async fn list_signing_keys(
+        &self,
+        institution_id: &str,
+    ) -> ApiResult<Vec<SigningKeyRecord>> {
+        let records = sqlx::query_as::<_, SigningKeyRecord>(
+            r#"
+            SELECT id, key_id, institution_id, currency, denomination, public_key_pem,
+                   private_key_encrypted,
+                   status, created_at, expires_at, revoked_at
+            FROM signing_keys
+            WHERE institution_id = $1
+            ORDER BY created_at, key_id, currency, denomination
+            "#,
+        )
+        .bind(institution_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(records)
+    }
+
+    /// Inserts the key for one denomination of a key set unless it exists.
+    /// Returns whether the row was written.
+    pub async fn insert_signing_key(
+        &self,
+        key_id: &str,
+        institution_id: &str,
         currency: &str,
-        merchant_id: Option<String>,
-    ) -> ApiResult<TokenRecord> {
-        let record = sqlx::query_as::<_, TokenRecord>(
+        denomination: u64,
+        public_key_pem: &str,
+        private_key_encrypted: &[u8],
+    ) -> ApiResult<bool> {
+        let result = sqlx::query(
             r#"
-            INSERT INTO tokens (serial_number, serial_hex, denomination, currency, merchant_id)
-            VALUES ($1, $2, $3, $4, $5)
-            RETURNING id, serial_number, serial_hex, denomination, currency, status, 
-                      redeemed_at, merchant_id, created_at
+            INSERT INTO signing_keys
+                (key_id, institution_id, currency, denomination, public_key_pem, private_key_encrypted)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (key_id, currency, denomination) DO NOTHING
             "#,
         )
-        .bind(serial_number)
-        .bind(serial_hex)
-        .bind(denomination as i64)
+        .bind(key_id)
+        .bind(institution_id)
         .bind(currency)
-        .bind(merchant_id)
-        .fetch_one(&self.pool)
+        .bind(bigint(denomination)?)
+        .bind(public_key_pem)
+        .bind(private_key_encrypted)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// The encrypted identity key of an institution, if it has one.
+    pub async fn get_identity_key(&self, institution_id: &str) -> ApiResult<Option<Vec<u8>>> {
+        let encrypted = sqlx::query_scalar::<_, Vec<u8>>(
+            r#"
+            SELECT private_key_encrypted FROM identity_keys WHERE institution_id = $1
+            "#,
+        )
+        .bind(institution_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(encrypted)
+    }
+
+    /// Stores an institution's identity key unless it has one. Returns
+    /// whether the row was written.
+    pub async fn insert_identity_key(
+        &self,
+        institution_id: &str,
+        public_key_pem: &str,
+        private_key_encrypted: &[u8],
+    ) -> ApiResult<bool> {
+        let result = sqlx::query(
+            r#"
+            INSERT INTO identity_keys (institution_id, public_key_pem, private_key_encrypted)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (institution_id) DO NOTHING
+            "#,
+        )
+        .bind(institution_id)
+        .bind(public_key_pem)
+        .bind(private_key_encrypted)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Creates an account together with its first API key.
+    pub async fn create_account(
+        &self,
+        name: &str,
+        kind: AccountKind,
+        currency: &str,
+        api_key_hash: &[u8],
+    ) -> ApiResult<AccountRecord> {
+        let mut tx = self.pool.begin().await?;
+
+        let account = sqlx::query_as::<_, AccountRecord>(
+            r#"
+            INSERT INTO accounts (name, kind, currency)
+            VALUES ($1, $2, $3)
+            RETURNING id, name, kind, currency, balance, created_at, updated_at
+            "#,
+        )
+        .bind(name)
+        .bind(kind.as_str())
+        .bind(currency)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query("INSERT INTO api_keys (key_hash, account_id) VALUES ($1, $2)")
+            .bind(api_key_hash)
+            .bind(account.id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(account)
+    }
+
+    pub async fn get_account(&self, account_id: Uuid) -> ApiResult<Option<AccountRecord>> {
+        let _timer = self.metrics.postgres_timer("get_account");
+        let record = sqlx::query_as::<_, AccountRecord>(
+            r#"
+            SELECT id, name, kind, currency, balance, created_at, updated_at
+            FROM accounts
+            WHERE id = $1
+            "#,
+        )
+        .bind(account_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(record)
+    }
+
+    /// The account an unrevoked API key belongs to, with its kind.
+    pub async fn find_account_by_api_key(
+        &self,
+        key_hash: &[u8],
+    ) -> ApiResult<Option<(Uuid, String, String)>> {
+        let _timer = self.metrics.postgres_timer("find_account_by_api_key");
+        let account = sqlx::query_as::<_, (Uuid, String, String)>(
+            r#"
+            SELECT accounts.id, accounts.kind, accounts.currency
+            FROM api_keys
+            JOIN accounts ON accounts.id = api_keys.account_id
+            WHERE api_keys.key_hash = $1 AND api_keys.revoked_at IS NULL
+            "#,
+        )
+        .bind(key_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(account)
+    }
+
+    /// Redemptions credited to the account in `[from, to)`, oldest first.
+    pub async fn list_redemptions(
+        &self,
+        account_id: Uuid,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> ApiResult<Vec<TransactionRecord>> {
+        let _timer = self.metrics.postgres_timer("list_redemptions");
+        let records = sqlx::query_as::<_, TransactionRecord>(
+            r#"
+            SELECT id, transaction_type, amount, denomination, token_count,
+                   institution_id, key_id, status, error_message, request_data, account_id,
+                   created_at
+            FROM transactions
+            WHERE account_id = $1
+              AND transaction_type = 'redeem'
+              AND created_at >= $2 AND created_at < $3
+            ORDER BY created_at, id
+            "#,
+        )
+        .bind(account_id)
+        .bind(from)
+        .bind(to)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(records)
+    }
+
+    /// Issuance records stored under any of `recovery_ids`.
+    pub async fn find_issued_tokens(
+        &self,
+        recovery_ids: &[Vec<u8>],
+    ) -> ApiResult<Vec<IssuedTokenRecord>> {
+        let _timer = self.metrics.postgres_timer("find_issued_tokens");
+        let records = sqlx::query_as::<_, IssuedTokenRecord>(
+            r#"
+            SELECT recovery_id, blinded_message, blind_signature, denomination, currency,
+                   key_id, expiry_epoch
+            FROM issued_tokens
+            WHERE recovery_id = ANY($1)
+            "#,
+        )
+        .bind(recovery_ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(records)
+    }
+
+    pub async fn find_payout(
+        &self,
+        account_id: Uuid,
+        idempotency_key: &str,
+    ) -> ApiResult<Option<PayoutRecord>> {
+        let _timer = self.metrics.postgres_timer("find_payout");
+        let record = sqlx::query_as::<_, PayoutRecord>(
+            r#"
+            SELECT id, account_id, amount, currency, destination, adapter, status, reference,
+                   error_message, created_at, updated_at
+            FROM payouts
+            WHERE account_id = $1 AND idempotency_key = $2
+            "#,
+        )
+        .bind(account_id)
+        .bind(idempotency_key)
+        .fetch_optional(&self.pool)
         .await?;
 
         Ok(record)
     }
 
-    pub async fn log_transaction(&self, log: TransactionLog<'_>) -> ApiResult<TransactionRecord> {
-        let record = sqlx::query_as::<_, TransactionRecord>(
-            r#"
-            INSERT INTO transactions 
-                (transaction_type, amount, denomination, token_count, institution_id, key_id, status, error_message)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
-            RETURNING id, transaction_type, amount, denomination, token_count, 
-                      institution_id, key_id, status, error_message, request_data, created_at
-            "#
-        )
-        .bind(log.transaction_type)
-        .bind(log.amount as i64)
-        .bind(log.denomination as i64)
-        .bind(log.token_count as i32)
-        .bind(log.institution_id)
-        .bind(log.key_id)
-        .bind(log.status)
-        .bind(log.error_message)
+    pub async fn complete_payout(
+        &self,
+        payout_id: Uuid,
+        reference: &str,
+    ) -> ApiResult<PayoutRecord> {
+        let _timer = self.metrics.postgres_timer("complete_payout");
+        let record = sqlx::query_as::<_, PayoutRecord>(
+            r#"
+            UPDATE payouts
+            SET status = 'completed', reference = $2, updated_at = NOW()
+            WHERE id = $1 AND status = 'pending'
+            RETURNING id, account_id, amount, currency, destination, adapter, status, reference,
+                      error_message, created_at, updated_at
+            "#,
+        )
+        .bind(payout_id)
+        .bind(reference)
         .fetch_one(&self.pool)
         .await?;
 
         Ok(record)
     }
+
+    /// Claims `idempotency_key` for a new request, or returns the existing
+    /// claim. Expired keys and claims whose request never committed within
+    /// `lock_timeout_seconds` are taken over.
+    pub async fn claim_idempotency_key(
+        &self,
+        endpoint: &str,
+        scope: &str,
+        idempotency_key: &str,
+        request_hash: &[u8],
+        ttl_seconds: i64,
+        lock_timeout_seconds: i64,
+    ) -> ApiResult<IdempotencyClaim> {
+        let _timer = self.metrics.postgres_timer("claim_idempotency_key");
+        let claimed = sqlx::query(
+            r#"
+            INSERT INTO idempotency_keys (endpoint, scope, idempotency_key, request_hash, expires_at)
+            VALUES ($1, $2, $3, $4, NOW() + make_interval(secs => $5))
+            ON CONFLICT (endpoint, scope, idempotency_key) DO UPDATE
+                SET request_hash = EXCLUDED.request_hash,
+                    response = NULL,
+                    locked_at = NOW(),
+                    expires_at = EXCLUDED.expires_at
+                WHERE idempotency_keys.expires_at < NOW()
+                   OR (idempotency_keys.response IS NULL
+                       AND idempotency_keys.locked_at < NOW() - make_interval(secs => $6))
+            "#,
+        )
+        .bind(endpoint)
+        .bind(scope)
+        .bind(idempotency_key)
+        .bind(request_hash)
+        .bind(ttl_seconds as f64)
+        .bind(lock_timeout_seconds as f64)
+        .execute(&self.pool)
+        .await?;
+
+        if claimed.rows_affected() == 1 {
+            return Ok(IdempotencyClaim::Claimed);
+        }
+
+        let record = sqlx::query_as::<_, IdempotencyRecord>(
+            r#"
+            SELECT request_hash, response
+            FROM idempotency_keys
+            WHERE endpoint = $1 AND scope = $2 AND idempotency_key = $3
+            "#,
+        )
+        .bind(endpoint)
+        .bind(scope)
+        .bind(idempotency_key)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(IdempotencyClaim::Existing(record))
+    }
+
+    /// Drops a claim whose request failed, so it can be retried.
+    pub async fn release_idempotency_key(
+        &self,
+        endpoint: &str,
+        scope: &str,
+        idempotency_key: &str,
+    ) -> ApiResult<()> {
+        let _timer = self.metrics.postgres_timer("release_idempotency_key");
+        sqlx::query(
+            r#"
+            DELETE FROM idempotency_keys
+            WHERE endpoint = $1 AND scope = $2 AND idempotency_key = $3 AND response IS NULL
+            "#,
+        )
+        .bind(endpoint)
+        .bind(scope)
+        .bind(idempotency_key)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+pub enum IdempotencyClaim {
+    Claimed,
+    Existing(IdempotencyRecord),
+}
+
+/// Takes `amount` from the account's balance, failing with
+/// `InsufficientFunds` and changing nothing if the balance is too low. The
+/// row stays locked until the caller's transaction ends.
+pub async fn debit_account(
+    conn: &mut PgConnection,
+    account_id: Uuid,
+    amount: &Amount,
+) -> ApiResult<Amount> {
+    let balance = lock_balance(conn, account_id, amount).await?;
+    if balance < *amount {
+        return Err(ApiError::InsufficientFunds {
+            required: amount.clone(),
+            available: balance,
+        });
+    }
+
+    update_balance(conn, account_id, &balance.checked_sub(amount)?).await
+}
+
+/// Adds `amount` to the account's balance. The row stays locked until the
+/// caller's transaction ends.
+pub async fn credit_account(
+    conn: &mut PgConnection,
+    account_id: Uuid,
+    amount: &Amount,
+) -> ApiResult<Amount> {
+    let balance = lock_balance(conn, account_id, amount).await?;
+    update_balance(conn, account_id, &balance.checked_add(amount)?).await
+}
+
+/// Locks the account row and reads its balance, which must be held in the
+/// currency of `amount`.
+async fn lock_balance(
+    conn: &mut PgConnection,
+    account_id: Uuid,
+    amount: &Amount,
+) -> ApiResult<Amount> {
+    let (balance, currency) = sqlx::query_as::<_, (i64, String)>(
+        "SELECT balance, currency FROM accounts WHERE id = $1 FOR UPDATE",
+    )
+    .bind(account_id)
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(ApiError::AccountNotFound)?;
+
+    if currency != amount.currency() {
+        return Err(ApiError::CurrencyMismatch {
+            expected: currency,
+            found: amount.currency().to_string(),
+        });
+    }
+    let balance = u64::try_from(balance).map_err(|_| {
+        ApiError::Internal(format!("Account {} has a negative balance", account_id))
+    })?;
+
+    Ok(amount.with_minor_units(balance))
+}
+
+async fn update_balance(
+    conn: &mut PgConnection,
+    account_id: Uuid,
+    balance: &Amount,
+) -> ApiResult<Amount> {
+    sqlx::query(
+        r#"
+        UPDATE accounts
+        SET balance = $2, updated_at = NOW()
+        WHERE id = $1
+        "#,
+    )
+    .bind(account_id)
+    .bind(balance.to_i64()?)
+    .execute(conn)
+    .await?;
+
+    Ok(balance.clone())
+}
+
+/// Records a pending payout. Returns `None` if the account already has a
+/// payout with the same idempotency key.
+pub async fn insert_payout(
+    conn: &mut PgConnection,
+    payout: NewPayout<'_>,
+) -> ApiResult<Option<PayoutRecord>> {
+    let record = sqlx::query_as::<_, PayoutRecord>(
+        r#"
+        INSERT INTO payouts
+            (account_id, amount, currency, destination, adapter, idempotency_key)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (account_id, idempotency_key) DO NOTHING
+        RETURNING id, account_id, amount, currency, destination, adapter, status, reference,
+                  error_message, created_at, updated_at
+        "#,
+    )
+    .bind(payout.account_id)
+    .bind(payout.amount.to_i64()?)
+    .bind(payout.amount.currency())
+    .bind(payout.destination)
+    .bind(payout.adapter)
+    .bind(payout.idempotency_key)
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(record)
+}
+
+/// Marks a pending payout failed. The caller credits the amount back in the
+/// same transaction.
+pub async fn fail_payout(
+    conn: &mut PgConnection,
+    payout_id: Uuid,
+    error_message: &str,
+) -> ApiResult<PayoutRecord> {
+    let record = sqlx::query_as::<_, PayoutRecord>(
+        r#"
+        UPDATE payouts
+        SET status = 'failed', error_message = $2, updated_at = NOW()
+        WHERE id = $1 AND status = 'pending'
+        RETURNING id, account_id, amount, currency, destination, adapter, status, reference,
+                  error_message, created_at, updated_at
+        "#,
+    )
+    .bind(payout_id)
+    .bind(error_message)
+    .fetch_one(conn)
+    .await?;
+
+    Ok(record)
+}
+
+/// Stores the response of a claimed request. Runs in the request's
+/// transaction so the response is kept exactly when its effects are.
+pub async fn complete_idempotency_key(
+    conn: &mut PgConnection,
+    endpoint: &str,
+    scope: &str,
+    idempotency_key: &str,
+    response: serde_json::Value,
+) -> ApiResult<()> {
+    sqlx::query(
+        r#"
+        UPDATE idempotency_keys
+        SET response = $4
+        WHERE endpoint = $1 AND scope = $2 AND idempotency_key = $3
+        "#,
+    )
+    .bind(endpoint)
+    .bind(scope)
+    .bind(idempotency_key)
+    .bind(response)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn insert_transaction_log<'e, E: PgExecutor<'e>>(
+    executor: E,
+    log: TransactionLog<'_>,
+) -> ApiResult<TransactionRecord> {
+    let record = sqlx::query_as::<_, TransactionRecord>(
+        r#"
+        INSERT INTO transactions 
+            (transaction_type, amount, denomination, token_count, institution_id, key_id, status,
+             error_message, account_id)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        RETURNING id, transaction_type, amount, denomination, token_count, 
+                  institution_id, key_id, status, error_message, request_data, account_id,
+                  created_at
+        "#,
+    )
+    .bind(log.transaction_type)
+    .bind(log.amount.to_i64()?)
+    .bind(bigint(log.denomination)?)
+    .bind(log.token_count as i32)
+    .bind(log.institution_id)
+    .bind(log.key_id)
+    .bind(log.status)
+    .bind(log.error_message)
+    .bind(log.account_id)
+    .fetch_one(executor)
+    .await?;
+
+    Ok(record)
+}
+
+/// Records a batch of spent tokens in the caller's transaction. Returns
+/// `false` if any serial is already recorded, in which case the caller must
+/// roll back. The partitions for the tokens' expiry epochs must exist.
+pub async fn insert_spent_tokens(
+    conn: &mut PgConnection,
+    tokens: &[SpentToken<'_>],
+) -> ApiResult<bool> {
+    let serials_hex: Vec<&str> = tokens.iter().map(|t| t.serial_hex.as_str()).collect();
+    let legacy = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM tokens WHERE expiry_epoch = 0 AND serial_hex = ANY($1))",
+    )
+    .bind(&serials_hex)
+    .fetch_one(&mut *conn)
+    .await?;
+    if legacy {
+        return Ok(false);
+    }
+
+    for token in tokens {
+        let result = sqlx::query(
+            r#"
+            INSERT INTO tokens
+                (serial_number, serial_hex, expiry_epoch, key_id, denomination, currency)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(token.serial_number)
+        .bind(&token.serial_hex)
+        .bind(bigint(token.expiry_epoch)?)
+        .bind(token.key_id)
+        .bind(bigint(token.denomination)?)
+        .bind(token.currency)
+        .execute(&mut *conn)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Keeps the blind signature of a seeded wallet's token. A retried request
+/// finds the record already there and leaves it.
+pub async fn insert_issued_token(conn: &mut PgConnection, token: &IssuedToken) -> ApiResult<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO issued_tokens
+            (recovery_id, blinded_message, blind_signature, denomination, currency, key_id,
+             expiry_epoch)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT (recovery_id) DO NOTHING
+        "#,
+    )
+    .bind(&token.recovery_id)
+    .bind(&token.blinded_message)
+    .bind(&token.blind_signature)
+    .bind(bigint(token.denomination)?)
+    .bind(&token.currency)
+    .bind(&token.key_id)
+    .bind(bigint(token.expiry_epoch)?)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Converts for a `BIGINT` column, refusing values that do not fit rather
+/// than wrapping them.
+fn bigint(value: u64) -> ApiResult<i64> {
+    i64::try_from(value).map_err(|_| ApiError::InvalidRequest(format!("{} is too large", value)))
 }
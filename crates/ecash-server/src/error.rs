@@ -1,4 +1,4 @@
-use axum::http::StatusCode;
+use axum::http::{header, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 use serde_json::json;
@@ -17,6 +17,9 @@ pub enum ApiError {
     #[error("Invalid denomination: {0}")]
     InvalidDenomination(u64),
 
+    #[error("Got {found} but the account holds {expected}")]
+    CurrencyMismatch { expected: String, found: String },
+
     #[error("Token already spent")]
     TokenAlreadySpent,
 
@@ -26,6 +29,36 @@ pub enum ApiError {
     #[error("Invalid signature")]
     InvalidSignature,
 
+    #[error("No active signing key")]
+    NoActiveKey,
+
+    #[error("No key directory is published")]
+    NoKeyDirectory,
+
+    #[error("Missing or invalid credentials")]
+    Unauthorized,
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Account not found")]
+    AccountNotFound,
+
+    #[error("Insufficient funds: required {required}, available {available}")]
+    InsufficientFunds {
+        required: ecash_core::Amount,
+        available: ecash_core::Amount,
+    },
+
+    #[error("Idempotency key reused with a different request")]
+    IdempotencyKeyReused,
+
+    #[error("A request with this idempotency key is in progress")]
+    RequestInProgress,
+
+    #[error("Rate limit exceeded; retry after {retry_after} seconds")]
+    RateLimitExceeded { retry_after: u64 },
+
     #[error("Invalid request: {0}")]
     InvalidRequest(String),
 
@@ -35,6 +68,17 @@ pub enum ApiError {
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
+        // Tells clients the request may succeed if repeated unchanged.
+        let retry_after = match self {
+            ApiError::RequestInProgress => Some(1),
+            ApiError::RateLimitExceeded { retry_after } => Some(retry_after),
+            _ => None,
+        };
+        let code = match self {
+            ApiError::RateLimitExceeded { .. } => Some("RATE_LIMIT_EXCEEDED"),
+            _ => None,
+        };
+
         let (status, error_message) = match self {
             ApiError::Database(_) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -46,6 +90,10 @@ impl IntoResponse for ApiError {
                 StatusCode::BAD_REQUEST,
                 format!("Invalid denomination: {}", d),
             ),
+            ApiError::CurrencyMismatch { expected, found } => (
+                StatusCode::BAD_REQUEST,
+                format!("Got {} but the account holds {}", found, expected),
+            ),
             ApiError::TokenAlreadySpent => {
                 (StatusCode::CONFLICT, "Token already spent".to_string())
             }
@@ -53,16 +101,59 @@ impl IntoResponse for ApiError {
             ApiError::InvalidSignature => {
                 (StatusCode::BAD_REQUEST, "Invalid signature".to_string())
             }
+            ApiError::NoActiveKey => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "No active signing key".to_string(),
+            ),
+            ApiError::NoKeyDirectory => (
+                StatusCode::NOT_FOUND,
+                "No key directory is published".to_string(),
+            ),
+            ApiError::Unauthorized => (
+                StatusCode::UNAUTHORIZED,
+                "Missing or invalid credentials".to_string(),
+            ),
+            ApiError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg),
+            ApiError::AccountNotFound => (StatusCode::NOT_FOUND, "Account not found".to_string()),
+            ApiError::InsufficientFunds {
+                required,
+                available,
+            } => (
+                StatusCode::PAYMENT_REQUIRED,
+                format!(
+                    "Insufficient funds: required {}, available {}",
+                    required, available
+                ),
+            ),
+            ApiError::IdempotencyKeyReused => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Idempotency key reused with a different request".to_string(),
+            ),
+            ApiError::RequestInProgress => (
+                StatusCode::CONFLICT,
+                "A request with this idempotency key is in progress".to_string(),
+            ),
+            ApiError::RateLimitExceeded { .. } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many requests".to_string(),
+            ),
             ApiError::InvalidRequest(msg) => (StatusCode::BAD_REQUEST, msg),
             ApiError::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
         };
 
-        let body = Json(json!({
+        let mut body = json!({
             "error": error_message,
             "status": status.as_u16(),
-        }));
+        });
+        if let Some(code) = code {
+            body["code"] = json!(code);
+            body["retry_after"] = json!(retry_after);
+        }
 
-        (status, body).into_response()
+        match retry_after {
+            Some(seconds) => (status, [(header::RETRY_AFTER, seconds)], Json(body)).into_response(),
+            None => (status, Json(body)).into_response(),
+        }
     }
 }
 
@@ -0,0 +1,250 @@
+//! Exchange of tokens for blind signatures on new ones, used by wallets to
+//! make change, consolidate and refresh tokens.
+//!
+//! The inputs are checked as in a redemption and the outputs as in a
+//! withdrawal, with the same limit on their number. The inputs are reserved
+//! in the spent-serial store before any output is signed, so resending spent
+//! tokens costs the server no signatures. Marking the inputs spent, logging
+//! the exchange, keeping the signatures of outputs with a recovery id and
+//! storing the idempotent response then succeed or fail together, as for a
+//! redemption, and the reservation is released if signing or any of those
+//! fails. No account is involved, so an exchange needs no credentials.
+
+use crate::db::{self, TransactionLog};
+use crate::error::{ApiError, ApiResult};
+use crate::idempotency::IdempotencyKey;
+use crate::redemption::{self, ValidatedBatch};
+use crate::restore;
+use crate::state::AppState;
+use ecash_core::{BlindSignature, ExchangeRequest, ExchangeResponse};
+use sqlx::{Postgres, Transaction};
+
+pub async fn process_exchange(
+    state: &AppState,
+    request: &ExchangeRequest,
+    idempotency_key: Option<&IdempotencyKey>,
+) -> ApiResult<ExchangeResponse> {
+    let max_tokens = state.config.institution.max_withdraw_tokens;
+    if request.outputs.len() > max_tokens {
+        return Err(ApiError::InvalidRequest(format!(
+            "At most {} tokens can be issued by one exchange",
+            max_tokens
+        )));
+    }
+
+    let batch = redemption::validate_batch(&state.institution, &request.inputs)?;
+
+    let Some(first) = request.outputs.first() else {
+        return Err(ApiError::InvalidRequest("No outputs provided".to_string()));
+    };
+    let key_id = first.key_id.clone();
+    let currency = first.currency.clone();
+
+    if request.outputs.iter().any(|output| output.key_id != key_id) {
+        return Err(ApiError::InvalidRequest(
+            "All outputs must use the same key".to_string(),
+        ));
+    }
+    if request
+        .outputs
+        .iter()
+        .map(|output| &output.currency)
+        .chain(request.inputs.iter().map(|token| &token.currency))
+        .any(|token_currency| *token_currency != currency)
+    {
+        return Err(ApiError::InvalidRequest(
+            "Inputs and outputs must be in the same currency".to_string(),
+        ));
+    }
+    if let Some(output) = request
+        .outputs
+        .iter()
+        .find(|output| !state.is_valid_denomination(&output.currency, output.denomination))
+    {
+        return Err(ApiError::InvalidDenomination(output.denomination));
+    }
+    restore::validate_recovery_ids(&request.outputs)?;
+
+    state
+        .institution
+        .validate_expiry_epoch(request.expiry_epoch)
+        .map_err(ApiError::Ecash)?;
+    let expires_at = state
+        .institution
+        .expiry_time(request.expiry_epoch)
+        .map_err(ApiError::Ecash)?;
+
+    let currency = state.currency(&currency)?;
+    let fee = currency.amount(state.config.institution.exchange_fee);
+    request.check_value(currency, &fee)?;
+
+    redemption::with_reservation(state, &batch, || async {
+        let mut blind_signatures = Vec::with_capacity(request.outputs.len());
+        for output in &request.outputs {
+            let _timer = state.metrics.signing_timer();
+            blind_signatures.push(
+                state
+                    .institution
+                    .sign_blinded_token(output)
+                    .map_err(ApiError::Ecash)?,
+            );
+        }
+
+        redemption::spend_reserved(state, &batch, |tx| {
+            record_exchange(
+                state,
+                request,
+                &batch,
+                tx,
+                blind_signatures,
+                expires_at.to_rfc3339(),
+                idempotency_key,
+            )
+        })
+        .await
+    })
+    .await
+}
+
+/// Writes the transaction log entry, the issued outputs and the stored
+/// idempotent response in `tx`, and commits it.
+async fn record_exchange(
+    state: &AppState,
+    request: &ExchangeRequest,
+    batch: &ValidatedBatch<'_>,
+    mut tx: Transaction<'static, Postgres>,
+    blind_signatures: Vec<BlindSignature>,
+    expires_at: String,
+    idempotency_key: Option<&IdempotencyKey>,
+) -> ApiResult<ExchangeResponse> {
+    let _timer = state.metrics.postgres_timer("exchange");
+    let currency = state.currency(batch.currency())?;
+    let key_id = request.outputs[0].key_id.clone();
+
+    let record = db::insert_transaction_log(
+        &mut *tx,
+        TransactionLog {
+            transaction_type: "exchange",
+            amount: batch.total_amount(),
+            denomination: request.inputs[0].denomination,
+            token_count: request.inputs.len(),
+            institution_id: state.institution_id(),
+            key_id: &key_id,
+            status: "success",
+            error_message: None,
+            account_id: None,
+        },
+    )
+    .await?;
+    restore::record_issued(
+        &mut tx,
+        &request.outputs,
+        &blind_signatures,
+        request.expiry_epoch,
+    )
+    .await?;
+
+    let response = ExchangeResponse {
+        blind_signatures,
+        key_id,
+        expires_at,
+        input_amount: batch.total_amount().clone(),
+        output_amount: request.output_amount(currency)?,
+        fee: currency.amount(state.config.institution.exchange_fee),
+        transaction_id: record.id.to_string(),
+    };
+
+    if let Some(idempotency_key) = idempotency_key {
+        idempotency_key.complete(&mut tx, &response).await?;
+    }
+    tx.commit().await?;
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ecash_core::{
+        Currency, Decomposition, Institution, InstitutionKey, KeyRing, KeyStatus, KeyValidity,
+        Wallet,
+    };
+    use rsa::RsaPrivateKey;
+    use std::collections::BTreeMap;
+
+    fn setup() -> (AppState, ExchangeRequest) {
+        let mut rng = rand::thread_rng();
+        let private_key = RsaPrivateKey::new(&mut rng, 1024).unwrap();
+        let public_key = private_key.to_public_key();
+
+        let mut keys = KeyRing::new();
+        keys.insert(
+            InstitutionKey::new(
+                "key_001".to_string(),
+                KeyStatus::Active,
+                KeyValidity::unbounded(),
+            )
+            .with_private_key("USD", 10, private_key),
+        );
+        let currencies = vec![Currency::new("USD", vec![10]).unwrap()];
+        let institution = Institution::new(keys, "inst_test".to_string(), currencies, 90);
+        let expiry_epoch = institution.current_expiry_epoch();
+        let amount = institution.currencies()[0].amount(20);
+
+        let wallet = Wallet::new(
+            BTreeMap::from([(10, public_key)]),
+            "inst_test".to_string(),
+            "key_001".to_string(),
+            "USD".to_string(),
+        );
+        let (blinded, metadata): (Vec<_>, Vec<_>) = wallet
+            .prepare_withdrawal(&amount, Decomposition::Greedy, expiry_epoch)
+            .unwrap()
+            .into_iter()
+            .unzip();
+        let signatures = blinded
+            .iter()
+            .map(|token| institution.sign_blinded_token(token).unwrap())
+            .collect();
+        let inputs = wallet.finalize_withdrawal(signatures, metadata).unwrap();
+        let outputs = wallet
+            .prepare_withdrawal(&amount, Decomposition::Greedy, expiry_epoch)
+            .unwrap()
+            .into_iter()
+            .map(|(blinded, _)| blinded)
+            .collect();
+
+        let request = ExchangeRequest {
+            inputs,
+            outputs,
+            expiry_epoch,
+        };
+        (AppState::for_tests(institution), request)
+    }
+
+    #[tokio::test]
+    async fn test_spent_inputs_are_refused_before_signing() {
+        let (state, request) = setup();
+        assert!(state.spent.mark_all_spent(&request.inputs).await.unwrap());
+
+        assert!(matches!(
+            process_exchange(&state, &request, None).await,
+            Err(ApiError::TokenAlreadySpent)
+        ));
+        let metrics = state.metrics.encode().unwrap();
+        assert!(metrics.contains("rsa_signing_duration_seconds_count 0"));
+    }
+
+    #[tokio::test]
+    async fn test_too_many_outputs_are_refused() {
+        let (state, mut request) = setup();
+        let max_tokens = state.config.institution.max_withdraw_tokens;
+        request.outputs = vec![request.outputs[0].clone(); max_tokens + 1];
+
+        assert!(matches!(
+            process_exchange(&state, &request, None).await,
+            Err(ApiError::InvalidRequest(_))
+        ));
+        assert!(!state.spent.is_spent(&request.inputs[0]).await.unwrap());
+    }
+}
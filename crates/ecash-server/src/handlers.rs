@@ -1,12 +1,28 @@
+use crate::auth::{self, AuthenticatedAccount};
+use crate::db::{self, NewPayout, TransactionLog};
 use crate::error::{ApiError, ApiResult};
+use crate::exchange;
+use crate::idempotency::{self, IdempotencyKey};
+use crate::models::{AccountKind, AccountRecord, PayoutRecord};
+use crate::payouts::PayoutInstruction;
+use crate::redemption;
+use crate::restore;
 use crate::state::AppState;
 use crate::types::{
-    HealthResponse, PublicKeyResponse, RedeemRequest, RedeemResponse, VerifyRequest,
-    VerifyResponse, WithdrawRequest, WithdrawResponse,
+    AccountResponse, CreateAccountRequest, CreateAccountResponse, DenominationKeyInfo,
+    DepositRequest, HealthResponse, KeyInfo, PayoutRequest, PayoutResponse, PublicKeyResponse,
+    RedeemRequest, RedeemResponse, RedemptionInfo, RestoreRequest, RestoreResponse,
+    SettlementQuery, SettlementResponse, VerifyRequest, VerifyResponse, WithdrawRequest,
+    WithdrawResponse,
+};
+use axum::extract::{Path, Query, State};
+use axum::http::HeaderMap;
+use axum::{Extension, Json};
+use chrono::{Duration, Utc};
+use ecash_core::{
+    Amount, ExchangeRequest, ExchangeResponse, InstitutionKey, Jwk, KeyDirectory,
+    SignedKeyDirectory,
 };
-use axum::extract::State;
-use axum::Json;
-use chrono::Utc;
 use rsa::traits::PublicKeyParts;
 use uuid::Uuid;
 
@@ -17,12 +33,10 @@ pub async fn health_check(State(state): State<AppState>) -> ApiResult<Json<Healt
         .map(|_| "ok")
         .unwrap_or("error");
 
-    let redis_status = state
-        .cache
-        .health_check()
-        .await
-        .map(|_| "ok")
-        .unwrap_or("error");
+    let redis_status = match &state.cache {
+        Some(cache) => cache.health_check().await.map(|_| "ok").unwrap_or("error"),
+        None => "disabled",
+    };
 
     Ok(Json(HealthResponse {
         status: "running".to_string(),
@@ -33,159 +47,573 @@ pub async fn health_check(State(state): State<AppState>) -> ApiResult<Json<Healt
 }
 
 pub async fn get_public_key(State(state): State<AppState>) -> ApiResult<Json<PublicKeyResponse>> {
-    let n = state.public_key.n();
-    let e = state.public_key.e();
+    let signing_key = state
+        .institution
+        .signing_key()
+        .ok_or(ApiError::NoActiveKey)?;
+    let default_currency = state.config.institution.default_currency();
+
+    let keys = state
+        .institution
+        .keys()
+        .iter()
+        .map(|key| KeyInfo {
+            key_id: key.key_id().to_string(),
+            status: key.status(),
+            created_at: key.validity().created_at.to_rfc3339(),
+            expires_at: key.validity().expires_at.map(|t| t.to_rfc3339()),
+            revoked_at: key.validity().revoked_at.map(|t| t.to_rfc3339()),
+            public_keys: denomination_keys(key),
+        })
+        .collect();
 
     Ok(Json(PublicKeyResponse {
-        key_id: state.key_id().to_string(),
+        key_id: signing_key.key_id().to_string(),
         institution_id: state.institution_id().to_string(),
-        public_key_n: n.to_string(),
-        public_key_e: e.to_string(),
-        denominations: state.denominations().to_vec(),
-        expires_at: None,
+        currency: default_currency.code.clone(),
+        denominations: default_currency.denominations.clone(),
+        currencies: state.config.institution.currencies.clone(),
+        variant: state.institution.variant(),
+        expiry_epoch: state.institution.current_expiry_epoch(),
+        exchange_fee: state.config.institution.exchange_fee,
+        expires_at: signing_key.validity().expires_at.map(|t| t.to_rfc3339()),
+        public_keys: denomination_keys(signing_key),
+        keys,
+    }))
+}
+
+/// Every key set with its validity window, signed with the institution's
+/// identity key. Signed afresh on each request, so `next_update` is always
+/// `KEY_DIRECTORY_TTL_SECONDS` ahead.
+pub async fn get_key_directory(
+    State(state): State<AppState>,
+) -> ApiResult<Json<SignedKeyDirectory>> {
+    let identity_key = state
+        .identity_key
+        .as_ref()
+        .ok_or(ApiError::NoKeyDirectory)?;
+    let now = Utc::now();
+    let directory = KeyDirectory::new(
+        &state.institution,
+        now,
+        now + Duration::seconds(state.config.keys.directory_ttl_seconds),
+    );
+
+    Ok(Json(SignedKeyDirectory::sign(&directory, identity_key)?))
+}
+
+fn denomination_keys(key: &InstitutionKey) -> Vec<DenominationKeyInfo> {
+    key.public_keys()
+        .map(|(currency, denomination, public_key)| DenominationKeyInfo {
+            currency: currency.to_string(),
+            denomination,
+            public_key_n: public_key.n().to_string(),
+            public_key_e: public_key.e().to_string(),
+            jwk: Jwk::from_public_key(public_key),
+        })
+        .collect()
+}
+
+pub async fn get_account(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthenticatedAccount>,
+) -> ApiResult<Json<AccountResponse>> {
+    let account = state
+        .db
+        .get_account(auth.account_id)
+        .await?
+        .ok_or(ApiError::AccountNotFound)?;
+
+    Ok(Json(account_response(&state, account)?))
+}
+
+pub async fn create_account(
+    State(state): State<AppState>,
+    Json(request): Json<CreateAccountRequest>,
+) -> ApiResult<Json<CreateAccountResponse>> {
+    if request.name.trim().is_empty() {
+        return Err(ApiError::InvalidRequest(
+            "Account name is required".to_string(),
+        ));
+    }
+
+    let currency = match &request.currency {
+        Some(code) => state.currency(code)?,
+        None => state.config.institution.default_currency(),
+    };
+
+    let api_key = auth::generate_api_key();
+    let account = state
+        .db
+        .create_account(
+            &request.name,
+            request.kind,
+            &currency.code,
+            &auth::hash_api_key(&api_key),
+        )
+        .await?;
+
+    tracing::info!("Created {} account {}", request.kind.as_str(), account.id);
+
+    Ok(Json(CreateAccountResponse {
+        account: account_response(&state, account)?,
+        api_key,
     }))
 }
 
+pub async fn deposit(
+    State(state): State<AppState>,
+    Path(account_id): Path<Uuid>,
+    Json(request): Json<DepositRequest>,
+) -> ApiResult<Json<AccountResponse>> {
+    // The credit itself checks that this is the account's currency.
+    let amount = state.account_amount(request.amount.currency(), &request.amount)?;
+
+    let mut tx = state.db.begin().await?;
+
+    db::credit_account(&mut tx, account_id, &amount).await?;
+    db::insert_transaction_log(
+        &mut *tx,
+        TransactionLog {
+            transaction_type: "deposit",
+            amount: &amount,
+            denomination: 0,
+            token_count: 0,
+            institution_id: state.institution_id(),
+            key_id: "",
+            status: "success",
+            error_message: None,
+            account_id: Some(account_id),
+        },
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    let account = state
+        .db
+        .get_account(account_id)
+        .await?
+        .ok_or(ApiError::AccountNotFound)?;
+
+    Ok(Json(account_response(&state, account)?))
+}
+
+fn account_response(state: &AppState, account: AccountRecord) -> ApiResult<AccountResponse> {
+    let balance = Amount::from_i64(account.balance, state.currency(&account.currency)?)?;
+
+    Ok(AccountResponse {
+        account_id: account.id.to_string(),
+        name: account.name,
+        kind: AccountKind::parse(&account.kind).unwrap_or(AccountKind::Customer),
+        currency: account.currency,
+        balance,
+        updated_at: account.updated_at.to_rfc3339(),
+    })
+}
+
 pub async fn withdraw(
     State(state): State<AppState>,
+    Extension(auth): Extension<AuthenticatedAccount>,
+    headers: HeaderMap,
     Json(request): Json<WithdrawRequest>,
 ) -> ApiResult<Json<WithdrawResponse>> {
-    if !state.is_valid_denomination(request.denomination) {
-        return Err(ApiError::InvalidDenomination(request.denomination));
-    }
+    let idempotency_key =
+        IdempotencyKey::from_headers(&headers, "withdraw", auth.account_id.to_string(), &request)?;
+    idempotency::run(&state, idempotency_key.as_ref(), || {
+        process_withdraw(&state, &auth, &request, idempotency_key.as_ref())
+    })
+    .await
+    .map(Json)
+}
 
+async fn process_withdraw(
+    state: &AppState,
+    auth: &AuthenticatedAccount,
+    request: &WithdrawRequest,
+    idempotency_key: Option<&IdempotencyKey>,
+) -> ApiResult<WithdrawResponse> {
     if request.blinded_tokens.is_empty() {
         return Err(ApiError::InvalidRequest("No tokens provided".to_string()));
     }
+    let max_tokens = state.config.institution.max_withdraw_tokens;
+    if request.blinded_tokens.len() > max_tokens {
+        return Err(ApiError::InvalidRequest(format!(
+            "At most {} tokens can be withdrawn at once",
+            max_tokens
+        )));
+    }
 
-    let expected_count = request.amount.div_ceil(request.denomination);
-    if request.blinded_tokens.len() != expected_count as usize {
+    state
+        .institution
+        .validate_expiry_epoch(request.expiry_epoch)
+        .map_err(ApiError::Ecash)?;
+    let expires_at = state
+        .institution
+        .expiry_time(request.expiry_epoch)
+        .map_err(ApiError::Ecash)?;
+
+    // The tokens are paid for from the account's balance, so they must be in
+    // its currency.
+    if let Some(token) = request
+        .blinded_tokens
+        .iter()
+        .find(|token| token.currency != auth.currency)
+    {
+        return Err(ApiError::CurrencyMismatch {
+            expected: auth.currency.clone(),
+            found: token.currency.clone(),
+        });
+    }
+    if let Some(token) = request
+        .blinded_tokens
+        .iter()
+        .find(|token| !state.is_valid_denomination(&token.currency, token.denomination))
+    {
+        return Err(ApiError::InvalidDenomination(token.denomination));
+    }
+    restore::validate_recovery_ids(&request.blinded_tokens)?;
+
+    // The account is debited by `amount`, so it must be exactly the value of
+    // the tokens issued.
+    let amount = state.account_amount(&auth.currency, &request.amount)?;
+    let currency = state.currency(&auth.currency)?;
+    let value = request
+        .blinded_tokens
+        .iter()
+        .try_fold(currency.amount(0), |sum, token| {
+            sum.checked_add(&token.amount(currency)?)
+        })?;
+    if value != amount {
         return Err(ApiError::InvalidRequest(format!(
-            "Expected {} tokens for amount {} with denomination {}",
-            expected_count, request.amount, request.denomination
+            "Tokens are not worth exactly {}",
+            amount
         )));
     }
 
-    let mut blind_signatures = Vec::new();
+    let key_id = request.blinded_tokens[0].key_id.clone();
+    if request
+        .blinded_tokens
+        .iter()
+        .any(|token| token.key_id != key_id)
+    {
+        return Err(ApiError::InvalidRequest(
+            "All tokens must use the same key".to_string(),
+        ));
+    }
 
-    for blinded_token in &request.blinded_tokens {
-        if blinded_token.denomination != request.denomination {
-            return Err(ApiError::InvalidRequest(
-                "All tokens must have same denomination".to_string(),
-            ));
-        }
+    let timer = state.metrics.postgres_timer("withdraw");
+    let mut tx = state.db.begin().await?;
 
-        let signature = state
-            .institution
-            .sign_blinded_token(blinded_token)
-            .map_err(ApiError::Ecash)?;
+    // Debited before signing, so an account that cannot pay is turned away
+    // without any private-key operation. The debit is rolled back with the
+    // transaction if signing fails.
+    let balance = db::debit_account(&mut tx, auth.account_id, &amount).await?;
 
-        blind_signatures.push(signature);
+    let mut blind_signatures = Vec::with_capacity(request.blinded_tokens.len());
+    for blinded_token in &request.blinded_tokens {
+        let _timer = state.metrics.signing_timer();
+        blind_signatures.push(
+            state
+                .institution
+                .sign_blinded_token(blinded_token)
+                .map_err(ApiError::Ecash)?,
+        );
     }
 
-    let transaction_id = Uuid::new_v4().to_string();
-    let expires_at = state.institution.expiry_time();
-
-    let _ = state
-        .db
-        .log_transaction(crate::db::TransactionLog {
+    let record = db::insert_transaction_log(
+        &mut *tx,
+        TransactionLog {
             transaction_type: "withdraw",
-            amount: request.amount,
-            denomination: request.denomination,
+            amount: &amount,
+            denomination: request.blinded_tokens[0].denomination,
             token_count: request.blinded_tokens.len(),
             institution_id: state.institution_id(),
-            key_id: state.key_id(),
+            key_id: &key_id,
             status: "success",
             error_message: None,
-        })
-        .await;
-
-    Ok(Json(WithdrawResponse {
+            account_id: Some(auth.account_id),
+        },
+    )
+    .await?;
+    restore::record_issued(
+        &mut tx,
+        &request.blinded_tokens,
+        &blind_signatures,
+        request.expiry_epoch,
+    )
+    .await?;
+
+    let response = WithdrawResponse {
         blind_signatures,
-        key_id: state.key_id().to_string(),
+        key_id,
         expires_at: expires_at.to_rfc3339(),
-        transaction_id,
-    }))
+        transaction_id: record.id.to_string(),
+        balance,
+    };
+
+    if let Some(idempotency_key) = idempotency_key {
+        idempotency_key.complete(&mut tx, &response).await?;
+    }
+    tx.commit().await?;
+    drop(timer);
+
+    state.metrics.record_withdrawal(
+        request
+            .blinded_tokens
+            .iter()
+            .map(|token| (token.currency.as_str(), token.denomination)),
+        &response.key_id,
+    );
+
+    Ok(response)
 }
 
+/// Redeems tokens into the authenticated merchant account's balance, from
+/// which they are settled and paid out.
 pub async fn redeem(
     State(state): State<AppState>,
+    Extension(auth): Extension<AuthenticatedAccount>,
+    headers: HeaderMap,
     Json(request): Json<RedeemRequest>,
 ) -> ApiResult<Json<RedeemResponse>> {
-    if request.tokens.is_empty() {
-        return Err(ApiError::InvalidRequest("No tokens provided".to_string()));
-    }
+    auth.require_merchant()?;
+
+    let idempotency_key =
+        IdempotencyKey::from_headers(&headers, "redeem", auth.account_id.to_string(), &request)?;
+    idempotency::run(&state, idempotency_key.as_ref(), || async {
+        let result = async {
+            let batch = redemption::validate_batch(&state.institution, &request.tokens)?;
+            if batch.currency() != auth.currency {
+                return Err(ApiError::CurrencyMismatch {
+                    expected: auth.currency.clone(),
+                    found: batch.currency().to_string(),
+                });
+            }
+            redemption::commit_batch(&state, &batch, auth.account_id, idempotency_key.as_ref())
+                .await
+        }
+        .await;
 
-    let mut accepted_count = 0;
-    let mut total_amount = 0u64;
-    let transaction_id = Uuid::new_v4().to_string();
+        match &result {
+            Ok(_) => state.metrics.record_redemption(
+                request
+                    .tokens
+                    .iter()
+                    .map(|token| (token.currency.as_str(), token.denomination)),
+            ),
+            Err(error) => state.metrics.record_spend_error("redeem", error),
+        }
+        result
+    })
+    .await
+    .map(Json)
+}
 
-    for token in &request.tokens {
-        if token.is_expired() {
-            return Err(ApiError::TokenExpired);
+/// Exchanges tokens for blind signatures on new ones. Open to anonymous
+/// clients: the idempotency key is bound to the request, which only the
+/// holder of the input tokens can repeat.
+pub async fn exchange(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<ExchangeRequest>,
+) -> ApiResult<Json<ExchangeResponse>> {
+    let idempotency_key =
+        IdempotencyKey::from_headers(&headers, "exchange", String::new(), &request)?;
+    idempotency::run(&state, idempotency_key.as_ref(), || async {
+        let result = exchange::process_exchange(&state, &request, idempotency_key.as_ref()).await;
+
+        match &result {
+            Ok(response) => state.metrics.record_exchange(
+                request
+                    .inputs
+                    .iter()
+                    .map(|token| (token.currency.as_str(), token.denomination)),
+                request
+                    .outputs
+                    .iter()
+                    .map(|output| (output.currency.as_str(), output.denomination)),
+                &response.key_id,
+            ),
+            Err(error) => state.metrics.record_spend_error("exchange", error),
         }
+        result
+    })
+    .await
+    .map(Json)
+}
 
-        let serial_hex = token.serial_hex();
+/// Redemptions credited to the merchant over a period.
+pub async fn get_settlement(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthenticatedAccount>,
+    Query(query): Query<SettlementQuery>,
+) -> ApiResult<Json<SettlementResponse>> {
+    auth.require_merchant()?;
+
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query.from.unwrap_or(to - Duration::hours(24));
+    if from >= to {
+        return Err(ApiError::InvalidRequest(
+            "Settlement period must end after it starts".to_string(),
+        ));
+    }
 
-        if state.cache.is_token_spent(&serial_hex).await? {
-            return Err(ApiError::TokenAlreadySpent);
-        }
+    let account = state
+        .db
+        .get_account(auth.account_id)
+        .await?
+        .ok_or(ApiError::AccountNotFound)?;
+    let currency = state.currency(&account.currency)?;
+    let records = state.db.list_redemptions(auth.account_id, from, to).await?;
+
+    let redemptions = records
+        .into_iter()
+        .map(|record| {
+            Ok(RedemptionInfo {
+                transaction_id: record.id.to_string(),
+                amount: Amount::from_i64(record.amount, currency)?,
+                token_count: record.token_count as u64,
+                timestamp: record.created_at.to_rfc3339(),
+            })
+        })
+        .collect::<ApiResult<Vec<_>>>()?;
+    let total_amount = Amount::sum(currency, redemptions.iter().map(|r| &r.amount))?;
+
+    Ok(Json(SettlementResponse {
+        account_id: account.id.to_string(),
+        currency: account.currency,
+        from: from.to_rfc3339(),
+        to: to.to_rfc3339(),
+        redemption_count: redemptions.len(),
+        token_count: redemptions.iter().map(|r| r.token_count).sum(),
+        total_amount,
+        redemptions,
+    }))
+}
 
-        if state.db.check_token_spent(&serial_hex).await? {
-            return Err(ApiError::TokenAlreadySpent);
-        }
+/// Debits the merchant and hands the amount to the payout adapter. An
+/// `Idempotency-Key` is stored with the payout, and a retry returns the
+/// payout it created.
+pub async fn create_payout(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthenticatedAccount>,
+    headers: HeaderMap,
+    Json(request): Json<PayoutRequest>,
+) -> ApiResult<Json<PayoutResponse>> {
+    auth.require_merchant()?;
+
+    let amount = state.account_amount(&auth.currency, &request.amount)?;
+    let destination = request.destination.trim();
+    if destination.is_empty() || destination.len() > 255 {
+        return Err(ApiError::InvalidRequest(
+            "Destination must be 1 to 255 characters".to_string(),
+        ));
+    }
 
-        let is_valid = state
-            .institution
-            .verify_token(token)
-            .map_err(ApiError::Ecash)?;
-        if !is_valid {
-            return Err(ApiError::InvalidSignature);
+    let idempotency_key = idempotency::header_value(&headers)?;
+    if let Some(key) = idempotency_key {
+        if let Some(existing) = state.db.find_payout(auth.account_id, key).await? {
+            let existing = payout_response(&state, existing)?;
+            if existing.amount != amount || existing.destination != destination {
+                return Err(ApiError::IdempotencyKeyReused);
+            }
+            return Ok(Json(existing));
         }
+    }
 
-        if !state
-            .cache
-            .check_and_mark_spent(&serial_hex, 86400 * 90)
-            .await?
-        {
-            return Err(ApiError::TokenAlreadySpent);
-        }
+    let mut tx = state.db.begin().await?;
+
+    db::debit_account(&mut tx, auth.account_id, &amount).await?;
+    let payout = db::insert_payout(
+        &mut tx,
+        NewPayout {
+            account_id: auth.account_id,
+            amount: &amount,
+            destination,
+            adapter: state.payouts.name(),
+            idempotency_key,
+        },
+    )
+    .await?
+    // A concurrent request with the same key got there first.
+    .ok_or(ApiError::RequestInProgress)?;
+    db::insert_transaction_log(
+        &mut *tx,
+        payout_log(&state, "payout", &amount, auth.account_id),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    let instruction = PayoutInstruction {
+        payout_id: payout.id,
+        account_id: auth.account_id,
+        amount: amount.clone(),
+        destination: payout.destination.clone(),
+    };
 
-        let _ = state
-            .db
-            .mark_token_spent(
-                token.serial_number.clone(),
-                serial_hex,
-                token.denomination,
-                &token.currency,
-                request.merchant_id.clone(),
+    let payout = match state.payouts.send(&instruction).await {
+        Ok(reference) => state.db.complete_payout(payout.id, &reference).await?,
+        Err(error) => {
+            tracing::warn!("Payout {} failed: {}", payout.id, error);
+
+            let mut tx = state.db.begin().await?;
+            let payout = db::fail_payout(&mut tx, payout.id, &error.to_string()).await?;
+            db::credit_account(&mut tx, auth.account_id, &amount).await?;
+            db::insert_transaction_log(
+                &mut *tx,
+                payout_log(&state, "payout_reversal", &amount, auth.account_id),
             )
             .await?;
+            tx.commit().await?;
+
+            payout
+        }
+    };
+
+    Ok(Json(payout_response(&state, payout)?))
+}
 
-        accepted_count += 1;
-        total_amount += token.denomination;
+fn payout_log<'a>(
+    state: &'a AppState,
+    transaction_type: &'a str,
+    amount: &'a Amount,
+    account_id: Uuid,
+) -> TransactionLog<'a> {
+    TransactionLog {
+        transaction_type,
+        amount,
+        denomination: 0,
+        token_count: 0,
+        institution_id: state.institution_id(),
+        key_id: "",
+        status: "success",
+        error_message: None,
+        account_id: Some(account_id),
     }
+}
 
-    let _ = state
-        .db
-        .log_transaction(crate::db::TransactionLog {
-            transaction_type: "redeem",
-            amount: total_amount,
-            denomination: request.tokens[0].denomination,
-            token_count: request.tokens.len(),
-            institution_id: state.institution_id(),
-            key_id: state.key_id(),
-            status: "success",
-            error_message: None,
-        })
-        .await;
+fn payout_response(state: &AppState, payout: PayoutRecord) -> ApiResult<PayoutResponse> {
+    let amount = Amount::from_i64(payout.amount, state.currency(&payout.currency)?)?;
+
+    Ok(PayoutResponse {
+        payout_id: payout.id.to_string(),
+        amount,
+        destination: payout.destination,
+        status: payout.status,
+        reference: payout.reference,
+        error: payout.error_message,
+        created_at: payout.created_at.to_rfc3339(),
+    })
+}
 
-    Ok(Json(RedeemResponse {
-        accepted_count,
-        total_amount,
-        transaction_id,
-        timestamp: Utc::now().to_rfc3339(),
-    }))
+/// Returns the stored blind signatures of a seeded wallet's tokens.
+pub async fn restore(
+    State(state): State<AppState>,
+    Json(request): Json<RestoreRequest>,
+) -> ApiResult<Json<RestoreResponse>> {
+    restore::find_issued(&state, &request).await.map(Json)
 }
 
 pub async fn verify(
@@ -193,20 +621,10 @@ pub async fn verify(
     Json(request): Json<VerifyRequest>,
 ) -> ApiResult<Json<VerifyResponse>> {
     let token = &request.token;
-    let serial_hex = token.serial_hex();
+    state.currency(&token.currency)?;
 
     let expired = token.is_expired();
-    let spent_redis = state
-        .cache
-        .is_token_spent(&serial_hex)
-        .await
-        .unwrap_or(false);
-    let spent_db = state
-        .db
-        .check_token_spent(&serial_hex)
-        .await
-        .unwrap_or(false);
-    let spent = spent_redis || spent_db;
+    let spent = state.spent.is_spent(token).await?;
 
     let valid = if expired || spent {
         false
@@ -214,20 +632,62 @@ pub async fn verify(
         state.institution.verify_token(token).unwrap_or(false)
     };
 
-    let message = if expired {
-        "Token has expired".to_string()
+    let (result, message) = if expired {
+        ("expired", "Token has expired")
     } else if spent {
-        "Token has already been spent".to_string()
+        ("spent", "Token has already been spent")
     } else if !valid {
-        "Invalid token signature".to_string()
+        ("invalid", "Invalid token signature")
     } else {
-        "Token is valid".to_string()
+        ("valid", "Token is valid")
     };
+    state.metrics.record_verification(result);
 
     Ok(Json(VerifyResponse {
         valid,
         expired,
         spent,
-        message,
+        message: message.to_string(),
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ecash_core::{Currency, Institution, KeyRing};
+
+    fn account(kind: AccountKind) -> AuthenticatedAccount {
+        AuthenticatedAccount {
+            account_id: Uuid::new_v4(),
+            kind,
+            currency: "USD".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_merchants_redeem() {
+        let currencies = vec![Currency::new("USD", vec![10]).unwrap()];
+        let institution = Institution::new(KeyRing::new(), "inst_test".to_string(), currencies, 90);
+        let state = AppState::for_tests(institution);
+        let request = || Json(RedeemRequest { tokens: Vec::new() });
+
+        let customer = redeem(
+            State(state.clone()),
+            Extension(account(AccountKind::Customer)),
+            HeaderMap::new(),
+            request(),
+        )
+        .await;
+        assert!(matches!(customer, Err(ApiError::Forbidden(_))));
+
+        // A merchant gets past the role check to the empty batch.
+        let merchant = redeem(
+            State(state),
+            Extension(account(AccountKind::Merchant)),
+            HeaderMap::new(),
+            request(),
+        )
+        .await;
+        assert!(matches!(merchant, Err(ApiError::InvalidRequest(_))));
+    }
+}
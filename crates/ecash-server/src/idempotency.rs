@@ -0,0 +1,195 @@
+//! `Idempotency-Key` support for withdraw, redeem, exchange and payouts.
+//!
+//! The first request carrying a key claims it. Its successful response is
+//! stored in the same database transaction as the request's other effects,
+//! so a retry either replays that response or, if nothing was committed,
+//! runs the request again. Keys are scoped per endpoint and client, and bound
+//! to a hash of the request: reusing a key for a different request is
+//! refused.
+
+use crate::db::{self, IdempotencyClaim};
+use crate::error::{ApiError, ApiResult};
+use crate::state::AppState;
+use axum::http::HeaderMap;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgConnection;
+use std::future::Future;
+
+pub const HEADER: &str = "idempotency-key";
+
+const MAX_KEY_LEN: usize = 255;
+
+pub struct IdempotencyKey {
+    endpoint: &'static str,
+    /// The client the key belongs to, empty for unauthenticated requests.
+    scope: String,
+    key: String,
+    request_hash: Vec<u8>,
+}
+
+impl IdempotencyKey {
+    /// Reads the key from the request headers, if the client sent one.
+    pub fn from_headers<T: Serialize>(
+        headers: &HeaderMap,
+        endpoint: &'static str,
+        scope: String,
+        request: &T,
+    ) -> ApiResult<Option<Self>> {
+        let Some(key) = header_value(headers)? else {
+            return Ok(None);
+        };
+
+        let body = serde_json::to_vec(request)
+            .map_err(|e| ApiError::Internal(format!("Failed to hash request: {}", e)))?;
+
+        Ok(Some(Self {
+            endpoint,
+            scope,
+            key: key.to_string(),
+            request_hash: Sha256::digest(&body).to_vec(),
+        }))
+    }
+
+    /// Stores `response` for replay. Call inside the transaction that
+    /// commits the request's effects.
+    pub async fn complete<T: Serialize>(
+        &self,
+        conn: &mut PgConnection,
+        response: &T,
+    ) -> ApiResult<()> {
+        let response = serde_json::to_value(response)
+            .map_err(|e| ApiError::Internal(format!("Failed to store response: {}", e)))?;
+        db::complete_idempotency_key(conn, self.endpoint, &self.scope, &self.key, response).await
+    }
+}
+
+/// The raw `Idempotency-Key` header, validated but not yet bound to a
+/// request.
+pub fn header_value(headers: &HeaderMap) -> ApiResult<Option<&str>> {
+    let Some(value) = headers.get(HEADER) else {
+        return Ok(None);
+    };
+
+    value
+        .to_str()
+        .ok()
+        .filter(|key| !key.is_empty() && key.len() <= MAX_KEY_LEN)
+        .map(Some)
+        .ok_or_else(|| {
+            ApiError::InvalidRequest(format!(
+                "Idempotency-Key must be 1 to {} visible ASCII characters",
+                MAX_KEY_LEN
+            ))
+        })
+}
+
+/// Runs `handler` at most once per idempotency key: a completed earlier
+/// request with the same key is answered with its stored response, and a
+/// failed one releases the key so the client can retry.
+pub async fn run<T, F, Fut>(
+    state: &AppState,
+    key: Option<&IdempotencyKey>,
+    handler: F,
+) -> ApiResult<T>
+where
+    T: DeserializeOwned,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = ApiResult<T>>,
+{
+    let Some(key) = key else {
+        return handler().await;
+    };
+
+    let config = &state.config.idempotency;
+    let claim = state
+        .db
+        .claim_idempotency_key(
+            key.endpoint,
+            &key.scope,
+            &key.key,
+            &key.request_hash,
+            config.ttl_seconds,
+            config.lock_timeout_seconds,
+        )
+        .await?;
+
+    if let IdempotencyClaim::Existing(record) = claim {
+        if record.request_hash != key.request_hash {
+            return Err(ApiError::IdempotencyKeyReused);
+        }
+        let response = record.response.ok_or(ApiError::RequestInProgress)?;
+        return serde_json::from_value(response)
+            .map_err(|e| ApiError::Internal(format!("Stored response is invalid: {}", e)));
+    }
+
+    let result = handler().await;
+    if result.is_err() {
+        if let Err(error) = state
+            .db
+            .release_idempotency_key(key.endpoint, &key.scope, &key.key)
+            .await
+        {
+            // The claim lapses after the lock timeout anyway.
+            tracing::warn!("Failed to release idempotency key: {}", error);
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use serde_json::json;
+
+    fn headers(key: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(HEADER, HeaderValue::from_str(key).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_key_is_bound_to_request() {
+        let request = json!({"amount": 100, "denomination": 50});
+        let other = json!({"amount": 150, "denomination": 50});
+
+        let first =
+            IdempotencyKey::from_headers(&headers("k-1"), "withdraw", String::new(), &request)
+                .unwrap()
+                .unwrap();
+        let retry =
+            IdempotencyKey::from_headers(&headers("k-1"), "withdraw", String::new(), &request)
+                .unwrap()
+                .unwrap();
+        let changed =
+            IdempotencyKey::from_headers(&headers("k-1"), "withdraw", String::new(), &other)
+                .unwrap()
+                .unwrap();
+
+        assert_eq!(first.request_hash, retry.request_hash);
+        assert_ne!(first.request_hash, changed.request_hash);
+
+        assert!(IdempotencyKey::from_headers(
+            &HeaderMap::new(),
+            "withdraw",
+            String::new(),
+            &request
+        )
+        .unwrap()
+        .is_none());
+        assert!(
+            IdempotencyKey::from_headers(&headers(""), "withdraw", String::new(), &request)
+                .is_err()
+        );
+        assert!(IdempotencyKey::from_headers(
+            &headers(&"k".repeat(256)),
+            "withdraw",
+            String::new(),
+            &request
+        )
+        .is_err());
+    }
+}
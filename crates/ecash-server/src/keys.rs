@@ -0,0 +1,374 @@
+use crate::config::{Config, KeyConfig};
+use crate::db::Database;
+use crate::error::{ApiError, ApiResult};
+use crate::models::SigningKeyRecord;
+use ecash_core::{keyformat, InstitutionKey, KeyRing, KeyStatus, KeyValidity};
+use rsa::pkcs8::{DecodePrivateKey, EncodePrivateKey, EncodePublicKey, LineEnding};
+use rsa::RsaPrivateKey;
+use std::path::Path;
+
+/// Loads the institution keyring. Each key set (`key_id`) holds one RSA key
+/// per denomination of each configured currency; missing keys are generated
+/// only when `GENERATE_SIGNING_KEY` is set.
+///
+/// With `SIGNING_KEY_DIR` the ring holds the single key set `KEY_ID`, read from
+/// PEM files (encrypted PKCS#8 if `KEY_ENCRYPTION_SECRET` is set, otherwise
+/// PKCS#8 or PKCS#1) and expiring at `SIGNING_KEY_EXPIRES_AT`.
+/// Otherwise every key set of the institution in the `signing_keys` table is
+/// loaded, with its `status`, `expires_at` and `revoked_at` deciding whether
+/// it signs, only redeems, or is refused. Private keys are stored there as
+/// encrypted PKCS#8 documents, so every replica pointed at the same database
+/// signs with the same keys.
+pub async fn load_keyring(config: &Config, db: &Database) -> ApiResult<KeyRing> {
+    let institution = &config.institution;
+    let mut keys = KeyRing::new();
+
+    match &config.keys.private_key_dir {
+        Some(dir) => {
+            let validity = KeyValidity {
+                expires_at: config.keys.expires_at,
+                ..KeyValidity::unbounded()
+            };
+            let mut key =
+                InstitutionKey::new(institution.key_id.clone(), KeyStatus::Active, validity);
+            for (currency, denomination) in denomination_keys(config) {
+                let path = Path::new(dir).join(format!(
+                    "{}_{}_{}.pem",
+                    institution.key_id, currency, denomination
+                ));
+                let private_key = load_from_file(&config.keys, &path)?;
+                key = key.with_private_key(currency, denomination, private_key);
+            }
+            keys.insert(key);
+        }
+        None => {
+            let secret = config.keys.encryption_secret.as_ref().ok_or_else(|| {
+                ApiError::Internal(
+                    "KEY_ENCRYPTION_SECRET is required to store signing keys in the database"
+                        .to_string(),
+                )
+            })?;
+            ensure_database_keys(config, db, secret.expose()).await?;
+
+            let records = db.list_signing_keys(&institution.institution_id).await?;
+            for (_, records) in group_by_key_id(records) {
+                keys.insert(key_from_records(records, secret.expose())?);
+            }
+        }
+    }
+
+    for key in keys.iter() {
+        tracing::info!(
+            "Loaded key {} ({}, {} denominations, expires {:?})",
+            key.key_id(),
+            key.status(),
+            key.public_keys().count(),
+            key.validity().expires_at
+        );
+    }
+
+    Ok(keys)
+}
+
+/// Loads the institution's identity key, which signs its key directory.
+/// Wallets pin it, so unlike signing keys it is never rotated. With
+/// `SIGNING_KEY_DIR` it is the file `<institution_id>_identity.pem` there;
+/// otherwise it is kept encrypted in the `identity_keys` table. It is
+/// created only when `GENERATE_SIGNING_KEY` is set, and without it no key
+/// directory is published.
+pub async fn load_identity_key(config: &Config, db: &Database) -> ApiResult<Option<RsaPrivateKey>> {
+    let institution_id = &config.institution.institution_id;
+
+    if let Some(dir) = &config.keys.private_key_dir {
+        let path = Path::new(dir).join(format!("{}_identity.pem", institution_id));
+        if !path.exists() && !config.keys.generate {
+            tracing::warn!(
+                "Identity key {} not found; no key directory is published",
+                path.display()
+            );
+            return Ok(None);
+        }
+        return load_from_file(&config.keys, &path).map(Some);
+    }
+
+    let secret = config.keys.encryption_secret.as_ref().ok_or_else(|| {
+        ApiError::Internal(
+            "KEY_ENCRYPTION_SECRET is required to store keys in the database".to_string(),
+        )
+    })?;
+
+    if db.get_identity_key(institution_id).await?.is_none() {
+        if !config.keys.generate {
+            tracing::warn!(
+                "Institution {} has no identity key; no key directory is published",
+                institution_id
+            );
+            return Ok(None);
+        }
+
+        let private_key = generate_key(config.keys.bits)?;
+        let public_key_pem = private_key
+            .to_public_key()
+            .to_public_key_pem(LineEnding::LF)
+            .map_err(|e| ApiError::Internal(format!("Failed to encode public key: {}", e)))?;
+        // As with signing keys, the first replica to store one wins.
+        if db
+            .insert_identity_key(
+                institution_id,
+                &public_key_pem,
+                &encrypt_private_key(&private_key, secret.expose())?,
+            )
+            .await?
+        {
+            tracing::info!("Stored new identity key for {}", institution_id);
+        }
+    }
+
+    let encrypted = db
+        .get_identity_key(institution_id)
+        .await?
+        .ok_or_else(|| ApiError::Internal("Identity key disappeared".to_string()))?;
+    decrypt_private_key(&encrypted, secret.expose()).map(Some)
+}
+
+fn load_from_file(keys: &KeyConfig, path: &Path) -> ApiResult<RsaPrivateKey> {
+    if path.exists() {
+        let pem = std::fs::read_to_string(path)
+            .map_err(|e| ApiError::Internal(format!("Failed to read signing key: {}", e)))?;
+        let private_key = match &keys.encryption_secret {
+            Some(secret) => RsaPrivateKey::from_pkcs8_encrypted_pem(&pem, secret.expose())
+                .map_err(|e| e.to_string()),
+            None => keyformat::private_key_from_pem(&pem).map_err(|e| e.to_string()),
+        }
+        .map_err(|e| ApiError::Internal(format!("Invalid signing key file: {}", e)))?;
+
+        tracing::info!("Loaded signing key from {}", path.display());
+        return Ok(private_key);
+    }
+
+    if !keys.generate {
+        return Err(ApiError::Internal(format!(
+            "Signing key file {} not found; set GENERATE_SIGNING_KEY=true to create it",
+            path.display()
+        )));
+    }
+
+    let private_key = generate_key(keys.bits)?;
+    let pem = match &keys.encryption_secret {
+        Some(secret) => {
+            private_key.to_pkcs8_encrypted_pem(rand::thread_rng(), secret.expose(), LineEnding::LF)
+        }
+        None => private_key.to_pkcs8_pem(LineEnding::LF),
+    }
+    .map_err(|e| ApiError::Internal(format!("Failed to encode signing key: {}", e)))?;
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| ApiError::Internal(format!("Failed to create key directory: {}", e)))?;
+    }
+    write_private_file(path, pem.as_bytes())
+        .map_err(|e| ApiError::Internal(format!("Failed to write signing key: {}", e)))?;
+
+    tracing::info!("Wrote new signing key to {}", path.display());
+    Ok(private_key)
+}
+
+/// Every configured `(currency, denomination)` pair, each of which has its
+/// own key.
+fn denomination_keys(config: &Config) -> impl Iterator<Item = (&str, u64)> {
+    config.institution.currencies.iter().flat_map(|currency| {
+        currency
+            .denominations
+            .iter()
+            .map(|&denomination| (currency.code.as_str(), denomination))
+    })
+}
+
+/// Makes sure the configured key set has a key for every denomination of
+/// every currency.
+async fn ensure_database_keys(config: &Config, db: &Database, secret: &str) -> ApiResult<()> {
+    let institution = &config.institution;
+    let key_id = &institution.key_id;
+
+    for (currency, denomination) in denomination_keys(config) {
+        let existing = db.get_signing_key(key_id, currency, denomination).await?;
+
+        let record = match existing {
+            Some(record) => record,
+            None => {
+                if !config.keys.generate {
+                    return Err(ApiError::Internal(format!(
+                        "Signing key {} has no key for {} {}; set GENERATE_SIGNING_KEY=true to create it",
+                        key_id, denomination, currency
+                    )));
+                }
+
+                let private_key = generate_key(config.keys.bits)?;
+                let encrypted = encrypt_private_key(&private_key, secret)?;
+                let public_key_pem = private_key
+                    .to_public_key()
+                    .to_public_key_pem(LineEnding::LF)
+                    .map_err(|e| {
+                        ApiError::Internal(format!("Failed to encode public key: {}", e))
+                    })?;
+
+                // Another replica may have won the race; whichever row landed
+                // first is the key everyone uses.
+                if db
+                    .insert_signing_key(
+                        key_id,
+                        &institution.institution_id,
+                        currency,
+                        denomination,
+                        &public_key_pem,
+                        &encrypted,
+                    )
+                    .await?
+                {
+                    tracing::info!(
+                        "Stored new signing key {} for {} {}",
+                        key_id,
+                        denomination,
+                        currency
+                    );
+                }
+
+                db.get_signing_key(key_id, currency, denomination)
+                    .await?
+                    .ok_or_else(|| {
+                        ApiError::Internal(format!("Signing key {} disappeared", key_id))
+                    })?
+            }
+        };
+
+        if record.institution_id != institution.institution_id {
+            return Err(ApiError::Internal(format!(
+                "Signing key {} belongs to institution {}",
+                key_id, record.institution_id
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Groups rows into key sets, keeping the order in which each set first
+/// appears so later sets take precedence for signing.
+fn group_by_key_id(records: Vec<SigningKeyRecord>) -> Vec<(String, Vec<SigningKeyRecord>)> {
+    let mut sets: Vec<(String, Vec<SigningKeyRecord>)> = Vec::new();
+    for record in records {
+        match sets.iter_mut().find(|(key_id, _)| *key_id == record.key_id) {
+            Some((_, set)) => set.push(record),
+            None => sets.push((record.key_id.clone(), vec![record])),
+        }
+    }
+    sets
+}
+
+/// Builds a key set from its rows. Status and validity are kept per key set;
+/// only active sets have their private halves decrypted, redeem-only and
+/// revoked ones are loaded from the stored public keys.
+fn key_from_records(records: Vec<SigningKeyRecord>, secret: &str) -> ApiResult<InstitutionKey> {
+    let first = &records[0];
+    let status = KeyStatus::parse(&first.status).map_err(|_| {
+        ApiError::Internal(format!(
+            "Signing key {} has unknown status {}",
+            first.key_id, first.status
+        ))
+    })?;
+    let validity = KeyValidity {
+        created_at: first.created_at,
+        expires_at: first.expires_at,
+        revoked_at: first.revoked_at,
+    };
+    let mut key = InstitutionKey::new(first.key_id.clone(), status, validity);
+
+    for record in records {
+        let Some(denomination) = record.denomination else {
+            tracing::warn!(
+                "Skipping signing key {} without a denomination; rotate to a per-denomination key set",
+                record.key_id
+            );
+            continue;
+        };
+        let denomination = denomination as u64;
+
+        if status == KeyStatus::Active {
+            let encrypted = record.private_key_encrypted.ok_or_else(|| {
+                ApiError::Internal(format!(
+                    "Signing key {} has no stored private key for {}",
+                    record.key_id, denomination
+                ))
+            })?;
+            let private_key = decrypt_private_key(&encrypted, secret)?;
+            key = key.with_private_key(&record.currency, denomination, private_key);
+        } else {
+            let public_key =
+                keyformat::public_key_from_pem(&record.public_key_pem).map_err(|e| {
+                    ApiError::Internal(format!(
+                        "Invalid public key for signing key {}: {}",
+                        record.key_id, e
+                    ))
+                })?;
+            key = key.with_public_key(&record.currency, denomination, public_key);
+        }
+    }
+
+    Ok(key)
+}
+
+fn generate_key(bits: usize) -> ApiResult<RsaPrivateKey> {
+    tracing::info!("Generating new RSA key pair ({}-bit)...", bits);
+
+    let mut rng = rand::thread_rng();
+    RsaPrivateKey::new(&mut rng, bits)
+        .map_err(|e| ApiError::Internal(format!("Key generation failed: {}", e)))
+}
+
+/// Encrypts the key as a PKCS#8 `EncryptedPrivateKeyInfo` (PBES2 with scrypt
+/// and AES-256-CBC), deriving the key-encryption key from `secret`.
+pub fn encrypt_private_key(private_key: &RsaPrivateKey, secret: &str) -> ApiResult<Vec<u8>> {
+    let document = private_key
+        .to_pkcs8_encrypted_der(rand::thread_rng(), secret)
+        .map_err(|e| ApiError::Internal(format!("Failed to encrypt signing key: {}", e)))?;
+    Ok(document.as_bytes().to_vec())
+}
+
+pub fn decrypt_private_key(encrypted: &[u8], secret: &str) -> ApiResult<RsaPrivateKey> {
+    RsaPrivateKey::from_pkcs8_encrypted_der(encrypted, secret)
+        .map_err(|e| ApiError::Internal(format!("Failed to decrypt signing key: {}", e)))
+}
+
+#[cfg(unix)]
+fn write_private_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_private_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypted_key_round_trip() {
+        let private_key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+
+        let encrypted = encrypt_private_key(&private_key, "correct horse").unwrap();
+        let decrypted = decrypt_private_key(&encrypted, "correct horse").unwrap();
+        assert_eq!(decrypted, private_key);
+
+        assert!(decrypt_private_key(&encrypted, "battery staple").is_err());
+    }
+}
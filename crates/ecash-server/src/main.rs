@@ -1,21 +1,34 @@
+mod auth;
 mod cache;
 mod config;
 mod db;
 mod error;
+mod exchange;
 mod handlers;
+mod idempotency;
+mod keys;
+mod metrics;
 mod models;
+mod payouts;
+mod ratelimit;
+mod redemption;
+mod restore;
+mod spent;
 mod state;
 mod types;
 
 use crate::cache::RedisCache;
 use crate::config::Config;
 use crate::db::Database;
-use crate::state::{generate_or_load_keys, AppState};
+use crate::metrics::Metrics;
+use crate::ratelimit::{Route, RouteLimit};
+use crate::state::AppState;
 use axum::routing::{get, post};
-use axum::Router;
+use axum::{middleware, Router};
 use ecash_core::Institution;
 use sqlx::postgres::PgPoolOptions;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -42,35 +55,125 @@ async fn main() -> anyhow::Result<()> {
 
     sqlx::migrate!("./migrations").run(&db_pool).await.ok();
 
-    let database = Database::new(db_pool);
+    let metrics = Arc::new(Metrics::new()?);
 
-    let cache = RedisCache::new(&config.redis.url).await?;
-    tracing::info!("Redis connected");
+    let database = Database::new(db_pool, metrics.clone());
 
-    let (private_key, public_key) = generate_or_load_keys()?;
+    let cache = match &config.redis.url {
+        Some(url) => {
+            let cache = RedisCache::new(url, metrics.clone()).await?;
+            tracing::info!("Redis connected");
+            Some(cache)
+        }
+        None => {
+            tracing::info!("REDIS_URL not set; running without Redis");
+            None
+        }
+    };
+
+    let keyring = keys::load_keyring(&config, &database).await?;
 
     let institution = Institution::new(
-        private_key,
+        keyring,
         config.institution.institution_id.clone(),
-        config.institution.key_id.clone(),
-        config.institution.denominations.clone(),
+        config.institution.currencies.clone(),
         config.institution.token_expiry_days,
     );
 
-    tracing::info!(
-        "Institution initialized: {} (key: {})",
-        institution.institution_id(),
-        config.institution.key_id
-    );
-
-    let state = AppState::new(institution, public_key, database, cache, config.clone()).await;
+    match institution.signing_key() {
+        Some(key) => tracing::info!(
+            "Institution initialized: {} (signing key: {})",
+            institution.institution_id(),
+            key.key_id()
+        ),
+        None => tracing::warn!(
+            "Institution initialized: {} with no active signing key; withdrawals are disabled",
+            institution.institution_id()
+        ),
+    }
+
+    let identity_key = keys::load_identity_key(&config, &database).await?;
+
+    let payout_adapter = payouts::from_config(&config.payouts)?;
+    tracing::info!("Payout adapter: {}", payout_adapter.name());
+
+    let state = AppState::new(
+        institution,
+        identity_key,
+        database,
+        cache,
+        config.clone(),
+        payout_adapter,
+        metrics,
+    )
+    .await?;
+    tracing::info!("Spent serial store: {}", state.spent.name());
+    spent::spawn_pruning(state.spent.clone(), &config.spent);
+
+    let rate_limit =
+        |route| middleware::from_fn_with_state(RouteLimit::new(&state, route), ratelimit::enforce);
+
+    let account_routes = Router::new()
+        .route("/api/v1/account", get(handlers::get_account))
+        .route(
+            "/api/v1/withdraw",
+            post(handlers::withdraw).layer(rate_limit(Route::Withdraw)),
+        )
+        .route(
+            "/api/v1/redeem",
+            post(handlers::redeem).layer(rate_limit(Route::Redeem)),
+        )
+        .route(
+            "/api/v1/merchant/settlements",
+            get(handlers::get_settlement),
+        )
+        .route("/api/v1/merchant/payouts", post(handlers::create_payout))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_account,
+        ));
+
+    let admin_routes = Router::new()
+        .route("/api/v1/admin/accounts", post(handlers::create_account))
+        .route(
+            "/api/v1/admin/accounts/:account_id/deposit",
+            post(handlers::deposit),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_admin,
+        ));
+
+    let open_routes = Router::new()
+        .route(
+            "/api/v1/verify",
+            post(handlers::verify).layer(rate_limit(Route::Verify)),
+        )
+        .route(
+            "/api/v1/exchange",
+            post(handlers::exchange).layer(rate_limit(Route::Exchange)),
+        )
+        .route(
+            "/api/v1/restore",
+            post(handlers::restore).layer(rate_limit(Route::Restore)),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::optional_account,
+        ));
 
     let app = Router::new()
         .route("/health", get(handlers::health_check))
+        .route("/metrics", get(metrics::serve_metrics))
         .route("/api/v1/keys", get(handlers::get_public_key))
-        .route("/api/v1/withdraw", post(handlers::withdraw))
-        .route("/api/v1/redeem", post(handlers::redeem))
-        .route("/api/v1/verify", post(handlers::verify))
+        .route("/api/v1/keys/directory", get(handlers::get_key_directory))
+        .merge(open_routes)
+        .merge(account_routes)
+        .merge(admin_routes)
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track_requests,
+        ))
         .layer(TraceLayer::new_for_http())
         .with_state(state);
 
@@ -78,7 +181,11 @@ async fn main() -> anyhow::Result<()> {
     tracing::info!("Server listening on {}", addr);
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
@@ -0,0 +1,315 @@
+//! Prometheus metrics served at `/metrics`.
+//!
+//! Labels are limited to values with a small, configured range (route
+//! templates, status codes, currencies, denominations, key ids, store
+//! operations). Nothing that identifies a token or a client, such as a
+//! serial number or account id, is ever used as a label.
+
+use crate::error::{ApiError, ApiResult};
+use crate::state::AppState;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramTimer, HistogramVec, IntCounter, IntCounterVec,
+    Opts, Registry, TextEncoder,
+};
+use std::time::Instant;
+
+/// Latency buckets for store round trips and RSA operations, in seconds.
+const FAST_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    withdrawals: IntCounter,
+    redemptions: IntCounter,
+    exchanges: IntCounter,
+    verifications: IntCounterVec,
+    tokens_signed: IntCounterVec,
+    tokens_redeemed: IntCounterVec,
+    double_spend_rejections: IntCounter,
+    signature_failures: IntCounterVec,
+    signing_duration: Histogram,
+    postgres_duration: HistogramVec,
+    redis_duration: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new();
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by route and status"),
+            &["endpoint", "method", "status"],
+        )?;
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "HTTP request latency by route",
+            ),
+            &["endpoint", "method"],
+        )?;
+        let withdrawals = IntCounter::new("withdrawals_total", "Completed withdrawals")?;
+        let redemptions = IntCounter::new("redemptions_total", "Completed redemption batches")?;
+        let exchanges = IntCounter::new("exchanges_total", "Completed exchanges")?;
+        let verifications = IntCounterVec::new(
+            Opts::new("verifications_total", "Token verifications by outcome"),
+            &["result"],
+        )?;
+        let tokens_signed = IntCounterVec::new(
+            Opts::new("tokens_signed_total", "Blind signatures issued"),
+            &["currency", "denomination", "key_id"],
+        )?;
+        let tokens_redeemed = IntCounterVec::new(
+            Opts::new(
+                "tokens_redeemed_total",
+                "Tokens accepted for redemption or exchange",
+            ),
+            &["currency", "denomination"],
+        )?;
+        let double_spend_rejections = IntCounter::new(
+            "double_spend_rejections_total",
+            "Redemptions and exchanges refused because a token was already spent",
+        )?;
+        let signature_failures = IntCounterVec::new(
+            Opts::new(
+                "signature_failures_total",
+                "Tokens presented with an invalid signature",
+            ),
+            &["endpoint"],
+        )?;
+        let signing_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "rsa_signing_duration_seconds",
+                "Time to blind-sign one token",
+            )
+            .buckets(FAST_BUCKETS.to_vec()),
+        )?;
+        let postgres_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "postgres_query_duration_seconds",
+                "Postgres latency by operation",
+            )
+            .buckets(FAST_BUCKETS.to_vec()),
+            &["operation"],
+        )?;
+        let redis_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "redis_command_duration_seconds",
+                "Redis latency by operation",
+            )
+            .buckets(FAST_BUCKETS.to_vec()),
+            &["operation"],
+        )?;
+
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(http_request_duration.clone()))?;
+        registry.register(Box::new(withdrawals.clone()))?;
+        registry.register(Box::new(redemptions.clone()))?;
+        registry.register(Box::new(exchanges.clone()))?;
+        registry.register(Box::new(verifications.clone()))?;
+        registry.register(Box::new(tokens_signed.clone()))?;
+        registry.register(Box::new(tokens_redeemed.clone()))?;
+        registry.register(Box::new(double_spend_rejections.clone()))?;
+        registry.register(Box::new(signature_failures.clone()))?;
+        registry.register(Box::new(signing_duration.clone()))?;
+        registry.register(Box::new(postgres_duration.clone()))?;
+        registry.register(Box::new(redis_duration.clone()))?;
+
+        Ok(Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            withdrawals,
+            redemptions,
+            exchanges,
+            verifications,
+            tokens_signed,
+            tokens_redeemed,
+            double_spend_rejections,
+            signature_failures,
+            signing_duration,
+            postgres_duration,
+            redis_duration,
+        })
+    }
+
+    /// Starts timing a Postgres operation; the time is recorded when the
+    /// returned timer is dropped.
+    pub fn postgres_timer(&self, operation: &str) -> HistogramTimer {
+        self.postgres_duration
+            .with_label_values(&[operation])
+            .start_timer()
+    }
+
+    pub fn redis_timer(&self, operation: &str) -> HistogramTimer {
+        self.redis_duration
+            .with_label_values(&[operation])
+            .start_timer()
+    }
+
+    pub fn signing_timer(&self) -> HistogramTimer {
+        self.signing_duration.start_timer()
+    }
+
+    /// Counts a completed withdrawal, given each signed token's currency and
+    /// denomination.
+    pub fn record_withdrawal<'a>(
+        &self,
+        tokens: impl IntoIterator<Item = (&'a str, u64)>,
+        key_id: &str,
+    ) {
+        self.withdrawals.inc();
+        for (currency, denomination) in tokens {
+            self.tokens_signed
+                .with_label_values(&[currency, &denomination.to_string(), key_id])
+                .inc();
+        }
+    }
+
+    /// Counts a completed redemption batch, given each token's currency and
+    /// denomination.
+    pub fn record_redemption<'a>(&self, tokens: impl IntoIterator<Item = (&'a str, u64)>) {
+        self.redemptions.inc();
+        for (currency, denomination) in tokens {
+            self.tokens_redeemed
+                .with_label_values(&[currency, &denomination.to_string()])
+                .inc();
+        }
+    }
+
+    /// Counts a completed exchange, given each input's and each output's
+    /// currency and denomination.
+    pub fn record_exchange<'a>(
+        &self,
+        inputs: impl IntoIterator<Item = (&'a str, u64)>,
+        outputs: impl IntoIterator<Item = (&'a str, u64)>,
+        key_id: &str,
+    ) {
+        self.exchanges.inc();
+        for (currency, denomination) in inputs {
+            self.tokens_redeemed
+                .with_label_values(&[currency, &denomination.to_string()])
+                .inc();
+        }
+        for (currency, denomination) in outputs {
+            self.tokens_signed
+                .with_label_values(&[currency, &denomination.to_string(), key_id])
+                .inc();
+        }
+    }
+
+    pub fn record_verification(&self, result: &str) {
+        self.verifications.with_label_values(&[result]).inc();
+        if result == "invalid" {
+            self.signature_failures.with_label_values(&["verify"]).inc();
+        }
+    }
+
+    /// Counts the rejections worth alerting on from a failed redemption or
+    /// exchange at `endpoint`.
+    pub fn record_spend_error(&self, endpoint: &str, error: &ApiError) {
+        match error {
+            ApiError::TokenAlreadySpent => self.double_spend_rejections.inc(),
+            ApiError::InvalidSignature => {
+                self.signature_failures.with_label_values(&[endpoint]).inc()
+            }
+            _ => {}
+        }
+    }
+
+    fn record_request(&self, endpoint: &str, method: &str, status: StatusCode, seconds: f64) {
+        self.http_requests
+            .with_label_values(&[endpoint, method, status.as_str()])
+            .inc();
+        self.http_request_duration
+            .with_label_values(&[endpoint, method])
+            .observe(seconds);
+    }
+
+    pub fn encode(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+}
+
+/// Counts and times every routed request, labelled by its route template so
+/// path parameters never become label values.
+pub async fn track_requests(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let endpoint = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = request.method().as_str().to_string();
+    let start = Instant::now();
+
+    let response = next.run(request).await;
+
+    state.metrics.record_request(
+        &endpoint,
+        &method,
+        response.status(),
+        start.elapsed().as_secs_f64(),
+    );
+    response
+}
+
+pub async fn serve_metrics(State(state): State<AppState>) -> ApiResult<Response> {
+    let body = state
+        .metrics
+        .encode()
+        .map_err(|e| ApiError::Internal(format!("Failed to encode metrics: {}", e)))?;
+
+    Ok(([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_are_exported_with_bounded_labels() {
+        let metrics = Metrics::new().unwrap();
+
+        metrics.record_withdrawal([("USD", 50), ("USD", 50), ("USD", 10)], "key_001");
+        metrics.record_redemption([("USD", 50), ("USD", 50)]);
+        metrics.record_spend_error("redeem", &ApiError::TokenAlreadySpent);
+        metrics.record_spend_error("redeem", &ApiError::InvalidSignature);
+        metrics.record_exchange([("USD", 100)], [("USD", 50), ("USD", 50)], "key_001");
+        metrics.record_verification("invalid");
+        metrics.record_request("/api/v1/withdraw", "POST", StatusCode::OK, 0.01);
+        drop(metrics.signing_timer());
+        drop(metrics.postgres_timer("withdraw"));
+
+        let text = metrics.encode().unwrap();
+        assert!(text.contains(
+            r#"tokens_signed_total{currency="USD",denomination="50",key_id="key_001"} 4"#
+        ));
+        assert!(text.contains(
+            r#"tokens_signed_total{currency="USD",denomination="10",key_id="key_001"} 1"#
+        ));
+        assert!(text.contains(r#"tokens_redeemed_total{currency="USD",denomination="50"} 2"#));
+        assert!(text.contains(r#"tokens_redeemed_total{currency="USD",denomination="100"} 1"#));
+        assert!(text.contains("exchanges_total 1"));
+        assert!(text.contains("double_spend_rejections_total 1"));
+        assert!(text.contains(r#"signature_failures_total{endpoint="redeem"} 1"#));
+        assert!(text.contains(r#"signature_failures_total{endpoint="verify"} 1"#));
+        assert!(text.contains(
+            r#"http_requests_total{endpoint="/api/v1/withdraw",method="POST",status="200"} 1"#
+        ));
+        assert!(text.contains("rsa_signing_duration_seconds_count 1"));
+        assert!(text.contains(r#"postgres_query_duration_seconds_count{operation="withdraw"} 1"#));
+        assert!(!text.contains("serial"));
+    }
+}
@@ -1,19 +1,67 @@
+use crate::error::{ApiError, ApiResult};
 use chrono::{DateTime, Utc};
-use serde::Serialize;
+use ecash_core::IssuedToken;
+use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, FromRow, Serialize)]
-pub struct TokenRecord {
+pub struct SigningKeyRecord {
     pub id: Uuid,
-    pub serial_number: Vec<u8>,
-    pub serial_hex: String,
-    pub denomination: i64,
+    pub key_id: String,
+    pub institution_id: String,
     pub currency: String,
+    /// `None` for keys created before keys were per denomination.
+    pub denomination: Option<i64>,
+    pub public_key_pem: String,
+    #[serde(skip_serializing)]
+    pub private_key_encrypted: Option<Vec<u8>>,
     pub status: String,
-    pub redeemed_at: DateTime<Utc>,
-    pub merchant_id: Option<String>,
     pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountKind {
+    Customer,
+    Merchant,
+}
+
+impl AccountKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccountKind::Customer => "customer",
+            AccountKind::Merchant => "merchant",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "customer" => Some(AccountKind::Customer),
+            "merchant" => Some(AccountKind::Merchant),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct AccountRecord {
+    pub id: Uuid,
+    pub name: String,
+    pub kind: String,
+    pub currency: String,
+    pub balance: i64,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct IdempotencyRecord {
+    pub request_hash: Vec<u8>,
+    /// `None` while the original request is still in flight.
+    pub response: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Clone, FromRow, Serialize)]
@@ -28,5 +76,51 @@ pub struct TransactionRecord {
     pub status: String,
     pub error_message: Option<String>,
     pub request_data: Option<serde_json::Value>,
+    pub account_id: Option<Uuid>,
     pub created_at: DateTime<Utc>,
 }
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct PayoutRecord {
+    pub id: Uuid,
+    pub account_id: Uuid,
+    pub amount: i64,
+    pub currency: String,
+    pub destination: String,
+    pub adapter: String,
+    pub status: String,
+    pub reference: Option<String>,
+    pub error_message: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct IssuedTokenRecord {
+    pub recovery_id: Vec<u8>,
+    pub blinded_message: Vec<u8>,
+    pub blind_signature: Vec<u8>,
+    pub denomination: i64,
+    pub currency: String,
+    pub key_id: String,
+    pub expiry_epoch: i64,
+}
+
+impl IssuedTokenRecord {
+    pub fn into_issued_token(self) -> ApiResult<IssuedToken> {
+        let unsigned = |value: i64| {
+            u64::try_from(value).map_err(|_| {
+                ApiError::Internal(format!("Negative value {} in issued_tokens", value))
+            })
+        };
+        Ok(IssuedToken {
+            recovery_id: self.recovery_id,
+            blinded_message: self.blinded_message,
+            blind_signature: self.blind_signature,
+            denomination: unsigned(self.denomination)?,
+            currency: self.currency,
+            key_id: self.key_id,
+            expiry_epoch: unsigned(self.expiry_epoch)?,
+        })
+    }
+}
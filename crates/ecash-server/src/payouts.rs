@@ -0,0 +1,184 @@
+//! Payout adapters that move merchant balance out of the system.
+//!
+//! A payout is debited and recorded as `pending` before its adapter is
+//! called. The adapter's reference completes it; an adapter error marks it
+//! `failed` and credits the amount back. A payout left `pending` by a crash
+//! between those steps is still debited and must be reconciled against the
+//! adapter by an operator.
+
+use crate::config::PayoutConfig;
+use async_trait::async_trait;
+use chrono::Utc;
+use ecash_core::Amount;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// What an adapter is asked to pay out.
+#[derive(Debug, Clone, Serialize)]
+pub struct PayoutInstruction {
+    pub payout_id: Uuid,
+    pub account_id: Uuid,
+    pub amount: Amount,
+    pub destination: String,
+}
+
+#[async_trait]
+pub trait PayoutAdapter: Send + Sync {
+    /// Stored with each payout to record which adapter handled it.
+    fn name(&self) -> &'static str;
+
+    /// Sends the payout, returning the adapter's reference for it. Must be
+    /// safe to repeat for the same `payout_id`.
+    async fn send(&self, payout: &PayoutInstruction) -> anyhow::Result<String>;
+}
+
+pub fn from_config(config: &PayoutConfig) -> anyhow::Result<Arc<dyn PayoutAdapter>> {
+    match config.adapter.as_str() {
+        "file" => Ok(Arc::new(FilePayoutAdapter::new(&config.file_path))),
+        "mock" => Ok(Arc::new(MockPayoutAdapter::new())),
+        other => anyhow::bail!("Unknown PAYOUT_ADAPTER {:?}; expected file or mock", other),
+    }
+}
+
+/// Appends each payout as a JSON line to a local file, for development and
+/// for settling by hand.
+pub struct FilePayoutAdapter {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+#[derive(Serialize)]
+struct FilePayoutEntry<'a> {
+    #[serde(flatten)]
+    payout: &'a PayoutInstruction,
+    reference: &'a str,
+    requested_at: String,
+}
+
+impl FilePayoutAdapter {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+#[async_trait]
+impl PayoutAdapter for FilePayoutAdapter {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    async fn send(&self, payout: &PayoutInstruction) -> anyhow::Result<String> {
+        let reference = format!("file:{}", payout.payout_id);
+        let mut line = serde_json::to_vec(&FilePayoutEntry {
+            payout,
+            reference: &reference,
+            requested_at: Utc::now().to_rfc3339(),
+        })?;
+        line.push(b'\n');
+
+        // Appends from concurrent payouts must not interleave.
+        let _guard = self.lock.lock().await;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(&line).await?;
+        file.sync_data().await?;
+
+        Ok(reference)
+    }
+}
+
+/// Accepts every payout without sending anything.
+#[derive(Default)]
+pub struct MockPayoutAdapter {
+    sent: std::sync::Mutex<Vec<PayoutInstruction>>,
+}
+
+impl MockPayoutAdapter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[cfg(test)]
+    fn sent(&self) -> Vec<PayoutInstruction> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl PayoutAdapter for MockPayoutAdapter {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    async fn send(&self, payout: &PayoutInstruction) -> anyhow::Result<String> {
+        tracing::info!(
+            "Mock payout {} of {} to {}",
+            payout.payout_id,
+            payout.amount,
+            payout.destination
+        );
+        self.sent.lock().unwrap().push(payout.clone());
+        Ok(format!("mock:{}", payout.payout_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ecash_core::Currency;
+
+    fn instruction(amount: u64) -> PayoutInstruction {
+        PayoutInstruction {
+            payout_id: Uuid::new_v4(),
+            account_id: Uuid::new_v4(),
+            amount: Currency::new("USD", vec![1]).unwrap().amount(amount),
+            destination: "acct-123".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_adapter_appends_json_lines() {
+        let path = std::env::temp_dir().join(format!("payouts-{}.jsonl", Uuid::new_v4()));
+        let adapter = FilePayoutAdapter::new(&path);
+
+        let first = instruction(100);
+        let second = instruction(250);
+        let reference = adapter.send(&first).await.unwrap();
+        adapter.send(&second).await.unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["payout_id"], first.payout_id.to_string());
+        assert_eq!(lines[0]["reference"], reference);
+        assert_eq!(lines[1]["amount"]["value"], "2.50");
+        assert_eq!(lines[1]["amount"]["currency"], "USD");
+    }
+
+    #[tokio::test]
+    async fn test_mock_adapter_records_payouts() {
+        let adapter = MockPayoutAdapter::new();
+        let payout = instruction(100);
+
+        let reference = adapter.send(&payout).await.unwrap();
+
+        assert_eq!(reference, format!("mock:{}", payout.payout_id));
+        assert_eq!(adapter.sent().len(), 1);
+        assert_eq!(adapter.sent()[0].destination, "acct-123");
+    }
+}
@@ -0,0 +1,311 @@
+//! Per-client rate limits for withdraw, redeem and verify.
+//!
+//! Requests are counted in fixed windows of `RATE_LIMIT_WINDOW_SECONDS`.
+//! Withdrawals are limited per account, redemptions per redeeming merchant,
+//! verifications per API key and exchanges per account, or per client IP
+//! for anonymous requests.
+//! Counters live in Redis so every replica shares them; while Redis is
+//! unreachable each process falls back to counting on its own. Responses
+//! carry `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset`
+//! (Unix seconds at which the window ends).
+
+use crate::auth::{self, AuthenticatedAccount};
+use crate::cache::RedisCache;
+use crate::config::RateLimitConfig;
+use crate::error::{ApiError, ApiResult};
+use crate::state::AppState;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+const LIMIT_HEADER: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+const REMAINING_HEADER: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+const RESET_HEADER: HeaderName = HeaderName::from_static("x-ratelimit-reset");
+
+/// Local windows are pruned once this many clients are tracked.
+const MAX_LOCAL_ENTRIES: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    Withdraw,
+    Redeem,
+    Verify,
+    Exchange,
+    Restore,
+}
+
+impl Route {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Route::Withdraw => "withdraw",
+            Route::Redeem => "redeem",
+            Route::Verify => "verify",
+            Route::Exchange => "exchange",
+            Route::Restore => "restore",
+        }
+    }
+
+    fn limit(&self, config: &RateLimitConfig) -> u32 {
+        match self {
+            Route::Withdraw => config.withdraw,
+            Route::Redeem => config.redeem,
+            Route::Verify => config.verify,
+            Route::Exchange => config.exchange,
+            Route::Restore => config.restore,
+        }
+    }
+}
+
+/// Outcome of counting one request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitStatus {
+    pub limit: u32,
+    pub remaining: u32,
+    /// Unix time at which the current window ends.
+    pub reset: i64,
+    pub allowed: bool,
+}
+
+impl RateLimitStatus {
+    fn new(limit: u32, count: u64, reset: i64) -> Self {
+        Self {
+            limit,
+            remaining: (limit as u64).saturating_sub(count) as u32,
+            reset,
+            allowed: count <= limit as u64,
+        }
+    }
+
+    /// Seconds until the window ends, at least one.
+    pub fn retry_after(&self, now: i64) -> u64 {
+        (self.reset - now).max(1) as u64
+    }
+
+    fn apply(&self, headers: &mut HeaderMap) {
+        headers.insert(LIMIT_HEADER, HeaderValue::from(self.limit));
+        headers.insert(REMAINING_HEADER, HeaderValue::from(self.remaining));
+        headers.insert(RESET_HEADER, HeaderValue::from(self.reset));
+    }
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    redis: Option<Arc<RedisCache>>,
+    local: Mutex<HashMap<String, (i64, u64)>>,
+}
+
+impl RateLimiter {
+    /// Counts in Redis when `redis` is given, locally otherwise.
+    pub fn new(config: RateLimitConfig, redis: Option<Arc<RedisCache>>) -> Self {
+        Self {
+            config,
+            redis,
+            local: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a request by `client` against the route's limit, or returns
+    /// `None` if the route is unlimited.
+    pub async fn check(&self, route: Route, client: &str, now: i64) -> Option<RateLimitStatus> {
+        let limit = route.limit(&self.config);
+        if limit == 0 {
+            return None;
+        }
+
+        let window_seconds = self.config.window_seconds.max(1) as i64;
+        let window = now.div_euclid(window_seconds);
+        let reset = (window + 1) * window_seconds;
+        let key = format!("ratelimit:{}:{}:{}", route.as_str(), client, window);
+
+        let count = match &self.redis {
+            Some(redis) => match redis.increment_window(&key, window_seconds).await {
+                Ok(count) => count,
+                Err(error) => {
+                    tracing::warn!(
+                        "Rate limit counter unavailable, counting locally: {}",
+                        error
+                    );
+                    self.increment_local(key, window)
+                }
+            },
+            None => self.increment_local(key, window),
+        };
+
+        Some(RateLimitStatus::new(limit, count, reset))
+    }
+
+    fn increment_local(&self, key: String, window: i64) -> u64 {
+        let mut local = self.local.lock().unwrap();
+        if local.len() >= MAX_LOCAL_ENTRIES {
+            local.retain(|_, (entry_window, _)| *entry_window == window);
+        }
+
+        let entry = local.entry(key).or_insert((window, 0));
+        entry.1 += 1;
+        entry.1
+    }
+
+    fn trust_forwarded_for(&self) -> bool {
+        self.config.trust_forwarded_for
+    }
+}
+
+/// Middleware state binding the shared limiter to one route.
+#[derive(Clone)]
+pub struct RouteLimit {
+    state: AppState,
+    route: Route,
+}
+
+impl RouteLimit {
+    pub fn new(state: &AppState, route: Route) -> Self {
+        Self {
+            state: state.clone(),
+            route,
+        }
+    }
+}
+
+/// Rejects the request with `429 Too Many Requests` once its client has used
+/// up the route's limit. Must run after authentication so authenticated
+/// clients are counted by account or key rather than by address.
+pub async fn enforce(
+    State(limit): State<RouteLimit>,
+    request: Request,
+    next: Next,
+) -> ApiResult<Response> {
+    let limiter = &limit.state.rate_limiter;
+    let client = client_id(limit.route, &request, limiter.trust_forwarded_for());
+    let now = Utc::now().timestamp();
+
+    let Some(status) = limiter.check(limit.route, &client, now).await else {
+        return Ok(next.run(request).await);
+    };
+
+    let mut response = if status.allowed {
+        next.run(request).await
+    } else {
+        tracing::debug!("Rate limit exceeded on {}", limit.route.as_str());
+        ApiError::RateLimitExceeded {
+            retry_after: status.retry_after(now),
+        }
+        .into_response()
+    };
+
+    status.apply(response.headers_mut());
+    Ok(response)
+}
+
+/// Who a request is counted against. Withdrawals, redemptions and exchanges
+/// count per account, verifications per API key; restores and anonymous
+/// requests per address.
+fn client_id(route: Route, request: &Request, trust_forwarded_for: bool) -> String {
+    if let Some(account) = request.extensions().get::<AuthenticatedAccount>() {
+        match route {
+            Route::Withdraw | Route::Redeem | Route::Exchange => {
+                return format!("account:{}", account.account_id);
+            }
+            Route::Verify => {
+                if let Some(key) = auth::credential(request.headers()) {
+                    // A prefix of the hash is enough to tell keys apart
+                    // without putting the key itself into Redis.
+                    return format!("key:{}", hex::encode(&auth::hash_api_key(key)[..16]));
+                }
+            }
+            Route::Restore => {}
+        }
+    }
+
+    let forwarded = trust_forwarded_for
+        .then(|| request.headers().get("x-forwarded-for"))
+        .flatten()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(|ip| ip.trim().to_string());
+    let peer = || {
+        request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string())
+    };
+
+    format!(
+        "ip:{}",
+        forwarded
+            .or_else(peer)
+            .unwrap_or_else(|| "unknown".to_string())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(withdraw: u32) -> RateLimiter {
+        RateLimiter::new(
+            RateLimitConfig {
+                backend: "memory".to_string(),
+                window_seconds: 60,
+                withdraw,
+                redeem: 100,
+                verify: 0,
+                exchange: 100,
+                restore: 0,
+                trust_forwarded_for: false,
+            },
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_limit_applies_per_client_and_window() {
+        let limiter = limiter(3);
+        let now = 1_701_774_610;
+
+        for remaining in [2, 1, 0] {
+            let status = limiter.check(Route::Withdraw, "a", now).await.unwrap();
+            assert!(status.allowed);
+            assert_eq!(status.remaining, remaining);
+            assert_eq!(status.reset, 1_701_774_660);
+        }
+
+        let denied = limiter.check(Route::Withdraw, "a", now).await.unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.remaining, 0);
+        assert_eq!(denied.retry_after(now), 50);
+
+        assert!(
+            limiter
+                .check(Route::Withdraw, "b", now)
+                .await
+                .unwrap()
+                .allowed
+        );
+        assert!(
+            limiter
+                .check(Route::Redeem, "a", now)
+                .await
+                .unwrap()
+                .allowed
+        );
+        assert!(
+            limiter
+                .check(Route::Withdraw, "a", now + 50)
+                .await
+                .unwrap()
+                .allowed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_zero_limit_disables_route() {
+        let limiter = limiter(0);
+        assert!(limiter.check(Route::Withdraw, "a", 0).await.is_none());
+        assert!(limiter.check(Route::Verify, "a", 0).await.is_none());
+    }
+}
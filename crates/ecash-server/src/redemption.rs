@@ -0,0 +1,351 @@
+//! All-or-nothing redemption of a token batch.
+//!
+//! Every token is checked before any serial is marked spent. The serials are
+//! then reserved in the spent-serial store in a single atomic step, and the
+//! credit to the redeeming account is recorded in one Postgres transaction
+//! that also records the serials when Postgres holds them; if that
+//! transaction fails the reservation is released, so a failed request
+//! changes no state.
+
+use crate::db::{self, TransactionLog};
+use crate::error::{ApiError, ApiResult};
+use crate::idempotency::IdempotencyKey;
+use crate::state::AppState;
+use crate::types::RedeemResponse;
+use ecash_core::{Amount, Institution, Token};
+use sqlx::{Postgres, Transaction};
+use std::collections::HashSet;
+use std::future::Future;
+use uuid::Uuid;
+
+/// A batch in which every token is unexpired, correctly signed and unique,
+/// and all are in one currency.
+pub struct ValidatedBatch<'a> {
+    tokens: &'a [Token],
+    total_amount: Amount,
+}
+
+impl ValidatedBatch<'_> {
+    pub fn total_amount(&self) -> &Amount {
+        &self.total_amount
+    }
+
+    /// The currency shared by every token of the batch.
+    pub fn currency(&self) -> &str {
+        &self.tokens[0].currency
+    }
+}
+
+/// Checks the whole batch without touching any store, failing on the first
+/// bad token.
+pub fn validate_batch<'a>(
+    institution: &Institution,
+    tokens: &'a [Token],
+) -> ApiResult<ValidatedBatch<'a>> {
+    if tokens.is_empty() {
+        return Err(ApiError::InvalidRequest("No tokens provided".to_string()));
+    }
+
+    let currency = institution
+        .currency(&tokens[0].currency)
+        .map_err(ApiError::Ecash)?;
+    let mut seen = HashSet::with_capacity(tokens.len());
+    let mut total_amount = currency.amount(0);
+
+    for token in tokens {
+        if token.is_expired() {
+            return Err(ApiError::TokenExpired);
+        }
+
+        if token.currency != tokens[0].currency {
+            return Err(ApiError::InvalidRequest(
+                "All tokens must be in the same currency".to_string(),
+            ));
+        }
+
+        if !seen.insert(&token.serial_number) {
+            return Err(ApiError::InvalidRequest(
+                "Batch contains the same token twice".to_string(),
+            ));
+        }
+
+        if !institution.verify_token(token).map_err(ApiError::Ecash)? {
+            return Err(ApiError::InvalidSignature);
+        }
+
+        total_amount = total_amount
+            .checked_add(&token.amount(currency)?)
+            .map_err(|_| ApiError::InvalidRequest("Batch amount overflows".to_string()))?;
+    }
+
+    Ok(ValidatedBatch {
+        tokens,
+        total_amount,
+    })
+}
+
+/// Marks every token of the batch spent and credits its value to
+/// `account_id`, or does neither.
+pub async fn commit_batch(
+    state: &AppState,
+    batch: &ValidatedBatch<'_>,
+    account_id: Uuid,
+    idempotency_key: Option<&IdempotencyKey>,
+) -> ApiResult<RedeemResponse> {
+    spend_batch(state, batch, |tx| {
+        record_batch(state, batch, tx, account_id, idempotency_key)
+    })
+    .await
+}
+
+/// Reserves every token of the batch, opens the ledger transaction, marks the
+/// tokens spent in it, then hands it to `record`, which writes what the
+/// tokens were spent on and commits. If any step fails the reservation is
+/// released.
+pub async fn spend_batch<T, F, Fut>(
+    state: &AppState,
+    batch: &ValidatedBatch<'_>,
+    record: F,
+) -> ApiResult<T>
+where
+    F: FnOnce(Transaction<'static, Postgres>) -> Fut,
+    Fut: Future<Output = ApiResult<T>>,
+{
+    with_reservation(state, batch, || spend_reserved(state, batch, record)).await
+}
+
+/// Reserves every token of the batch and runs `spend`, releasing the
+/// reservation if it fails. A batch with a spent token is refused before
+/// `spend` is called.
+pub async fn with_reservation<T, F, Fut>(
+    state: &AppState,
+    batch: &ValidatedBatch<'_>,
+    spend: F,
+) -> ApiResult<T>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = ApiResult<T>>,
+{
+    if !state.spent.reserve(batch.tokens).await? {
+        return Err(ApiError::TokenAlreadySpent);
+    }
+
+    let result = spend().await;
+
+    if result.is_err() {
+        if let Err(release_error) = state.spent.release(batch.tokens).await {
+            // The serials are refused until the reservation expires, with
+            // nothing recorded for them.
+            tracing::error!(
+                "Failed to release serials after aborted spend: {}",
+                release_error
+            );
+        }
+    }
+
+    result
+}
+
+/// Opens the ledger transaction for a reserved batch, marks its tokens spent
+/// in it and hands it to `record`.
+pub async fn spend_reserved<T, F, Fut>(
+    state: &AppState,
+    batch: &ValidatedBatch<'_>,
+    record: F,
+) -> ApiResult<T>
+where
+    F: FnOnce(Transaction<'static, Postgres>) -> Fut,
+    Fut: Future<Output = ApiResult<T>>,
+{
+    let mut tx = state.db.begin().await?;
+    if !state.spent.mark_all_spent_in(&mut tx, batch.tokens).await? {
+        return Err(ApiError::TokenAlreadySpent);
+    }
+    record(tx).await
+}
+
+/// Writes the account credit, the transaction log entry and the stored
+/// idempotent response in `tx`, and commits it.
+async fn record_batch(
+    state: &AppState,
+    batch: &ValidatedBatch<'_>,
+    mut tx: Transaction<'static, Postgres>,
+    account_id: Uuid,
+    idempotency_key: Option<&IdempotencyKey>,
+) -> ApiResult<RedeemResponse> {
+    let _timer = state.metrics.postgres_timer("redeem");
+
+    let balance = db::credit_account(&mut tx, account_id, &batch.total_amount).await?;
+    let record = db::insert_transaction_log(
+        &mut *tx,
+        TransactionLog {
+            transaction_type: "redeem",
+            amount: &batch.total_amount,
+            denomination: batch.tokens[0].denomination,
+            token_count: batch.tokens.len(),
+            institution_id: state.institution_id(),
+            key_id: &batch.tokens[0].key_id,
+            status: "success",
+            error_message: None,
+            account_id: Some(account_id),
+        },
+    )
+    .await?;
+
+    let response = RedeemResponse {
+        accepted_count: batch.tokens.len(),
+        total_amount: batch.total_amount.clone(),
+        transaction_id: record.id.to_string(),
+        timestamp: record.created_at.to_rfc3339(),
+        balance,
+    };
+
+    if let Some(idempotency_key) = idempotency_key {
+        idempotency_key.complete(&mut tx, &response).await?;
+    }
+    tx.commit().await?;
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Utc};
+    use ecash_core::{
+        Currency, Decomposition, InstitutionKey, KeyRing, KeyStatus, KeyValidity, Wallet,
+    };
+    use rsa::RsaPrivateKey;
+    use std::collections::BTreeMap;
+
+    fn setup() -> (Institution, Vec<Token>) {
+        let mut rng = rand::thread_rng();
+        let private_key = RsaPrivateKey::new(&mut rng, 1024).unwrap();
+        let public_key = private_key.to_public_key();
+
+        let mut keys = KeyRing::new();
+        keys.insert(
+            InstitutionKey::new(
+                "key_001".to_string(),
+                KeyStatus::Active,
+                KeyValidity::unbounded(),
+            )
+            .with_private_key("USD", 10, private_key),
+        );
+        let currencies = vec![Currency::new("USD", vec![10]).unwrap()];
+        let institution = Institution::new(keys, "inst_test".to_string(), currencies, 90);
+
+        let wallet = Wallet::new(
+            BTreeMap::from([(10, public_key)]),
+            "inst_test".to_string(),
+            "key_001".to_string(),
+            "USD".to_string(),
+        );
+        let (blinded, metadata): (Vec<_>, Vec<_>) = wallet
+            .prepare_withdrawal(
+                &institution.currencies()[0].amount(50),
+                Decomposition::Greedy,
+                institution.current_expiry_epoch(),
+            )
+            .unwrap()
+            .into_iter()
+            .unzip();
+        let signatures = blinded
+            .iter()
+            .map(|token| institution.sign_blinded_token(token).unwrap())
+            .collect();
+        let tokens = wallet.finalize_withdrawal(signatures, metadata).unwrap();
+
+        (institution, tokens)
+    }
+
+    #[test]
+    fn test_valid_batch_is_accepted_whole() {
+        let (institution, tokens) = setup();
+
+        let batch = validate_batch(&institution, &tokens).unwrap();
+        assert_eq!(batch.tokens.len(), 5);
+        assert_eq!(batch.total_amount.minor_units(), 50);
+    }
+
+    #[test]
+    fn test_mid_batch_failures_reject_the_whole_batch() {
+        let (institution, tokens) = setup();
+
+        let mut forged = tokens.clone();
+        forged[2].signature[0] ^= 1;
+        assert!(matches!(
+            validate_batch(&institution, &forged),
+            Err(ApiError::InvalidSignature)
+        ));
+
+        let mut expired = tokens.clone();
+        expired[2].expires_at = Utc::now() - Duration::seconds(1);
+        assert!(matches!(
+            validate_batch(&institution, &expired),
+            Err(ApiError::TokenExpired)
+        ));
+
+        let mut duplicated = tokens.clone();
+        duplicated[2] = duplicated[0].clone();
+        assert!(matches!(
+            validate_batch(&institution, &duplicated),
+            Err(ApiError::InvalidRequest(_))
+        ));
+
+        let mut relabelled = tokens.clone();
+        relabelled[2].denomination = 500;
+        assert!(matches!(
+            validate_batch(&institution, &relabelled),
+            Err(ApiError::Ecash(_))
+        ));
+
+        let mut mixed = tokens.clone();
+        mixed[2].currency = "EUR".to_string();
+        assert!(matches!(
+            validate_batch(&institution, &mixed),
+            Err(ApiError::InvalidRequest(_))
+        ));
+
+        assert!(matches!(
+            validate_batch(&institution, &[]),
+            Err(ApiError::InvalidRequest(_))
+        ));
+    }
+
+    async fn assert_none_spent(state: &AppState, tokens: &[Token]) {
+        for token in tokens {
+            assert!(!state.spent.is_spent(token).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_with_a_spent_token_reserves_nothing() {
+        let (institution, tokens) = setup();
+        let state = AppState::for_tests(institution);
+        assert!(state.spent.mark_all_spent(&tokens[2..3]).await.unwrap());
+
+        let batch = validate_batch(&state.institution, &tokens).unwrap();
+        let result = spend_batch(&state, &batch, |_tx| async { Ok(()) }).await;
+
+        assert!(matches!(result, Err(ApiError::TokenAlreadySpent)));
+        assert_none_spent(&state, &tokens[..2]).await;
+        assert_none_spent(&state, &tokens[3..]).await;
+    }
+
+    #[tokio::test]
+    async fn test_failed_spend_releases_the_reservation() {
+        let (institution, tokens) = setup();
+        let state = AppState::for_tests(institution);
+
+        let batch = validate_batch(&state.institution, &tokens).unwrap();
+        let result: ApiResult<()> = with_reservation(&state, &batch, || async {
+            Err(ApiError::Internal("spend failed".to_string()))
+        })
+        .await;
+
+        assert!(matches!(result, Err(ApiError::Internal(_))));
+        assert_none_spent(&state, &tokens).await;
+        assert!(state.spent.reserve(&tokens).await.unwrap());
+    }
+}
@@ -0,0 +1,117 @@
+//! Issuance records for wallet recovery.
+//!
+//! A seeded wallet sends a recovery id with each blinded token it withdraws
+//! or receives from an exchange. The blind signature is stored under that id
+//! in the same transaction that debits the account or logs the exchange, so
+//! a signature is kept exactly when the token was paid for. A wallet
+//! restored from its seed phrase re-derives the ids and fetches the records
+//! with `/api/v1/restore`. Only stored signatures are returned; nothing is
+//! signed again, so the endpoint is not a signing oracle and needs no
+//! credentials.
+
+use crate::db;
+use crate::error::{ApiError, ApiResult};
+use crate::state::AppState;
+use crate::types::{RestoreRequest, RestoreResponse};
+use ecash_core::recovery::RECOVERY_ID_LEN;
+use ecash_core::{BlindSignature, BlindedToken, IssuedToken};
+use sqlx::PgConnection;
+
+/// Recovery ids accepted by one `/api/v1/restore` request.
+pub const MAX_RECOVERY_IDS: usize = 1000;
+
+/// Refuses recovery ids that a seeded wallet could not have derived, before
+/// anything is signed.
+pub fn validate_recovery_ids(blinded_tokens: &[BlindedToken]) -> ApiResult<()> {
+    if blinded_tokens
+        .iter()
+        .filter_map(|token| token.recovery_id.as_ref())
+        .any(|recovery_id| recovery_id.len() != RECOVERY_ID_LEN)
+    {
+        return Err(ApiError::InvalidRequest(format!(
+            "Recovery ids must be {} bytes",
+            RECOVERY_ID_LEN
+        )));
+    }
+    Ok(())
+}
+
+/// Stores the signature of every token that carries a recovery id. Runs in
+/// the transaction that pays for the tokens.
+pub async fn record_issued(
+    conn: &mut PgConnection,
+    blinded_tokens: &[BlindedToken],
+    blind_signatures: &[BlindSignature],
+    expiry_epoch: u64,
+) -> ApiResult<()> {
+    for (token, signature) in blinded_tokens.iter().zip(blind_signatures) {
+        let Some(recovery_id) = &token.recovery_id else {
+            continue;
+        };
+        db::insert_issued_token(
+            &mut *conn,
+            &IssuedToken {
+                recovery_id: recovery_id.clone(),
+                blinded_message: token.blinded_message.clone(),
+                blind_signature: signature.signature.clone(),
+                denomination: token.denomination,
+                currency: token.currency.clone(),
+                key_id: token.key_id.clone(),
+                expiry_epoch,
+            },
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// The stored records for the requested ids. Unknown ids are left out, which
+/// is how a restoring wallet finds the end of what it issued.
+pub async fn find_issued(state: &AppState, request: &RestoreRequest) -> ApiResult<RestoreResponse> {
+    if request.recovery_ids.is_empty() {
+        return Err(ApiError::InvalidRequest(
+            "No recovery ids provided".to_string(),
+        ));
+    }
+    if request.recovery_ids.len() > MAX_RECOVERY_IDS {
+        return Err(ApiError::InvalidRequest(format!(
+            "At most {} recovery ids per request",
+            MAX_RECOVERY_IDS
+        )));
+    }
+
+    let records = state.db.find_issued_tokens(&request.recovery_ids).await?;
+    let tokens = records
+        .into_iter()
+        .map(|record| record.into_issued_token())
+        .collect::<ApiResult<Vec<_>>>()?;
+
+    Ok(RestoreResponse { tokens })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blinded(recovery_id: Option<Vec<u8>>) -> BlindedToken {
+        BlindedToken {
+            blinded_message: vec![1; 256],
+            denomination: 50,
+            currency: "USD".to_string(),
+            key_id: "key_001".to_string(),
+            recovery_id,
+        }
+    }
+
+    #[test]
+    fn test_recovery_ids_must_have_the_derived_length() {
+        assert!(
+            validate_recovery_ids(&[blinded(None), blinded(Some(vec![7; RECOVERY_ID_LEN]))])
+                .is_ok()
+        );
+        assert!(matches!(
+            validate_recovery_ids(&[blinded(Some(vec![7; 4096]))]),
+            Err(ApiError::InvalidRequest(_))
+        ));
+    }
+}
@@ -0,0 +1,601 @@
+//! Stores of spent token serials, the double-spend check.
+//!
+//! `SPENT_STORE` selects the backend: `memory` keeps serials in the process
+//! (tests and single-node development only; a restart forgets them),
+//! `redis` in Redis, `postgres` in the `tokens` table, and `layered` puts
+//! Redis in front of Postgres so most double spends are refused without a
+//! database round trip while Postgres stays the durable record.
+//!
+//! A redemption reserves its serials before its ledger transaction and
+//! releases them if that transaction fails. Postgres records serials inside
+//! the ledger transaction itself, so a serial is in `tokens` exactly when
+//! what it was spent on is committed; Redis and memory reservations are
+//! only a pre-check, and with `redis` or `memory` alone a crash between
+//! reservation and commit leaves the serials spent without a credit.
+//!
+//! A token's expiry epoch is bound into its signature and expired tokens are
+//! refused, so a serial only has to be remembered until its token expires.
+//! Postgres keeps serials in one partition of `tokens` per
+//! `SPENT_PARTITION_DAYS` expiry epochs and a background task drops each
+//! partition once its last epoch is `SPENT_PRUNE_MARGIN_DAYS` in the past;
+//! Redis keys expire the same margin after their token.
+
+use crate::cache::RedisCache;
+use crate::config::{Config, SpentStoreConfig};
+use crate::db::{self, Database, SpentToken, TokenPartition};
+use crate::error::{ApiError, ApiResult};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use ecash_core::{message, Token};
+use sqlx::PgConnection;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+#[async_trait]
+pub trait SpentSerialStore: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn is_spent(&self, token: &Token) -> ApiResult<bool>;
+
+    /// Marks every token spent, or none of them. Returns `false`, changing
+    /// nothing, if any of them is already spent.
+    async fn mark_all_spent(&self, tokens: &[Token]) -> ApiResult<bool>;
+
+    /// Undoes [`mark_all_spent`](Self::mark_all_spent) for a redemption that
+    /// could not be committed.
+    async fn unmark(&self, tokens: &[Token]) -> ApiResult<()>;
+
+    /// Reserves the tokens ahead of the redemption's ledger transaction.
+    /// Returns `false`, changing nothing, if any of them is already spent.
+    async fn reserve(&self, tokens: &[Token]) -> ApiResult<bool> {
+        self.mark_all_spent(tokens).await
+    }
+
+    /// Marks the reserved tokens spent in the ledger transaction `conn`.
+    /// Returns `false` if any of them is already spent, in which case the
+    /// transaction must be rolled back.
+    async fn mark_all_spent_in(
+        &self,
+        _conn: &mut PgConnection,
+        _tokens: &[Token],
+    ) -> ApiResult<bool> {
+        Ok(true)
+    }
+
+    /// Undoes [`reserve`](Self::reserve) after the ledger transaction was
+    /// rolled back.
+    async fn release(&self, tokens: &[Token]) -> ApiResult<()> {
+        self.unmark(tokens).await
+    }
+
+    /// Forgets the serials of tokens that expired before `expired_before`.
+    async fn prune(&self, expired_before: DateTime<Utc>) -> ApiResult<()>;
+}
+
+pub fn from_config(
+    config: &Config,
+    db: &Arc<Database>,
+    cache: Option<&Arc<RedisCache>>,
+) -> anyhow::Result<Arc<dyn SpentSerialStore>> {
+    let spent = &config.spent;
+    let backend = spent.backend.as_str();
+    let margin = Duration::days(spent.prune_margin_days);
+    let redis = || {
+        cache
+            .map(|cache| RedisSpentStore::new(cache.clone(), margin))
+            .ok_or_else(|| anyhow::anyhow!("SPENT_STORE={} requires REDIS_URL", backend))
+    };
+    // Tokens expire at the end of the epoch in which their lifetime ends.
+    let legacy_lifetime =
+        Duration::days(config.institution.token_expiry_days) + Duration::seconds(EPOCH_SECONDS);
+    let postgres = || PostgresSpentStore::new(db.clone(), spent.partition_days, legacy_lifetime);
+
+    Ok(match backend {
+        "memory" => Arc::new(MemorySpentStore::new()),
+        "redis" => Arc::new(redis()?),
+        "postgres" => Arc::new(postgres()),
+        "layered" => Arc::new(LayeredSpentStore::new(
+            Arc::new(redis()?),
+            Arc::new(postgres()),
+        )),
+        other => anyhow::bail!(
+            "Unknown SPENT_STORE {:?}; expected memory, redis, postgres or layered",
+            other
+        ),
+    })
+}
+
+const EPOCH_SECONDS: i64 = message::EXPIRY_EPOCH_SECONDS;
+
+/// Prunes `store` every `SPENT_PRUNE_INTERVAL_SECONDS`, unless that is `0`.
+pub fn spawn_pruning(store: Arc<dyn SpentSerialStore>, config: &SpentStoreConfig) {
+    if config.prune_interval_seconds == 0 {
+        tracing::info!("Pruning of spent serials is disabled");
+        return;
+    }
+
+    let margin = Duration::days(config.prune_margin_days);
+    let period = std::time::Duration::from_secs(config.prune_interval_seconds);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(error) = store.prune(Utc::now() - margin).await {
+                tracing::warn!("Failed to prune {} spent store: {}", store.name(), error);
+            }
+        }
+    });
+}
+
+/// The partition of `partition_days` epochs that `expiry_epoch` falls in.
+fn partition_of(expiry_epoch: u64, partition_days: u64) -> TokenPartition {
+    let start = expiry_epoch - expiry_epoch % partition_days;
+    TokenPartition {
+        start,
+        end: start + partition_days,
+    }
+}
+
+fn expiry_epoch(token: &Token) -> ApiResult<u64> {
+    message::epoch_of_expiry(&token.expires_at)
+        .map_err(|_| ApiError::InvalidRequest("Token expiry is not an epoch boundary".to_string()))
+}
+
+#[derive(Default)]
+pub struct MemorySpentStore {
+    serials: Mutex<HashMap<Vec<u8>, DateTime<Utc>>>,
+}
+
+impl MemorySpentStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl SpentSerialStore for MemorySpentStore {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn is_spent(&self, token: &Token) -> ApiResult<bool> {
+        Ok(self
+            .serials
+            .lock()
+            .unwrap()
+            .contains_key(&token.serial_number))
+    }
+
+    async fn mark_all_spent(&self, tokens: &[Token]) -> ApiResult<bool> {
+        let mut serials = self.serials.lock().unwrap();
+        if tokens
+            .iter()
+            .any(|token| serials.contains_key(&token.serial_number))
+        {
+            return Ok(false);
+        }
+        serials.extend(
+            tokens
+                .iter()
+                .map(|token| (token.serial_number.clone(), token.expires_at)),
+        );
+        Ok(true)
+    }
+
+    async fn unmark(&self, tokens: &[Token]) -> ApiResult<()> {
+        let mut serials = self.serials.lock().unwrap();
+        for token in tokens {
+            serials.remove(&token.serial_number);
+        }
+        Ok(())
+    }
+
+    async fn prune(&self, expired_before: DateTime<Utc>) -> ApiResult<()> {
+        self.serials
+            .lock()
+            .unwrap()
+            .retain(|_, expires_at| *expires_at >= expired_before);
+        Ok(())
+    }
+}
+
+pub struct RedisSpentStore {
+    cache: Arc<RedisCache>,
+    margin: Duration,
+}
+
+impl RedisSpentStore {
+    /// Keys are kept until `margin` after their token expires.
+    pub fn new(cache: Arc<RedisCache>, margin: Duration) -> Self {
+        Self { cache, margin }
+    }
+
+    fn ttl_seconds(&self, token: &Token, now: DateTime<Utc>) -> i64 {
+        (token.expires_at + self.margin - now).num_seconds().max(1)
+    }
+}
+
+#[async_trait]
+impl SpentSerialStore for RedisSpentStore {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    async fn is_spent(&self, token: &Token) -> ApiResult<bool> {
+        self.cache.is_token_spent(&token.serial_hex()).await
+    }
+
+    async fn mark_all_spent(&self, tokens: &[Token]) -> ApiResult<bool> {
+        let now = Utc::now();
+        let serials: Vec<(String, i64)> = tokens
+            .iter()
+            .map(|token| (token.serial_hex(), self.ttl_seconds(token, now)))
+            .collect();
+        self.cache.mark_all_spent(&serials).await
+    }
+
+    async fn unmark(&self, tokens: &[Token]) -> ApiResult<()> {
+        let serials_hex: Vec<String> = tokens.iter().map(Token::serial_hex).collect();
+        self.cache.unmark_spent(&serials_hex).await
+    }
+
+    async fn prune(&self, _expired_before: DateTime<Utc>) -> ApiResult<()> {
+        // Keys expire on their own.
+        Ok(())
+    }
+}
+
+pub struct PostgresSpentStore {
+    db: Arc<Database>,
+    partition_days: u64,
+    /// How long serials recorded before partitioning, which carry no
+    /// expiry, are kept after their redemption.
+    legacy_lifetime: Duration,
+    /// Partitions known to exist, by first epoch.
+    partitions: Mutex<HashSet<u64>>,
+}
+
+impl PostgresSpentStore {
+    pub fn new(db: Arc<Database>, partition_days: u64, legacy_lifetime: Duration) -> Self {
+        Self {
+            db,
+            partition_days: partition_days.max(1),
+            legacy_lifetime,
+            partitions: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn spent_tokens<'a>(&self, tokens: &'a [Token]) -> ApiResult<Vec<SpentToken<'a>>> {
+        tokens
+            .iter()
+            .map(|token| {
+                Ok(SpentToken {
+                    serial_number: &token.serial_number,
+                    serial_hex: token.serial_hex(),
+                    expiry_epoch: expiry_epoch(token)?,
+                    key_id: &token.key_id,
+                    denomination: token.denomination,
+                    currency: &token.currency,
+                })
+            })
+            .collect()
+    }
+
+    async fn ensure_partitions(&self, tokens: &[SpentToken<'_>]) -> ApiResult<()> {
+        let missing: HashSet<u64> = {
+            let known = self.partitions.lock().unwrap();
+            tokens
+                .iter()
+                .map(|token| partition_of(token.expiry_epoch, self.partition_days).start)
+                .filter(|start| !known.contains(start))
+                .collect()
+        };
+
+        for start in missing {
+            self.db
+                .create_token_partition(&partition_of(start, self.partition_days))
+                .await?;
+            self.partitions.lock().unwrap().insert(start);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SpentSerialStore for PostgresSpentStore {
+    fn name(&self) -> &'static str {
+        "postgres"
+    }
+
+    async fn is_spent(&self, token: &Token) -> ApiResult<bool> {
+        self.db
+            .check_token_spent(&token.serial_hex(), expiry_epoch(token)?)
+            .await
+    }
+
+    async fn mark_all_spent(&self, tokens: &[Token]) -> ApiResult<bool> {
+        let spent = self.spent_tokens(tokens)?;
+        self.ensure_partitions(&spent).await?;
+        self.db.mark_tokens_spent(&spent).await
+    }
+
+    async fn unmark(&self, tokens: &[Token]) -> ApiResult<()> {
+        self.db
+            .unmark_tokens_spent(&self.spent_tokens(tokens)?)
+            .await
+    }
+
+    async fn reserve(&self, tokens: &[Token]) -> ApiResult<bool> {
+        // Partitions are DDL, so they are created outside the ledger
+        // transaction; the serials themselves are checked inside it.
+        self.ensure_partitions(&self.spent_tokens(tokens)?).await?;
+        Ok(true)
+    }
+
+    async fn mark_all_spent_in(
+        &self,
+        conn: &mut PgConnection,
+        tokens: &[Token],
+    ) -> ApiResult<bool> {
+        db::insert_spent_tokens(conn, &self.spent_tokens(tokens)?).await
+    }
+
+    async fn release(&self, _tokens: &[Token]) -> ApiResult<()> {
+        // Nothing was written outside the rolled-back transaction.
+        Ok(())
+    }
+
+    async fn prune(&self, expired_before: DateTime<Utc>) -> ApiResult<()> {
+        for partition in self.db.list_token_partitions().await? {
+            // The partition's last epoch is `end - 1`.
+            let expired = message::epoch_expiry(partition.end.saturating_sub(1))
+                .is_ok_and(|expires_at| expires_at < expired_before);
+            if !expired {
+                continue;
+            }
+
+            self.db.drop_token_partition(&partition).await?;
+            self.partitions.lock().unwrap().remove(&partition.start);
+            tracing::info!(
+                "Dropped expired spent-serial partition {}",
+                partition.name()
+            );
+        }
+
+        let deleted = self
+            .db
+            .delete_legacy_tokens(expired_before - self.legacy_lifetime)
+            .await?;
+        if deleted > 0 {
+            tracing::info!(
+                "Deleted {} expired spent serials without an expiry",
+                deleted
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A fast `front` store backed by an authoritative `back` store. Serials are
+/// marked in both and are spent if either store says so; while the front is
+/// unavailable the back store alone decides.
+pub struct LayeredSpentStore {
+    front: Arc<dyn SpentSerialStore>,
+    back: Arc<dyn SpentSerialStore>,
+}
+
+impl LayeredSpentStore {
+    pub fn new(front: Arc<dyn SpentSerialStore>, back: Arc<dyn SpentSerialStore>) -> Self {
+        Self { front, back }
+    }
+}
+
+#[async_trait]
+impl SpentSerialStore for LayeredSpentStore {
+    fn name(&self) -> &'static str {
+        "layered"
+    }
+
+    async fn is_spent(&self, token: &Token) -> ApiResult<bool> {
+        match self.front.is_spent(token).await {
+            Ok(true) => Ok(true),
+            Ok(false) => self.back.is_spent(token).await,
+            Err(error) => {
+                tracing::warn!(
+                    "{} spent store unavailable, checking {}: {}",
+                    self.front.name(),
+                    self.back.name(),
+                    error
+                );
+                self.back.is_spent(token).await
+            }
+        }
+    }
+
+    async fn mark_all_spent(&self, tokens: &[Token]) -> ApiResult<bool> {
+        let reserved = match self.front.mark_all_spent(tokens).await {
+            Ok(false) => return Ok(false),
+            Ok(true) => true,
+            Err(error) => {
+                tracing::warn!(
+                    "{} spent store unavailable, marking in {} only: {}",
+                    self.front.name(),
+                    self.back.name(),
+                    error
+                );
+                false
+            }
+        };
+
+        let marked = self.back.mark_all_spent(tokens).await;
+        if reserved && !matches!(marked, Ok(true)) {
+            if let Err(release_error) = self.front.unmark(tokens).await {
+                // The back store decides, so the serials are only refused
+                // until the front store forgets them.
+                tracing::error!(
+                    "Failed to release {} reservation: {}",
+                    self.front.name(),
+                    release_error
+                );
+            }
+        }
+        marked
+    }
+
+    async fn unmark(&self, tokens: &[Token]) -> ApiResult<()> {
+        let front = self.front.unmark(tokens).await;
+        self.back.unmark(tokens).await?;
+        front
+    }
+
+    async fn reserve(&self, tokens: &[Token]) -> ApiResult<bool> {
+        let reserved = match self.front.reserve(tokens).await {
+            Ok(false) => return Ok(false),
+            Ok(true) => true,
+            Err(error) => {
+                tracing::warn!(
+                    "{} spent store unavailable, reserving in {} only: {}",
+                    self.front.name(),
+                    self.back.name(),
+                    error
+                );
+                false
+            }
+        };
+
+        let back = self.back.reserve(tokens).await;
+        if reserved && !matches!(back, Ok(true)) {
+            if let Err(release_error) = self.front.release(tokens).await {
+                tracing::error!(
+                    "Failed to release {} reservation: {}",
+                    self.front.name(),
+                    release_error
+                );
+            }
+        }
+        back
+    }
+
+    async fn mark_all_spent_in(
+        &self,
+        conn: &mut PgConnection,
+        tokens: &[Token],
+    ) -> ApiResult<bool> {
+        self.back.mark_all_spent_in(conn, tokens).await
+    }
+
+    async fn release(&self, tokens: &[Token]) -> ApiResult<()> {
+        let front = self.front.release(tokens).await;
+        self.back.release(tokens).await?;
+        front
+    }
+
+    async fn prune(&self, expired_before: DateTime<Utc>) -> ApiResult<()> {
+        let front = self.front.prune(expired_before).await;
+        self.back.prune(expired_before).await?;
+        front
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn token(serial: u8) -> Token {
+        Token {
+            serial_number: vec![serial; 32],
+            denomination: 10,
+            currency: "USD".to_string(),
+            signature: vec![],
+            issued_at: Utc::now(),
+            expires_at: Utc::now(),
+            institution_id: "inst_test".to_string(),
+            key_id: "key_001".to_string(),
+            msg_prefix: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_marks_all_or_nothing() {
+        let store = MemorySpentStore::new();
+        let batch = [token(1), token(2)];
+
+        assert!(store.mark_all_spent(&batch).await.unwrap());
+        assert!(store.is_spent(&token(1)).await.unwrap());
+
+        assert!(!store.mark_all_spent(&[token(3), token(2)]).await.unwrap());
+        assert!(!store.is_spent(&token(3)).await.unwrap());
+
+        store.unmark(&batch).await.unwrap();
+        assert!(!store.is_spent(&token(1)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_layered_store_releases_front_when_back_refuses() {
+        let front = Arc::new(MemorySpentStore::new());
+        let back = Arc::new(MemorySpentStore::new());
+        let store = LayeredSpentStore::new(front.clone(), back.clone());
+
+        // Spent according to the back store only, e.g. after the front store
+        // lost its data.
+        back.mark_all_spent(&[token(2)]).await.unwrap();
+
+        assert!(store.is_spent(&token(2)).await.unwrap());
+        assert!(!store.mark_all_spent(&[token(1), token(2)]).await.unwrap());
+        assert!(!front.is_spent(&token(1)).await.unwrap());
+
+        assert!(store.mark_all_spent(&[token(1)]).await.unwrap());
+        assert!(front.is_spent(&token(1)).await.unwrap());
+        assert!(back.is_spent(&token(1)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_layered_store_releases_both_reservations() {
+        let front = Arc::new(MemorySpentStore::new());
+        let back = Arc::new(MemorySpentStore::new());
+        let store = LayeredSpentStore::new(front.clone(), back.clone());
+        let batch = [token(1), token(2)];
+
+        assert!(store.reserve(&batch).await.unwrap());
+        assert!(front.is_spent(&token(1)).await.unwrap());
+        assert!(!store.reserve(&[token(2)]).await.unwrap());
+
+        store.release(&batch).await.unwrap();
+        assert!(!store.is_spent(&token(1)).await.unwrap());
+        assert!(store.reserve(&batch).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_prunes_expired_serials() {
+        let store = MemorySpentStore::new();
+        let now = Utc::now();
+        let mut expired = token(1);
+        expired.expires_at = now - Duration::days(8);
+        let current = token(2);
+
+        store.mark_all_spent(&[expired, current]).await.unwrap();
+        store.prune(now - Duration::days(7)).await.unwrap();
+
+        assert!(!store.is_spent(&token(1)).await.unwrap());
+        assert!(store.is_spent(&token(2)).await.unwrap());
+    }
+
+    #[test]
+    fn test_partitions_cover_whole_epoch_ranges() {
+        let partition = partition_of(20_003, 7);
+        assert_eq!(
+            partition,
+            TokenPartition {
+                start: 19_999,
+                end: 20_006
+            }
+        );
+        assert_eq!(partition_of(19_999, 7), partition);
+        assert_eq!(partition_of(20_006, 7).start, 20_006);
+
+        assert_eq!(partition.name(), "tokens_epochs_19999_20006");
+        assert_eq!(TokenPartition::parse(&partition.name()), Some(partition));
+        assert_eq!(TokenPartition::parse("tokens_legacy"), None);
+    }
+}
@@ -1,63 +1,182 @@
 use crate::cache::RedisCache;
 use crate::config::Config;
 use crate::db::Database;
-use crate::error::ApiResult;
-use ecash_core::Institution;
-use rsa::{RsaPrivateKey, RsaPublicKey};
+use crate::error::{ApiError, ApiResult};
+use crate::metrics::Metrics;
+use crate::payouts::PayoutAdapter;
+use crate::ratelimit::RateLimiter;
+use crate::spent::{self, SpentSerialStore};
+use ecash_core::{Amount, Currency, Institution};
+use rsa::RsaPrivateKey;
 use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct AppState {
     pub institution: Arc<Institution>,
-    pub public_key: Arc<RsaPublicKey>,
+    /// Signs the key directory; none is published without it.
+    pub identity_key: Option<Arc<RsaPrivateKey>>,
     pub db: Arc<Database>,
-    pub cache: Arc<RedisCache>,
+    pub cache: Option<Arc<RedisCache>>,
+    pub spent: Arc<dyn SpentSerialStore>,
     pub config: Arc<Config>,
+    pub payouts: Arc<dyn PayoutAdapter>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub metrics: Arc<Metrics>,
 }
 
 impl AppState {
     pub async fn new(
         institution: Institution,
-        public_key: RsaPublicKey,
+        identity_key: Option<RsaPrivateKey>,
         db: Database,
-        cache: RedisCache,
+        cache: Option<RedisCache>,
         config: Config,
-    ) -> Self {
-        Self {
+        payouts: Arc<dyn PayoutAdapter>,
+        metrics: Arc<Metrics>,
+    ) -> anyhow::Result<Self> {
+        let db = Arc::new(db);
+        let cache = cache.map(Arc::new);
+        let spent = spent::from_config(&config, &db, cache.as_ref())?;
+
+        let rate_limit_cache =
+            match config.rate_limits.backend.as_str() {
+                "redis" => Some(cache.clone().ok_or_else(|| {
+                    anyhow::anyhow!("RATE_LIMIT_BACKEND=redis requires REDIS_URL")
+                })?),
+                "memory" => None,
+                other => anyhow::bail!(
+                    "Unknown RATE_LIMIT_BACKEND {:?}; expected redis or memory",
+                    other
+                ),
+            };
+        let rate_limiter = RateLimiter::new(config.rate_limits.clone(), rate_limit_cache);
+
+        Ok(Self {
             institution: Arc::new(institution),
-            public_key: Arc::new(public_key),
-            db: Arc::new(db),
-            cache: Arc::new(cache),
+            identity_key: identity_key.map(Arc::new),
+            db,
+            cache,
+            spent,
             config: Arc::new(config),
-        }
+            payouts,
+            rate_limiter: Arc::new(rate_limiter),
+            metrics,
+        })
     }
 
     pub fn institution_id(&self) -> &str {
         &self.config.institution.institution_id
     }
 
-    pub fn key_id(&self) -> &str {
-        &self.config.institution.key_id
+    pub fn currency(&self, code: &str) -> ApiResult<&Currency> {
+        self.institution.currency(code).map_err(ApiError::Ecash)
     }
 
-    pub fn denominations(&self) -> &[u64] {
-        &self.config.institution.denominations
+    /// Reads an amount sent by a client as one of `currency`, the currency
+    /// of the account it is paid from or into.
+    pub fn account_amount(&self, currency: &str, amount: &Amount) -> ApiResult<Amount> {
+        if amount.currency() != currency {
+            return Err(ApiError::CurrencyMismatch {
+                expected: currency.to_string(),
+                found: amount.currency().to_string(),
+            });
+        }
+        let amount = self.currency(currency)?.normalize(amount)?;
+        if amount.is_zero() {
+            return Err(ApiError::InvalidRequest(
+                "Amount must be positive".to_string(),
+            ));
+        }
+        Ok(amount)
     }
 
-    pub fn is_valid_denomination(&self, denomination: u64) -> bool {
-        self.denominations().contains(&denomination)
+    pub fn is_valid_denomination(&self, currency: &str, denomination: u64) -> bool {
+        self.institution
+            .validate_denomination(currency, denomination)
+            .is_ok()
     }
 }
 
-pub fn generate_or_load_keys() -> ApiResult<(RsaPrivateKey, RsaPublicKey)> {
-    tracing::info!("Generating new RSA key pair (3072-bit)...");
-
-    let mut rng = rand::thread_rng();
-    let private_key = RsaPrivateKey::new(&mut rng, 3072)
-        .map_err(|e| crate::error::ApiError::Internal(format!("Key generation failed: {}", e)))?;
+#[cfg(test)]
+impl AppState {
+    /// A state for handler tests: serials are kept in memory and the
+    /// database pool never connects, so only paths that fail before reaching
+    /// Postgres can be exercised.
+    pub fn for_tests(institution: Institution) -> Self {
+        use crate::config::*;
+        use crate::payouts::MockPayoutAdapter;
+        use crate::spent::MemorySpentStore;
+        use sqlx::postgres::PgPoolOptions;
 
-    let public_key = private_key.to_public_key();
+        let config = Config {
+            server: ServerConfig {
+                host: "127.0.0.1".to_string(),
+                port: 0,
+            },
+            database: DatabaseConfig {
+                url: "postgres://localhost/ecash_test".to_string(),
+                max_connections: 1,
+            },
+            redis: RedisConfig { url: None },
+            spent: SpentStoreConfig {
+                backend: "memory".to_string(),
+                partition_days: 7,
+                prune_margin_days: 7,
+                prune_interval_seconds: 0,
+            },
+            institution: InstitutionConfig {
+                institution_id: institution.institution_id().to_string(),
+                key_id: "key_001".to_string(),
+                token_expiry_days: 90,
+                currencies: institution.currencies().to_vec(),
+                exchange_fee: 0,
+                max_withdraw_tokens: 100,
+            },
+            keys: KeyConfig {
+                private_key_dir: None,
+                encryption_secret: None,
+                generate: false,
+                bits: 2048,
+                expires_at: None,
+                directory_ttl_seconds: 3600,
+            },
+            idempotency: IdempotencyConfig {
+                ttl_seconds: 86400,
+                lock_timeout_seconds: 60,
+            },
+            auth: AuthConfig {
+                admin_api_key: None,
+            },
+            payouts: PayoutConfig {
+                adapter: "mock".to_string(),
+                file_path: String::new(),
+            },
+            rate_limits: RateLimitConfig {
+                backend: "memory".to_string(),
+                window_seconds: 60,
+                withdraw: 0,
+                redeem: 0,
+                verify: 0,
+                exchange: 0,
+                restore: 0,
+                trust_forwarded_for: false,
+            },
+        };
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let pool = PgPoolOptions::new()
+            .connect_lazy(&config.database.url)
+            .unwrap();
 
-    tracing::info!("RSA key pair generated successfully");
-    Ok((private_key, public_key))
+        Self {
+            institution: Arc::new(institution),
+            identity_key: None,
+            db: Arc::new(Database::new(pool, metrics.clone())),
+            cache: None,
+            spent: Arc::new(MemorySpentStore::new()),
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limits.clone(), None)),
+            config: Arc::new(config),
+            payouts: Arc::new(MockPayoutAdapter::new()),
+            metrics,
+        }
+    }
 }
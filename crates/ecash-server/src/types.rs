@@ -1,43 +1,85 @@
-use ecash_core::{BlindSignature, BlindedToken, Token};
+use crate::models::AccountKind;
+use chrono::{DateTime, Utc};
+use ecash_core::{
+    Amount, BlindSignature, BlindedToken, Currency, IssuedToken, Jwk, KeyStatus, RsaBssaVariant,
+    Token,
+};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Deserialize)]
+/// Blinded tokens may mix denominations; together they must be worth
+/// exactly `amount`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WithdrawRequest {
-    pub amount: u64,
-    pub denomination: u64,
+    pub amount: Amount,
+    pub expiry_epoch: u64,
     pub blinded_tokens: Vec<BlindedToken>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WithdrawResponse {
     pub blind_signatures: Vec<BlindSignature>,
     pub key_id: String,
     pub expires_at: String,
     pub transaction_id: String,
+    /// Account balance after the withdrawal was debited.
+    pub balance: Amount,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RedeemRequest {
     pub tokens: Vec<Token>,
-    pub merchant_id: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RedeemResponse {
     pub accepted_count: usize,
-    pub total_amount: u64,
+    pub total_amount: Amount,
     pub transaction_id: String,
     pub timestamp: String,
+    /// Balance of the redeeming account after it was credited.
+    pub balance: Amount,
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct PublicKeyResponse {
     pub key_id: String,
     pub institution_id: String,
-    pub public_key_n: String,
-    pub public_key_e: String,
+    /// The default currency and its denominations, for clients that predate
+    /// `currencies`.
+    pub currency: String,
     pub denominations: Vec<u64>,
+    /// Every currency issued, with its exponent and denominations.
+    pub currencies: Vec<Currency>,
+    pub variant: RsaBssaVariant,
+    pub expiry_epoch: u64,
+    /// Kept from the inputs of every `/api/v1/exchange`, in minor units of
+    /// the exchanged currency.
+    pub exchange_fee: u64,
     pub expires_at: Option<String>,
+    /// Keys of the active key set, one per denomination of each currency.
+    pub public_keys: Vec<DenominationKeyInfo>,
+    pub keys: Vec<KeyInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyInfo {
+    pub key_id: String,
+    pub status: KeyStatus,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub revoked_at: Option<String>,
+    pub public_keys: Vec<DenominationKeyInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DenominationKeyInfo {
+    pub currency: String,
+    pub denomination: u64,
+    /// Decimal modulus and exponent, for clients that predate `jwk`.
+    pub public_key_n: String,
+    pub public_key_e: String,
+    /// The same key as a JWK, with its RFC 7638 thumbprint as `kid`.
+    pub jwk: Jwk,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -60,3 +102,99 @@ pub struct VerifyResponse {
     pub spent: bool,
     pub message: String,
 }
+
+/// Recovery ids derived from a wallet's seed, at most
+/// `restore::MAX_RECOVERY_IDS` of them.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RestoreRequest {
+    pub recovery_ids: Vec<Vec<u8>>,
+}
+
+/// The stored issuance records; ids with none are left out.
+#[derive(Debug, Clone, Serialize)]
+pub struct RestoreResponse {
+    pub tokens: Vec<IssuedToken>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountResponse {
+    pub account_id: String,
+    pub name: String,
+    pub kind: AccountKind,
+    pub currency: String,
+    pub balance: Amount,
+    pub updated_at: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateAccountRequest {
+    pub name: String,
+    #[serde(default = "default_account_kind")]
+    pub kind: AccountKind,
+    /// Defaults to the institution's first currency.
+    pub currency: Option<String>,
+}
+
+fn default_account_kind() -> AccountKind {
+    AccountKind::Customer
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CreateAccountResponse {
+    pub account: AccountResponse,
+    /// Shown only once; the server keeps just its hash.
+    pub api_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DepositRequest {
+    /// Must be in the account's currency.
+    pub amount: Amount,
+}
+
+/// Settlement period; defaults to the 24 hours before `to`, which defaults to
+/// now.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SettlementQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SettlementResponse {
+    pub account_id: String,
+    pub currency: String,
+    pub from: String,
+    pub to: String,
+    pub redemption_count: usize,
+    pub token_count: u64,
+    pub total_amount: Amount,
+    pub redemptions: Vec<RedemptionInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RedemptionInfo {
+    pub transaction_id: String,
+    pub amount: Amount,
+    pub token_count: u64,
+    pub timestamp: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PayoutRequest {
+    pub amount: Amount,
+    /// Adapter-specific account to pay into.
+    pub destination: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PayoutResponse {
+    pub payout_id: String,
+    pub amount: Amount,
+    pub destination: String,
+    /// `pending`, `completed` or `failed`; failed payouts were credited back.
+    pub status: String,
+    pub reference: Option<String>,
+    pub error: Option<String>,
+    pub created_at: String,
+}
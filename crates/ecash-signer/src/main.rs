@@ -0,0 +1,146 @@
+//! A signer node of a threshold signing committee.
+//!
+//! `ecash-signer deal <threshold> <signers> <bits> <dir>` generates a key and
+//! writes its public half and one file per share to `dir`. Each share then
+//! goes to its own node, which `ecash-signer serve` runs: it answers
+//! partial signature requests over blinded messages, which a combiner
+//! assembles with `ecash_core::ThresholdPublicKey::combine`.
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use ecash_core::{threshold, EcashError, KeyShare, PartialSignature, ThresholdPublicKey};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+#[derive(Debug, Deserialize)]
+struct SignRequest {
+    blinded_message: Vec<u8>,
+}
+
+#[derive(Debug, Serialize)]
+struct HealthResponse {
+    status: String,
+    index: usize,
+    threshold: usize,
+    signers: usize,
+}
+
+struct SignError(EcashError);
+
+impl IntoResponse for SignError {
+    fn into_response(self) -> Response {
+        let body = Json(serde_json::json!({ "error": self.0.to_string() }));
+        (StatusCode::BAD_REQUEST, body).into_response()
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "info,ecash_signer=debug".into()),
+        )
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("deal") => deal(&args[1..]),
+        Some("serve") | None => serve().await,
+        Some(command) => anyhow::bail!("Unknown command {}; expected deal or serve", command),
+    }
+}
+
+fn deal(args: &[String]) -> anyhow::Result<()> {
+    let [threshold, signers, bits, dir] = args else {
+        anyhow::bail!("Usage: ecash-signer deal <threshold> <signers> <bits> <dir>");
+    };
+    let dir = Path::new(dir);
+
+    tracing::info!("Generating a {}-bit key from safe primes", bits);
+    let (public_key, shares) =
+        threshold::deal(bits.parse()?, threshold.parse()?, signers.parse()?)?;
+
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(
+        dir.join("public_key.json"),
+        serde_json::to_vec_pretty(&public_key)?,
+    )?;
+    for share in &shares {
+        let path = dir.join(format!("share_{}.json", share.index));
+        write_secret(&path, &serde_json::to_vec_pretty(share)?)?;
+        tracing::info!("Wrote share {} to {}", share.index, path.display());
+    }
+
+    Ok(())
+}
+
+/// Writes a file readable only by its owner.
+fn write_secret(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    std::io::Write::write_all(&mut options.open(path)?, contents)
+}
+
+async fn serve() -> anyhow::Result<()> {
+    let share_file = std::env::var("SIGNER_SHARE_FILE")
+        .map_err(|_| anyhow::anyhow!("SIGNER_SHARE_FILE must be set"))?;
+    let share: KeyShare = serde_json::from_slice(&std::fs::read(&share_file)?)?;
+    let addr: SocketAddr = std::env::var("SIGNER_BIND_ADDRESS")
+        .unwrap_or_else(|_| "127.0.0.1:8090".to_string())
+        .parse()?;
+
+    tracing::info!(
+        "Holding share {} of a {}-of-{} key",
+        share.index,
+        share.public_key.threshold,
+        share.public_key.signers
+    );
+
+    let app = Router::new()
+        .route("/health", get(health_check))
+        .route("/api/v1/public-key", get(get_public_key))
+        .route("/api/v1/sign", post(sign_partial))
+        .with_state(Arc::new(share));
+
+    tracing::info!("Signer listening on {}", addr);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+async fn health_check(State(share): State<Arc<KeyShare>>) -> Json<HealthResponse> {
+    Json(HealthResponse {
+        status: "running".to_string(),
+        index: share.index,
+        threshold: share.public_key.threshold,
+        signers: share.public_key.signers,
+    })
+}
+
+async fn get_public_key(State(share): State<Arc<KeyShare>>) -> Json<ThresholdPublicKey> {
+    Json(share.public_key.clone())
+}
+
+async fn sign_partial(
+    State(share): State<Arc<KeyShare>>,
+    Json(request): Json<SignRequest>,
+) -> Result<Json<PartialSignature>, SignError> {
+    let partial = share
+        .sign_partial(&request.blinded_message)
+        .map_err(SignError)?;
+    tracing::debug!("Signed a partial signature with share {}", share.index);
+
+    Ok(Json(partial))
+}
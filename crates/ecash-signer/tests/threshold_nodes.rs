@@ -0,0 +1,197 @@
+//! Deals a 3-of-5 key with the `ecash-signer` binary, runs each share in
+//! its own node process, and signs through them over HTTP.
+
+use ecash_core::{
+    BlindUser, EcashError, KeyShare, PartialSignature, RsaBssaVariant, ThresholdPublicKey,
+};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+const THRESHOLD: usize = 3;
+const SIGNERS: usize = 5;
+
+/// Node processes, killed when the test ends however it ends.
+struct Nodes {
+    dir: PathBuf,
+    children: Vec<Child>,
+    urls: Vec<String>,
+}
+
+impl Drop for Nodes {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn signer() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_ecash-signer"))
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> T {
+    serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap()
+}
+
+async fn start_nodes(name: &str) -> (Nodes, ThresholdPublicKey) {
+    let dir = std::env::temp_dir().join(format!("ecash-signer-{}-{}", name, std::process::id()));
+    let mut nodes = Nodes {
+        dir: dir.clone(),
+        children: Vec::new(),
+        urls: Vec::new(),
+    };
+
+    let status = signer()
+        .args(["deal", &THRESHOLD.to_string(), &SIGNERS.to_string(), "1024"])
+        .arg(&dir)
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let public_key: ThresholdPublicKey = read_json(&dir.join("public_key.json"));
+
+    for index in 1..=SIGNERS {
+        let share_file = dir.join(format!("share_{}.json", index));
+        assert_eq!(read_json::<KeyShare>(&share_file).index, index);
+
+        let addr = format!("127.0.0.1:{}", free_port());
+        let child = signer()
+            .arg("serve")
+            .env("SIGNER_SHARE_FILE", &share_file)
+            .env("SIGNER_BIND_ADDRESS", &addr)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        nodes.children.push(child);
+        nodes.urls.push(format!("http://{}", addr));
+    }
+
+    let client = reqwest::Client::new();
+    for url in &nodes.urls {
+        let mut attempts = 0;
+        while client.get(format!("{}/health", url)).send().await.is_err() {
+            attempts += 1;
+            assert!(attempts < 100, "signer at {} did not start", url);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let served: ThresholdPublicKey = client
+            .get(format!("{}/api/v1/public-key", url))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(served, public_key);
+    }
+
+    (nodes, public_key)
+}
+
+async fn request_partials(urls: &[&String], blinded_message: &[u8]) -> Vec<PartialSignature> {
+    let client = reqwest::Client::new();
+    let mut partials = Vec::new();
+    for url in urls {
+        let response = client
+            .post(format!("{}/api/v1/sign", url))
+            .json(&serde_json::json!({ "blinded_message": blinded_message }))
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        partials.push(response.json().await.unwrap());
+    }
+    partials
+}
+
+/// Every way of choosing `size` of the first `count` indices.
+fn subsets(count: usize, size: usize) -> Vec<Vec<usize>> {
+    (0u32..1 << count)
+        .filter(|mask| mask.count_ones() as usize == size)
+        .map(|mask| (0..count).filter(|i| mask & (1 << i) != 0).collect())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_any_threshold_of_nodes_signs() {
+    let (nodes, public_key) = start_nodes("any").await;
+
+    let user = BlindUser::new(
+        public_key.public_key().unwrap(),
+        RsaBssaVariant::Sha384PssRandomized,
+    );
+    let msg_prefix = user.variant().generate_msg_prefix();
+    let prepared = user.variant().prepare(&msg_prefix, b"token").unwrap();
+    let (blinded, inverse) = user.blind_message(&prepared).unwrap();
+
+    for subset in subsets(SIGNERS, THRESHOLD) {
+        let urls: Vec<&String> = subset.iter().map(|&i| &nodes.urls[i]).collect();
+        let partials = request_partials(&urls, &blinded).await;
+
+        let blind_signature = public_key.combine(&blinded, &partials).unwrap();
+        let signature = user
+            .finalize(&prepared, &blind_signature, &inverse)
+            .unwrap();
+        assert!(user.verify_signature(&prepared, &signature));
+    }
+
+    for subset in subsets(SIGNERS, THRESHOLD - 1) {
+        let urls: Vec<&String> = subset.iter().map(|&i| &nodes.urls[i]).collect();
+        let partials = request_partials(&urls, &blinded).await;
+
+        assert!(matches!(
+            public_key.combine(&blinded, &partials),
+            Err(EcashError::InsufficientShares)
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_signing_survives_losing_nodes_down_to_threshold() {
+    let (mut nodes, public_key) = start_nodes("survives").await;
+    let user = BlindUser::new(
+        public_key.public_key().unwrap(),
+        RsaBssaVariant::Sha384PssDeterministic,
+    );
+    let prepared = user.variant().prepare(&[], b"token").unwrap();
+    let (blinded, _) = user.blind_message(&prepared).unwrap();
+
+    // The combiner asks every node and uses whichever answer.
+    let client = reqwest::Client::new();
+    for stopped in 0..SIGNERS {
+        let mut partials = Vec::new();
+        for url in &nodes.urls {
+            let response = client
+                .post(format!("{}/api/v1/sign", url))
+                .json(&serde_json::json!({ "blinded_message": blinded }))
+                .send()
+                .await;
+            if let Ok(response) = response {
+                partials.push(response.json::<PartialSignature>().await.unwrap());
+            }
+        }
+        assert_eq!(partials.len(), SIGNERS - stopped);
+
+        let combined = public_key.combine(&blinded, &partials);
+        if partials.len() >= THRESHOLD {
+            assert!(combined.is_ok());
+        } else {
+            assert!(matches!(combined, Err(EcashError::InsufficientShares)));
+        }
+
+        nodes.children[stopped].kill().unwrap();
+        nodes.children[stopped].wait().unwrap();
+    }
+}